@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return)]
+
 #[macro_use]
 extern crate slog;
 
@@ -8,12 +10,13 @@ use simple_error::bail;
 use slog::Drain;
 use std::os::unix::io::AsRawFd;
 
-use raphdb::{client, server, Result};
+use raphdb::{client, inspect, server, Result};
 
 pub async fn exec(logger: slog::Logger, matches: &clap::ArgMatches<'_>) -> Result<()> {
     match matches.subcommand() {
         (server::CMD_NAME, Some(matches)) => server::run(logger, matches).await,
         (client::CMD_NAME, Some(matches)) => client::run(logger, matches).await,
+        (name, Some(matches)) => inspect::run(logger, name, matches).await,
         ("", None) => bail!("no subcommand was used"),
         _ => unreachable!("match arms should cover all the possible cases"),
     }
@@ -27,6 +30,7 @@ pub async fn set_up_logger_and_exec() -> i32 {
         .version(env!("CARGO_PKG_VERSION"))
         .subcommand(server::cmd())
         .subcommand(client::cmd())
+        .subcommands(inspect::cmds())
         .get_matches();
 
     let stderr = std::io::stderr();
@@ -1,16 +1,41 @@
 use crate::connection::{
-    cmd::{Get, Set},
+    cmd::{Cdc, Cluster, Del, Discard, Exec, Expire, Get, Multi, Persist, Psync, Publish, Save, Scan, Set, Subscribe, Ttl, Unwatch, Watch},
     Connection, Frame,
 };
+use crate::server::key_value_store::{Change, Expiry, ScanOptions};
 
 use bytes::Bytes;
 use std::io::{Error, ErrorKind};
+use std::time::{Duration, UNIX_EPOCH};
 use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_stream::Stream;
 
 pub struct Client {
     connection: Connection,
 }
 
+/// A message received by a subscription.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub channel: String,
+
+    /// The pattern `channel` matched, `None` for a channel subscription.
+    pub pattern: Option<String>,
+
+    pub content: Bytes,
+}
+
+/// How a replica catches up with its primary, see `Client::psync`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resync {
+    /// The primary sent every live key as of the change numbered `seq`. They
+    /// replace the keys of the replica.
+    Full { seq: u64, entries: Vec<Change> },
+
+    /// The primary streams the changes following the offset of the replica.
+    Partial,
+}
+
 pub async fn connect<T: ToSocketAddrs>(addr: T) -> crate::Result<Client> {
     let socket = TcpStream::connect(addr).await?;
     let connection = Connection::new(socket);
@@ -32,11 +57,17 @@ impl Client {
     }
 
     pub async fn set(&mut self, key: &str, value: Bytes) -> crate::Result<()> {
-        self.set_cmd(Set::new(key, value)).await
+        self.set_cmd(Set::new(key, value, None)).await
+    }
+
+    /// Set `key` to hold `value`, the key is removed once `expiration` has
+    /// elapsed.
+    pub async fn set_expires(&mut self, key: &str, value: Bytes, expiration: Duration) -> crate::Result<()> {
+        self.set_cmd(Set::new(key, value, Some(expiration))).await
     }
 
     async fn set_cmd(&mut self, cmd: Set) -> crate::Result<()> {
-        let frame = cmd.into_frame();
+        let frame = cmd.into_frame()?;
         self.connection.write_frame(&frame).await?;
 
         match self.read_response().await? {
@@ -45,6 +76,284 @@ impl Client {
         }
     }
 
+    /// Remove `keys`. Returns the number of keys that existed.
+    pub async fn del(&mut self, keys: &[&str]) -> crate::Result<u64> {
+        let frame = Del::new(keys).into_frame();
+        self.connection.write_frame(&frame).await?;
+
+        match self.read_response().await? {
+            Frame::Integer(count) if count >= 0 => Ok(count as u64),
+            frame => Err(frame.to_error()),
+        }
+    }
+
+    /// Remove `key` once `expiration` has elapsed. Returns `false` if the key
+    /// does not exist.
+    pub async fn expire(&mut self, key: &str, expiration: Duration) -> crate::Result<bool> {
+        let frame = Expire::new(key, expiration).into_frame()?;
+        self.connection.write_frame(&frame).await?;
+        self.read_bool().await
+    }
+
+    /// How long `key` has left to live.
+    pub async fn ttl(&mut self, key: &str) -> crate::Result<Expiry> {
+        let frame = Ttl::new(key).into_frame();
+        self.connection.write_frame(&frame).await?;
+
+        match self.read_response().await? {
+            Frame::Integer(-2) => Ok(Expiry::Missing),
+            Frame::Integer(-1) => Ok(Expiry::Persistent),
+            Frame::Integer(millis) if millis >= 0 => Ok(Expiry::After(Duration::from_millis(millis as u64))),
+            frame => Err(frame.to_error()),
+        }
+    }
+
+    /// Remove the expiration of `key`. Returns `false` if the key does not
+    /// exist or has no expiration.
+    pub async fn persist(&mut self, key: &str) -> crate::Result<bool> {
+        let frame = Persist::new(key).into_frame();
+        self.connection.write_frame(&frame).await?;
+        self.read_bool().await
+    }
+
+    /// Return a page of the entries selected by `options`, in key order.
+    ///
+    /// `cursor` is `None` for the first page, then the cursor returned with the
+    /// previous page. The returned cursor is `None` once the range is
+    /// exhausted.
+    pub async fn scan(&mut self, options: &ScanOptions, cursor: Option<&str>) -> crate::Result<(Vec<(String, Bytes)>, Option<String>)> {
+        let frame = Scan::new(options.clone(), cursor.map(str::to_string)).into_frame()?;
+        self.connection.write_frame(&frame).await?;
+
+        let mut parts = match self.read_response().await? {
+            Frame::Array(parts) => parts.into_iter(),
+            frame => return Err(frame.to_error()),
+        };
+        let cursor = match parts.next() {
+            Some(Frame::Bulk(cursor)) => Some(String::from_utf8(cursor.to_vec())?),
+            Some(Frame::Null) => None,
+            Some(frame) => return Err(frame.to_error()),
+            None => return Err("protocol error; empty SCAN reply".into()),
+        };
+
+        let mut entries = Vec::new();
+        while let Some(key) = parts.next() {
+            match (key, parts.next()) {
+                (Frame::Bulk(key), Some(Frame::Bulk(value))) => entries.push((String::from_utf8(key.to_vec())?, value)),
+                (frame, _) => return Err(frame.to_error()),
+            }
+        }
+
+        Ok((entries, cursor))
+    }
+
+    /// Make the server write a snapshot of its keys, returns once it is on
+    /// disk.
+    pub async fn save(&mut self) -> crate::Result<()> {
+        self.connection.write_frame(&Save::new().into_frame()).await?;
+        self.read_ok().await
+    }
+
+    /// Make the server write a snapshot of its keys in the background.
+    pub async fn bgsave(&mut self) -> crate::Result<()> {
+        self.connection.write_frame(&Save::new_background().into_frame()).await?;
+        match self.read_response().await? {
+            Frame::Simple(_) => Ok(()),
+            frame => Err(frame.to_error()),
+        }
+    }
+
+    /// Send `message` to the subscribers of `channel`. Returns the number of
+    /// subscriptions it was sent to.
+    pub async fn publish(&mut self, channel: &str, message: Bytes) -> crate::Result<u64> {
+        let frame = Publish::new(channel, message).into_frame();
+        self.connection.write_frame(&frame).await?;
+
+        match self.read_response().await? {
+            Frame::Integer(count) if count >= 0 => Ok(count as u64),
+            frame => Err(frame.to_error()),
+        }
+    }
+
+    /// Subscribe to `channels`. Returns the messages published to them from
+    /// now on, until the stream is dropped or the server closes the
+    /// connection.
+    ///
+    /// The connection only serves the subscription afterwards, the client is
+    /// consumed.
+    pub async fn subscribe(self, channels: &[&str]) -> crate::Result<impl Stream<Item = crate::Result<Message>>> {
+        self.subscribe_cmd(Subscribe::new(channels), channels.len()).await
+    }
+
+    /// Subscribe to the channels matching the glob `patterns`, like
+    /// `subscribe`.
+    pub async fn psubscribe(self, patterns: &[&str]) -> crate::Result<impl Stream<Item = crate::Result<Message>>> {
+        self.subscribe_cmd(Subscribe::new_pattern(patterns), patterns.len()).await
+    }
+
+    async fn subscribe_cmd(mut self, cmd: Subscribe, count: usize) -> crate::Result<impl Stream<Item = crate::Result<Message>>> {
+        self.connection.write_frame(&cmd.into_frame()).await?;
+
+        // The server confirms each subscription.
+        for _ in 0..count {
+            match self.read_response().await? {
+                Frame::Array(parts) if matches!(parts.first(), Some(kind) if *kind == "subscribe" || *kind == "psubscribe") => {}
+                frame => return Err(frame.to_error()),
+            }
+        }
+
+        Ok(async_stream::try_stream! {
+            while let Some(frame) = self.connection.read_frame().await? {
+                let message = to_message(frame)?;
+                yield message;
+            }
+        })
+    }
+
+    /// Stream the changes numbered `from` and later, then the changes as they
+    /// are committed, until the stream is dropped or the server closes the
+    /// connection. To resume after a disconnection, stream again from the
+    /// sequence number following the last change received.
+    ///
+    /// The connection only serves the stream afterwards, the client is
+    /// consumed.
+    pub async fn changes(mut self, from: u64) -> crate::Result<impl Stream<Item = crate::Result<Change>>> {
+        self.connection.write_frame(&Cdc::new_subscribe(from).into_frame()?).await?;
+        self.read_ok().await?;
+
+        Ok(async_stream::try_stream! {
+            while let Some(frame) = self.connection.read_frame().await? {
+                let change = to_change(frame)?;
+                yield change;
+            }
+        })
+    }
+
+    /// Replicate the store of the server, whose changes up to the one
+    /// numbered `offset` were already applied, 0 if none was. Returns how to
+    /// catch up with the server, then the changes that follow.
+    ///
+    /// The connection only serves the replication afterwards, the client is
+    /// consumed.
+    pub async fn psync(mut self, offset: u64) -> crate::Result<(Resync, impl Stream<Item = crate::Result<Change>>)> {
+        self.connection.write_frame(&Psync::new(offset).into_frame()?).await?;
+
+        let resync = match self.read_response().await? {
+            Frame::Array(parts) => match &parts[..] {
+                [kind, Frame::Integer(continued)] if *kind == "continue" && *continued as u64 == offset => Resync::Partial,
+                [kind, Frame::Integer(seq), Frame::Integer(count)] if *kind == "fullresync" && *seq >= 0 && *count >= 0 => {
+                    let mut entries = Vec::with_capacity(*count as usize);
+                    for _ in 0..*count {
+                        entries.push(to_change(self.read_response().await?)?);
+                    }
+                    Resync::Full { seq: *seq as u64, entries }
+                }
+                _ => return Err(Frame::Array(parts).to_error()),
+            },
+            frame => return Err(frame.to_error()),
+        };
+
+        let changes = async_stream::try_stream! {
+            while let Some(frame) = self.connection.read_frame().await? {
+                let change = to_change(frame)?;
+                yield change;
+            }
+        };
+        Ok((resync, changes))
+    }
+
+    /// The state of a cluster node, as `field:value` lines.
+    pub async fn cluster_info(&mut self) -> crate::Result<String> {
+        self.connection.write_frame(&Cluster::Info.into_frame()?).await?;
+        match self.read_response().await? {
+            Frame::Bulk(info) => Ok(String::from_utf8(info.to_vec())?),
+            frame => Err(frame.to_error()),
+        }
+    }
+
+    /// Add the node `id`, listening on `address`, to the cluster of the
+    /// server, which must be its leader. Returns once the change is
+    /// committed.
+    pub async fn cluster_add(&mut self, id: u64, address: &str) -> crate::Result<()> {
+        let frame = Cluster::Add {
+            id,
+            address: address.to_string(),
+        }
+        .into_frame()?;
+        self.connection.write_frame(&frame).await?;
+        self.read_ok().await
+    }
+
+    /// Remove the node `id` from the cluster of the server, which must be its
+    /// leader. Returns once the change is committed.
+    pub async fn cluster_remove(&mut self, id: u64) -> crate::Result<()> {
+        self.connection.write_frame(&Cluster::Remove { id }.into_frame()?).await?;
+        self.read_ok().await
+    }
+
+    /// Watch `keys`: the next `set_all` of the client applies nothing if one of
+    /// them is modified in the meantime.
+    pub async fn watch(&mut self, keys: &[&str]) -> crate::Result<()> {
+        let frame = Watch::new(keys).into_frame();
+        self.connection.write_frame(&frame).await?;
+        self.read_ok().await
+    }
+
+    /// Forget the keys watched by the client.
+    pub async fn unwatch(&mut self) -> crate::Result<()> {
+        self.connection.write_frame(&Unwatch::new().into_frame()).await?;
+        self.read_ok().await
+    }
+
+    /// Set every key of `entries` to its value in a single transaction: either
+    /// all of them are set or none is. Returns `false` if nothing was set
+    /// because a watched key was modified.
+    pub async fn set_all(&mut self, entries: &[(&str, Bytes)]) -> crate::Result<bool> {
+        self.connection.write_frame(&Multi::new().into_frame()).await?;
+        self.read_ok().await?;
+
+        for (key, value) in entries {
+            let frame = Set::new(key, value.clone(), None).into_frame()?;
+            self.connection.write_frame(&frame).await?;
+            match self.read_response().await {
+                Ok(Frame::Simple(response)) if response == "QUEUED" => {}
+                response => {
+                    // Leave the transaction before reporting the error.
+                    self.connection.write_frame(&Discard::new().into_frame()).await?;
+                    self.read_ok().await?;
+                    return Err(match response {
+                        Ok(frame) => frame.to_error(),
+                        Err(err) => err,
+                    });
+                }
+            }
+        }
+
+        self.connection.write_frame(&Exec::new().into_frame()).await?;
+        match self.read_response().await? {
+            Frame::Array(responses) if responses.len() == entries.len() => Ok(true),
+            Frame::Null => Ok(false),
+            frame => Err(frame.to_error()),
+        }
+    }
+
+    /// Read a simple `OK` response.
+    async fn read_ok(&mut self) -> crate::Result<()> {
+        match self.read_response().await? {
+            Frame::Simple(response) if response == "OK" => Ok(()),
+            frame => Err(frame.to_error()),
+        }
+    }
+
+    /// Read an integer response that is either `0` or `1`.
+    async fn read_bool(&mut self) -> crate::Result<bool> {
+        match self.read_response().await? {
+            Frame::Integer(0) => Ok(false),
+            Frame::Integer(1) => Ok(true),
+            frame => Err(frame.to_error()),
+        }
+    }
+
     async fn read_response(&mut self) -> crate::Result<Frame> {
         let response = self.connection.read_frame().await?;
 
@@ -61,3 +370,68 @@ impl Client {
         }
     }
 }
+
+/// Read a `[message, channel, content]` or `[pmessage, pattern, channel,
+/// content]` frame.
+fn to_message(frame: Frame) -> crate::Result<Message> {
+    let mut parts = match frame {
+        Frame::Array(parts) => parts.into_iter(),
+        frame => return Err(frame.to_error()),
+    };
+
+    let pattern = match parts.next() {
+        Some(kind) if kind == "message" => None,
+        Some(kind) if kind == "pmessage" => Some(next_string(&mut parts)?),
+        Some(frame) => return Err(frame.to_error()),
+        None => return Err("protocol error; empty message".into()),
+    };
+    let channel = next_string(&mut parts)?;
+    let content = match parts.next() {
+        Some(Frame::Bulk(content)) => content,
+        Some(frame) => return Err(frame.to_error()),
+        None => return Err("protocol error; message without content".into()),
+    };
+
+    Ok(Message { channel, pattern, content })
+}
+
+fn to_change(frame: Frame) -> crate::Result<Change> {
+    let mut parts = match frame {
+        Frame::Array(parts) => parts.into_iter(),
+        frame => return Err(frame.to_error()),
+    };
+
+    match parts.next() {
+        Some(kind) if kind == "change" => {}
+        Some(frame) => return Err(frame.to_error()),
+        None => return Err("protocol error; empty change".into()),
+    }
+    let seq = match parts.next() {
+        Some(Frame::Integer(seq)) if seq > 0 => seq as u64,
+        Some(frame) => return Err(frame.to_error()),
+        None => return Err("protocol error; truncated change".into()),
+    };
+    let key = next_string(&mut parts)?;
+    let value = match parts.next() {
+        Some(Frame::Bulk(value)) => Some(value),
+        Some(Frame::Null) => None,
+        Some(frame) => return Err(frame.to_error()),
+        None => return Err("protocol error; truncated change".into()),
+    };
+    let expires_at = match parts.next() {
+        Some(Frame::Integer(millis)) if millis >= 0 => Some(UNIX_EPOCH + Duration::from_millis(millis as u64)),
+        Some(Frame::Null) => None,
+        Some(frame) => return Err(frame.to_error()),
+        None => return Err("protocol error; truncated change".into()),
+    };
+
+    Ok(Change { seq, key, value, expires_at })
+}
+
+fn next_string(parts: &mut impl Iterator<Item = Frame>) -> crate::Result<String> {
+    match parts.next() {
+        Some(Frame::Bulk(value)) => Ok(String::from_utf8(value.to_vec())?),
+        Some(frame) => Err(frame.to_error()),
+        None => Err("protocol error; truncated message".into()),
+    }
+}
@@ -1,13 +1,37 @@
+#[allow(clippy::module_inception)]
 pub mod client;
 
+use crate::server::key_value_store::ScanOptions;
 use clap::{AppSettings, Arg, SubCommand};
+use simple_error::bail;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio_stream::{Stream, StreamExt};
 
 pub const CMD_NAME: &str = "start-client";
 
 const CMD_SET_NAME: &str = "set";
 const CMD_GET_NAME: &str = "get";
+const CMD_DEL_NAME: &str = "del";
+const CMD_EXPIRE_NAME: &str = "expire";
+const CMD_TTL_NAME: &str = "ttl";
+const CMD_PERSIST_NAME: &str = "persist";
+const CMD_SCAN_NAME: &str = "scan";
+const CMD_PUBLISH_NAME: &str = "publish";
+const CMD_SUBSCRIBE_NAME: &str = "subscribe";
+const CMD_CDC_NAME: &str = "cdc";
 const KEY_ARG: &str = "key";
 const VALUE_ARG: &str = "value";
+const EXPIRE_ARG: &str = "expire";
+const START_ARG: &str = "start";
+const END_ARG: &str = "end";
+const PREFIX_ARG: &str = "prefix";
+const LIMIT_ARG: &str = "limit";
+const REVERSE_ARG: &str = "reverse";
+const CHANNEL_ARG: &str = "channel";
+const MESSAGE_ARG: &str = "message";
+const PATTERN_ARG: &str = "pattern";
+const FROM_ARG: &str = "from";
 
 pub fn cmd<'a, 'b>() -> clap::App<'a, 'b> {
     let key_arg = Arg::with_name("key")
@@ -25,6 +49,21 @@ pub fn cmd<'a, 'b>() -> clap::App<'a, 'b> {
         .value_name(VALUE_ARG)
         .help("The keys's value key.");
 
+    let expire_arg = Arg::with_name(EXPIRE_ARG)
+        .short("e")
+        .long("expire")
+        .takes_value(true)
+        .value_name("seconds")
+        .help("Number of seconds after which the key expires.");
+
+    let channel_arg = Arg::with_name(CHANNEL_ARG)
+        .short("c")
+        .long("channel")
+        .takes_value(true)
+        .required(true)
+        .value_name(CHANNEL_ARG)
+        .help("The pub/sub channel.");
+
     clap::App::new(CMD_NAME)
         .about("starts a raphDB client")
         .setting(AppSettings::ArgRequiredElseHelp)
@@ -32,9 +71,104 @@ pub fn cmd<'a, 'b>() -> clap::App<'a, 'b> {
             SubCommand::with_name(CMD_SET_NAME)
                 .about("Sets a key/value pair.")
                 .arg(key_arg.clone())
-                .arg(value_arg),
+                .arg(value_arg)
+                .arg(expire_arg.clone()),
+        )
+        .subcommand(SubCommand::with_name(CMD_GET_NAME).about("Gets the value from a key.").arg(key_arg.clone()))
+        .subcommand(
+            SubCommand::with_name(CMD_DEL_NAME)
+                .about("Deletes keys and their values.")
+                .arg(key_arg.clone().multiple(true).number_of_values(1)),
+        )
+        .subcommand(
+            SubCommand::with_name(CMD_EXPIRE_NAME)
+                .about("Sets a key to expire after a number of seconds.")
+                .arg(key_arg.clone())
+                .arg(expire_arg.required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name(CMD_TTL_NAME)
+                .about("Gets the time a key has left to live.")
+                .arg(key_arg.clone()),
+        )
+        .subcommand(SubCommand::with_name(CMD_PERSIST_NAME).about("Removes the expiration of a key.").arg(key_arg))
+        .subcommand(
+            SubCommand::with_name(CMD_SCAN_NAME)
+                .about("Lists the key/value pairs of a key range, in key order.")
+                .arg(
+                    Arg::with_name(START_ARG)
+                        .long("start")
+                        .takes_value(true)
+                        .value_name("key")
+                        .help("Smallest key listed."),
+                )
+                .arg(
+                    Arg::with_name(END_ARG)
+                        .long("end")
+                        .takes_value(true)
+                        .value_name("key")
+                        .help("Keys listed are smaller than this one."),
+                )
+                .arg(
+                    Arg::with_name(PREFIX_ARG)
+                        .short("p")
+                        .long("prefix")
+                        .takes_value(true)
+                        .value_name(PREFIX_ARG)
+                        .help("Only list the keys starting with this prefix."),
+                )
+                .arg(
+                    Arg::with_name(LIMIT_ARG)
+                        .short("l")
+                        .long("limit")
+                        .takes_value(true)
+                        .value_name("count")
+                        .help("Number of pairs fetched per request."),
+                )
+                .arg(
+                    Arg::with_name(REVERSE_ARG)
+                        .short("r")
+                        .long("reverse")
+                        .help("List the keys in descending order."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(CMD_PUBLISH_NAME)
+                .about("Sends a message to the subscribers of a channel.")
+                .arg(channel_arg.clone())
+                .arg(
+                    Arg::with_name(MESSAGE_ARG)
+                        .short("m")
+                        .long("message")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name(MESSAGE_ARG)
+                        .help("The message sent."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(CMD_SUBSCRIBE_NAME)
+                .about("Prints the messages sent to channels until interrupted.")
+                .arg(channel_arg.multiple(true).number_of_values(1))
+                .arg(
+                    Arg::with_name(PATTERN_ARG)
+                        .long("pattern")
+                        .help("Treat the channels as glob patterns, such as 'news.*'."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(CMD_CDC_NAME)
+                .about("Prints the changes committed to the store until interrupted.")
+                .arg(
+                    Arg::with_name(FROM_ARG)
+                        .short("f")
+                        .long("from")
+                        .takes_value(true)
+                        .value_name("seq")
+                        .default_value("1")
+                        .help("Sequence number of the first change printed."),
+                ),
         )
-        .subcommand(SubCommand::with_name(CMD_GET_NAME).about("Gets the value from a key.").arg(key_arg))
 }
 
 pub async fn run(logger: slog::Logger, matches: &clap::ArgMatches<'_>) -> crate::Result<()> {
@@ -45,21 +179,83 @@ pub async fn run(logger: slog::Logger, matches: &clap::ArgMatches<'_>) -> crate:
         (CMD_SET_NAME, Some(m)) => {
             let key = m.value_of(KEY_ARG).expect("key arg is required");
             let value = m.value_of(VALUE_ARG).expect("value arg is required").to_string();
-            set(logger, client, key, value).await?
+            let expire = m.value_of(EXPIRE_ARG).map(parse_seconds).transpose()?;
+            set(logger, client, key, value, expire).await?
         }
         (CMD_GET_NAME, Some(m)) => {
             let key = m.value_of(KEY_ARG).expect("key arg is required");
             get(logger, client, key).await?;
         }
+        (CMD_DEL_NAME, Some(m)) => {
+            let keys: Vec<&str> = m.values_of(KEY_ARG).expect("key arg is required").collect();
+            del(logger, client, &keys).await?;
+        }
+        (CMD_EXPIRE_NAME, Some(m)) => {
+            let key = m.value_of(KEY_ARG).expect("key arg is required");
+            let seconds = parse_seconds(m.value_of(EXPIRE_ARG).expect("expire arg is required"))?;
+            expire(logger, client, key, seconds).await?;
+        }
+        (CMD_TTL_NAME, Some(m)) => {
+            let key = m.value_of(KEY_ARG).expect("key arg is required");
+            ttl(logger, client, key).await?;
+        }
+        (CMD_PERSIST_NAME, Some(m)) => {
+            let key = m.value_of(KEY_ARG).expect("key arg is required");
+            persist(logger, client, key).await?;
+        }
+        (CMD_SCAN_NAME, Some(m)) => {
+            let limit = match m.value_of(LIMIT_ARG) {
+                Some(limit) => match limit.parse() {
+                    Ok(limit) => Some(limit),
+                    Err(_) => bail!("invalid limit {:?}", limit),
+                },
+                None => None,
+            };
+            let options = ScanOptions {
+                start: m.value_of(START_ARG).map(str::to_string),
+                end: m.value_of(END_ARG).map(str::to_string),
+                prefix: m.value_of(PREFIX_ARG).map(str::to_string),
+                limit,
+                reverse: m.is_present(REVERSE_ARG),
+            };
+            scan(logger, client, options).await?;
+        }
+        (CMD_PUBLISH_NAME, Some(m)) => {
+            let channel = m.value_of(CHANNEL_ARG).expect("channel arg is required");
+            let message = m.value_of(MESSAGE_ARG).expect("message arg is required").to_string();
+            publish(logger, client, channel, message).await?;
+        }
+        (CMD_SUBSCRIBE_NAME, Some(m)) => {
+            let channels: Vec<&str> = m.values_of(CHANNEL_ARG).expect("channel arg is required").collect();
+            subscribe(logger, client, &channels, m.is_present(PATTERN_ARG)).await?;
+        }
+        (CMD_CDC_NAME, Some(m)) => {
+            let from = m.value_of(FROM_ARG).expect("from arg has a default value");
+            let from = match from.parse() {
+                Ok(from) => from,
+                Err(_) => bail!("invalid sequence number {:?}", from),
+            };
+            changes(logger, client, from).await?;
+        }
         _ => unreachable!("match arms should cover all the possible cases"),
     }
 
     Ok(())
 }
 
-pub async fn set(logger: slog::Logger, mut client: client::Client, key: &str, value: String) -> crate::Result<()> {
-    info!(logger, "Setting key: {:?} | value: {:?}", key, value);
-    client.set(key, value.into()).await?;
+fn parse_seconds(value: &str) -> crate::Result<Duration> {
+    match value.parse() {
+        Ok(seconds) => Ok(Duration::from_secs(seconds)),
+        Err(_) => bail!("invalid number of seconds {:?}", value),
+    }
+}
+
+pub async fn set(logger: slog::Logger, mut client: client::Client, key: &str, value: String, expire: Option<Duration>) -> crate::Result<()> {
+    info!(logger, "Setting key: {:?} | value: {:?} | expire: {:?}", key, value, expire);
+    match expire {
+        Some(expire) => client.set_expires(key, value.into(), expire).await?,
+        None => client.set(key, value.into()).await?,
+    }
     return Ok(());
 }
 
@@ -69,3 +265,86 @@ pub async fn get(logger: slog::Logger, mut client: client::Client, key: &str) ->
     info!(logger, "KEY = {:?} | VALUE = {:?}", key, result);
     return Ok(());
 }
+
+pub async fn del(logger: slog::Logger, mut client: client::Client, keys: &[&str]) -> crate::Result<()> {
+    info!(logger, "Deleting keys: {:?}", keys);
+    let count = client.del(keys).await?;
+    info!(logger, "Deleted {:?} keys", count);
+    return Ok(());
+}
+
+pub async fn expire(logger: slog::Logger, mut client: client::Client, key: &str, expire: Duration) -> crate::Result<()> {
+    info!(logger, "Setting key: {:?} to expire in {:?}", key, expire);
+    let found = client.expire(key, expire).await?;
+    info!(logger, "KEY = {:?} | FOUND = {:?}", key, found);
+    return Ok(());
+}
+
+pub async fn ttl(logger: slog::Logger, mut client: client::Client, key: &str) -> crate::Result<()> {
+    info!(logger, "Getting time to live of key: {:?}", key);
+    let ttl = client.ttl(key).await?;
+    info!(logger, "KEY = {:?} | TTL = {:?}", key, ttl);
+    return Ok(());
+}
+
+pub async fn persist(logger: slog::Logger, mut client: client::Client, key: &str) -> crate::Result<()> {
+    info!(logger, "Removing expiration of key: {:?}", key);
+    let removed = client.persist(key).await?;
+    info!(logger, "KEY = {:?} | REMOVED = {:?}", key, removed);
+    return Ok(());
+}
+
+/// List every pair of the range, fetching it one page at a time.
+pub async fn scan(logger: slog::Logger, mut client: client::Client, options: ScanOptions) -> crate::Result<()> {
+    info!(logger, "Scanning: {:?}", options);
+    let mut cursor = None;
+    loop {
+        let (entries, next) = client.scan(&options, cursor.as_deref()).await?;
+        for (key, value) in entries {
+            info!(logger, "KEY = {:?} | VALUE = {:?}", key, value);
+        }
+        cursor = match next {
+            Some(next) => Some(next),
+            None => break,
+        };
+    }
+    return Ok(());
+}
+
+pub async fn publish(logger: slog::Logger, mut client: client::Client, channel: &str, message: String) -> crate::Result<()> {
+    info!(logger, "Publishing to channel: {:?} | message: {:?}", channel, message);
+    let count = client.publish(channel, message.into()).await?;
+    info!(logger, "Sent to {:?} subscriptions", count);
+    return Ok(());
+}
+
+/// Print the messages of the subscription until the server closes the
+/// connection.
+pub async fn subscribe(logger: slog::Logger, client: client::Client, channels: &[&str], pattern: bool) -> crate::Result<()> {
+    info!(logger, "Subscribing to channels: {:?}", channels);
+    let mut messages: Pin<Box<dyn Stream<Item = crate::Result<client::Message>>>> = if pattern {
+        Box::pin(client.psubscribe(channels).await?)
+    } else {
+        Box::pin(client.subscribe(channels).await?)
+    };
+    while let Some(message) = messages.next().await {
+        let message = message?;
+        info!(logger, "CHANNEL = {:?} | MESSAGE = {:?}", message.channel, message.content);
+    }
+    return Ok(());
+}
+
+/// Print the changes of the store until the server closes the connection.
+pub async fn changes(logger: slog::Logger, client: client::Client, from: u64) -> crate::Result<()> {
+    info!(logger, "Streaming changes from: {:?}", from);
+    let changes = client.changes(from).await?;
+    tokio::pin!(changes);
+    while let Some(change) = changes.next().await {
+        let change = change?;
+        info!(
+            logger,
+            "SEQ = {:?} | KEY = {:?} | VALUE = {:?} | EXPIRES AT = {:?}", change.seq, change.key, change.value, change.expires_at
+        );
+    }
+    return Ok(());
+}
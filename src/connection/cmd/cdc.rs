@@ -0,0 +1,110 @@
+use crate::{
+    connection::{Connection, Frame, Parser},
+    server::{
+        key_value_store::{Change, Changes},
+        Shutdown,
+    },
+    AsyncKeyValueStore,
+};
+
+use bytes::Bytes;
+use std::convert::TryInto;
+use std::time::UNIX_EPOCH;
+use tokio_stream::StreamExt;
+
+/// Stream the changes committed to the store, for change data capture.
+///
+/// `CDC SUBSCRIBE from-seq` replies with `OK`, then pushes every change
+/// numbered `from-seq` or later as `[change, seq, key, value, expires_at]`,
+/// and keeps pushing the changes as they are committed. `value` is `nil` for
+/// a deleted or expired key, `expires_at` is in milliseconds since the epoch
+/// or `nil`.
+///
+/// A consumer resumes after a disconnection by subscribing from the sequence
+/// number following the last change it received. The reply is an error if the
+/// backend does not number its changes, or if the changes from `from-seq` were
+/// compacted away.
+#[derive(Debug)]
+pub struct Cdc {
+    from: u64,
+}
+
+impl Cdc {
+    pub fn new_subscribe(from: u64) -> Cdc {
+        Cdc { from }
+    }
+
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Cdc> {
+        let subcommand = parser.next_string()?;
+        match &subcommand.to_lowercase()[..] {
+            "subscribe" => Ok(Cdc { from: parser.next_int()? }),
+            _ => Err(format!("protocol error; unknown CDC subcommand {:?}", subcommand).into()),
+        }
+    }
+
+    /// Stream the changes to the connection. Returns once the stream ends, the
+    /// connection is closed by the peer, or the server shuts down.
+    pub async fn apply(self, kv: &dyn AsyncKeyValueStore, dst: &mut Connection, shutdown: &mut Shutdown) -> crate::Result<()> {
+        let changes = match kv.changes(self.from).await {
+            Ok(changes) => changes,
+            Err(err) => {
+                dst.write_frame(&Frame::Error(format!("ERR {}", err))).await?;
+                return Ok(());
+            }
+        };
+        dst.write_frame(&Frame::Simple("OK".to_string())).await?;
+        push_changes(changes, dst, shutdown).await
+    }
+
+    pub fn into_frame(self) -> crate::Result<Frame> {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("cdc".as_bytes()));
+        frame.push_bulk(Bytes::from("subscribe".as_bytes()));
+        frame.push_int(self.from.try_into()?);
+        return Ok(frame);
+    }
+}
+
+/// Push `changes` to the connection until the stream ends, the connection is
+/// closed by the peer, or the server shuts down. Commands are rejected
+/// meanwhile.
+pub(super) async fn push_changes(mut changes: Changes, dst: &mut Connection, shutdown: &mut Shutdown) -> crate::Result<()> {
+    loop {
+        tokio::select! {
+            change = changes.next() => match change {
+                Some(Ok(change)) => dst.write_frame(&change_frame(change)?).await?,
+                Some(Err(err)) => {
+                    dst.write_frame(&Frame::Error(format!("ERR {}", err))).await?;
+                    return Ok(());
+                }
+                None => return Ok(()),
+            },
+            res = dst.read_frame() => {
+                if res?.is_none() {
+                    // The peer closed the connection.
+                    return Ok(());
+                }
+                let response = Frame::Error("ERR no command is allowed while streaming changes".to_string());
+                dst.write_frame(&response).await?;
+            }
+            _ = shutdown.recv() => return Ok(()),
+        }
+    }
+}
+
+/// `[change, seq, key, value, expires_at]`.
+pub(super) fn change_frame(change: Change) -> crate::Result<Frame> {
+    let mut frame = Frame::array();
+    frame.push_bulk(Bytes::from("change".as_bytes()));
+    frame.push_int(change.seq.try_into()?);
+    frame.push_bulk(Bytes::from(change.key.into_bytes()));
+    match change.value {
+        Some(value) => frame.push_bulk(value),
+        None => frame.push_null(),
+    }
+    match change.expires_at {
+        Some(expires_at) => frame.push_int(expires_at.duration_since(UNIX_EPOCH)?.as_millis().try_into()?),
+        None => frame.push_null(),
+    }
+    return Ok(frame);
+}
@@ -0,0 +1,79 @@
+use crate::{
+    connection::{Connection, Frame, Parser},
+    server::raft::{Node, NodeId},
+};
+
+use bytes::Bytes;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+/// Administration of a cluster:
+///
+/// - `CLUSTER INFO` replies with the state of the node, as `field:value`
+///   lines.
+/// - `CLUSTER ADD id host:port` adds the node `id`, listening on `host:port`,
+///   to the cluster.
+/// - `CLUSTER REMOVE id` removes the node `id` from the cluster.
+///
+/// Members are added and removed one at a time, by the leader. Both reply with
+/// `OK` once the change is committed.
+#[derive(Debug)]
+pub enum Cluster {
+    Info,
+    Add { id: NodeId, address: String },
+    Remove { id: NodeId },
+}
+
+impl Cluster {
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Cluster> {
+        let subcommand = parser.next_string()?;
+        match &subcommand.to_lowercase()[..] {
+            "info" => Ok(Cluster::Info),
+            "add" => Ok(Cluster::Add {
+                id: parser.next_int()?,
+                address: parser.next_string()?,
+            }),
+            "remove" => Ok(Cluster::Remove { id: parser.next_int()? }),
+            _ => Err(format!("protocol error; unknown CLUSTER subcommand {:?}", subcommand).into()),
+        }
+    }
+
+    pub(crate) async fn apply(self, node: &Arc<Node>, dst: &mut Connection) -> crate::Result<()> {
+        if let (Cluster::Add { .. } | Cluster::Remove { .. }, Some(refusal)) = (&self, node.refusal()) {
+            dst.write_frame(&Frame::Error(refusal)).await?;
+            return Ok(());
+        }
+        let response = match self {
+            Cluster::Info => Frame::Bulk(Bytes::from(node.info())),
+            Cluster::Add { id, address } => change_frame(node.add_member(id, address).await),
+            Cluster::Remove { id } => change_frame(node.remove_member(id).await),
+        };
+        dst.write_frame(&response).await?;
+        return Ok(());
+    }
+
+    pub fn into_frame(self) -> crate::Result<Frame> {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("cluster".as_bytes()));
+        match self {
+            Cluster::Info => frame.push_bulk(Bytes::from("info".as_bytes())),
+            Cluster::Add { id, address } => {
+                frame.push_bulk(Bytes::from("add".as_bytes()));
+                frame.push_int(id.try_into()?);
+                frame.push_bulk(Bytes::from(address.into_bytes()));
+            }
+            Cluster::Remove { id } => {
+                frame.push_bulk(Bytes::from("remove".as_bytes()));
+                frame.push_int(id.try_into()?);
+            }
+        }
+        return Ok(frame);
+    }
+}
+
+fn change_frame(changed: crate::Result<()>) -> Frame {
+    match changed {
+        Ok(()) => Frame::Simple("OK".to_string()),
+        Err(err) => Frame::Error(format!("ERR {}", err)),
+    }
+}
@@ -0,0 +1,59 @@
+use crate::{
+    connection::{Frame, Parser, ParserError},
+    AsyncKeyValueStore,
+};
+
+use bytes::Bytes;
+
+/// Removes one or more keys. Replies with the number of keys that existed.
+#[derive(Debug)]
+pub struct Del {
+    keys: Vec<String>,
+}
+
+impl Del {
+    pub fn new(keys: &[impl ToString]) -> Del {
+        Del {
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+        }
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Del> {
+        // At least one key is required.
+        let mut keys = vec![parser.next_string()?];
+
+        loop {
+            match parser.next_string() {
+                Ok(key) => keys.push(key),
+                Err(ParserError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Del { keys })
+    }
+
+    pub async fn execute(self, kv: &dyn AsyncKeyValueStore) -> crate::Result<Frame> {
+        let mut count = 0;
+        for key in self.keys.iter() {
+            if kv.delete(key).await? {
+                count += 1;
+            }
+        }
+
+        Ok(Frame::Integer(count))
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("del".as_bytes()));
+        for key in self.keys {
+            frame.push_bulk(Bytes::from(key.into_bytes()));
+        }
+        return frame;
+    }
+}
@@ -0,0 +1,29 @@
+use crate::{
+    connection::{Connection, Frame},
+    server::Transaction,
+};
+
+use bytes::Bytes;
+
+/// Drop the commands queued since `MULTI` and unwatch every key.
+#[derive(Debug, Default)]
+pub struct Discard {}
+
+impl Discard {
+    pub fn new() -> Discard {
+        Discard {}
+    }
+
+    pub async fn apply(self, transaction: &mut Transaction, dst: &mut Connection) -> crate::Result<()> {
+        let response = transaction.discard();
+        dst.write_frame(&response).await?;
+
+        Ok(())
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("discard".as_bytes()));
+        return frame;
+    }
+}
@@ -0,0 +1,32 @@
+use crate::{
+    connection::{Connection, Frame},
+    server::Transaction,
+    AsyncKeyValueStore,
+};
+
+use bytes::Bytes;
+
+/// Apply the commands queued since `MULTI` as a whole. Replies with the array
+/// of their replies, or `nil` if a watched key was modified in the meantime
+/// and nothing was applied.
+#[derive(Debug, Default)]
+pub struct Exec {}
+
+impl Exec {
+    pub fn new() -> Exec {
+        Exec {}
+    }
+
+    pub async fn apply(self, transaction: &mut Transaction, kv: Box<dyn AsyncKeyValueStore>, dst: &mut Connection) -> crate::Result<()> {
+        let response = transaction.exec(kv).await;
+        dst.write_frame(&response).await?;
+
+        Ok(())
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("exec".as_bytes()));
+        return frame;
+    }
+}
@@ -0,0 +1,54 @@
+use crate::{
+    connection::{Frame, Parser},
+    AsyncKeyValueStore,
+};
+
+use bytes::Bytes;
+use std::convert::TryInto;
+use std::time::Duration;
+
+/// Set a timeout on `key`, after which it is deleted. Replies with `1` if the
+/// timeout was set, `0` if the key does not exist.
+///
+/// `EXPIRE key seconds` or `PEXPIRE key milliseconds`
+#[derive(Debug)]
+pub struct Expire {
+    key: String,
+    expire: Duration,
+}
+
+impl Expire {
+    pub fn new(key: impl ToString, expire: Duration) -> Expire {
+        Expire { key: key.to_string(), expire }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Parse an `EXPIRE` command.
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Expire> {
+        let key = parser.next_string()?;
+        let expire = Duration::from_secs(parser.next_int()?);
+        Ok(Expire { key, expire })
+    }
+
+    /// Parse a `PEXPIRE` command.
+    pub fn parse_frames_millis(parser: &mut Parser) -> crate::Result<Expire> {
+        let key = parser.next_string()?;
+        let expire = Duration::from_millis(parser.next_int()?);
+        Ok(Expire { key, expire })
+    }
+
+    pub async fn execute(self, kv: &dyn AsyncKeyValueStore) -> crate::Result<Frame> {
+        Ok(Frame::Integer(kv.expire(&self.key, self.expire).await? as i64))
+    }
+
+    pub fn into_frame(self) -> crate::Result<Frame> {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("pexpire".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_int(self.expire.as_millis().try_into()?);
+        return Ok(frame);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
-    connection::{Connection, Frame, Parser},
-    KeyValueStore,
+    connection::{Frame, Parser},
+    AsyncKeyValueStore,
 };
 
 use bytes::Bytes;
@@ -20,16 +20,14 @@ impl Get {
         Ok(Get { key })
     }
 
-    pub async fn apply(self, kv: Box<dyn KeyValueStore>, dst: &mut Connection) -> crate::Result<()> {
-        let response = if let Some(value) = kv.get(&self.key)? {
+    pub async fn execute(self, kv: &dyn AsyncKeyValueStore) -> crate::Result<Frame> {
+        let response = if let Some(value) = kv.get(&self.key).await? {
             Frame::Bulk(value)
         } else {
             Frame::Null
         };
 
-        dst.write_frame(&response).await?;
-
-        Ok(())
+        Ok(response)
     }
 
     pub fn into_frame(self) -> Frame {
@@ -1,20 +1,75 @@
+mod cdc;
+pub use cdc::Cdc;
+mod cluster;
+pub use cluster::Cluster;
+mod del;
+pub use del::Del;
+mod discard;
+pub use discard::Discard;
+mod exec;
+pub use exec::Exec;
+mod expire;
+pub use expire::Expire;
 mod get;
 pub use get::Get;
+mod multi;
+pub use multi::Multi;
+mod persist;
+pub use persist::Persist;
+mod psync;
+pub use psync::Psync;
+mod publish;
+pub use publish::Publish;
+mod raft;
+pub use raft::Raft;
+mod save;
+pub use save::Save;
+mod scan;
+pub use scan::Scan;
 mod set;
 pub use set::Set;
+mod subscribe;
+pub use subscribe::{Subscribe, Unsubscribe};
+mod ttl;
+pub use ttl::Ttl;
 mod unknown;
 pub use unknown::Unknown;
+mod unwatch;
+pub use unwatch::Unwatch;
+mod watch;
+pub use watch::Watch;
 
 use crate::{
     connection::{Connection, Frame, Parser},
-    KeyValueStore,
+    server::{PubSub, Role, Shutdown, Transaction},
+    AsyncKeyValueStore,
 };
 
+use simple_error::bail;
+
 #[derive(Debug)]
 pub enum Command {
+    Cdc(Cdc),
+    Cluster(Cluster),
+    Del(Del),
+    Discard(Discard),
+    Exec(Exec),
+    Expire(Expire),
     Get(Get),
+    Multi(Multi),
+    Persist(Persist),
+    Psync(Psync),
+    Publish(Publish),
+    Raft(Raft),
+    Save(Save),
+    Scan(Scan),
     Set(Set),
+    Subscribe(Subscribe),
+    Ttl(Ttl),
     Unknown(Unknown),
+    Unsubscribe(Unsubscribe),
+    Unwatch(Unwatch),
+    Watch(Watch),
 }
 
 impl Command {
@@ -24,8 +79,31 @@ impl Command {
         let command_name = parser.next_string()?.to_lowercase();
 
         let command = match &command_name[..] {
+            "cdc" => Command::Cdc(Cdc::parse_frames(&mut parser)?),
+            "cluster" => Command::Cluster(Cluster::parse_frames(&mut parser)?),
+            "del" => Command::Del(Del::parse_frames(&mut parser)?),
+            "discard" => Command::Discard(Discard::new()),
+            "exec" => Command::Exec(Exec::new()),
+            "expire" => Command::Expire(Expire::parse_frames(&mut parser)?),
+            "pexpire" => Command::Expire(Expire::parse_frames_millis(&mut parser)?),
             "get" => Command::Get(Get::parse_frames(&mut parser)?),
+            "multi" => Command::Multi(Multi::new()),
+            "persist" => Command::Persist(Persist::parse_frames(&mut parser)?),
+            "psync" => Command::Psync(Psync::parse_frames(&mut parser)?),
+            "publish" => Command::Publish(Publish::parse_frames(&mut parser)?),
+            "raft" => Command::Raft(Raft::parse_frames(&mut parser)?),
+            "save" => Command::Save(Save::new()),
+            "bgsave" => Command::Save(Save::new_background()),
+            "scan" => Command::Scan(Scan::parse_frames(&mut parser)?),
             "set" => Command::Set(Set::parse_frames(&mut parser)?),
+            "subscribe" => Command::Subscribe(Subscribe::parse_frames(&mut parser, false)?),
+            "psubscribe" => Command::Subscribe(Subscribe::parse_frames(&mut parser, true)?),
+            "ttl" => Command::Ttl(Ttl::parse_frames(&mut parser)?),
+            "pttl" => Command::Ttl(Ttl::parse_frames_millis(&mut parser)?),
+            "unsubscribe" => Command::Unsubscribe(Unsubscribe::parse_frames(&mut parser, false)?),
+            "punsubscribe" => Command::Unsubscribe(Unsubscribe::parse_frames(&mut parser, true)?),
+            "unwatch" => Command::Unwatch(Unwatch::new()),
+            "watch" => Command::Watch(Watch::parse_frames(&mut parser)?),
             _ => {
                 // The command is not recognized and an Unknown command is
                 // returned.
@@ -42,21 +120,300 @@ impl Command {
         Ok(command)
     }
 
-    pub async fn apply(self, kv: Box<dyn KeyValueStore>, dst: &mut Connection) -> crate::Result<()> {
+    /// Apply the command to `kv` and write the response to `dst`.
+    ///
+    /// The transaction commands update `transaction`. While a transaction is
+    /// active, the other commands are queued instead of being applied.
+    ///
+    /// The pub/sub commands go to `pub_sub`. `SUBSCRIBE` keeps serving the
+    /// connection in subscriber mode until it unsubscribes from everything or
+    /// `shutdown` is received. `CDC SUBSCRIBE` and `PSYNC` likewise keep
+    /// streaming the changes of `kv`.
+    ///
+    /// The data commands the server does not serve in its `role` are rejected:
+    /// a replica rejects the commands modifying keys, a node of a cluster
+    /// redirects them to the leader. `RAFT` and `CLUSTER` are only served by
+    /// the nodes of a cluster.
+    pub async fn apply(
+        self,
+        kv: Box<dyn AsyncKeyValueStore>,
+        dst: &mut Connection,
+        transaction: &mut Transaction,
+        pub_sub: &PubSub,
+        shutdown: &mut Shutdown,
+        role: &Role,
+    ) -> crate::Result<()> {
+        use Command::*;
+
+        match self {
+            // Pub/sub commands take effect immediately, they can not be
+            // queued. The transaction is aborted.
+            Publish(_) | Subscribe(_) | Unsubscribe(_) if transaction.is_active() => {
+                transaction.fail();
+                let response = Frame::Error("ERR pub/sub commands inside MULTI are not allowed".to_string());
+                dst.write_frame(&response).await?;
+                Ok(())
+            }
+            Cdc(_) | Psync(_) if transaction.is_active() => {
+                transaction.fail();
+                let response = Frame::Error("ERR CDC and PSYNC inside MULTI are not allowed".to_string());
+                dst.write_frame(&response).await?;
+                Ok(())
+            }
+            Cluster(_) | Raft(_) if transaction.is_active() => {
+                transaction.fail();
+                let response = Frame::Error("ERR CLUSTER and RAFT inside MULTI are not allowed".to_string());
+                dst.write_frame(&response).await?;
+                Ok(())
+            }
+            Cluster(cmd) => match role {
+                Role::Node(node) => cmd.apply(node, dst).await,
+                _ => {
+                    dst.write_frame(&Frame::Error("ERR the server is not in cluster mode".to_string())).await?;
+                    Ok(())
+                }
+            },
+            Raft(cmd) => match role {
+                Role::Node(node) => cmd.apply(node, dst).await,
+                _ => {
+                    dst.write_frame(&Frame::Error("ERR the server is not in cluster mode".to_string())).await?;
+                    Ok(())
+                }
+            },
+            Cdc(cmd) => cmd.apply(kv.as_ref(), dst, shutdown).await,
+            Psync(cmd) => cmd.apply(kv.as_ref(), dst, shutdown).await,
+            Publish(cmd) => cmd.apply(pub_sub, dst).await,
+            Subscribe(cmd) => cmd.apply(pub_sub, dst, shutdown).await,
+            Unsubscribe(cmd) => cmd.apply(dst).await,
+            Discard(cmd) => cmd.apply(transaction, dst).await,
+            Exec(cmd) => cmd.apply(transaction, kv, dst).await,
+            Multi(cmd) => cmd.apply(transaction, dst).await,
+            Unwatch(cmd) => cmd.apply(transaction, dst).await,
+            Watch(cmd) => cmd.apply(transaction, dst).await,
+            Unknown(cmd) => {
+                // An unknown command can not be queued, the transaction is
+                // aborted.
+                transaction.fail();
+                cmd.apply(dst).await
+            }
+            cmd => {
+                let response = match role.refusal(&cmd) {
+                    Some(refusal) => {
+                        transaction.fail();
+                        Frame::Error(refusal)
+                    }
+                    None if transaction.is_active() => transaction.queue(cmd),
+                    None => transaction.run(cmd, kv.as_ref()).await?,
+                };
+                dst.write_frame(&response).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Apply a data command to `kv` and return its response.
+    ///
+    /// Transaction commands and unknown commands can not be executed this way.
+    pub async fn execute(self, kv: &dyn AsyncKeyValueStore) -> crate::Result<Frame> {
         use Command::*;
 
         match self {
-            Get(cmd) => cmd.apply(kv, dst).await,
-            Set(cmd) => cmd.apply(kv, dst).await,
-            Unknown(cmd) => cmd.apply(dst).await,
+            Del(cmd) => cmd.execute(kv).await,
+            Expire(cmd) => cmd.execute(kv).await,
+            Get(cmd) => cmd.execute(kv).await,
+            Persist(cmd) => cmd.execute(kv).await,
+            Save(cmd) => cmd.execute(kv).await,
+            Scan(cmd) => cmd.execute(kv).await,
+            Set(cmd) => cmd.execute(kv).await,
+            Ttl(cmd) => cmd.execute(kv).await,
+            cmd => bail!("{:?} can not be executed on a key value store", cmd),
+        }
+    }
+
+    /// Keys whose value or expiration may be modified by the command.
+    pub fn modified_keys(&self) -> Vec<&str> {
+        use Command::*;
+
+        match self {
+            Del(cmd) => cmd.keys().iter().map(String::as_str).collect(),
+            Expire(cmd) => vec![cmd.key()],
+            Persist(cmd) => vec![cmd.key()],
+            Set(cmd) => vec![cmd.key()],
+            _ => Vec::new(),
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::server::key_value_store::ScanOptions;
+    use bytes::Bytes;
+    use std::time::Duration;
+
     #[tokio::test]
     async fn test_from_frame() {
         assert!(true)
     }
+
+    #[tokio::test]
+    async fn test_parse_commands() {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("GET"));
+        frame.push_bulk(Bytes::from("key"));
+        assert!(matches!(Command::from_frame(frame), Ok(Command::Get(_))));
+
+        let frame = Del::new(&["key1", "key2"]).into_frame();
+        match Command::from_frame(frame) {
+            Ok(Command::Del(del)) => assert_eq!(del.into_frame(), Del::new(&["key1", "key2"]).into_frame()),
+            other => panic!("expected a DEL command, got {:?}", other),
+        }
+
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("DEL"));
+        assert!(Command::from_frame(frame).is_err());
+
+        let frame = Set::new("key", Bytes::from("value"), Some(Duration::from_millis(1500))).into_frame().unwrap();
+        match Command::from_frame(frame) {
+            Ok(Command::Set(set)) => assert_eq!(
+                set.into_frame().unwrap(),
+                Set::new("key", Bytes::from("value"), Some(Duration::from_millis(1500))).into_frame().unwrap()
+            ),
+            other => panic!("expected a SET command, got {:?}", other),
+        }
+
+        let mut frame = Frame::array();
+        for part in ["SET", "key", "value", "EX", "10"] {
+            frame.push_bulk(Bytes::from(part));
+        }
+        match Command::from_frame(frame) {
+            Ok(Command::Set(set)) => assert_eq!(
+                set.into_frame().unwrap(),
+                Set::new("key", Bytes::from("value"), Some(Duration::from_secs(10))).into_frame().unwrap()
+            ),
+            other => panic!("expected a SET command, got {:?}", other),
+        }
+
+        let mut frame = Frame::array();
+        for part in ["SET", "key", "value", "KEEPTTL"] {
+            frame.push_bulk(Bytes::from(part));
+        }
+        assert!(Command::from_frame(frame).is_err());
+
+        let mut frame = Frame::array();
+        for part in ["EXPIRE", "key", "10"] {
+            frame.push_bulk(Bytes::from(part));
+        }
+        match Command::from_frame(frame) {
+            Ok(Command::Expire(expire)) => assert_eq!(expire.into_frame().unwrap(), Expire::new("key", Duration::from_secs(10)).into_frame().unwrap()),
+            other => panic!("expected an EXPIRE command, got {:?}", other),
+        }
+
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("TTL"));
+        frame.push_bulk(Bytes::from("key"));
+        assert!(matches!(Command::from_frame(frame), Ok(Command::Ttl(_))));
+        assert!(matches!(Command::from_frame(Persist::new("key").into_frame()), Ok(Command::Persist(_))));
+
+        let options = ScanOptions {
+            start: Some("a".to_string()),
+            prefix: Some("ab".to_string()),
+            limit: Some(10),
+            reverse: true,
+            ..ScanOptions::default()
+        };
+        let frame = Scan::new(options.clone(), Some("abc".to_string())).into_frame().unwrap();
+        match Command::from_frame(frame) {
+            Ok(Command::Scan(scan)) => assert_eq!(scan.into_frame().unwrap(), Scan::new(options, Some("abc".to_string())).into_frame().unwrap()),
+            other => panic!("expected a SCAN command, got {:?}", other),
+        }
+
+        let mut frame = Frame::array();
+        for part in ["SCAN", "LIMIT"] {
+            frame.push_bulk(Bytes::from(part));
+        }
+        assert!(Command::from_frame(frame).is_err());
+
+        assert!(matches!(Command::from_frame(Save::new().into_frame()), Ok(Command::Save(_))));
+        match Command::from_frame(Save::new_background().into_frame()) {
+            Ok(Command::Save(save)) => assert_eq!(save.into_frame(), Save::new_background().into_frame()),
+            other => panic!("expected a BGSAVE command, got {:?}", other),
+        }
+
+        let frame = Publish::new("news", Bytes::from("hello")).into_frame();
+        match Command::from_frame(frame) {
+            Ok(Command::Publish(publish)) => assert_eq!(publish.into_frame(), Publish::new("news", Bytes::from("hello")).into_frame()),
+            other => panic!("expected a PUBLISH command, got {:?}", other),
+        }
+        let frame = Subscribe::new_pattern(&["news.*", "weather"]).into_frame();
+        match Command::from_frame(frame) {
+            Ok(Command::Subscribe(subscribe)) => assert_eq!(subscribe.into_frame(), Subscribe::new_pattern(&["news.*", "weather"]).into_frame()),
+            other => panic!("expected a PSUBSCRIBE command, got {:?}", other),
+        }
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("SUBSCRIBE"));
+        assert!(Command::from_frame(frame).is_err());
+
+        let frame = Cdc::new_subscribe(42).into_frame().unwrap();
+        match Command::from_frame(frame) {
+            Ok(Command::Cdc(cdc)) => assert_eq!(cdc.into_frame().unwrap(), Cdc::new_subscribe(42).into_frame().unwrap()),
+            other => panic!("expected a CDC command, got {:?}", other),
+        }
+        let mut frame = Frame::array();
+        for part in ["CDC", "TAIL", "1"] {
+            frame.push_bulk(Bytes::from(part));
+        }
+        assert!(Command::from_frame(frame).is_err());
+        let frame = Psync::new(7).into_frame().unwrap();
+        match Command::from_frame(frame) {
+            Ok(Command::Psync(psync)) => assert_eq!(psync.into_frame().unwrap(), Psync::new(7).into_frame().unwrap()),
+            other => panic!("expected a PSYNC command, got {:?}", other),
+        }
+        let cluster = || Cluster::Add {
+            id: 4,
+            address: "127.0.0.1:7004".to_string(),
+        };
+        match Command::from_frame(cluster().into_frame().unwrap()) {
+            Ok(Command::Cluster(add)) => assert_eq!(add.into_frame().unwrap(), cluster().into_frame().unwrap()),
+            other => panic!("expected a CLUSTER command, got {:?}", other),
+        }
+        let mut frame = Frame::array();
+        for part in ["CLUSTER", "MEET", "127.0.0.1:7004"] {
+            frame.push_bulk(Bytes::from(part));
+        }
+        assert!(Command::from_frame(frame).is_err());
+        let mut frame = Frame::array();
+        for part in ["RAFT", "VOTE", "2", "1", "7", "1"] {
+            frame.push_bulk(Bytes::from(part));
+        }
+        assert!(matches!(Command::from_frame(frame), Ok(Command::Raft(_))));
+
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("PUNSUBSCRIBE"));
+        assert!(matches!(Command::from_frame(frame), Ok(Command::Unsubscribe(_))));
+
+        assert!(matches!(Command::from_frame(Multi::new().into_frame()), Ok(Command::Multi(_))));
+        assert!(matches!(Command::from_frame(Exec::new().into_frame()), Ok(Command::Exec(_))));
+        assert!(matches!(Command::from_frame(Discard::new().into_frame()), Ok(Command::Discard(_))));
+        assert!(matches!(Command::from_frame(Unwatch::new().into_frame()), Ok(Command::Unwatch(_))));
+        let frame = Watch::new(&["key1", "key2"]).into_frame();
+        match Command::from_frame(frame) {
+            Ok(Command::Watch(watch)) => assert_eq!(watch.into_frame(), Watch::new(&["key1", "key2"]).into_frame()),
+            other => panic!("expected a WATCH command, got {:?}", other),
+        }
+
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("WATCH"));
+        assert!(Command::from_frame(frame).is_err());
+
+        let mut frame = Frame::array();
+        for part in ["EXEC", "now"] {
+            frame.push_bulk(Bytes::from(part));
+        }
+        assert!(Command::from_frame(frame).is_err());
+
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("foo"));
+        assert!(matches!(Command::from_frame(frame), Ok(Command::Unknown(_))));
+    }
 }
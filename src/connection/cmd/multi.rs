@@ -0,0 +1,30 @@
+use crate::{
+    connection::{Connection, Frame},
+    server::Transaction,
+};
+
+use bytes::Bytes;
+
+/// Start a transaction. The following commands are queued until `EXEC`
+/// applies them all at once, or `DISCARD` drops them.
+#[derive(Debug, Default)]
+pub struct Multi {}
+
+impl Multi {
+    pub fn new() -> Multi {
+        Multi {}
+    }
+
+    pub async fn apply(self, transaction: &mut Transaction, dst: &mut Connection) -> crate::Result<()> {
+        let response = transaction.multi();
+        dst.write_frame(&response).await?;
+
+        Ok(())
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("multi".as_bytes()));
+        return frame;
+    }
+}
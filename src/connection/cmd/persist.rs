@@ -0,0 +1,39 @@
+use crate::{
+    connection::{Frame, Parser},
+    AsyncKeyValueStore,
+};
+
+use bytes::Bytes;
+
+/// Remove the timeout of `key`. Replies with `1` if the timeout was removed,
+/// `0` if the key does not exist or has no timeout.
+#[derive(Debug)]
+pub struct Persist {
+    key: String,
+}
+
+impl Persist {
+    pub fn new(key: impl ToString) -> Persist {
+        Persist { key: key.to_string() }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Persist> {
+        let key = parser.next_string()?;
+        Ok(Persist { key })
+    }
+
+    pub async fn execute(self, kv: &dyn AsyncKeyValueStore) -> crate::Result<Frame> {
+        Ok(Frame::Integer(kv.persist(&self.key).await? as i64))
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("persist".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        return frame;
+    }
+}
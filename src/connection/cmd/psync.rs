@@ -0,0 +1,81 @@
+use super::cdc::{change_frame, push_changes};
+use crate::{
+    connection::{Connection, Frame, Parser},
+    server::Shutdown,
+    AsyncKeyValueStore,
+};
+
+use bytes::Bytes;
+use std::convert::TryInto;
+
+/// Replicate the store to a replica.
+///
+/// `PSYNC offset` gives the sequence number of the last change the replica
+/// applied, 0 if it has none. If the changes following it can still be
+/// streamed, and the store has them all (it did not lose the last ones or get
+/// wiped since), the reply is `[continue, offset]`. Otherwise it is
+/// `[fullresync, seq, count]`, followed by `count` changes holding every live
+/// key as of the change numbered `seq`, which replace the keys of the
+/// replica.
+///
+/// The changes that follow are then pushed like with `CDC SUBSCRIBE`, until
+/// the connection is closed or the server shuts down.
+#[derive(Debug)]
+pub struct Psync {
+    offset: u64,
+}
+
+impl Psync {
+    pub fn new(offset: u64) -> Psync {
+        Psync { offset }
+    }
+
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Psync> {
+        Ok(Psync { offset: parser.next_int()? })
+    }
+
+    pub async fn apply(self, kv: &dyn AsyncKeyValueStore, dst: &mut Connection, shutdown: &mut Shutdown) -> crate::Result<()> {
+        if self.offset > 0 {
+            if let Ok(changes) = kv.changes(self.offset + 1).await {
+                let mut frame = Frame::array();
+                frame.push_bulk(Bytes::from("continue".as_bytes()));
+                frame.push_int(self.offset.try_into()?);
+                dst.write_frame(&frame).await?;
+                return push_changes(changes, dst, shutdown).await;
+            }
+        }
+
+        // The stream starts right after the snapshot.
+        let snapshot = match kv.snapshot().await {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                dst.write_frame(&Frame::Error(format!("ERR {}", err))).await?;
+                return Ok(());
+            }
+        };
+        let changes = match kv.changes(snapshot.seq + 1).await {
+            Ok(changes) => changes,
+            Err(err) => {
+                dst.write_frame(&Frame::Error(format!("ERR {}", err))).await?;
+                return Ok(());
+            }
+        };
+
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("fullresync".as_bytes()));
+        frame.push_int(snapshot.seq.try_into()?);
+        frame.push_int(snapshot.entries.len().try_into()?);
+        dst.write_frame(&frame).await?;
+        for entry in snapshot.entries {
+            dst.write_frame(&change_frame(entry)?).await?;
+        }
+        return push_changes(changes, dst, shutdown).await;
+    }
+
+    pub fn into_frame(self) -> crate::Result<Frame> {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("psync".as_bytes()));
+        frame.push_int(self.offset.try_into()?);
+        return Ok(frame);
+    }
+}
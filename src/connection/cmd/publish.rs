@@ -0,0 +1,45 @@
+use crate::{
+    connection::{Connection, Frame, Parser},
+    server::PubSub,
+};
+
+use bytes::Bytes;
+
+/// Send a message to the subscribers of a channel. Replies with the number of
+/// subscriptions it was sent to.
+#[derive(Debug)]
+pub struct Publish {
+    channel: String,
+    message: Bytes,
+}
+
+impl Publish {
+    pub fn new(channel: impl ToString, message: Bytes) -> Publish {
+        Publish {
+            channel: channel.to_string(),
+            message,
+        }
+    }
+
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Publish> {
+        let channel = parser.next_string()?;
+        let message = parser.next_bytes()?;
+
+        Ok(Publish { channel, message })
+    }
+
+    pub async fn apply(self, pub_sub: &PubSub, dst: &mut Connection) -> crate::Result<()> {
+        let receivers = pub_sub.publish(&self.channel, self.message);
+        dst.write_frame(&Frame::Integer(receivers as i64)).await?;
+
+        Ok(())
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("publish".as_bytes()));
+        frame.push_bulk(Bytes::from(self.channel.into_bytes()));
+        frame.push_bulk(self.message);
+        return frame;
+    }
+}
@@ -0,0 +1,31 @@
+use crate::{
+    connection::{Connection, Frame, Parser},
+    server::raft::{Node, Request},
+};
+
+use std::sync::Arc;
+
+/// An RPC between the nodes of a cluster: `RAFT VOTE`, `RAFT APPEND` or
+/// `RAFT SNAPSHOT`, see `server::raft`. Replies with the frame of the
+/// response, or an error if the server is not a node of a cluster.
+#[derive(Debug)]
+pub struct Raft {
+    request: Request,
+}
+
+impl Raft {
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Raft> {
+        Ok(Raft {
+            request: Request::parse_frames(parser)?,
+        })
+    }
+
+    pub(crate) async fn apply(self, node: &Arc<Node>, dst: &mut Connection) -> crate::Result<()> {
+        let response = match node.handle(self.request).await.and_then(|response| response.to_frame()) {
+            Ok(frame) => frame,
+            Err(err) => Frame::Error(format!("ERR {}", err)),
+        };
+        dst.write_frame(&response).await?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,45 @@
+use crate::{connection::Frame, AsyncKeyValueStore};
+
+use bytes::Bytes;
+
+/// Write a snapshot of every key to disk. `SAVE` replies with `OK` once the
+/// snapshot is synced, `BGSAVE` replies as soon as it starts.
+///
+/// Backends that persist every write reply with an error.
+#[derive(Debug)]
+pub struct Save {
+    background: bool,
+}
+
+impl Save {
+    pub fn new() -> Save {
+        Save { background: false }
+    }
+
+    pub fn new_background() -> Save {
+        Save { background: true }
+    }
+
+    pub async fn execute(self, kv: &dyn AsyncKeyValueStore) -> crate::Result<Frame> {
+        let saved = if self.background { kv.bgsave().await } else { kv.save().await };
+        let response = match saved {
+            Ok(()) if self.background => Frame::Simple("Background saving started".to_string()),
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(format!("ERR {}", err)),
+        };
+        Ok(response)
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        let name = if self.background { "bgsave" } else { "save" };
+        frame.push_bulk(Bytes::from(name.as_bytes()));
+        return frame;
+    }
+}
+
+impl Default for Save {
+    fn default() -> Self {
+        Save::new()
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{
+    connection::{Frame, Parser, ParserError},
+    server::key_value_store::ScanOptions,
+    AsyncKeyValueStore,
+};
+
+use bytes::Bytes;
+use std::convert::TryInto;
+
+/// Number of entries returned by a page when no `LIMIT` is given.
+pub const DEFAULT_LIMIT: usize = 100;
+
+/// Return a page of the entries of a key range, in key order.
+///
+/// `SCAN [CURSOR cursor] [START key] [END key] [PREFIX prefix] [LIMIT count] [REV]`
+///
+/// The reply is a flat array: the cursor of the next page, or `nil` once the
+/// range is exhausted, followed by the key and the value of every entry. The
+/// next page is requested by sending the same command with the returned
+/// cursor.
+#[derive(Debug)]
+pub struct Scan {
+    options: ScanOptions,
+    cursor: Option<String>,
+}
+
+impl Scan {
+    pub fn new(options: ScanOptions, cursor: Option<String>) -> Scan {
+        Scan { options, cursor }
+    }
+
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Scan> {
+        let mut options = ScanOptions::default();
+        let mut cursor = None;
+
+        loop {
+            let option = match parser.next_string() {
+                Ok(option) => option,
+                Err(ParserError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            match &option.to_lowercase()[..] {
+                "cursor" => cursor = Some(parser.next_string()?),
+                "start" => options.start = Some(parser.next_string()?),
+                "end" => options.end = Some(parser.next_string()?),
+                "prefix" => options.prefix = Some(parser.next_string()?),
+                "limit" => options.limit = Some(parser.next_int()?.try_into()?),
+                "rev" => options.reverse = true,
+                _ => return Err(format!("protocol error; unknown SCAN option {:?}", option).into()),
+            }
+        }
+
+        Ok(Scan { options, cursor })
+    }
+
+    pub async fn execute(self, kv: &dyn AsyncKeyValueStore) -> crate::Result<Frame> {
+        let mut options = self.options;
+        let limit = options.limit.unwrap_or(DEFAULT_LIMIT);
+
+        // The cursor narrows the range to the entries after the ones already
+        // returned.
+        if let Some(cursor) = self.cursor {
+            if options.reverse {
+                options.end = Some(options.end.map(|end| end.min(cursor.clone())).unwrap_or(cursor));
+            } else {
+                options.start = Some(options.start.map(|start| start.max(cursor.clone())).unwrap_or(cursor));
+            }
+        }
+
+        // One more entry tells whether there is a next page.
+        options.limit = Some(limit.saturating_add(1));
+        let mut entries = kv.scan(&options).await?;
+        let cursor = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(key, _)| next_cursor(key, options.reverse))
+        } else {
+            None
+        };
+
+        let mut response = Frame::array();
+        match cursor {
+            Some(cursor) => response.push_bulk(Bytes::from(cursor.into_bytes())),
+            None => response.push_null(),
+        }
+        for (key, value) in entries {
+            response.push_bulk(Bytes::from(key.into_bytes()));
+            response.push_bulk(value);
+        }
+
+        Ok(response)
+    }
+
+    pub fn into_frame(self) -> crate::Result<Frame> {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("scan".as_bytes()));
+
+        let bounds = [
+            ("cursor", self.cursor),
+            ("start", self.options.start),
+            ("end", self.options.end),
+            ("prefix", self.options.prefix),
+        ];
+        for (name, bound) in bounds {
+            if let Some(bound) = bound {
+                frame.push_bulk(Bytes::from(name.as_bytes()));
+                frame.push_bulk(Bytes::from(bound.into_bytes()));
+            }
+        }
+        if let Some(limit) = self.options.limit {
+            frame.push_bulk(Bytes::from("limit".as_bytes()));
+            frame.push_int(limit.try_into()?);
+        }
+        if self.options.reverse {
+            frame.push_bulk(Bytes::from("rev".as_bytes()));
+        }
+
+        return Ok(frame);
+    }
+}
+
+/// Cursor resuming a scan after `last`, the last key returned.
+///
+/// Ascending scans resume at the smallest key greater than `last`, descending
+/// scans at the keys smaller than `last`, which is an exclusive end.
+fn next_cursor(last: &str, reverse: bool) -> String {
+    if reverse {
+        last.to_string()
+    } else {
+        format!("{}\0", last)
+    }
+}
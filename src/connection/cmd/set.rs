@@ -1,42 +1,68 @@
 use crate::{
-    connection::{Connection, Frame, Parser},
-    KeyValueStore,
+    connection::{Frame, Parser, ParserError},
+    AsyncKeyValueStore,
 };
 
 use bytes::Bytes;
+use std::convert::TryInto;
+use std::time::Duration;
 
+/// Set `key` to hold the string `value`, optionally expiring after a while.
+///
+/// `SET key value [EX seconds|PX milliseconds]`
 #[derive(Debug)]
 pub struct Set {
     key: String,
     value: Bytes,
+    expire: Option<Duration>,
 }
 
 impl Set {
-    pub fn new(key: impl ToString, value: Bytes) -> Set {
-        Set { key: key.to_string(), value }
+    pub fn new(key: impl ToString, value: Bytes, expire: Option<Duration>) -> Set {
+        Set {
+            key: key.to_string(),
+            value,
+            expire,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
     }
 
     pub fn parse_frames(parser: &mut Parser) -> crate::Result<Set> {
         let key = parser.next_string()?;
         let value = parser.next_bytes()?;
 
-        Ok(Set { key, value })
-    }
+        // The expiration is optional. If nothing else follows, then it is
+        // `None`.
+        let expire = match parser.next_string() {
+            Ok(s) if s.to_uppercase() == "EX" => Some(Duration::from_secs(parser.next_int()?)),
+            Ok(s) if s.to_uppercase() == "PX" => Some(Duration::from_millis(parser.next_int()?)),
+            Ok(_) => return Err("currently `SET` only supports the expiration option".into()),
+            Err(ParserError::EndOfStream) => None,
+            Err(err) => return Err(err.into()),
+        };
 
-    pub async fn apply(self, kv: Box<dyn KeyValueStore>, dst: &mut Connection) -> crate::Result<()> {
-        kv.set(self.key, self.value)?;
-
-        let response = Frame::Simple("OK".to_string());
-        dst.write_frame(&response).await?;
+        Ok(Set { key, value, expire })
+    }
 
-        Ok(())
+    pub async fn execute(self, kv: &dyn AsyncKeyValueStore) -> crate::Result<Frame> {
+        kv.set(self.key, self.value, self.expire).await?;
+        Ok(Frame::Simple("OK".to_string()))
     }
 
-    pub fn into_frame(self) -> Frame {
+    pub fn into_frame(self) -> crate::Result<Frame> {
         let mut frame = Frame::array();
         frame.push_bulk(Bytes::from("set".as_bytes()));
         frame.push_bulk(Bytes::from(self.key.into_bytes()));
         frame.push_bulk(self.value);
-        return frame;
+        if let Some(expire) = self.expire {
+            // The expiration is always sent in milliseconds, the most
+            // precise unit.
+            frame.push_bulk(Bytes::from("px".as_bytes()));
+            frame.push_int(expire.as_millis().try_into()?);
+        }
+        return Ok(frame);
     }
 }
@@ -0,0 +1,221 @@
+use crate::{
+    connection::{Command, Connection, Frame, Parser, ParserError},
+    server::{Message, PubSub, Shutdown},
+};
+
+use bytes::Bytes;
+use std::pin::Pin;
+use tokio::sync::broadcast;
+use tokio_stream::{Stream, StreamExt, StreamMap};
+
+/// Messages received by a subscription.
+type Messages = Pin<Box<dyn Stream<Item = Message> + Send>>;
+
+/// Subscriptions of a connection in subscriber mode.
+type Subscriptions = StreamMap<Subscription, Messages>;
+
+/// A channel, or a glob pattern of channels, a connection subscribed to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Subscription {
+    name: String,
+    pattern: bool,
+}
+
+/// Subscribe the connection to channels, or with `PSUBSCRIBE` to the channels
+/// matching glob patterns. Replies with a confirmation per channel, holding
+/// the number of subscriptions of the connection.
+///
+/// The connection then enters subscriber mode: the messages published to its
+/// channels are pushed to it, and only the commands changing its
+/// subscriptions are accepted. It leaves subscriber mode once it unsubscribed
+/// from everything.
+#[derive(Debug)]
+pub struct Subscribe {
+    channels: Vec<String>,
+    pattern: bool,
+}
+
+/// Unsubscribe the connection from channels, or with `PUNSUBSCRIBE` from
+/// patterns. Without arguments, unsubscribes it from all of them.
+#[derive(Debug)]
+pub struct Unsubscribe {
+    channels: Vec<String>,
+    pattern: bool,
+}
+
+impl Subscribe {
+    pub fn new(channels: &[impl ToString]) -> Subscribe {
+        Subscribe {
+            channels: channels.iter().map(|channel| channel.to_string()).collect(),
+            pattern: false,
+        }
+    }
+
+    pub fn new_pattern(patterns: &[impl ToString]) -> Subscribe {
+        Subscribe {
+            channels: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            pattern: true,
+        }
+    }
+
+    pub fn parse_frames(parser: &mut Parser, pattern: bool) -> crate::Result<Subscribe> {
+        // At least one channel is required.
+        let mut channels = vec![parser.next_string()?];
+        channels.extend(parse_names(parser)?);
+
+        Ok(Subscribe { channels, pattern })
+    }
+
+    /// Subscribe and serve the connection in subscriber mode. Returns once
+    /// the connection has no subscription left, is closed by the peer, or the
+    /// server shuts down.
+    pub async fn apply(self, pub_sub: &PubSub, dst: &mut Connection, shutdown: &mut Shutdown) -> crate::Result<()> {
+        let mut subscriptions = Subscriptions::new();
+        self.subscribe(pub_sub, &mut subscriptions, dst).await?;
+
+        while !subscriptions.is_empty() {
+            tokio::select! {
+                Some((subscription, message)) = subscriptions.next() => {
+                    dst.write_frame(&message_frame(&subscription, message)).await?;
+                }
+                res = dst.read_frame() => {
+                    let frame = match res? {
+                        Some(frame) => frame,
+                        // The peer closed the connection.
+                        None => return Ok(()),
+                    };
+                    match Command::from_frame(frame)? {
+                        Command::Subscribe(cmd) => cmd.subscribe(pub_sub, &mut subscriptions, dst).await?,
+                        Command::Unsubscribe(cmd) => cmd.unsubscribe(&mut subscriptions, dst).await?,
+                        _ => {
+                            let response = Frame::Error("ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE are allowed in subscriber mode".to_string());
+                            dst.write_frame(&response).await?;
+                        }
+                    }
+                }
+                _ = shutdown.recv() => return Ok(()),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn subscribe(self, pub_sub: &PubSub, subscriptions: &mut Subscriptions, dst: &mut Connection) -> crate::Result<()> {
+        let kind = if self.pattern { "psubscribe" } else { "subscribe" };
+        for name in self.channels {
+            let subscription = Subscription {
+                name: name.clone(),
+                pattern: self.pattern,
+            };
+            // Subscribing twice to a channel only confirms it again.
+            if !subscriptions.contains_key(&subscription) {
+                let receiver = if self.pattern { pub_sub.psubscribe(&name) } else { pub_sub.subscribe(&name) };
+                subscriptions.insert(subscription, into_stream(receiver));
+            }
+            dst.write_frame(&confirmation_frame(kind, Some(name), subscriptions.len())).await?;
+        }
+
+        Ok(())
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        let name = if self.pattern { "psubscribe" } else { "subscribe" };
+        frame.push_bulk(Bytes::from(name.as_bytes()));
+        for channel in self.channels {
+            frame.push_bulk(Bytes::from(channel.into_bytes()));
+        }
+        return frame;
+    }
+}
+
+impl Unsubscribe {
+    pub fn parse_frames(parser: &mut Parser, pattern: bool) -> crate::Result<Unsubscribe> {
+        let channels = parse_names(parser)?;
+
+        Ok(Unsubscribe { channels, pattern })
+    }
+
+    /// Reply to an unsubscription outside of subscriber mode, where the
+    /// connection has no subscription.
+    pub async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
+        self.unsubscribe(&mut Subscriptions::new(), dst).await
+    }
+
+    async fn unsubscribe(self, subscriptions: &mut Subscriptions, dst: &mut Connection) -> crate::Result<()> {
+        let kind = if self.pattern { "punsubscribe" } else { "unsubscribe" };
+        let names = if self.channels.is_empty() {
+            subscriptions
+                .keys()
+                .filter(|subscription| subscription.pattern == self.pattern)
+                .map(|subscription| subscription.name.clone())
+                .collect()
+        } else {
+            self.channels
+        };
+
+        if names.is_empty() {
+            dst.write_frame(&confirmation_frame(kind, None, subscriptions.len())).await?;
+        }
+        for name in names {
+            let subscription = Subscription { name, pattern: self.pattern };
+            subscriptions.remove(&subscription);
+            dst.write_frame(&confirmation_frame(kind, Some(subscription.name), subscriptions.len())).await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_names(parser: &mut Parser) -> crate::Result<Vec<String>> {
+    let mut names = Vec::new();
+    loop {
+        match parser.next_string() {
+            Ok(name) => names.push(name),
+            Err(ParserError::EndOfStream) => return Ok(names),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Turn `receiver` into a stream. A subscriber lagging too far behind misses
+/// the oldest messages instead of failing.
+fn into_stream(mut receiver: broadcast::Receiver<Message>) -> Messages {
+    Box::pin(async_stream::stream! {
+        loop {
+            match receiver.recv().await {
+                Ok(message) => yield message,
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// `[kind, channel, count]`, confirms a change of the subscriptions of the
+/// connection.
+fn confirmation_frame(kind: &'static str, channel: Option<String>, count: usize) -> Frame {
+    let mut frame = Frame::array();
+    frame.push_bulk(Bytes::from(kind.as_bytes()));
+    match channel {
+        Some(channel) => frame.push_bulk(Bytes::from(channel.into_bytes())),
+        None => frame.push_null(),
+    }
+    frame.push_int(count as i64);
+    return frame;
+}
+
+/// `[message, channel, content]`, or `[pmessage, pattern, channel, content]`
+/// for a pattern subscription.
+fn message_frame(subscription: &Subscription, message: Message) -> Frame {
+    let mut frame = Frame::array();
+    if subscription.pattern {
+        frame.push_bulk(Bytes::from("pmessage".as_bytes()));
+        frame.push_bulk(Bytes::from(subscription.name.clone().into_bytes()));
+    } else {
+        frame.push_bulk(Bytes::from("message".as_bytes()));
+    }
+    frame.push_bulk(Bytes::from(message.channel.into_bytes()));
+    frame.push_bulk(message.content);
+    return frame;
+}
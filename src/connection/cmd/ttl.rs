@@ -0,0 +1,60 @@
+use crate::{
+    connection::{Frame, Parser},
+    server::key_value_store::Expiry,
+    AsyncKeyValueStore,
+};
+
+use bytes::Bytes;
+use std::convert::TryInto;
+
+/// Reply with the time `key` has left to live, `-1` if it does not expire and
+/// `-2` if it does not exist.
+///
+/// `TTL key` replies in seconds, `PTTL key` in milliseconds.
+#[derive(Debug)]
+pub struct Ttl {
+    key: String,
+    millis: bool,
+}
+
+impl Ttl {
+    /// A `PTTL` command, the most precise of the two.
+    pub fn new(key: impl ToString) -> Ttl {
+        Ttl {
+            key: key.to_string(),
+            millis: true,
+        }
+    }
+
+    /// Parse a `TTL` command.
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Ttl> {
+        let key = parser.next_string()?;
+        Ok(Ttl { key, millis: false })
+    }
+
+    /// Parse a `PTTL` command.
+    pub fn parse_frames_millis(parser: &mut Parser) -> crate::Result<Ttl> {
+        let key = parser.next_string()?;
+        Ok(Ttl { key, millis: true })
+    }
+
+    pub async fn execute(self, kv: &dyn AsyncKeyValueStore) -> crate::Result<Frame> {
+        let ttl = match kv.ttl(&self.key).await? {
+            Expiry::Missing => -2,
+            Expiry::Persistent => -1,
+            Expiry::After(duration) if self.millis => duration.as_millis().try_into()?,
+            // Round to the closest second, like redis.
+            Expiry::After(duration) => ((duration.as_millis() + 500) / 1000).try_into()?,
+        };
+
+        Ok(Frame::Integer(ttl))
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        let name = if self.millis { "pttl" } else { "ttl" };
+        frame.push_bulk(Bytes::from(name.as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        return frame;
+    }
+}
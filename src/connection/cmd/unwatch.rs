@@ -0,0 +1,29 @@
+use crate::{
+    connection::{Connection, Frame},
+    server::Transaction,
+};
+
+use bytes::Bytes;
+
+/// Forget the keys watched by the connection.
+#[derive(Debug, Default)]
+pub struct Unwatch {}
+
+impl Unwatch {
+    pub fn new() -> Unwatch {
+        Unwatch {}
+    }
+
+    pub async fn apply(self, transaction: &mut Transaction, dst: &mut Connection) -> crate::Result<()> {
+        transaction.unwatch();
+        dst.write_frame(&Frame::Simple("OK".to_string())).await?;
+
+        Ok(())
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("unwatch".as_bytes()));
+        return frame;
+    }
+}
@@ -0,0 +1,52 @@
+use crate::{
+    connection::{Connection, Frame, Parser, ParserError},
+    server::Transaction,
+};
+
+use bytes::Bytes;
+
+/// Watch keys for the next transaction of the connection. `EXEC` applies
+/// nothing if one of them is modified before it runs.
+#[derive(Debug)]
+pub struct Watch {
+    keys: Vec<String>,
+}
+
+impl Watch {
+    pub fn new(keys: &[impl ToString]) -> Watch {
+        Watch {
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+        }
+    }
+
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Watch> {
+        // At least one key is required.
+        let mut keys = vec![parser.next_string()?];
+
+        loop {
+            match parser.next_string() {
+                Ok(key) => keys.push(key),
+                Err(ParserError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Watch { keys })
+    }
+
+    pub async fn apply(self, transaction: &mut Transaction, dst: &mut Connection) -> crate::Result<()> {
+        let response = transaction.watch(self.keys);
+        dst.write_frame(&response).await?;
+
+        Ok(())
+    }
+
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("watch".as_bytes()));
+        for key in self.keys {
+            frame.push_bulk(Bytes::from(key.into_bytes()));
+        }
+        return frame;
+    }
+}
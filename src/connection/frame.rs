@@ -9,7 +9,7 @@ use std::io::Cursor;
 pub enum Frame {
     Simple(String),
     Error(String),
-    Integer(u64),
+    Integer(i64),
     Bulk(Bytes),
     Null,
     Array(Vec<Frame>),
@@ -40,8 +40,7 @@ impl Frame {
     /// # Panics
     ///
     /// panics if `self` is not an Frame::Array
-    #[allow(dead_code)]
-    pub fn push_int(&mut self, value: u64) {
+    pub fn push_int(&mut self, value: i64) {
         match self {
             Frame::Array(vec) => {
                 vec.push(Frame::Integer(value));
@@ -50,11 +49,37 @@ impl Frame {
         }
     }
 
-    /// Creates bytes from the corresponding Frame
+    /// Push a frame, a nested array for instance, into the array. `self` must
+    /// be an Array frame.
     ///
     /// # Panics
     ///
-    /// Panics if self is a Frame::Array as async does not allow recursion
+    /// panics if `self` is not an Frame::Array
+    pub fn push_frame(&mut self, frame: Frame) {
+        match self {
+            Frame::Array(vec) => {
+                vec.push(frame);
+            }
+            _ => panic!("not an array frame"),
+        }
+    }
+
+    /// Push a "null" frame into the array. `self` must be an Array frame.
+    ///
+    /// # Panics
+    ///
+    /// panics if `self` is not an Frame::Array
+    pub fn push_null(&mut self) {
+        match self {
+            Frame::Array(vec) => {
+                vec.push(Frame::Null);
+            }
+            _ => panic!("not an array frame"),
+        }
+    }
+
+    /// Creates bytes from the corresponding Frame. Arrays are encoded with
+    /// all of their entries, nested arrays included.
     pub fn create_bytes(&self) -> std::io::Result<BytesMut> {
         let mut buffer = BytesMut::new();
         match self {
@@ -84,10 +109,14 @@ impl Frame {
                 buffer.put(val.clone());
                 buffer.put(&b"\r\n"[..]);
             }
-            // Encoding an `Array` from within a value cannot be done using a
-            // recursive strategy. In general, async fns do not support
-            // recursion.
-            Frame::Array(_val) => unreachable!(),
+            Frame::Array(val) => {
+                buffer.put_u8(b'*');
+                buffer.put(val.len().to_string().as_bytes());
+                buffer.put(&b"\r\n"[..]);
+                for entry in val.iter() {
+                    buffer.put(entry.create_bytes()?);
+                }
+            }
         };
 
         return Ok(buffer);
@@ -107,7 +136,7 @@ impl Frame {
                 Ok(())
             }
             b':' => {
-                let _ = get_decimal(src)?;
+                let _ = get_int(src)?;
                 Ok(())
             }
             b'$' => {
@@ -143,8 +172,8 @@ impl Frame {
                 Ok(Frame::Error(string))
             }
             b':' => {
-                let len = get_decimal(src)?;
-                Ok(Frame::Integer(len))
+                let value = get_int(src)?;
+                Ok(Frame::Integer(value))
             }
             b'$' => {
                 if b'-' == peek_u8(src)? {
@@ -268,6 +297,15 @@ fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<u64, FrameError> {
     atoi::<u64>(line).ok_or_else(|| "protocol error; invalid frame format".into())
 }
 
+/// Read a new-line terminated signed integer
+fn get_int(src: &mut Cursor<&[u8]>) -> Result<i64, FrameError> {
+    use atoi::atoi;
+
+    let line = get_line(src)?;
+
+    atoi::<i64>(line).ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
 /// Find a line
 fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], FrameError> {
     // Scan the bytes directly
@@ -304,8 +342,13 @@ mod test {
                     (Frame::Simple("foo".to_string()), BytesMut::from("+foo\r\n")),
                     (Frame::Error("foo".to_string()), BytesMut::from("-foo\r\n")),
                     (Frame::Integer(10), BytesMut::from(":10\r\n")),
+                    (Frame::Integer(-2), BytesMut::from(":-2\r\n")),
                     (Frame::Null, BytesMut::from("$-1\r\n")),
                     (Frame::Bulk(Bytes::from("foo")), BytesMut::from("$3\r\nfoo\r\n")),
+                    (
+                        Frame::Array(vec![Frame::Integer(1), Frame::Array(vec![Frame::Null])]),
+                        BytesMut::from("*2\r\n:1\r\n*1\r\n$-1\r\n"),
+                    ),
                 ],
             };
         }
@@ -337,7 +380,7 @@ mod test {
     #[tokio::test]
     async fn test_push_int() {
         let mut frame = Frame::array();
-        let integer: u64 = 10;
+        let integer: i64 = 10;
         frame.push_int(integer);
 
         let expected = vec![Frame::Integer(integer)];
@@ -1,6 +1,7 @@
 use crate::connection::{Frame, ParserError};
 
 use bytes::Bytes;
+use std::convert::TryInto;
 use std::{str, vec};
 
 #[derive(Debug)]
@@ -68,7 +69,6 @@ impl Parser {
     ///
     /// If the next entry cannot be represented as an integer, then an error is
     /// returned.
-    #[allow(dead_code)]
     pub fn next_int(&mut self) -> Result<u64, ParserError> {
         use atoi::atoi;
 
@@ -76,7 +76,7 @@ impl Parser {
 
         match self.next()? {
             // An integer frame type is already stored as an integer.
-            Frame::Integer(v) => Ok(v),
+            Frame::Integer(v) => v.try_into().map_err(|_| MSG.into()),
             // Simple and bulk frames must be parsed as integers. If the parsing
             // fails, an error is returned.
             Frame::Simple(data) => atoi::<u64>(data.as_bytes()).ok_or_else(|| MSG.into()),
@@ -85,6 +85,24 @@ impl Parser {
         }
     }
 
+    /// Return the next entry as is.
+    pub fn next_frame(&mut self) -> Result<Frame, ParserError> {
+        self.next()
+    }
+
+    /// Return a parser of the next entry, which must be an array.
+    pub fn next_array(&mut self) -> Result<Parser, ParserError> {
+        match self.next()? {
+            Frame::Array(array) => Ok(Parser { parts: array.into_iter() }),
+            frame => Err(format!("protocol error; expected array frame, got {:?}", frame).into()),
+        }
+    }
+
+    /// Returns `true` if every entry was returned.
+    pub fn is_empty(&self) -> bool {
+        self.parts.len() == 0
+    }
+
     /// Ensure there are no more entries in the array
     pub fn finish(&mut self) -> Result<(), ParserError> {
         if self.parts.next().is_none() {
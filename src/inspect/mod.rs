@@ -0,0 +1,124 @@
+//! Offline commands working directly on the data directory of a simple-store
+//! backend, without starting the server. They are meant for nodes that do
+//! not start anymore.
+
+use crate::server::key_value_store::simple_store::inspect::{HintStatus, Log, SegmentReport};
+use crate::server::key_value_store::simple_store::SimpleStore;
+use clap::Arg;
+use simple_error::bail;
+use std::path::PathBuf;
+
+pub const VERIFY_CMD_NAME: &str = "verify";
+pub const DUMP_CMD_NAME: &str = "dump";
+pub const STATS_CMD_NAME: &str = "stats";
+pub const REPAIR_CMD_NAME: &str = "repair";
+
+const DATA_DIR_ARG: &str = "data-dir";
+const TOP_ARG: &str = "top";
+
+fn data_dir_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(DATA_DIR_ARG)
+        .value_name("DIR")
+        .long("data-dir")
+        .takes_value(true)
+        .default_value(".")
+        .help("The data directory of the simple-store backend.")
+}
+
+/// The `verify`, `dump`, `stats` and `repair` commands.
+pub fn cmds<'a, 'b>() -> Vec<clap::App<'a, 'b>> {
+    let top_arg = Arg::with_name(TOP_ARG)
+        .long("top")
+        .takes_value(true)
+        .value_name("count")
+        .default_value("10")
+        .help("Number of largest keys listed.");
+
+    vec![
+        clap::App::new(VERIFY_CMD_NAME)
+            .about("Checks every record of the log of a stopped simple-store backend")
+            .arg(data_dir_arg()),
+        clap::App::new(DUMP_CMD_NAME)
+            .about("Prints the records of the log of a simple-store backend as JSON lines")
+            .arg(data_dir_arg()),
+        clap::App::new(STATS_CMD_NAME)
+            .about("Reports the space used by the log of a simple-store backend and its largest keys")
+            .arg(data_dir_arg())
+            .arg(top_arg),
+        clap::App::new(REPAIR_CMD_NAME)
+            .about("Rewrites the log of a stopped simple-store backend without its unreadable records")
+            .arg(data_dir_arg()),
+    ]
+}
+
+pub async fn run(logger: slog::Logger, name: &str, matches: &clap::ArgMatches<'_>) -> crate::Result<()> {
+    let data_dir = PathBuf::from(matches.value_of(DATA_DIR_ARG).expect("data-dir arg has a default value"));
+
+    match name {
+        VERIFY_CMD_NAME => {
+            let reports = Log::open(&data_dir)?.verify()?;
+            for report in reports.iter() {
+                println!("{}", describe(report));
+            }
+            let corrupted = reports.iter().filter(|report| report.is_corrupted()).count();
+            if corrupted > 0 {
+                bail!(
+                    "{:?} of {:?} segments are corrupted, run `raphdb repair` to drop their unreadable records",
+                    corrupted,
+                    reports.len()
+                );
+            }
+            info!(logger, "Verified {:?} segments.", reports.len());
+        }
+        DUMP_CMD_NAME => {
+            let stdout = std::io::stdout();
+            let count = Log::open(&data_dir)?.dump(&mut stdout.lock())?;
+            info!(logger, "Dumped {:?} records.", count);
+        }
+        STATS_CMD_NAME => {
+            let top = match matches.value_of(TOP_ARG).expect("top arg has a default value").parse() {
+                Ok(top) => top,
+                Err(_) => bail!("invalid number of keys {:?}", matches.value_of(TOP_ARG)),
+            };
+            let stats = Log::open(&data_dir)?.stats(top)?;
+            println!("segments: {}", stats.segments);
+            println!("total bytes: {}", stats.total_bytes);
+            println!("live bytes: {}", stats.live_bytes);
+            println!("dead bytes: {}", stats.dead_bytes);
+            println!("keys: {}", stats.keys);
+            println!("expiring keys: {}", stats.expiring_keys);
+            println!("largest keys:");
+            for (key, len) in stats.largest_keys {
+                println!("  {} {:?}", len, key);
+            }
+        }
+        REPAIR_CMD_NAME => {
+            SimpleStore::repair(logger.clone(), &data_dir).await?;
+            info!(logger, "Repaired the log of {:?}.", data_dir);
+        }
+        _ => unreachable!("match arms should cover all the commands"),
+    }
+
+    Ok(())
+}
+
+/// One line summing up the report of a segment.
+fn describe(report: &SegmentReport) -> String {
+    let mut line = format!("{:?}: {} bytes, {} records", report.path, report.len, report.records);
+    for damage in report.damaged.iter() {
+        line.push_str(&format!(", unreadable bytes {}..{}: {}", damage.range.start, damage.range.end, damage.reason));
+    }
+    if let Some(len) = report.torn_len {
+        line.push_str(&format!(", torn write of {} bytes at the end, dropped on startup", len));
+    }
+    match &report.hint {
+        HintStatus::Unused | HintStatus::Valid => {}
+        HintStatus::Missing => line.push_str(", no hint file"),
+        HintStatus::Invalid(err) => line.push_str(&format!(", invalid hint file: {}", err)),
+        HintStatus::Stale => line.push_str(", hint file does not match the segment"),
+    }
+    if !report.is_corrupted() {
+        line.push_str(", ok");
+    }
+    return line;
+}
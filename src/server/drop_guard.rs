@@ -1,4 +1,4 @@
-use crate::KeyValueStore;
+use crate::AsyncKeyValueStore;
 
 /// A wrapper around a `Db` instance. This exists to allow orderly cleanup
 /// of the `Db` by signalling the background purge task to shut down when
@@ -7,19 +7,19 @@ use crate::KeyValueStore;
 pub struct DropGuard {
     /// The `Db` instance that will be shut down when this `DbHolder` struct
     /// is dropped.
-    pub kv_store: Box<dyn KeyValueStore>,
+    pub kv_store: Box<dyn AsyncKeyValueStore>,
 }
 
 impl DropGuard {
     /// Create a new `DbHolder`, wrapping a `Db` instance. When this is dropped
     /// the `Db`'s purge task will be shut down.
-    pub fn new(kv_store: Box<dyn KeyValueStore>) -> DropGuard {
+    pub fn new(kv_store: Box<dyn AsyncKeyValueStore>) -> DropGuard {
         DropGuard { kv_store }
     }
 
     // // Get the shared database. Internally, this is an
     // // `Arc`, so a clone only increments the ref count.
-    // pub fn kv_store(&'a self) -> Box<dyn KeyValueStore + 'a> {
+    // pub fn kv_store(&'a self) -> Box<dyn AsyncKeyValueStore + 'a> {
     //     self.kv_store.clone()
     // }
 }
@@ -1,7 +1,7 @@
-use crate::KeyValueStore;
+use crate::AsyncKeyValueStore;
 use crate::{
     connection::{Command, Connection},
-    server::Shutdown,
+    server::{PubSub, Role, Shutdown, Transaction},
 };
 
 use std::sync::Arc;
@@ -16,7 +16,7 @@ pub struct Handler {
     /// When a command is received from `connection`, it is applied with `db`.
     /// The implementation of the command is in the `cmd` module. Each command
     /// will need to interact with `db` in order to complete the work.
-    pub kv: Box<dyn KeyValueStore>,
+    pub kv: Box<dyn AsyncKeyValueStore>,
 
     /// The TCP connection decorated with the redis protocol encoder / decoder
     /// implemented using a buffered `TcpStream`.
@@ -27,6 +27,18 @@ pub struct Handler {
     /// the byte level protocol parsing details encapsulated in `Connection`.
     pub connection: Connection,
 
+    /// Transaction state of the connection: the commands queued since `MULTI`
+    /// and the keys watched by the connection.
+    pub transaction: Transaction,
+
+    /// Pub/sub channels of the server. After `SUBSCRIBE`, the handler pushes
+    /// the messages published to the subscribed channels to `connection`.
+    pub pub_sub: Arc<PubSub>,
+
+    /// What the server is to the other servers: a replica rejects the commands
+    /// modifying keys, a node of a cluster serves them only as the leader.
+    pub role: Role,
+
     /// Max connection semaphore.
     ///
     /// When the handler is dropped, a permit is returned to this semaphore. If
@@ -88,7 +100,15 @@ impl Handler {
             // command to write response frames directly to the connection. In
             // the case of pub/sub, multiple frames may be send back to the
             // peer.
-            cmd.apply(self.kv.clone(), &mut self.connection).await?;
+            cmd.apply(
+                self.kv.clone(),
+                &mut self.connection,
+                &mut self.transaction,
+                &self.pub_sub,
+                &mut self.shutdown,
+                &self.role,
+            )
+            .await?;
         }
 
         Ok(())
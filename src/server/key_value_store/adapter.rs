@@ -0,0 +1,135 @@
+use crate::Result;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::time::Duration;
+
+use super::{AsyncKeyValueStore, Expiry, KeyValueStore, ScanOptions, WriteBatch};
+
+/// Serves a synchronous `KeyValueStore` through the `AsyncKeyValueStore`
+/// interface.
+///
+/// The calls of a store doing disk I/O run on Tokio's blocking thread pool,
+/// so a disk wait never stalls the other connections served by the same
+/// worker. The calls of an in-memory store never wait, they run in place.
+#[derive(Debug, Clone)]
+pub struct SyncAdapter {
+    kv: Box<dyn KeyValueStore>,
+
+    /// True if the calls run in place instead of on the blocking pool.
+    in_place: bool,
+}
+
+impl SyncAdapter {
+    /// Adapt a store whose calls may block on I/O.
+    pub fn new(kv: Box<dyn KeyValueStore>) -> SyncAdapter {
+        SyncAdapter { kv, in_place: false }
+    }
+
+    /// Adapt a store whose calls never block.
+    pub fn in_memory(kv: Box<dyn KeyValueStore>) -> SyncAdapter {
+        SyncAdapter { kv, in_place: true }
+    }
+
+    async fn run<T, F>(&self, call: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn KeyValueStore) -> Result<T> + Send + 'static,
+    {
+        if self.in_place {
+            return call(self.kv.as_ref());
+        }
+        let kv = self.kv.clone();
+        return tokio::task::spawn_blocking(move || call(kv.as_ref())).await?;
+    }
+}
+
+#[async_trait]
+impl AsyncKeyValueStore for SyncAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        let key = key.to_string();
+        self.run(move |kv| kv.get(&key)).await
+    }
+
+    async fn set(&self, key: String, value: Bytes, expire: Option<Duration>) -> Result<()> {
+        self.run(move |kv| kv.set(key, value, expire)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        let key = key.to_string();
+        self.run(move |kv| kv.delete(&key)).await
+    }
+
+    async fn expire(&self, key: &str, expire: Duration) -> Result<bool> {
+        let key = key.to_string();
+        self.run(move |kv| kv.expire(&key, expire)).await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Expiry> {
+        let key = key.to_string();
+        self.run(move |kv| kv.ttl(&key)).await
+    }
+
+    async fn persist(&self, key: &str) -> Result<bool> {
+        let key = key.to_string();
+        self.run(move |kv| kv.persist(&key)).await
+    }
+
+    async fn scan(&self, options: &ScanOptions) -> Result<Vec<(String, Bytes)>> {
+        let options = options.clone();
+        self.run(move |kv| kv.scan(&options)).await
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.run(move |kv| kv.write_batch(batch)).await
+    }
+
+    /// Always runs on the blocking pool, saving writes to disk even for an
+    /// in-memory store.
+    async fn save(&self) -> Result<()> {
+        let kv = self.kv.clone();
+        return tokio::task::spawn_blocking(move || kv.save()).await?;
+    }
+
+    async fn bgsave(&self) -> Result<()> {
+        self.run(move |kv| kv.bgsave()).await
+    }
+
+    fn shutdown_purge_task(&self) {
+        self.kv.shutdown_purge_task();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::key_value_store::btree::BTreeStore;
+    use crate::server::key_value_store::mini_redis::MiniRedis;
+    use crate::server::key_value_store::Notifier;
+
+    #[tokio::test]
+    async fn test_sync_adapter() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let dir = tempfile::tempdir().unwrap();
+        let stores = vec![
+            SyncAdapter::in_memory(Box::new(MiniRedis::in_memory())),
+            SyncAdapter::new(Box::new(BTreeStore::new(logger, dir.path(), Notifier::default()).await.unwrap())),
+        ];
+
+        for store in stores {
+            store.set("a".to_string(), Bytes::from("1"), None).await.unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put("b", Bytes::from("2"));
+            batch.delete("a");
+            store.write_batch(batch).await.unwrap();
+
+            assert_eq!(store.get("a").await.unwrap(), None);
+            assert_eq!(store.get("b").await.unwrap(), Some(Bytes::from("2")));
+            assert_eq!(store.ttl("b").await.unwrap(), Expiry::Persistent);
+            assert!(!store.persist("b").await.unwrap());
+            assert!(store.delete("b").await.unwrap());
+            assert_eq!(store.scan(&ScanOptions::default()).await.unwrap(), Vec::new());
+            store.shutdown_purge_task();
+        }
+    }
+}
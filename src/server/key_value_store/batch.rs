@@ -0,0 +1,84 @@
+use bytes::Bytes;
+use std::iter::FromIterator;
+use std::time::Duration;
+
+/// A change applied by `KeyValueStore::write_batch`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Write {
+    /// Set the value of the key, expiring after `expire` if it is not `None`.
+    Set { key: String, value: Bytes, expire: Option<Duration> },
+
+    /// Remove the key and its value.
+    Delete { key: String },
+}
+
+/// Puts and deletes applied together by `KeyValueStore::write_batch`.
+///
+/// The writes are applied in the order they were added: a later write to a
+/// key replaces an earlier one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch {
+    writes: Vec<Write>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Set the value of `key`.
+    pub fn put(&mut self, key: impl ToString, value: Bytes) {
+        self.push(Write::Set {
+            key: key.to_string(),
+            value,
+            expire: None,
+        });
+    }
+
+    /// Set the value of `key`, the key is removed once `expire` has elapsed.
+    pub fn put_expiring(&mut self, key: impl ToString, value: Bytes, expire: Duration) {
+        self.push(Write::Set {
+            key: key.to_string(),
+            value,
+            expire: Some(expire),
+        });
+    }
+
+    /// Remove `key`.
+    pub fn delete(&mut self, key: impl ToString) {
+        self.push(Write::Delete { key: key.to_string() });
+    }
+
+    pub fn push(&mut self, write: Write) {
+        self.writes.push(write);
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Write> {
+        self.writes.iter()
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = Write;
+    type IntoIter = std::vec::IntoIter<Write>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.writes.into_iter()
+    }
+}
+
+impl FromIterator<Write> for WriteBatch {
+    fn from_iter<I: IntoIterator<Item = Write>>(writes: I) -> WriteBatch {
+        WriteBatch {
+            writes: writes.into_iter().collect(),
+        }
+    }
+}
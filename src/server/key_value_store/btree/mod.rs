@@ -0,0 +1,643 @@
+mod node;
+mod pager;
+mod tree;
+
+use crate::Result;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use super::expiration::{purge_expired_tasks, to_instant, Expirations, ExpiringValue, PurgeExpired};
+use super::lock::LockFile;
+use super::{Expiry, KeyEvent, KeyValueStore, Notifier, ScanOptions, Write, WriteBatch};
+use pager::Pager;
+use tree::BTree;
+
+/// Size of a tree page, in bytes.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Largest encoded key and value pair a leaf accepts. Keeping entries well
+/// below half a page guarantees that any overflowing node can be split in two
+/// nodes that fit in a page.
+pub const MAX_ENTRY_SIZE: usize = 1024;
+
+/// Number of pages kept in the page cache by default.
+const DEFAULT_CACHE_PAGES: usize = 1024;
+
+/// Delay before the background task removes again the expired keys it failed
+/// to remove.
+const PURGE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const BTREE_FILE: &str = "btree.raphdb";
+
+/// Page-based B+tree backend.
+///
+/// Keys are kept sorted in a B+tree whose nodes are fixed-size pages of a
+/// single file. Point reads walk from the root to a leaf, touching one page
+/// per level, and the pages most recently used are kept decoded in memory.
+/// Every write is committed, and synced, before returning.
+///
+/// Pages are updated in place once journaled, see `Pager`: after a crash the
+/// tree is as of the last commit, or of the commit in progress if its journal
+/// was synced.
+///
+/// Values are stored with their expiration time, reads skip the expired ones.
+/// The expirations are tracked in memory, rebuilt from the leaves when the
+/// tree is opened, and a background task removes each key once it expires.
+///
+/// A `BTreeStore` is a handle to shared state. Cloning it is shallow and only
+/// incurs an atomic ref count increment.
+#[derive(Debug, Clone)]
+pub struct BTreeStore {
+    logger: slog::Logger,
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// Reads go through the page cache, which they update, so both reads and
+    /// writes need exclusive access to the tree.
+    tree: Mutex<BTree>,
+
+    /// Tracks when the keys expire. Only updated under the tree lock, and
+    /// locked after it.
+    expirations: Mutex<Expirations>,
+
+    /// Lock of the tree file, released on shutdown.
+    lock: Mutex<Option<LockFile>>,
+
+    /// Publishes the keyspace notifications of the store.
+    notifier: Notifier,
+
+    logger: slog::Logger,
+
+    /// Notifies the background task purging expired keys.
+    expiration_task: Notify,
+
+    /// True when the store is shutting down. Setting this to `true` signals
+    /// to the background task to exit.
+    shutdown: AtomicBool,
+}
+
+impl BTreeStore {
+    /// Open the tree of the data directory `data_dir`, creating it if needed.
+    /// The keyspace notifications are published with `notifier`.
+    pub async fn new(logger: slog::Logger, data_dir: &Path, notifier: Notifier) -> Result<BTreeStore> {
+        std::fs::create_dir_all(data_dir)?;
+        return BTreeStore::open(logger, data_dir.join(BTREE_FILE), DEFAULT_CACHE_PAGES, notifier);
+    }
+
+    /// Open the tree stored in the file at `path`, creating it if needed, and
+    /// spawn the background task purging expired keys. `cache_pages` bounds
+    /// the number of pages kept in memory. Fails if another process has the
+    /// tree open.
+    pub fn open(logger: slog::Logger, path: impl AsRef<Path>, cache_pages: usize, notifier: Notifier) -> Result<BTreeStore> {
+        let mut lock_name = path.as_ref().file_name().unwrap_or_default().to_os_string();
+        lock_name.push(".lock");
+        let lock = LockFile::acquire(&logger, path.as_ref().with_file_name(lock_name))?;
+
+        let pager = Pager::open(&logger, path.as_ref(), cache_pages)?;
+        info!(logger, "Opened B+tree with {:?} pages.", pager.page_count());
+
+        // Every leaf is read to find the expiring keys.
+        let mut tree = BTree::new(pager);
+        let mut expirations = Expirations::default();
+        tree.visit_from("", |key, value| {
+            expirations.set(key, value.expires_at.map(to_instant));
+            return true;
+        })?;
+
+        let shared = Arc::new(Shared {
+            tree: Mutex::new(tree),
+            expirations: Mutex::new(expirations),
+            lock: Mutex::new(Some(lock)),
+            notifier,
+            logger: logger.clone(),
+            expiration_task: Notify::new(),
+            shutdown: AtomicBool::new(false),
+        });
+        tokio::spawn(purge_expired_tasks(shared.clone()));
+        return Ok(BTreeStore { logger, shared });
+    }
+}
+
+impl KeyValueStore for BTreeStore {
+    fn get(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        let value = live(self.shared.tree.lock().unwrap().get(key)?).map(|value| value.data);
+        debug!(self.logger, "Get: {:?} | {:?}", key, value);
+        return Ok(value);
+    }
+
+    fn set(&self, key: String, value: Bytes, expire: Option<Duration>) -> crate::Result<()> {
+        debug!(self.logger, "Set: {:?} | {:?} | {:?}", key, value, expire);
+        let expires_at = expire.map(|duration| SystemTime::now() + duration);
+        let mut tree = self.shared.tree.lock().unwrap();
+        tree.insert(key.clone(), ExpiringValue::new(value, expires_at))?;
+        self.shared.expire_at(&key, expires_at);
+        drop(tree);
+
+        self.shared.notifier.notify(KeyEvent::Set, &key);
+        if expire.is_some() {
+            self.shared.notifier.notify(KeyEvent::Expire, &key);
+        }
+        return Ok(());
+    }
+
+    /// An expired key the background task did not remove yet is removed,
+    /// but reported missing.
+    fn delete(&self, key: &str) -> crate::Result<bool> {
+        let mut tree = self.shared.tree.lock().unwrap();
+        let existed = live(tree.get(key)?).is_some();
+        tree.remove(key)?;
+        self.shared.expire_at(key, None);
+        drop(tree);
+
+        if existed {
+            self.shared.notifier.notify(KeyEvent::Del, key);
+        }
+        debug!(self.logger, "Delete: {:?} | {:?}", key, existed);
+        return Ok(existed);
+    }
+
+    /// Rewrite the value of `key` with its new expiration time.
+    fn expire(&self, key: &str, expire: Duration) -> crate::Result<bool> {
+        let expires_at = Some(SystemTime::now() + expire);
+        let exists = self.shared.update(key, |value| Some(ExpiringValue { expires_at, ..value }))?;
+        if exists {
+            self.shared.notifier.notify(KeyEvent::Expire, key);
+        }
+        debug!(self.logger, "Expire: {:?} | {:?} | {:?}", key, expire, exists);
+        return Ok(exists);
+    }
+
+    fn ttl(&self, key: &str) -> crate::Result<Expiry> {
+        return Ok(match live(self.shared.tree.lock().unwrap().get(key)?) {
+            Some(value) => value.expiry(SystemTime::now()),
+            None => Expiry::Missing,
+        });
+    }
+
+    /// Rewrite the value of `key` without an expiration time.
+    fn persist(&self, key: &str) -> crate::Result<bool> {
+        let persisted = self.shared.update(key, |value| {
+            value.expires_at?;
+            Some(ExpiringValue { expires_at: None, ..value })
+        })?;
+        if persisted {
+            self.shared.notifier.notify(KeyEvent::Persist, key);
+        }
+        debug!(self.logger, "Persist: {:?} | {:?}", key, persisted);
+        return Ok(persisted);
+    }
+
+    /// Walks the leaf chain from the start of the range. Descending scans
+    /// walk the whole range, the leaves are only linked in ascending order.
+    fn scan(&self, options: &ScanOptions) -> crate::Result<Vec<(String, Bytes)>> {
+        let upper = options.upper();
+        let limit = if options.reverse { None } else { options.limit };
+
+        // The expired values the background task did not remove yet are
+        // skipped.
+        let now = SystemTime::now();
+        let mut entries = Vec::new();
+        self.shared.tree.lock().unwrap().visit_from(options.lower().unwrap_or_default(), |key, value| {
+            if upper.as_deref().map(|upper| key >= upper).unwrap_or(false) || Some(entries.len()) == limit {
+                return false;
+            }
+            if !value.is_expired(now) {
+                entries.push(Ok((key.to_string(), value.data.clone())));
+            }
+            return true;
+        })?;
+
+        let entries = options.collect(entries)?;
+        debug!(self.logger, "Scan: {:?} | {:?} entries", options, entries.len());
+        return Ok(entries);
+    }
+
+    /// The writes are applied under the tree lock, readers see them all at
+    /// once, and the modified pages are committed once: after a crash the
+    /// tree holds all of them or none. A failed write discards the others.
+    fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        let now = SystemTime::now();
+        let mut writes = Vec::with_capacity(batch.len());
+        for write in batch {
+            match write {
+                Write::Set { key, value, expire } => writes.push((key, Some(ExpiringValue::new(value, expire.map(|duration| now + duration))))),
+                Write::Delete { key } => writes.push((key, None)),
+            }
+        }
+
+        debug!(self.logger, "Write batch: {:?} writes", writes.len());
+        let mut tree = self.shared.tree.lock().unwrap();
+        // Only deletes of existing keys are notified.
+        let mut exists = HashMap::new();
+        let mut events = Vec::with_capacity(writes.len());
+        for (key, value) in &writes {
+            if let Some(value) = value {
+                events.push((KeyEvent::Set, key.clone()));
+                if value.expires_at.is_some() {
+                    events.push((KeyEvent::Expire, key.clone()));
+                }
+            } else {
+                let existed = match exists.get(key) {
+                    Some(existed) => *existed,
+                    None => live(tree.get(key)?).is_some(),
+                };
+                if existed {
+                    events.push((KeyEvent::Del, key.clone()));
+                }
+            }
+            exists.insert(key.clone(), value.is_some());
+        }
+        let expirations: Vec<_> = writes
+            .iter()
+            .map(|(key, value)| (key.clone(), value.as_ref().and_then(|value| value.expires_at)))
+            .collect();
+        tree.apply(writes)?;
+        for (key, expires_at) in expirations {
+            self.shared.expire_at(&key, expires_at);
+        }
+        drop(tree);
+
+        for (event, key) in events {
+            self.shared.notifier.notify(event, &key);
+        }
+        return Ok(());
+    }
+
+    /// Signals the background task to shut down and releases the lock of the
+    /// tree file.
+    fn shutdown_purge_task(&self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.shared.expiration_task.notify_one();
+        self.shared.lock.lock().unwrap().take();
+    }
+}
+
+impl Shared {
+    /// Track that `key` now expires at `expires_at`, waking the background
+    /// task up if it is the next key to expire. Called under the tree lock,
+    /// once the change is committed.
+    fn expire_at(&self, key: &str, expires_at: Option<SystemTime>) {
+        if self.expirations.lock().unwrap().set(key, expires_at.map(to_instant)) {
+            self.expiration_task.notify_one();
+        }
+    }
+
+    /// Replace the live value of `key` with the one `update` returns, if it
+    /// returns one. Returns `true` if the value was replaced.
+    fn update(&self, key: &str, update: impl FnOnce(ExpiringValue) -> Option<ExpiringValue>) -> Result<bool> {
+        let mut tree = self.tree.lock().unwrap();
+        let value = match live(tree.get(key)?).and_then(update) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let expires_at = value.expires_at;
+        tree.insert(key.to_string(), value)?;
+        self.expire_at(key, expires_at);
+        return Ok(true);
+    }
+}
+
+impl PurgeExpired for Shared {
+    /// Remove every expired key, all in one commit. If that fails, the keys
+    /// are purged again after `PURGE_RETRY_DELAY`, reads skip them in the
+    /// meantime.
+    fn purge_expired_keys(&self) -> Option<Instant> {
+        let mut tree = self.tree.lock().unwrap();
+        let mut expirations = self.expirations.lock().unwrap();
+        if self.is_shutdown() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        while let Some(key) = expirations.pop_expired(now) {
+            expired.push(key);
+        }
+        if !expired.is_empty() {
+            match tree.apply(expired.iter().map(|key| (key.clone(), None)).collect()) {
+                Ok(()) => {
+                    for key in expired.iter() {
+                        self.notifier.notify(KeyEvent::Expired, key);
+                    }
+                }
+                Err(err) => {
+                    error!(self.logger, "err = {}, failed to remove {:?} expired keys", err, expired.len());
+                    for key in expired.iter() {
+                        expirations.set(key, Some(now + PURGE_RETRY_DELAY));
+                    }
+                }
+            }
+        }
+        return expirations.next_expiration();
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    fn expiration_task(&self) -> &Notify {
+        &self.expiration_task
+    }
+}
+
+/// `value`, unless it expired: the background task may not have removed it
+/// yet.
+fn live(value: Option<ExpiringValue>) -> Option<ExpiringValue> {
+    return value.filter(|value| !value.is_expired(SystemTime::now()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::key_value_store::KeyspaceEvents;
+    use crate::server::PubSub;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    fn key(i: usize) -> String {
+        format!("key{:05}", i)
+    }
+
+    fn value(i: usize) -> Bytes {
+        Bytes::from(format!("{}{}", "v".repeat(i % 200), i))
+    }
+
+    /// Visit 0..n in a scrambled but deterministic order.
+    fn scrambled(n: usize) -> impl Iterator<Item = usize> {
+        (0..n).map(move |i| (i * 7919) % n)
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BTreeStore::open(logger(), dir.path().join(BTREE_FILE), 16, Notifier::default()).unwrap();
+        for i in scrambled(500) {
+            store.set(key(i), value(i), None).unwrap();
+        }
+
+        let options = ScanOptions {
+            start: Some(key(100)),
+            end: Some(key(400)),
+            limit: Some(150),
+            ..ScanOptions::default()
+        };
+        let entries = store.scan(&options).unwrap();
+        assert_eq!(entries, (100..250).map(|i| (key(i), value(i))).collect::<Vec<_>>());
+
+        let options = ScanOptions {
+            prefix: Some("key000".to_string()),
+            limit: Some(5),
+            reverse: true,
+            ..ScanOptions::default()
+        };
+        let entries = store.scan(&options).unwrap();
+        assert_eq!(entries, (95..100).rev().map(|i| (key(i), value(i))).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_set_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BTreeStore::open(logger(), dir.path().join(BTREE_FILE), 16, Notifier::default()).unwrap();
+
+        assert_eq!(store.get("key").unwrap(), None);
+        store.set("key".to_string(), Bytes::from("value"), None).unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("value")));
+        store.set("key".to_string(), Bytes::from("other"), None).unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("other")));
+
+        let too_large = Bytes::from(vec![0u8; MAX_ENTRY_SIZE]);
+        assert!(store.set("key".to_string(), too_large, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_splits_and_merges() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(BTREE_FILE);
+        let store = BTreeStore::open(logger(), &path, 16, Notifier::default()).unwrap();
+
+        let n = 2000;
+        for i in scrambled(n) {
+            store.set(key(i), value(i), None).unwrap();
+        }
+        for i in 0..n {
+            assert_eq!(store.get(&key(i)).unwrap(), Some(value(i)));
+        }
+        let expected: Vec<String> = (0..n).map(key).collect();
+        assert_eq!(store.shared.tree.lock().unwrap().keys().unwrap(), expected);
+
+        for i in scrambled(n).filter(|i| i % 4 != 0) {
+            assert!(store.delete(&key(i)).unwrap());
+        }
+        assert!(!store.delete(&key(1)).unwrap());
+        let expected: Vec<String> = (0..n).filter(|i| i % 4 == 0).map(key).collect();
+        assert_eq!(store.shared.tree.lock().unwrap().keys().unwrap(), expected);
+
+        // Pages freed by the merges are reused before the file grows.
+        let page_count = store.shared.tree.lock().unwrap().pager().page_count();
+        for i in (0..n).filter(|i| i % 4 == 1) {
+            store.set(key(i), value(i), None).unwrap();
+        }
+        assert_eq!(store.shared.tree.lock().unwrap().pager().page_count(), page_count);
+        store.shutdown_purge_task();
+        drop(store);
+
+        let store = BTreeStore::open(logger(), &path, 16, Notifier::default()).unwrap();
+        for i in 0..n {
+            let expected = if i % 4 <= 1 { Some(value(i)) } else { None };
+            assert_eq!(store.get(&key(i)).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_crash_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(BTREE_FILE);
+        let store = BTreeStore::open(logger(), &path, 16, Notifier::default()).unwrap();
+        let n = 300;
+        for i in 0..n {
+            store.set(key(i), value(i), None).unwrap();
+        }
+
+        // A split is committed with the metadata and at least three pages,
+        // the crash leaves the metadata and one of them in place.
+        let mut crashed = n;
+        loop {
+            store.shared.tree.lock().unwrap().pager_mut().crash_after = Some(2);
+            if store.set(key(crashed), value(crashed), None).is_err() {
+                break;
+            }
+            crashed += 1;
+        }
+        store.shutdown_purge_task();
+        drop(store);
+
+        // The journal was synced, the commit is completed.
+        let store = BTreeStore::open(logger(), &path, 16, Notifier::default()).unwrap();
+        let expected: Vec<String> = (0..=crashed).map(key).collect();
+        assert_eq!(store.shared.tree.lock().unwrap().keys().unwrap(), expected);
+        for i in 0..=crashed {
+            assert_eq!(store.get(&key(i)).unwrap(), Some(value(i)));
+        }
+
+        // A crash while writing the journal leaves the tree as it was.
+        store.shared.tree.lock().unwrap().pager_mut().crash_after = Some(0);
+        assert!(store.set(key(crashed + 1), value(crashed + 1), None).is_err());
+        store.shutdown_purge_task();
+        drop(store);
+        let mut journal_name = BTREE_FILE.to_string();
+        journal_name.push_str(".journal");
+        let journal = std::fs::OpenOptions::new().write(true).open(dir.path().join(journal_name)).unwrap();
+        journal.set_len(journal.metadata().unwrap().len() - 1).unwrap();
+
+        let store = BTreeStore::open(logger(), &path, 16, Notifier::default()).unwrap();
+        assert_eq!(store.shared.tree.lock().unwrap().keys().unwrap(), expected);
+        store.set(key(crashed + 1), value(crashed + 1), None).unwrap();
+        assert_eq!(store.get(&key(crashed + 1)).unwrap(), Some(value(crashed + 1)));
+    }
+
+    #[tokio::test]
+    async fn test_write_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(BTREE_FILE);
+        let store = BTreeStore::open(logger(), &path, 16, Notifier::default()).unwrap();
+        for i in 0..100 {
+            store.set(key(i), value(i), None).unwrap();
+        }
+
+        // The large value fails the batch, the writes before it are dropped.
+        let mut batch = WriteBatch::new();
+        batch.put(key(1000), value(1000));
+        batch.delete(key(0));
+        batch.put(key(1001), Bytes::from(vec![0u8; MAX_ENTRY_SIZE]));
+        assert!(store.write_batch(batch).is_err());
+        assert_eq!(store.get(&key(1000)).unwrap(), None);
+        assert_eq!(store.get(&key(0)).unwrap(), Some(value(0)));
+        store.set(key(99), value(99), None).unwrap();
+        store.shutdown_purge_task();
+        drop(store);
+        let store = BTreeStore::open(logger(), &path, 16, Notifier::default()).unwrap();
+        assert_eq!(store.get(&key(1000)).unwrap(), None);
+        assert_eq!(store.get(&key(0)).unwrap(), Some(value(0)));
+
+        // A batch that splits leaves is committed at once, or not at all if
+        // the process crashes before its journal is synced.
+        let batch = || {
+            let mut batch = WriteBatch::new();
+            for i in 100..200 {
+                batch.put(key(i), value(i));
+            }
+            batch.delete(key(1));
+            batch
+        };
+        store.shared.tree.lock().unwrap().pager_mut().crash_after = Some(0);
+        assert!(store.write_batch(batch()).is_err());
+        store.shutdown_purge_task();
+        drop(store);
+        let mut journal_name = BTREE_FILE.to_string();
+        journal_name.push_str(".journal");
+        let journal = std::fs::OpenOptions::new().write(true).open(dir.path().join(journal_name)).unwrap();
+        journal.set_len(journal.metadata().unwrap().len() / 2).unwrap();
+
+        let store = BTreeStore::open(logger(), &path, 16, Notifier::default()).unwrap();
+        let expected: Vec<String> = (0..100).map(key).collect();
+        assert_eq!(store.shared.tree.lock().unwrap().keys().unwrap(), expected);
+
+        // Once its journal is synced, the batch survives a crash in the middle
+        // of the commit.
+        store.shared.tree.lock().unwrap().pager_mut().crash_after = Some(3);
+        assert!(store.write_batch(batch()).is_err());
+        store.shutdown_purge_task();
+        drop(store);
+        let store = BTreeStore::open(logger(), &path, 16, Notifier::default()).unwrap();
+        let expected: Vec<String> = (0..200).filter(|i| *i != 1).map(key).collect();
+        assert_eq!(store.shared.tree.lock().unwrap().keys().unwrap(), expected);
+        for i in (0..200).filter(|i| *i != 1) {
+            assert_eq!(store.get(&key(i)).unwrap(), Some(value(i)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expiration() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(BTREE_FILE);
+        let pub_sub = Arc::new(PubSub::default());
+        let mut events = pub_sub.psubscribe("__keyevent@0__:*");
+        let notifier = Notifier::new(KeyspaceEvents::from_str("EA").unwrap(), pub_sub.clone());
+        let store = BTreeStore::open(logger(), &path, 16, notifier).unwrap();
+
+        let hour = Duration::from_secs(3600);
+        store.set("short".to_string(), Bytes::from("value"), Some(Duration::from_millis(50))).unwrap();
+        store.set("long".to_string(), Bytes::from("value"), Some(hour)).unwrap();
+        store
+            .set("persisted".to_string(), Bytes::from("value"), Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(store.persist("persisted").unwrap());
+        assert!(!store.persist("persisted").unwrap());
+        store.set("forever".to_string(), Bytes::from("value"), None).unwrap();
+        assert!(store.expire("forever", hour).unwrap());
+        assert!(store.persist("forever").unwrap());
+        assert!(!store.expire("missing", hour).unwrap());
+        assert!(matches!(store.ttl("long").unwrap(), Expiry::After(ttl) if ttl > hour - Duration::from_secs(60) && ttl <= hour));
+        assert_eq!(store.ttl("forever").unwrap(), Expiry::Persistent);
+        assert_eq!(store.ttl("missing").unwrap(), Expiry::Missing);
+
+        // The background task removes the expired keys and notifies them.
+        let expected = [
+            ("set", "short"),
+            ("expire", "short"),
+            ("set", "long"),
+            ("expire", "long"),
+            ("set", "persisted"),
+            ("expire", "persisted"),
+            ("persist", "persisted"),
+            ("set", "forever"),
+            ("expire", "forever"),
+            ("persist", "forever"),
+            ("expired", "short"),
+        ];
+        for (event, key) in expected {
+            let message = events.recv().await.unwrap();
+            assert_eq!(message.channel, format!("__keyevent@0__:{}", event));
+            assert_eq!(message.content, Bytes::from(key));
+        }
+        assert_eq!(store.get("short").unwrap(), None);
+        assert_eq!(store.ttl("short").unwrap(), Expiry::Missing);
+        assert!(!store.delete("short").unwrap());
+        assert_eq!(store.shared.tree.lock().unwrap().keys().unwrap(), vec!["forever", "long", "persisted"]);
+
+        // A key that expires while the store is closed is hidden, then
+        // removed, once it is opened again. The others keep their expiration.
+        let mut batch = WriteBatch::new();
+        batch.put_expiring("closed", Bytes::from("value"), Duration::from_millis(50));
+        store.write_batch(batch).unwrap();
+        store.shutdown_purge_task();
+        drop(store);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let store = BTreeStore::open(logger(), &path, 16, Notifier::default()).unwrap();
+        assert_eq!(store.get("closed").unwrap(), None);
+        assert_eq!(store.scan(&ScanOptions::default()).unwrap().len(), 3);
+        assert!(matches!(store.ttl("long").unwrap(), Expiry::After(_)));
+        assert_eq!(store.ttl("persisted").unwrap(), Expiry::Persistent);
+        assert_eq!(store.ttl("forever").unwrap(), Expiry::Persistent);
+        for _ in 0..100 {
+            if store.shared.tree.lock().unwrap().get("closed").unwrap().is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(store.shared.tree.lock().unwrap().get("closed").unwrap(), None);
+        store.shutdown_purge_task();
+    }
+}
@@ -0,0 +1,154 @@
+use crate::Result;
+
+use bytes::{Buf, BufMut, Bytes};
+use simple_error::bail;
+use std::convert::TryInto;
+
+use super::ExpiringValue;
+use super::PAGE_SIZE;
+
+const FREE: u8 = 0;
+const LEAF: u8 = 1;
+const INTERNAL: u8 = 2;
+
+const LEAF_HEADER_LEN: usize = 1 + 2 + 4;
+const INTERNAL_HEADER_LEN: usize = 1 + 2;
+
+/// Decoded content of a tree page.
+///
+/// Page layouts, all integers are big endian:
+///
+/// ```text
+/// leaf:     | 1 | count (u16) | next leaf (u32) | key len (u16) | value len (u16) | expires at (u64) | key | value | ...
+/// internal: | 2 | count (u16) | child (u32) * (count + 1) | key len (u16) | key | ...
+/// free:     | 0 | next free page (u32) |
+/// ```
+///
+/// The expiration of a value is in milliseconds since the Unix epoch, 0 if
+/// the value does not expire. Leaves are chained through `next` in key order. An internal node with
+/// `count` keys has `count + 1` children: `children[i]` holds the keys lower
+/// than `keys[i]` and `children[i + 1]` the keys greater than or equal to it.
+#[derive(Debug, Clone)]
+pub enum Node {
+    Leaf { keys: Vec<String>, values: Vec<ExpiringValue>, next: u32 },
+    Internal { keys: Vec<String>, children: Vec<u32> },
+    Free { next: u32 },
+}
+
+impl Node {
+    pub fn empty_leaf() -> Node {
+        Node::Leaf {
+            keys: vec![],
+            values: vec![],
+            next: 0,
+        }
+    }
+
+    /// Number of bytes the node takes once encoded.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Node::Leaf { keys, values, .. } => LEAF_HEADER_LEN + keys.iter().zip(values.iter()).map(|(k, v)| leaf_entry_len(k, v)).sum::<usize>(),
+            Node::Internal { keys, children } => INTERNAL_HEADER_LEN + 4 * children.len() + keys.iter().map(|k| internal_key_len(k)).sum::<usize>(),
+            Node::Free { .. } => 1 + 4,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        if self.encoded_len() > PAGE_SIZE {
+            bail!("node of {:?} bytes does not fit in a page", self.encoded_len());
+        }
+
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        match self {
+            Node::Leaf { keys, values, next } => {
+                buf.put_u8(LEAF);
+                buf.put_u16(keys.len().try_into()?);
+                buf.put_u32(*next);
+                for (key, value) in keys.iter().zip(values.iter()) {
+                    buf.put_u16(key.len().try_into()?);
+                    buf.put_u16(value.data.len().try_into()?);
+                    buf.put_u64(value.expires_at_millis());
+                    buf.put(key.as_bytes());
+                    buf.put(&value.data[..]);
+                }
+            }
+            Node::Internal { keys, children } => {
+                buf.put_u8(INTERNAL);
+                buf.put_u16(keys.len().try_into()?);
+                for child in children.iter() {
+                    buf.put_u32(*child);
+                }
+                for key in keys.iter() {
+                    buf.put_u16(key.len().try_into()?);
+                    buf.put(key.as_bytes());
+                }
+            }
+            Node::Free { next } => {
+                buf.put_u8(FREE);
+                buf.put_u32(*next);
+            }
+        }
+        buf.resize(PAGE_SIZE, 0);
+
+        return Ok(buf);
+    }
+
+    pub fn decode(mut src: &[u8]) -> Result<Node> {
+        let node = match src.get_u8() {
+            FREE => Node::Free { next: src.get_u32() },
+            LEAF => {
+                let count = src.get_u16() as usize;
+                let next = src.get_u32();
+                let mut keys = Vec::with_capacity(count);
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    if src.remaining() < 12 {
+                        bail!("leaf page is corrupted");
+                    }
+                    let key_len = src.get_u16() as usize;
+                    let value_len = src.get_u16() as usize;
+                    let expires_at = src.get_u64();
+                    if src.remaining() < key_len + value_len {
+                        bail!("leaf page is corrupted");
+                    }
+                    keys.push(String::from_utf8(src[..key_len].to_vec())?);
+                    src.advance(key_len);
+                    values.push(ExpiringValue::from_millis(Bytes::copy_from_slice(&src[..value_len]), expires_at));
+                    src.advance(value_len);
+                }
+                Node::Leaf { keys, values, next }
+            }
+            INTERNAL => {
+                let count = src.get_u16() as usize;
+                if src.remaining() < 4 * (count + 1) {
+                    bail!("internal page is corrupted");
+                }
+                let children = (0..count + 1).map(|_| src.get_u32()).collect();
+                let mut keys = Vec::with_capacity(count);
+                for _ in 0..count {
+                    if src.remaining() < 2 {
+                        bail!("internal page is corrupted");
+                    }
+                    let key_len = src.get_u16() as usize;
+                    if src.remaining() < key_len {
+                        bail!("internal page is corrupted");
+                    }
+                    keys.push(String::from_utf8(src[..key_len].to_vec())?);
+                    src.advance(key_len);
+                }
+                Node::Internal { keys, children }
+            }
+            kind => bail!("unknown page type {:?}", kind),
+        };
+
+        return Ok(node);
+    }
+}
+
+pub fn leaf_entry_len(key: &str, value: &ExpiringValue) -> usize {
+    2 + 2 + 8 + key.len() + value.data.len()
+}
+
+pub fn internal_key_len(key: &str) -> usize {
+    2 + key.len()
+}
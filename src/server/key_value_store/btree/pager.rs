@@ -0,0 +1,401 @@
+use crate::Result;
+
+use bytes::{Buf, BufMut};
+use simple_error::bail;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use super::node::Node;
+use super::PAGE_SIZE;
+
+const MAGIC: u64 = 0x7261_7068_6274_7231; // "raphbtr1"
+const JOURNAL_MAGIC: u64 = 0x7261_7068_6a72_6e31; // "raphjrn1"
+
+/// Length of the journal header: the magic and the number of pages.
+const JOURNAL_HEADER_LEN: usize = 8 + 4;
+
+/// Page 0 of the file holds the tree metadata, never a node.
+const META_PAGE: u32 = 0;
+
+/// Reads and writes the fixed-size pages of the tree file.
+///
+/// Decoded pages are kept in a bounded cache, evicting the least recently
+/// used clean page when full. Modified pages are marked dirty and stay in the
+/// cache until `commit` writes them, the cache outgrowing its capacity if
+/// needed: the tree file only ever holds committed pages.
+///
+/// `commit` writes the dirty pages and the metadata to a redo journal next to
+/// the tree file, and only updates the pages in place once the journal is
+/// synced. A crash while pages are updated in place leaves the journal behind,
+/// and `open` writes its pages again. A journal torn by a crash is discarded,
+/// the tree file was not modified yet. Journal layout:
+///
+/// ```text
+/// | magic (u64) | page count (u32) | page id (u32) | page | ... | crc32 (u32) |
+/// ```
+///
+/// The checksum covers everything before itself.
+///
+/// Pages released by the tree are chained in a free list, starting at
+/// `free_head`, and reused by `allocate` before the file is grown.
+#[derive(Debug)]
+pub struct Pager {
+    file: File,
+    journal: File,
+
+    meta: Meta,
+
+    /// The metadata as of the last commit.
+    committed: Meta,
+
+    cache: HashMap<u32, CachedPage>,
+    capacity: usize,
+
+    /// Logical clock used to find the least recently used page.
+    tick: u64,
+
+    /// A commit failed while updating pages in place: the tree file may only
+    /// hold part of it until `open` writes the journal again.
+    broken: bool,
+
+    /// Number of pages `commit` updates in place before failing, as if the
+    /// process crashed.
+    #[cfg(test)]
+    pub crash_after: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Meta {
+    /// Page id of the root of the tree.
+    root: u32,
+
+    /// First page of the free list, `0` if the list is empty.
+    free_head: u32,
+
+    /// Number of pages in the file, including the meta page.
+    page_count: u32,
+}
+
+#[derive(Debug)]
+struct CachedPage {
+    node: Node,
+    dirty: bool,
+    last_used: u64,
+}
+
+impl Pager {
+    /// Open the tree file at `path`, creating it with an empty root leaf if it
+    /// does not exist yet. A commit interrupted by a crash is completed first.
+    pub fn open(logger: &slog::Logger, path: &Path, capacity: usize) -> Result<Pager> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+        let mut journal_name = path.file_name().unwrap_or_default().to_os_string();
+        journal_name.push(".journal");
+        let journal_path = path.with_file_name(journal_name);
+        let new_journal = !journal_path.exists();
+        let mut journal = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&journal_path)?;
+        if new_journal {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                File::open(dir)?.sync_all()?;
+            }
+        }
+
+        let mut data = Vec::new();
+        journal.read_to_end(&mut data)?;
+        if !data.is_empty() {
+            match parse_journal(&data) {
+                Some(pages) => {
+                    warn!(logger, "Completing the commit of {:?} pages interrupted by a crash", pages.len());
+                    for (id, page) in pages {
+                        file.write_all_at(page, page_offset(id))?;
+                    }
+                    file.sync_data()?;
+                }
+                None => warn!(logger, "Discarding {:?} bytes of journal torn by a crash", data.len()),
+            }
+            journal.set_len(0)?;
+            journal.sync_data()?;
+        }
+        let file_len = file.metadata()?.len();
+
+        let meta = Meta {
+            root: 1,
+            free_head: 0,
+            page_count: 2,
+        };
+        let mut pager = Pager {
+            file,
+            journal,
+            meta,
+            // Nothing is committed in a new file, its metadata is written on
+            // the first commit.
+            committed: Meta { page_count: 0, ..meta },
+            cache: HashMap::new(),
+            capacity: capacity.max(8),
+            tick: 0,
+            broken: false,
+            #[cfg(test)]
+            crash_after: None,
+        };
+
+        if file_len == 0 {
+            pager.write(1, Node::empty_leaf());
+            pager.commit()?;
+            return Ok(pager);
+        }
+
+        if file_len % PAGE_SIZE as u64 != 0 {
+            bail!("tree file length {:?} is not a multiple of the page size", file_len);
+        }
+
+        let mut meta = [0u8; 20];
+        pager.file.read_exact_at(&mut meta, 0)?;
+        let mut meta = &meta[..];
+        if meta.get_u64() != MAGIC {
+            bail!("{:?} is not a raphdb tree file", path);
+        }
+        pager.meta = Meta {
+            root: meta.get_u32(),
+            free_head: meta.get_u32(),
+            page_count: meta.get_u32(),
+        };
+        pager.committed = pager.meta;
+
+        if pager.meta.page_count as u64 * PAGE_SIZE as u64 > file_len {
+            bail!("tree file is truncated: expected {:?} pages", pager.meta.page_count);
+        }
+
+        return Ok(pager);
+    }
+
+    pub fn root(&self) -> u32 {
+        self.meta.root
+    }
+
+    pub fn set_root(&mut self, root: u32) {
+        self.meta.root = root;
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.meta.page_count
+    }
+
+    /// Borrow the node stored in page `id`, reading it from disk on a cache
+    /// miss.
+    pub fn read(&mut self, id: u32) -> Result<&Node> {
+        self.load(id)?;
+        Ok(&self.cache[&id].node)
+    }
+
+    /// Replace the node stored in page `id`. The page is written on the next
+    /// `commit`.
+    pub fn write(&mut self, id: u32, node: Node) {
+        self.tick += 1;
+        let last_used = self.tick;
+        if !self.cache.contains_key(&id) {
+            self.make_room();
+        }
+        self.cache.insert(id, CachedPage { node, dirty: true, last_used });
+    }
+
+    /// Store `node` in a page taken from the free list, or appended to the
+    /// file if the list is empty.
+    pub fn allocate(&mut self, node: Node) -> Result<u32> {
+        let id = if self.meta.free_head != 0 {
+            let id = self.meta.free_head;
+            self.meta.free_head = match self.read(id)? {
+                Node::Free { next } => *next,
+                _ => bail!("page {:?} is on the free list but is not free", id),
+            };
+            id
+        } else {
+            self.meta.page_count += 1;
+            self.meta.page_count - 1
+        };
+
+        self.write(id, node);
+        return Ok(id);
+    }
+
+    /// Give page `id` back to the free list.
+    pub fn free(&mut self, id: u32) {
+        let next = self.meta.free_head;
+        self.write(id, Node::Free { next });
+        self.meta.free_head = id;
+    }
+
+    /// Write every dirty page and the metadata to the journal, then to the
+    /// tree file, syncing both.
+    pub fn commit(&mut self) -> Result<()> {
+        if self.broken {
+            bail!("a commit of the tree failed, it must be reopened to be recovered");
+        }
+        let mut dirty: Vec<u32> = self.cache.iter().filter(|(_, page)| page.dirty).map(|(id, _)| *id).collect();
+        if dirty.is_empty() && self.meta == self.committed {
+            return Ok(());
+        }
+        dirty.sort_unstable();
+
+        let mut pages = Vec::with_capacity(dirty.len() + 1);
+        pages.push((META_PAGE, self.meta.encode()));
+        for id in dirty.iter() {
+            pages.push((*id, self.cache[id].node.encode()?));
+        }
+
+        let mut journal = Vec::with_capacity(JOURNAL_HEADER_LEN + pages.len() * (4 + PAGE_SIZE) + 4);
+        journal.put_u64(JOURNAL_MAGIC);
+        journal.put_u32(pages.len().try_into()?);
+        for (id, page) in pages.iter() {
+            journal.put_u32(*id);
+            journal.put_slice(page);
+        }
+        journal.put_u32(crc32fast::hash(&journal));
+        if let Err(e) = self.write_journal(&journal) {
+            // The commit failed, it must not be completed by the next `open`.
+            if self.journal.set_len(0).and_then(|_| self.journal.sync_data()).is_err() {
+                self.broken = true;
+            }
+            return Err(e);
+        }
+
+        // The commit is durable from here on, a failure leaves it for `open`
+        // to complete.
+        #[cfg(not(test))]
+        let in_place = &pages[..];
+        #[cfg(test)]
+        let in_place = &pages[..self.crash_after.unwrap_or(pages.len()).min(pages.len())];
+        for (id, page) in in_place.iter() {
+            if let Err(e) = self.file.write_all_at(page, page_offset(*id)) {
+                self.broken = true;
+                return Err(e.into());
+            }
+        }
+        #[cfg(test)]
+        if in_place.len() < pages.len() {
+            self.broken = true;
+            bail!("simulated crash after {:?} pages", in_place.len());
+        }
+        if let Err(e) = self.file.sync_data() {
+            self.broken = true;
+            return Err(e.into());
+        }
+        // Neither synced nor checked: a journal left behind only makes `open`
+        // write again pages the tree file already holds, and the next commit
+        // truncates it again before writing its own.
+        let _ = self.journal.set_len(0);
+
+        for id in dirty {
+            if let Some(page) = self.cache.get_mut(&id) {
+                page.dirty = false;
+            }
+        }
+        self.committed = self.meta;
+        return Ok(());
+    }
+
+    /// Discard the changes made since the last commit.
+    pub fn rollback(&mut self) {
+        self.cache.retain(|_, page| !page.dirty);
+        self.meta = self.committed;
+    }
+
+    fn write_journal(&mut self, journal: &[u8]) -> Result<()> {
+        self.journal.set_len(0)?;
+        self.journal.write_all_at(journal, 0)?;
+        self.journal.sync_data()?;
+        return Ok(());
+    }
+
+    fn load(&mut self, id: u32) -> Result<()> {
+        if id == META_PAGE || id >= self.meta.page_count {
+            bail!("page {:?} is out of bounds", id);
+        }
+
+        self.tick += 1;
+        if let Some(page) = self.cache.get_mut(&id) {
+            page.last_used = self.tick;
+            return Ok(());
+        }
+
+        if self.broken {
+            bail!("a commit of the tree failed, it must be reopened to be recovered");
+        }
+        let mut buf = vec![0u8; PAGE_SIZE];
+        self.file.read_exact_at(&mut buf, page_offset(id))?;
+        let node = Node::decode(&buf)?;
+
+        self.make_room();
+        self.cache.insert(
+            id,
+            CachedPage {
+                node,
+                dirty: false,
+                last_used: self.tick,
+            },
+        );
+        return Ok(());
+    }
+
+    /// Evict the least recently used clean page if the cache is full. Dirty
+    /// pages are never evicted, they are not committed yet.
+    fn make_room(&mut self) {
+        if self.cache.len() < self.capacity {
+            return;
+        }
+
+        let lru = self
+            .cache
+            .iter()
+            .filter(|(_, page)| !page.dirty)
+            .min_by_key(|(_, page)| page.last_used)
+            .map(|(id, _)| *id);
+        if let Some(id) = lru {
+            self.cache.remove(&id);
+        }
+    }
+}
+
+impl Meta {
+    fn encode(&self) -> Vec<u8> {
+        let mut meta = Vec::with_capacity(PAGE_SIZE);
+        meta.put_u64(MAGIC);
+        meta.put_u32(self.root);
+        meta.put_u32(self.free_head);
+        meta.put_u32(self.page_count);
+        meta.resize(PAGE_SIZE, 0);
+        return meta;
+    }
+}
+
+/// Decode the pages of a journal, with their id. Returns `None` if the
+/// journal is not whole, or its checksum does not match.
+fn parse_journal(data: &[u8]) -> Option<Vec<(u32, &[u8])>> {
+    if data.len() < JOURNAL_HEADER_LEN + 4 {
+        return None;
+    }
+    let (body, mut crc) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body) != crc.get_u32() {
+        return None;
+    }
+    let mut header = &body[..JOURNAL_HEADER_LEN];
+    if header.get_u64() != JOURNAL_MAGIC {
+        return None;
+    }
+    let count = header.get_u32() as usize;
+    if body.len() != JOURNAL_HEADER_LEN + count * (4 + PAGE_SIZE) {
+        return None;
+    }
+    let pages = body[JOURNAL_HEADER_LEN..]
+        .chunks(4 + PAGE_SIZE)
+        .map(|mut chunk| (chunk.get_u32(), chunk))
+        .collect();
+    return Some(pages);
+}
+
+fn page_offset(id: u32) -> u64 {
+    id as u64 * PAGE_SIZE as u64
+}
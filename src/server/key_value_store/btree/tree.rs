@@ -0,0 +1,411 @@
+use crate::Result;
+
+use simple_error::bail;
+
+use super::node::{internal_key_len, leaf_entry_len, Node};
+use super::pager::Pager;
+use super::ExpiringValue;
+use super::{MAX_ENTRY_SIZE, PAGE_SIZE};
+
+/// Nodes filled below this many bytes are merged with, or borrow entries from,
+/// a sibling.
+const MIN_FILL: usize = PAGE_SIZE / 4;
+
+/// B+tree of string keys and byte values, with their expiration, stored in
+/// the pages of a `Pager`.
+///
+/// Values only live in the leaves. A node that outgrows its page is split in
+/// two halves of similar byte size, and a node that drops below `MIN_FILL` is
+/// rebalanced with a sibling: merged with it if both fit in a single page,
+/// otherwise their entries are redistributed evenly. Pages released by merges
+/// go back to the pager's free list.
+#[derive(Debug)]
+pub struct BTree {
+    pager: Pager,
+}
+
+impl BTree {
+    pub fn new(pager: Pager) -> BTree {
+        BTree { pager }
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<ExpiringValue>> {
+        let mut id = self.pager.root();
+        loop {
+            match self.pager.read(id)? {
+                Node::Internal { keys, children } => id = children[child_index(keys, key)],
+                Node::Leaf { keys, values, .. } => {
+                    return Ok(keys.binary_search_by(|k| k.as_str().cmp(key)).ok().map(|i| values[i].clone()));
+                }
+                Node::Free { .. } => bail!("reached free page {:?} while walking the tree", id),
+            }
+        }
+    }
+
+    /// Insert or replace `key` and persist the changes.
+    pub fn insert(&mut self, key: String, value: ExpiringValue) -> Result<()> {
+        let result = self.put(key, value);
+        return self.commit(result);
+    }
+
+    /// Remove `key` and persist the changes. Returns `true` if the key existed.
+    pub fn remove(&mut self, key: &str) -> Result<bool> {
+        let result = self.take(key);
+        return self.commit(result);
+    }
+
+    /// Insert every key with a value and remove the others, in order, then
+    /// persist all the changes at once. Every write is checked first, if one
+    /// fails none of them is applied.
+    pub fn apply(&mut self, writes: Vec<(String, Option<ExpiringValue>)>) -> Result<()> {
+        for (key, value) in writes.iter() {
+            if let Some(value) = value {
+                check_entry(key, value)?;
+            }
+        }
+
+        let mut result = Ok(());
+        for (key, value) in writes {
+            result = match value {
+                Some(value) => self.put(key, value),
+                None => self.take(&key).map(|_| ()),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        return self.commit(result);
+    }
+
+    /// Commit the changes if `result`, the outcome of making them, is a
+    /// success. Otherwise, or if the commit fails, the changes are discarded.
+    fn commit<T>(&mut self, result: Result<T>) -> Result<T> {
+        let committed = result.and_then(|value| self.pager.commit().map(|_| value));
+        if committed.is_err() {
+            self.pager.rollback();
+        }
+        return committed;
+    }
+
+    #[cfg(test)]
+    pub fn pager(&self) -> &Pager {
+        &self.pager
+    }
+
+    #[cfg(test)]
+    pub fn pager_mut(&mut self) -> &mut Pager {
+        &mut self.pager
+    }
+
+    /// Every key of the tree, in order, collected by following the leaf chain.
+    #[cfg(test)]
+    pub fn keys(&mut self) -> Result<Vec<String>> {
+        let mut all = Vec::new();
+        self.visit_from("", |key, _| {
+            all.push(key.to_string());
+            true
+        })?;
+        return Ok(all);
+    }
+
+    /// Call `visit` on the entries whose key is at least `start`, in key
+    /// order, until it returns `false`. The entries are found by following
+    /// the leaf chain from the leaf that would hold `start`.
+    pub fn visit_from(&mut self, start: &str, mut visit: impl FnMut(&str, &ExpiringValue) -> bool) -> Result<()> {
+        let mut id = self.pager.root();
+        while let Node::Internal { keys, children } = self.pager.read(id)? {
+            id = children[child_index(keys, start)];
+        }
+
+        while id != 0 {
+            match self.pager.read(id)? {
+                Node::Leaf { keys, values, next } => {
+                    let first = keys.partition_point(|k| k.as_str() < start);
+                    for (key, value) in keys[first..].iter().zip(values[first..].iter()) {
+                        if !visit(key, value) {
+                            return Ok(());
+                        }
+                    }
+                    id = *next;
+                }
+                _ => bail!("page {:?} is not a leaf", id),
+            }
+        }
+        return Ok(());
+    }
+
+    /// Insert or replace `key`, without persisting the changes.
+    fn put(&mut self, key: String, value: ExpiringValue) -> Result<()> {
+        check_entry(&key, &value)?;
+
+        let root = self.pager.root();
+        if let Some((separator, right)) = self.insert_into(root, key, value)? {
+            let new_root = self.pager.allocate(Node::Internal {
+                keys: vec![separator],
+                children: vec![root, right],
+            })?;
+            self.pager.set_root(new_root);
+        }
+
+        return Ok(());
+    }
+
+    /// Remove `key`, without persisting the changes. Returns `true` if the key
+    /// existed.
+    fn take(&mut self, key: &str) -> Result<bool> {
+        let root = self.pager.root();
+        let removed = self.remove_from(root, key)?;
+
+        // An internal root left with a single child is replaced by that child,
+        // shrinking the tree by one level.
+        if let Node::Internal { keys, children } = self.pager.read(root)? {
+            if keys.is_empty() {
+                let child = children[0];
+                self.pager.set_root(child);
+                self.pager.free(root);
+            }
+        }
+
+        return Ok(removed);
+    }
+
+    /// Insert into the subtree rooted at `id`. If the node had to be split,
+    /// returns the first key of the new right node and its page.
+    fn insert_into(&mut self, id: u32, key: String, value: ExpiringValue) -> Result<Option<(String, u32)>> {
+        match self.pager.read(id)?.clone() {
+            Node::Leaf { mut keys, mut values, next } => {
+                match keys.binary_search(&key) {
+                    Ok(i) => values[i] = value,
+                    Err(i) => {
+                        keys.insert(i, key);
+                        values.insert(i, value);
+                    }
+                }
+
+                let node = Node::Leaf { keys, values, next };
+                if node.encoded_len() <= PAGE_SIZE {
+                    self.pager.write(id, node);
+                    return Ok(None);
+                }
+                return self.split(id, node).map(Some);
+            }
+            Node::Internal { mut keys, mut children } => {
+                let i = child_index(&keys, &key);
+                let split = match self.insert_into(children[i], key, value)? {
+                    Some(split) => split,
+                    None => return Ok(None),
+                };
+
+                keys.insert(i, split.0);
+                children.insert(i + 1, split.1);
+
+                let node = Node::Internal { keys, children };
+                if node.encoded_len() <= PAGE_SIZE {
+                    self.pager.write(id, node);
+                    return Ok(None);
+                }
+                return self.split(id, node).map(Some);
+            }
+            Node::Free { .. } => bail!("reached free page {:?} while walking the tree", id),
+        }
+    }
+
+    /// Split an overflowing node. The left half stays in page `id`.
+    fn split(&mut self, id: u32, node: Node) -> Result<(String, u32)> {
+        let (left, separator, right) = halve(node);
+        let right_id = self.pager.allocate(right)?;
+
+        let left = match left {
+            Node::Leaf { keys, values, .. } => Node::Leaf { keys, values, next: right_id },
+            left => left,
+        };
+        self.pager.write(id, left);
+
+        return Ok((separator, right_id));
+    }
+
+    fn remove_from(&mut self, id: u32, key: &str) -> Result<bool> {
+        match self.pager.read(id)?.clone() {
+            Node::Leaf { mut keys, mut values, next } => match keys.binary_search_by(|k| k.as_str().cmp(key)) {
+                Ok(i) => {
+                    keys.remove(i);
+                    values.remove(i);
+                    self.pager.write(id, Node::Leaf { keys, values, next });
+                    Ok(true)
+                }
+                Err(_) => Ok(false),
+            },
+            Node::Internal { keys, children } => {
+                let i = child_index(&keys, key);
+                if !self.remove_from(children[i], key)? {
+                    return Ok(false);
+                }
+
+                if self.pager.read(children[i])?.encoded_len() < MIN_FILL {
+                    // Rebalance with the left sibling, or the right one for
+                    // the first child.
+                    let left = if i > 0 { i - 1 } else { 0 };
+                    if children.len() > 1 {
+                        self.rebalance(id, left)?;
+                    }
+                }
+                Ok(true)
+            }
+            Node::Free { .. } => bail!("reached free page {:?} while walking the tree", id),
+        }
+    }
+
+    /// Rebalance the children `i` and `i + 1` of the internal node `parent_id`.
+    fn rebalance(&mut self, parent_id: u32, i: usize) -> Result<()> {
+        let (mut parent_keys, mut parent_children) = match self.pager.read(parent_id)?.clone() {
+            Node::Internal { keys, children } => (keys, children),
+            _ => bail!("page {:?} is not an internal node", parent_id),
+        };
+        let left_id = parent_children[i];
+        let right_id = parent_children[i + 1];
+
+        let left = self.pager.read(left_id)?.clone();
+        let right = self.pager.read(right_id)?.clone();
+
+        let combined = match (left, right) {
+            (
+                Node::Leaf {
+                    keys: mut left_keys,
+                    values: mut left_values,
+                    ..
+                },
+                Node::Leaf { keys, values, next },
+            ) => {
+                left_keys.extend(keys);
+                left_values.extend(values);
+                Node::Leaf {
+                    keys: left_keys,
+                    values: left_values,
+                    next,
+                }
+            }
+            (
+                Node::Internal {
+                    keys: mut left_keys,
+                    children: mut left_children,
+                },
+                Node::Internal { keys, children },
+            ) => {
+                // The separator comes down from the parent between the two
+                // halves.
+                left_keys.push(parent_keys[i].clone());
+                left_keys.extend(keys);
+                left_children.extend(children);
+                Node::Internal {
+                    keys: left_keys,
+                    children: left_children,
+                }
+            }
+            _ => bail!("siblings {:?} and {:?} are not of the same kind", left_id, right_id),
+        };
+
+        if combined.encoded_len() <= PAGE_SIZE {
+            self.pager.write(left_id, combined);
+            self.pager.free(right_id);
+            parent_keys.remove(i);
+            parent_children.remove(i + 1);
+        } else {
+            let (left, separator, right) = halve(combined);
+            let left = match left {
+                Node::Leaf { keys, values, .. } => Node::Leaf { keys, values, next: right_id },
+                left => left,
+            };
+            self.pager.write(left_id, left);
+            self.pager.write(right_id, right);
+            parent_keys[i] = separator;
+        }
+
+        self.pager.write(
+            parent_id,
+            Node::Internal {
+                keys: parent_keys,
+                children: parent_children,
+            },
+        );
+        return Ok(());
+    }
+}
+
+/// Fail if `key` and `value` are too large to be stored in a leaf.
+fn check_entry(key: &str, value: &ExpiringValue) -> Result<()> {
+    if leaf_entry_len(key, value) > MAX_ENTRY_SIZE {
+        bail!(
+            "key and value of {:?} bytes are too large, the limit is {:?}",
+            key.len() + value.data.len(),
+            MAX_ENTRY_SIZE
+        );
+    }
+    return Ok(());
+}
+
+/// Index of the child of an internal node that may hold `key`.
+fn child_index(keys: &[String], key: &str) -> usize {
+    keys.partition_point(|k| k.as_str() <= key)
+}
+
+/// Cut a node in two halves of similar byte size. Returns the left half, the
+/// separator key and the right half.
+///
+/// For leaves the separator is the first key of the right half and the left
+/// half still points to the old `next` leaf. For internal nodes the separator
+/// is moved up and belongs to neither half.
+fn halve(node: Node) -> (Node, String, Node) {
+    let half = node.encoded_len() / 2;
+    match node {
+        Node::Leaf { mut keys, mut values, next } => {
+            let mut size = 0;
+            let mut mid = keys.len() - 1;
+            for (i, (key, value)) in keys.iter().zip(values.iter()).enumerate() {
+                size += leaf_entry_len(key, value);
+                if size >= half {
+                    mid = i + 1;
+                    break;
+                }
+            }
+            let mid = mid.clamp(1, keys.len() - 1);
+
+            let right_keys = keys.split_off(mid);
+            let right_values = values.split_off(mid);
+            let separator = right_keys[0].clone();
+            (
+                Node::Leaf { keys, values, next },
+                separator,
+                Node::Leaf {
+                    keys: right_keys,
+                    values: right_values,
+                    next,
+                },
+            )
+        }
+        Node::Internal { mut keys, mut children } => {
+            let mut size = 0;
+            let mut mid = keys.len() / 2;
+            for (i, key) in keys.iter().enumerate() {
+                size += internal_key_len(key) + 4;
+                if size >= half {
+                    mid = i;
+                    break;
+                }
+            }
+            let mid = mid.clamp(1, keys.len() - 2);
+
+            let right_keys = keys.split_off(mid + 1);
+            let right_children = children.split_off(mid + 1);
+            let separator = keys.pop().expect("internal node has at least 3 keys");
+            (
+                Node::Internal { keys, children },
+                separator,
+                Node::Internal {
+                    keys: right_keys,
+                    children: right_children,
+                },
+            )
+        }
+        free => (free.clone(), String::new(), free),
+    }
+}
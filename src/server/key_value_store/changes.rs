@@ -0,0 +1,36 @@
+//! Changes made to a store, streamed for change data capture.
+
+use bytes::Bytes;
+use std::pin::Pin;
+use std::time::SystemTime;
+use tokio_stream::Stream;
+
+/// A write committed to the store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Position of the change: every change has a higher sequence number than
+    /// the ones committed before it. Numbers may be skipped.
+    pub seq: u64,
+
+    pub key: String,
+
+    /// The new value of the key, `None` if the key was deleted or expired.
+    pub value: Option<Bytes>,
+
+    /// When the new value expires, `None` if it does not.
+    pub expires_at: Option<SystemTime>,
+}
+
+/// Changes in the order of their sequence numbers. The stream ends with an
+/// error if it cannot read the next change.
+pub type Changes = Pin<Box<dyn Stream<Item = crate::Result<Change>> + Send>>;
+
+/// Every key of a store as of a change, to stream the later changes from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    /// Sequence number of the last change included in the snapshot.
+    pub seq: u64,
+
+    /// The live keys, each with the change that last wrote it.
+    pub entries: Vec<Change>,
+}
@@ -0,0 +1,194 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+use super::Expiry;
+
+/// Tracks when keys expire.
+///
+/// A `BTreeSet` is used to maintain expirations sorted by when they expire.
+/// This allows the background task to iterate it to find the key expiring
+/// next. Keys are part of the set entries, so several keys can expire at the
+/// same instant.
+#[derive(Debug, Default)]
+pub struct Expirations {
+    queue: BTreeSet<(Instant, String)>,
+
+    /// Instant at which each key of `queue` expires.
+    keys: HashMap<String, Instant>,
+}
+
+impl Expirations {
+    /// Set the instant at which `key` expires, replacing its previous
+    /// expiration. `None` removes the expiration.
+    ///
+    /// Returns `true` if `key` is now the **next** key to expire. In this case,
+    /// the background task needs to be woken up to update its state.
+    pub fn set(&mut self, key: &str, when: Option<Instant>) -> bool {
+        if let Some(prev) = self.keys.remove(key) {
+            self.queue.remove(&(prev, key.to_string()));
+        }
+
+        let when = match when {
+            Some(when) => when,
+            None => return false,
+        };
+        let notify = self.next_expiration().map(|expiration| expiration > when).unwrap_or(true);
+        self.queue.insert((when, key.to_string()));
+        self.keys.insert(key.to_string(), when);
+        return notify;
+    }
+
+    /// Instant at which `key` expires, if it has an expiration.
+    pub fn get(&self, key: &str) -> Option<Instant> {
+        self.keys.get(key).copied()
+    }
+
+    /// Returns `true` if `key` has an expiration that is already past.
+    pub fn is_expired(&self, key: &str) -> bool {
+        self.get(key).map(|when| when <= Instant::now()).unwrap_or(false)
+    }
+
+    pub fn next_expiration(&self) -> Option<Instant> {
+        self.queue.iter().next().map(|(when, _)| *when)
+    }
+
+    /// Remove and return the next key that expires at or before `now`.
+    pub fn pop_expired(&mut self, now: Instant) -> Option<String> {
+        let (when, key) = self.queue.iter().next()?;
+        if *when > now {
+            return None;
+        }
+
+        let entry = (*when, key.clone());
+        self.queue.remove(&entry);
+        self.keys.remove(&entry.1);
+        return Some(entry.1);
+    }
+}
+
+/// A value and the time it expires at, for the backends that store the
+/// expiration of a key along with its value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpiringValue {
+    pub data: Bytes,
+
+    /// When the value expires, `None` if it does not.
+    pub expires_at: Option<SystemTime>,
+}
+
+impl ExpiringValue {
+    pub fn new(data: Bytes, expires_at: Option<SystemTime>) -> ExpiringValue {
+        ExpiringValue { data, expires_at }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|when| when <= now)
+    }
+
+    /// How long the value has left to live as of `now`.
+    pub fn expiry(&self, now: SystemTime) -> Expiry {
+        match self.expires_at {
+            Some(when) if when <= now => Expiry::Missing,
+            Some(when) => Expiry::After(when.duration_since(now).unwrap_or_default()),
+            None => Expiry::Persistent,
+        }
+    }
+
+    /// The expiration as milliseconds since the Unix epoch, 0 if the value
+    /// does not expire.
+    pub fn expires_at_millis(&self) -> u64 {
+        match self.expires_at {
+            Some(when) => when.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as u64).unwrap_or(1).max(1),
+            None => 0,
+        }
+    }
+
+    /// Decode an expiration encoded by `expires_at_millis`.
+    pub fn from_millis(data: Bytes, millis: u64) -> ExpiringValue {
+        let expires_at = (millis > 0).then(|| UNIX_EPOCH + Duration::from_millis(millis));
+        ExpiringValue { data, expires_at }
+    }
+}
+
+/// Convert a wall clock time to an `Instant`, for the expiration tracking.
+/// Past times become the current instant.
+pub fn to_instant(when: SystemTime) -> Instant {
+    let now = Instant::now();
+    return now + when.duration_since(SystemTime::now()).unwrap_or_default();
+}
+
+/// Shared state of a backend whose keys expire.
+pub trait PurgeExpired: Send + Sync + 'static {
+    /// Purge all expired keys and return the `Instant` at which the **next**
+    /// key will expire. The background task will sleep until this instant.
+    fn purge_expired_keys(&self) -> Option<Instant>;
+
+    /// Returns `true` if the store is shutting down.
+    fn is_shutdown(&self) -> bool;
+
+    /// Notifies the background task handling expiration. The task waits on
+    /// this to be notified, then checks for expired values or the shutdown
+    /// signal.
+    fn expiration_task(&self) -> &Notify;
+}
+
+/// Routine executed by the background task.
+///
+/// Wait to be notified. On notification, purge any expired keys from the shared
+/// state handle. If `shutdown` is set, terminate the task.
+pub async fn purge_expired_tasks<T: PurgeExpired>(shared: Arc<T>) {
+    // If the shutdown flag is set, then the task should exit.
+    while !shared.is_shutdown() {
+        // Purge all keys that are expired. The function returns the instant at
+        // which the **next** key will expire. The worker should wait until the
+        // instant has passed then purge again.
+        if let Some(when) = shared.purge_expired_keys() {
+            // Wait until the next key expires **or** until the background task
+            // is notified. If the task is notified, then it must reload its
+            // state as new keys have been set to expire early. This is done by
+            // looping.
+            tokio::select! {
+                _ = time::sleep_until(when) => {}
+                _ = shared.expiration_task().notified() => {}
+            }
+        } else {
+            // There are no keys expiring in the future. Wait until the task is
+            // notified.
+            shared.expiration_task().notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[tokio::test]
+    async fn test_expirations() {
+        let now = Instant::now();
+        let mut expirations = Expirations::default();
+
+        assert!(expirations.set("a", Some(now + Duration::from_secs(10))));
+        assert!(!expirations.set("b", Some(now + Duration::from_secs(20))));
+        assert!(expirations.set("c", Some(now + Duration::from_secs(5))));
+        assert!(!expirations.set("d", None));
+
+        // Replacing and removing expirations drop the previous ones.
+        assert!(!expirations.set("c", Some(now + Duration::from_secs(30))));
+        assert!(!expirations.set("b", None));
+        assert_eq!(expirations.get("b"), None);
+        assert_eq!(expirations.next_expiration(), Some(now + Duration::from_secs(10)));
+
+        assert_eq!(expirations.pop_expired(now + Duration::from_secs(9)), None);
+        assert_eq!(expirations.pop_expired(now + Duration::from_secs(40)), Some("a".to_string()));
+        assert_eq!(expirations.pop_expired(now + Duration::from_secs(40)), Some("c".to_string()));
+        assert_eq!(expirations.pop_expired(now + Duration::from_secs(40)), None);
+        assert_eq!(expirations.next_expiration(), None);
+    }
+}
@@ -0,0 +1,98 @@
+//! Advisory lock keeping a single process at a time in a data directory.
+//!
+//! The lock is an exclusive `flock` on a lock file. The kernel releases it
+//! when its process exits, crashed or not, so a lock can never outlive its
+//! holder. The holder writes its pid to the file and clears it when it
+//! releases the lock: a pid found in a free lock file was left by a process
+//! that did not shut down cleanly.
+
+use crate::Result;
+
+use simple_error::bail;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// An exclusive lock on a lock file, released when dropped.
+#[derive(Debug)]
+pub struct LockFile {
+    file: File,
+}
+
+impl LockFile {
+    /// Take the lock of `path`, creating the file if needed. Fails if another
+    /// process holds it.
+    pub fn acquire(logger: &slog::Logger, path: impl AsRef<Path>) -> Result<LockFile> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+        // Safety: `flock` only reads the file descriptor, which is open for
+        // the duration of the call.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err.into());
+            }
+            match read_pid(&mut file)? {
+                Some(pid) => bail!("{:?} is locked by process {:?}, which is using the data directory", path, pid),
+                None => bail!("{:?} is locked by another process, which is using the data directory", path),
+            }
+        }
+
+        if let Some(pid) = read_pid(&mut file)? {
+            warn!(logger, "Found stale lock {:?} of process {:?}, which did not shut down cleanly.", path, pid);
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+
+        return Ok(LockFile { file });
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // Clearing the pid marks the release as clean. Closing the file
+        // releases the lock.
+        let _ = self.file.set_len(0).and_then(|_| self.file.sync_all());
+    }
+}
+
+/// The pid written in the lock file, `None` if it is empty or does not hold
+/// a pid.
+fn read_pid(file: &mut File) -> Result<Option<u32>> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut content)?;
+    return Ok(content.trim().parse().ok());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock_file() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("LOCK");
+
+        let lock = LockFile::acquire(&logger, &path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().trim(), std::process::id().to_string());
+        let err = LockFile::acquire(&logger, &path).unwrap_err();
+        assert!(err.to_string().contains(&std::process::id().to_string()));
+
+        // Releasing the lock clears the pid.
+        drop(lock);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+        // A pid left by a crashed process does not keep the lock from being
+        // taken.
+        std::fs::write(&path, "4194305\n").unwrap();
+        let lock = LockFile::acquire(&logger, &path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().trim(), std::process::id().to_string());
+        drop(lock);
+    }
+}
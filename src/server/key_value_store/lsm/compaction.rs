@@ -0,0 +1,109 @@
+use crate::Result;
+
+use std::iter::Peekable;
+use std::sync::Arc;
+
+use super::sstable::SsTable;
+use super::{Entry, Shared};
+
+/// A sorted stream of entries, as read from an SSTable or a memtable.
+pub type Source = Box<dyn Iterator<Item = Result<Entry>> + Send>;
+
+/// Merges the entries of several sorted sources into a single sorted stream.
+///
+/// `sources` must be ordered from newest to oldest. When several sources hold
+/// the same key, the entry of the newest one wins and the others are skipped.
+pub struct MergeIter {
+    sources: Vec<Peekable<Source>>,
+
+    /// Tombstones can only be dropped when the output replaces the oldest
+    /// table of the tree, as nothing older is left for them to hide.
+    drop_tombstones: bool,
+}
+
+impl MergeIter {
+    pub fn new(tables: &[Arc<SsTable>], drop_tombstones: bool) -> Result<MergeIter> {
+        let mut sources = Vec::with_capacity(tables.len());
+        for table in tables.iter() {
+            sources.push(Box::new(table.iter()?) as Source);
+        }
+        Ok(MergeIter::from_sources(sources, drop_tombstones))
+    }
+
+    pub fn from_sources(sources: Vec<Source>, drop_tombstones: bool) -> MergeIter {
+        let sources = sources.into_iter().map(Iterator::peekable).collect();
+        MergeIter { sources, drop_tombstones }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Find the source holding the smallest key. Sources are ordered
+            // newest first, so on ties the first one found wins.
+            let mut winner: Option<(usize, String)> = None;
+            for (i, source) in self.sources.iter_mut().enumerate() {
+                match source.peek() {
+                    None => {}
+                    Some(Err(_)) => return source.next(),
+                    Some(Ok((key, _))) if winner.as_ref().map(|(_, min)| key < min).unwrap_or(true) => {
+                        winner = Some((i, key.clone()));
+                    }
+                    Some(Ok(_)) => {}
+                }
+            }
+
+            let (i, key) = winner?;
+            let entry = match self.sources[i].next() {
+                Some(Ok(entry)) => entry,
+                other => return other,
+            };
+
+            // Skip the older versions of the key.
+            for source in self.sources.iter_mut() {
+                if let Some(Ok((other, _))) = source.peek() {
+                    if *other == key {
+                        source.next();
+                    }
+                }
+            }
+
+            if self.drop_tombstones && entry.1.is_none() {
+                continue;
+            }
+            return Some(Ok(entry));
+        }
+    }
+}
+
+/// Routine executed by the background compaction task.
+///
+/// Wait to be notified. On notification, merge the SSTables together if there
+/// are enough of them. If `shutdown` is set, terminate the task.
+pub async fn compaction_task(logger: slog::Logger, shared: Arc<Shared>) {
+    while !shared.is_shutdown() {
+        if !shared.needs_compaction() {
+            shared.background_task.notified().await;
+            continue;
+        }
+
+        // Merging tables is long, blocking work: keep it off the async
+        // workers.
+        let compact_shared = shared.clone();
+        match tokio::task::spawn_blocking(move || compact_shared.compact()).await {
+            Ok(Ok(())) => debug!(logger, "Compacted sstables"),
+            Ok(Err(err)) => {
+                error!(logger, "err = {}, failed to compact sstables", err);
+                // Wait for the next flush before trying again instead of
+                // spinning on a failing compaction.
+                shared.background_task.notified().await;
+            }
+            Err(err) => {
+                error!(logger, "err = {}, compaction task panicked", err);
+                shared.background_task.notified().await;
+            }
+        }
+    }
+}
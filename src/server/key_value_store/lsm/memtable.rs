@@ -0,0 +1,61 @@
+use std::collections::btree_map;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use super::ExpiringValue;
+
+/// In-memory, sorted write buffer of the LSM tree.
+///
+/// Every write lands here (after it has been appended to the write-ahead log)
+/// until the table grows past the configured size limit and is flushed to an
+/// SSTable. A `None` value is a tombstone: the key was removed and older
+/// SSTables must not be consulted for it.
+#[derive(Debug, Default, Clone)]
+pub struct MemTable {
+    entries: BTreeMap<String, Option<ExpiringValue>>,
+
+    /// Approximate number of bytes held by `entries`.
+    size: usize,
+}
+
+impl MemTable {
+    pub fn new() -> MemTable {
+        MemTable::default()
+    }
+
+    /// Returns `None` if the key is unknown to this table, `Some(None)` if the
+    /// key was removed and `Some(Some(value))` otherwise, even if the value
+    /// expired.
+    pub fn get(&self, key: &str) -> Option<Option<ExpiringValue>> {
+        self.entries.get(key).cloned()
+    }
+
+    pub fn insert(&mut self, key: String, value: Option<ExpiringValue>) {
+        self.size += entry_size(&key, &value);
+        let key_len = key.len();
+        if let Some(prev) = self.entries.insert(key, value) {
+            self.size -= key_len + prev.map(|v| v.data.len()).unwrap_or(0);
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, String, Option<ExpiringValue>> {
+        self.entries.iter()
+    }
+
+    /// Entries, tombstones included, whose key is within `range`.
+    pub fn range(&self, range: (Bound<String>, Bound<String>)) -> btree_map::Range<'_, String, Option<ExpiringValue>> {
+        self.entries.range(range)
+    }
+}
+
+fn entry_size(key: &str, value: &Option<ExpiringValue>) -> usize {
+    key.len() + value.as_ref().map(|v| v.data.len()).unwrap_or(0)
+}
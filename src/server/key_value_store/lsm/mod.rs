@@ -0,0 +1,1086 @@
+mod compaction;
+mod memtable;
+mod sstable;
+mod wal;
+
+use crate::Result;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use super::expiration::{purge_expired_tasks, to_instant, Expirations, ExpiringValue, PurgeExpired};
+use super::lock::LockFile;
+use super::{Expiry, KeyEvent, KeyValueStore, Notifier, ScanOptions, Write as StoreWrite, WriteBatch};
+use compaction::{compaction_task, MergeIter, Source};
+use memtable::MemTable;
+use sstable::SsTable;
+use wal::Wal;
+
+/// Log-structured merge-tree backend.
+///
+/// Writes are appended to a write-ahead log and buffered in a sorted
+/// in-memory `MemTable`. Once the memtable is large enough it is flushed to an
+/// immutable, sorted `SsTable` file. Reads go through the memtable first and
+/// then through the tables from newest to oldest.
+///
+/// As flushes accumulate tables, a background task merges them back into a
+/// single table, dropping overwritten values and tombstones along the way.
+/// The set of live tables is recorded in a `MANIFEST` file which is replaced
+/// atomically, so a crash in the middle of a flush or a compaction never
+/// exposes a partial state.
+///
+/// Values are stored with their expiration time, reads skip the expired ones.
+/// The expirations are tracked in memory, rebuilt from every table when the
+/// store is opened, and another background task writes a tombstone for each
+/// key once it expires.
+///
+/// An `LsmStore` is a handle to shared state. Cloning it is shallow and only
+/// incurs an atomic ref count increment.
+#[derive(Debug, Clone)]
+pub struct LsmStore {
+    logger: slog::Logger,
+    shared: Arc<Shared>,
+}
+
+/// Tuning knobs of an `LsmStore`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Size in bytes above which the memtable is flushed to an SSTable.
+    pub memtable_size_limit: usize,
+
+    /// Number of SSTables that triggers a background compaction.
+    pub compaction_trigger: usize,
+
+    /// Publishes the keyspace notifications of the store.
+    pub notifier: Notifier,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size_limit: 4 * 1024 * 1024,
+            compaction_trigger: 4,
+            notifier: Notifier::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    logger: slog::Logger,
+
+    /// Directory holding the write-ahead log, the manifest and the tables.
+    dir: PathBuf,
+
+    options: LsmOptions,
+
+    /// In-memory state. Readers only hold the lock long enough to look into
+    /// the memtables and to clone the list of tables, disk reads are done
+    /// without it.
+    state: RwLock<State>,
+
+    /// The write-ahead log. Its mutex also serializes writers, which keeps the
+    /// log and the memtable in the same order.
+    wal: Mutex<Wal>,
+
+    /// Held for the whole duration of a compaction so only one runs at a time.
+    compaction: Mutex<()>,
+
+    /// Lock of the directory, released on shutdown.
+    lock: Mutex<Option<LockFile>>,
+
+    /// Notifies the background compaction task that tables were flushed, or
+    /// that the store is shutting down.
+    background_task: Notify,
+
+    /// Notifies the background task purging expired keys.
+    expiration_task: Notify,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Receives every new write.
+    memtable: MemTable,
+
+    /// A memtable in the middle of being flushed. It still has to be consulted
+    /// by reads until its table is installed.
+    immutable: Option<Arc<MemTable>>,
+
+    /// Live tables, newest first.
+    tables: Vec<Arc<SsTable>>,
+
+    /// Identifier, and file name, of the next table to be written.
+    next_table_id: u64,
+
+    /// Tracks when the keys expire. Updated along with the memtable, under
+    /// the log lock.
+    expirations: Expirations,
+
+    /// True when the store is shutting down. Setting this to `true` signals
+    /// to the background task to exit.
+    shutdown: bool,
+}
+
+const LSM_DIR: &str = "lsm.raphdb";
+const WAL_FILE: &str = "wal.log";
+const MANIFEST_FILE: &str = "MANIFEST";
+const LOCK_FILE: &str = "LOCK";
+const SSTABLE_EXTENSION: &str = "sst";
+const TMP_EXTENSION: &str = "tmp";
+
+/// Value length marking a removed key in log and table entries.
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Length of the header of an entry, see `put_entry`.
+const ENTRY_HEADER_LEN: usize = 4 + 4 + 8;
+
+/// Delay before the background task writes again the tombstones of expired
+/// keys it failed to write.
+const PURGE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// An entry of the tree: a key and its value, `None` if the key was removed.
+type Entry = (String, Option<ExpiringValue>);
+
+impl LsmStore {
+    /// Open the tree of the data directory `data_dir`, creating it if needed.
+    pub async fn new(logger: slog::Logger, data_dir: &Path, options: LsmOptions) -> Result<LsmStore> {
+        return LsmStore::open(logger, data_dir.join(LSM_DIR), options).await;
+    }
+
+    /// Open the tree stored in `dir`, creating it if needed, and spawn the
+    /// background compaction task. Fails if another process has it open.
+    pub async fn open(logger: slog::Logger, dir: impl AsRef<Path>, options: LsmOptions) -> Result<LsmStore> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let lock = LockFile::acquire(&logger, dir.join(LOCK_FILE))?;
+
+        let table_ids = read_manifest(&dir)?;
+        let mut tables = Vec::with_capacity(table_ids.len());
+        for id in table_ids.iter() {
+            tables.push(Arc::new(SsTable::open(&table_path(&dir, *id), *id)?));
+        }
+
+        // Anything not referenced by the manifest is left over from a flush or
+        // compaction that did not complete.
+        let mut next_table_id = table_ids.iter().max().map(|id| id + 1).unwrap_or(0);
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|e| e.to_str());
+            if extension == Some(TMP_EXTENSION) {
+                std::fs::remove_file(&path)?;
+            } else if extension == Some(SSTABLE_EXTENSION) {
+                match path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) {
+                    Some(id) if table_ids.contains(&id) => {}
+                    Some(id) => {
+                        warn!(logger, "Removing unreferenced sstable {:?}", path);
+                        std::fs::remove_file(&path)?;
+                        next_table_id = next_table_id.max(id + 1);
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        let (wal, memtable, dropped) = Wal::open(&dir.join(WAL_FILE))?;
+        if dropped > 0 {
+            warn!(logger, "Dropped {:?} bytes of torn records at the end of the write-ahead log", dropped);
+        }
+        let expirations = load_expirations(&memtable, &tables)?;
+        info!(logger, "Opened LSM tree with {:?} sstables.", tables.len());
+
+        let shared = Arc::new(Shared {
+            logger: logger.clone(),
+            dir,
+            options,
+            state: RwLock::new(State {
+                memtable,
+                immutable: None,
+                tables,
+                next_table_id,
+                expirations,
+                shutdown: false,
+            }),
+            wal: Mutex::new(wal),
+            compaction: Mutex::new(()),
+            lock: Mutex::new(Some(lock)),
+            background_task: Notify::new(),
+            expiration_task: Notify::new(),
+        });
+
+        // Start the background tasks.
+        tokio::spawn(compaction_task(logger.clone(), shared.clone()));
+        tokio::spawn(purge_expired_tasks(shared.clone()));
+        if shared.needs_compaction() {
+            shared.background_task.notify_one();
+        }
+
+        return Ok(LsmStore { logger, shared });
+    }
+}
+
+impl KeyValueStore for LsmStore {
+    fn get(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        return Ok(self.shared.get(key)?.map(|value| value.data));
+    }
+
+    fn set(&self, key: String, value: Bytes, expire: Option<Duration>) -> crate::Result<()> {
+        debug!(self.logger, "Set: {:?} | {:?} | {:?}", key, value, expire);
+        let expires_at = expire.map(|duration| SystemTime::now() + duration);
+        self.shared.write(key.clone(), Some(ExpiringValue::new(value, expires_at)))?;
+        self.shared.options.notifier.notify(KeyEvent::Set, &key);
+        if expire.is_some() {
+            self.shared.options.notifier.notify(KeyEvent::Expire, &key);
+        }
+        return Ok(());
+    }
+
+    fn delete(&self, key: &str) -> crate::Result<bool> {
+        // Writing a tombstone for a missing key would be harmless, but the
+        // caller needs to know whether the key existed. The key is looked up
+        // under the log lock so that no write lands in between.
+        let mut wal = self.shared.wal.lock().unwrap();
+        let existed = self.shared.get(key)?.is_some();
+        if existed {
+            self.shared.write_locked(&mut wal, vec![(key.to_string(), None)])?;
+        }
+        drop(wal);
+        if existed {
+            self.shared.options.notifier.notify(KeyEvent::Del, key);
+        }
+        debug!(self.logger, "Delete: {:?} | {:?}", key, existed);
+        return Ok(existed);
+    }
+
+    /// Rewrite the value of `key` with its new expiration time.
+    fn expire(&self, key: &str, expire: Duration) -> crate::Result<bool> {
+        let expires_at = Some(SystemTime::now() + expire);
+        let exists = self.shared.update(key, |value| Some(ExpiringValue { expires_at, ..value }))?;
+        if exists {
+            self.shared.options.notifier.notify(KeyEvent::Expire, key);
+        }
+        debug!(self.logger, "Expire: {:?} | {:?} | {:?}", key, expire, exists);
+        return Ok(exists);
+    }
+
+    fn ttl(&self, key: &str) -> crate::Result<Expiry> {
+        return Ok(match self.shared.get(key)? {
+            Some(value) => value.expiry(SystemTime::now()),
+            None => Expiry::Missing,
+        });
+    }
+
+    /// Rewrite the value of `key` without an expiration time.
+    fn persist(&self, key: &str) -> crate::Result<bool> {
+        let persisted = self.shared.update(key, |value| {
+            value.expires_at?;
+            Some(ExpiringValue { expires_at: None, ..value })
+        })?;
+        if persisted {
+            self.shared.options.notifier.notify(KeyEvent::Persist, key);
+        }
+        debug!(self.logger, "Persist: {:?} | {:?}", key, persisted);
+        return Ok(persisted);
+    }
+
+    /// Merges the range of the memtables with the range of every table.
+    /// The memtable entries are copied under the lock, the tables are read
+    /// without it.
+    fn scan(&self, options: &ScanOptions) -> crate::Result<Vec<(String, Bytes)>> {
+        if options.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let (mut sources, tables) = {
+            let state = self.shared.state.read().unwrap();
+            let mut sources = Vec::new();
+            for memtable in std::iter::once(&state.memtable).chain(state.immutable.as_deref()) {
+                let entries: Vec<_> = memtable.range(options.range()).map(|(key, value)| Ok((key.clone(), value.clone()))).collect();
+                sources.push(Box::new(entries.into_iter()) as Source);
+            }
+            (sources, state.tables.clone())
+        };
+
+        let lower = options.lower().unwrap_or_default();
+        for table in tables.iter() {
+            sources.push(Box::new(table.iter_from(lower)?));
+        }
+
+        // Dropping the tombstones is safe here, they already shadowed the
+        // older entries of their key while merging. The expired values the
+        // background task did not remove yet are skipped too.
+        let now = SystemTime::now();
+        let entries = MergeIter::from_sources(sources, true).filter_map(|entry| match entry {
+            Ok((key, Some(value))) if !value.is_expired(now) => Some(Ok((key, value.data))),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        });
+        let entries = options.collect(entries)?;
+        debug!(self.logger, "Scan: {:?} | {:?} entries", options, entries.len());
+        return Ok(entries);
+    }
+
+    /// The writes are logged as a single record and applied under the log
+    /// lock: readers see them all at once, and they are replayed whole or not
+    /// at all after a crash. The deleted keys are looked up under the same
+    /// lock.
+    fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        let mut wal = self.shared.wal.lock().unwrap();
+        let now = SystemTime::now();
+        let mut entries = Vec::with_capacity(batch.len());
+        let mut events = Vec::new();
+        // Whether the keys written earlier in the batch exist, only deletes
+        // of existing keys are notified.
+        let mut exists = HashMap::new();
+        for write in batch {
+            match write {
+                StoreWrite::Set { key, value, expire } => {
+                    exists.insert(key.clone(), true);
+                    events.push((KeyEvent::Set, key.clone()));
+                    if expire.is_some() {
+                        events.push((KeyEvent::Expire, key.clone()));
+                    }
+                    entries.push((key, Some(ExpiringValue::new(value, expire.map(|duration| now + duration)))));
+                }
+                StoreWrite::Delete { key } => {
+                    let existed = match exists.get(&key) {
+                        Some(existed) => *existed,
+                        None => self.shared.get(&key)?.is_some(),
+                    };
+                    if existed {
+                        events.push((KeyEvent::Del, key.clone()));
+                    }
+                    exists.insert(key.clone(), false);
+                    entries.push((key, None));
+                }
+            }
+        }
+        debug!(self.logger, "Write batch: {:?} writes", entries.len());
+        self.shared.write_locked(&mut wal, entries)?;
+        drop(wal);
+        for (event, key) in events {
+            self.shared.options.notifier.notify(event, &key);
+        }
+        return Ok(());
+    }
+
+    /// Signals the background tasks to shut down and releases the
+    /// lock of the directory.
+    fn shutdown_purge_task(&self) {
+        let mut state = self.shared.state.write().unwrap();
+        state.shutdown = true;
+        drop(state);
+        self.shared.background_task.notify_one();
+        self.shared.expiration_task.notify_one();
+        self.shared.lock.lock().unwrap().take();
+    }
+}
+
+impl Shared {
+    /// The live value of `key`, `None` if the key does not exist or has
+    /// expired. The memtables are read under the lock, the tables without it.
+    fn get(&self, key: &str) -> Result<Option<ExpiringValue>> {
+        let value = self.lookup(key)?;
+        // The background task may not have removed the key yet.
+        return Ok(value.filter(|value| !value.is_expired(SystemTime::now())));
+    }
+
+    fn lookup(&self, key: &str) -> Result<Option<ExpiringValue>> {
+        let tables = {
+            let state = self.state.read().unwrap();
+            if let Some(value) = state.memtable.get(key) {
+                return Ok(value);
+            }
+            if let Some(value) = state.immutable.as_ref().and_then(|memtable| memtable.get(key)) {
+                return Ok(value);
+            }
+            state.tables.clone()
+        };
+
+        for table in tables.iter() {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+
+        return Ok(None);
+    }
+
+    /// Replace the live value of `key` with the one `update` returns, if it
+    /// returns one. The key is looked up and written under the log lock.
+    /// Returns `true` if the value was replaced.
+    fn update(&self, key: &str, update: impl FnOnce(ExpiringValue) -> Option<ExpiringValue>) -> Result<bool> {
+        let mut wal = self.wal.lock().unwrap();
+        let value = match self.get(key)?.and_then(update) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        self.write_locked(&mut wal, vec![(key.to_string(), Some(value))])?;
+        return Ok(true);
+    }
+
+    /// Log a write and apply it to the memtable, flushing the memtable if it
+    /// grew past its limit. A `None` value removes the key.
+    fn write(&self, key: String, value: Option<ExpiringValue>) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        self.write_locked(&mut wal, vec![(key, value)])
+    }
+
+    /// Log several writes as one record and apply them to the memtable under
+    /// a single lock. `wal` is the locked log.
+    fn write_locked(&self, wal: &mut Wal, entries: Vec<Entry>) -> Result<()> {
+        match &entries[..] {
+            [] => return Ok(()),
+            [(key, value)] => wal.append(key, value)?,
+            entries => wal.append_batch(entries)?,
+        }
+
+        let (memtable_size, expires_next) = {
+            let mut state = self.state.write().unwrap();
+            let mut expires_next = false;
+            for (key, value) in entries {
+                let expires_at = value.as_ref().and_then(|value| value.expires_at);
+                expires_next |= state.expirations.set(&key, expires_at.map(to_instant));
+                state.memtable.insert(key, value);
+            }
+            (state.memtable.size(), expires_next)
+        };
+        if expires_next {
+            self.expiration_task.notify_one();
+        }
+
+        if memtable_size >= self.options.memtable_size_limit {
+            self.flush(wal)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Persist the memtable as a new SSTable and reset the write-ahead log.
+    ///
+    /// Takes the locked log to ensure no write happens during the flush.
+    fn flush(&self, wal: &mut Wal) -> Result<()> {
+        let (memtable, id) = {
+            let mut state = self.state.write().unwrap();
+            if state.memtable.is_empty() {
+                return Ok(());
+            }
+            let memtable = Arc::new(std::mem::take(&mut state.memtable));
+            state.immutable = Some(memtable.clone());
+            let id = state.next_table_id;
+            state.next_table_id += 1;
+            (memtable, id)
+        };
+
+        let entries = memtable.iter().map(|(key, value)| Ok((key.clone(), value.clone())));
+        let table = match SsTable::create(&table_path(&self.dir, id), id, entries) {
+            Ok(table) => table,
+            Err(err) => {
+                // No write could have happened since the swap as the log is
+                // locked, give the entries back to the memtable. They are still
+                // in the log.
+                let mut state = self.state.write().unwrap();
+                state.immutable = None;
+                state.memtable = Arc::try_unwrap(memtable).unwrap_or_else(|memtable| (*memtable).clone());
+                return Err(err);
+            }
+        };
+
+        let needs_compaction = {
+            let mut state = self.state.write().unwrap();
+            state.tables.insert(0, Arc::new(table));
+            state.immutable = None;
+            write_manifest(&self.dir, &state.tables)?;
+            state.tables.len() >= self.options.compaction_trigger
+        };
+
+        wal.reset()?;
+
+        if needs_compaction {
+            self.background_task.notify_one();
+        }
+        return Ok(());
+    }
+
+    /// Merge every live table into a single one.
+    ///
+    /// Tables flushed while the merge runs are newer than its inputs and are
+    /// kept in front of the merged table.
+    fn compact(&self) -> Result<()> {
+        let _compaction = self.compaction.lock().unwrap();
+
+        let (inputs, id) = {
+            let mut state = self.state.write().unwrap();
+            if state.tables.len() < 2 {
+                return Ok(());
+            }
+            let id = state.next_table_id;
+            state.next_table_id += 1;
+            (state.tables.clone(), id)
+        };
+
+        // The inputs are all the tables there are, so the output becomes the
+        // oldest table and tombstones can be dropped.
+        let merged = SsTable::create(&table_path(&self.dir, id), id, MergeIter::new(&inputs, true)?)?;
+
+        {
+            let mut state = self.state.write().unwrap();
+            let input_ids: HashSet<u64> = inputs.iter().map(|table| table.id()).collect();
+            state.tables.retain(|table| !input_ids.contains(&table.id()));
+            state.tables.push(Arc::new(merged));
+            write_manifest(&self.dir, &state.tables)?;
+        }
+
+        // Readers may still hold the inputs, their open file handles keep
+        // working after the files are unlinked.
+        for table in inputs.iter() {
+            std::fs::remove_file(table.path())?;
+        }
+
+        return Ok(());
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.state.read().unwrap().tables.len() >= self.options.compaction_trigger
+    }
+
+    /// Returns `true` if the store is shutting down.
+    fn is_shutdown(&self) -> bool {
+        self.state.read().unwrap().shutdown
+    }
+}
+
+impl PurgeExpired for Shared {
+    /// Write a tombstone for every expired key, all in one record. If that
+    /// fails, the keys are purged again after `PURGE_RETRY_DELAY`, reads skip
+    /// them in the meantime.
+    fn purge_expired_keys(&self) -> Option<Instant> {
+        let mut wal = self.wal.lock().unwrap();
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        {
+            let mut state = self.state.write().unwrap();
+            if state.shutdown {
+                return None;
+            }
+            while let Some(key) = state.expirations.pop_expired(now) {
+                expired.push(key);
+            }
+        }
+
+        let tombstones = expired.iter().map(|key| (key.clone(), None)).collect();
+        match self.write_locked(&mut wal, tombstones) {
+            Ok(()) => {
+                for key in expired.iter() {
+                    self.options.notifier.notify(KeyEvent::Expired, key);
+                }
+            }
+            Err(err) => {
+                error!(self.logger, "err = {}, failed to remove {:?} expired keys", err, expired.len());
+                let mut state = self.state.write().unwrap();
+                for key in expired.iter() {
+                    state.expirations.set(key, Some(now + PURGE_RETRY_DELAY));
+                }
+            }
+        }
+
+        return self.state.read().unwrap().expirations.next_expiration();
+    }
+
+    fn is_shutdown(&self) -> bool {
+        Shared::is_shutdown(self)
+    }
+
+    fn expiration_task(&self) -> &Notify {
+        &self.expiration_task
+    }
+}
+
+/// Track the expiration of every live key of the memtable and the tables.
+/// Reads every table.
+fn load_expirations(memtable: &MemTable, tables: &[Arc<SsTable>]) -> Result<Expirations> {
+    let entries: Vec<_> = memtable.iter().map(|(key, value)| Ok((key.clone(), value.clone()))).collect();
+    let mut sources = vec![Box::new(entries.into_iter()) as Source];
+    for table in tables.iter() {
+        sources.push(Box::new(table.iter()?));
+    }
+
+    let mut expirations = Expirations::default();
+    for entry in MergeIter::from_sources(sources, true) {
+        if let (key, Some(ExpiringValue { expires_at: Some(when), .. })) = entry? {
+            expirations.set(&key, Some(to_instant(when)));
+        }
+    }
+    return Ok(expirations);
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.{}", id, SSTABLE_EXTENSION))
+}
+
+/// Returns the ids of the live tables, newest first.
+fn read_manifest(dir: &Path) -> Result<Vec<u64>> {
+    let content = match std::fs::read_to_string(dir.join(MANIFEST_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut ids = Vec::new();
+    for line in content.lines() {
+        ids.push(line.parse::<u64>().map_err(|_| format!("manifest line {:?} is not a table id", line))?);
+    }
+    return Ok(ids);
+}
+
+/// Atomically replace the manifest with the ids of `tables`.
+fn write_manifest(dir: &Path, tables: &[Arc<SsTable>]) -> Result<()> {
+    let mut content = String::new();
+    for table in tables.iter() {
+        content.push_str(&format!("{}\n", table.id()));
+    }
+
+    let tmp_path = dir.join(MANIFEST_FILE).with_extension(TMP_EXTENSION);
+    {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, dir.join(MANIFEST_FILE))?;
+    std::fs::File::open(dir)?.sync_all()?;
+
+    return Ok(());
+}
+
+/// Encode an entry:
+///
+/// ```text
+/// | key len (u32) | value len (u32) | expires at (u64) | key | value |
+/// ```
+///
+/// The expiration is in milliseconds since the Unix epoch, 0 if the value does
+/// not expire. A removed key is encoded with a `TOMBSTONE` value length, no
+/// expiration and no value.
+fn put_entry(buf: &mut BytesMut, key: &str, value: &Option<ExpiringValue>) {
+    buf.put_u32(key.len() as u32);
+    match value {
+        Some(value) => {
+            buf.put_u32(value.data.len() as u32);
+            buf.put_u64(value.expires_at_millis());
+            buf.put(key.as_bytes());
+            buf.put(&value.data[..]);
+        }
+        None => {
+            buf.put_u32(TOMBSTONE);
+            buf.put_u64(0);
+            buf.put(key.as_bytes());
+        }
+    }
+}
+
+/// Decode an entry written by `put_entry`, advancing `src` past it.
+///
+/// Returns `None` if `src` does not hold a whole, valid entry.
+fn get_entry(src: &mut &[u8]) -> Option<Entry> {
+    if src.remaining() < ENTRY_HEADER_LEN {
+        return None;
+    }
+    let mut header = &src[..ENTRY_HEADER_LEN];
+    let key_len: usize = header.get_u32().try_into().ok()?;
+    let raw_value_len = header.get_u32();
+    let expires_at = header.get_u64();
+    let value_len: usize = if raw_value_len == TOMBSTONE { 0 } else { raw_value_len.try_into().ok()? };
+
+    if src.remaining() < ENTRY_HEADER_LEN + key_len + value_len {
+        return None;
+    }
+    let body = &src[ENTRY_HEADER_LEN..];
+    let key = String::from_utf8(body[..key_len].to_vec()).ok()?;
+    let value = if raw_value_len == TOMBSTONE {
+        None
+    } else {
+        Some(ExpiringValue::from_millis(
+            Bytes::copy_from_slice(&body[key_len..key_len + value_len]),
+            expires_at,
+        ))
+    };
+
+    src.advance(ENTRY_HEADER_LEN + key_len + value_len);
+    return Some((key, value));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::key_value_store::KeyspaceEvents;
+    use crate::server::PubSub;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    fn small_options() -> LsmOptions {
+        LsmOptions {
+            memtable_size_limit: 64,
+            compaction_trigger: 3,
+            ..LsmOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LsmStore::open(logger(), dir.path(), LsmOptions::default()).await.unwrap();
+
+        assert_eq!(store.get("key").unwrap(), None);
+        store.set("key".to_string(), Bytes::from("value"), None).unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("value")));
+        store.set("key".to_string(), Bytes::from("other"), None).unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("other")));
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_flush_and_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LsmStore::open(logger(), dir.path(), small_options()).await.unwrap();
+
+        for round in 0..3 {
+            for i in 0..50 {
+                store.set(format!("key{:03}", i), Bytes::from(format!("value{}-{}", i, round)), None).unwrap();
+            }
+        }
+        assert!(store.shared.state.read().unwrap().tables.len() > 1);
+
+        store.shared.compact().unwrap();
+        assert_eq!(store.shared.state.read().unwrap().tables.len(), 1);
+        for i in 0..50 {
+            assert_eq!(store.get(&format!("key{:03}", i)).unwrap(), Some(Bytes::from(format!("value{}-2", i))));
+        }
+        assert_eq!(store.get("key999").unwrap(), None);
+        store.shutdown_purge_task();
+
+        // Everything, flushed or still in the log, survives a restart.
+        let store = LsmStore::open(logger(), dir.path(), small_options()).await.unwrap();
+        for i in 0..50 {
+            assert_eq!(store.get(&format!("key{:03}", i)).unwrap(), Some(Bytes::from(format!("value{}-2", i))));
+        }
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LsmStore::open(logger(), dir.path(), small_options()).await.unwrap();
+
+        for i in 0..20 {
+            store.set(format!("key{:03}", i), Bytes::from(format!("value{}", i)), None).unwrap();
+        }
+        // Tombstones shadow the values already flushed to sstables.
+        for i in (0..20).step_by(2) {
+            assert!(store.delete(&format!("key{:03}", i)).unwrap());
+        }
+        assert!(!store.delete("key000").unwrap());
+        assert!(!store.delete("missing").unwrap());
+
+        let check = |store: &LsmStore| {
+            for i in 0..20 {
+                let expected = if i % 2 == 0 { None } else { Some(Bytes::from(format!("value{}", i))) };
+                assert_eq!(store.get(&format!("key{:03}", i)).unwrap(), expected);
+            }
+        };
+        check(&store);
+        store.shared.compact().unwrap();
+        check(&store);
+        store.shutdown_purge_task();
+
+        let store = LsmStore::open(logger(), dir.path(), small_options()).await.unwrap();
+        check(&store);
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LsmStore::open(logger(), dir.path(), small_options()).await.unwrap();
+
+        // Spread the keys, their overwrites and tombstones over the tables and
+        // the memtable.
+        for i in 0..100 {
+            store.set(format!("key{:03}", i), Bytes::from(format!("value{}", i)), None).unwrap();
+        }
+        for i in (0..100).step_by(3) {
+            store.set(format!("key{:03}", i), Bytes::from(format!("new{}", i)), None).unwrap();
+        }
+        for i in (0..100).step_by(5) {
+            store.delete(&format!("key{:03}", i)).unwrap();
+        }
+        assert!(store.shared.state.read().unwrap().tables.len() > 1);
+
+        let expected = |range: &mut dyn Iterator<Item = usize>| -> Vec<(String, Bytes)> {
+            range
+                .filter(|i| i % 5 != 0)
+                .map(|i| {
+                    let value = if i % 3 == 0 { format!("new{}", i) } else { format!("value{}", i) };
+                    (format!("key{:03}", i), Bytes::from(value))
+                })
+                .collect()
+        };
+
+        let options = ScanOptions {
+            start: Some("key040".to_string()),
+            end: Some("key080".to_string()),
+            ..ScanOptions::default()
+        };
+        assert_eq!(store.scan(&options).unwrap(), expected(&mut (40..80)));
+
+        let options = ScanOptions {
+            prefix: Some("key09".to_string()),
+            limit: Some(4),
+            reverse: true,
+            ..ScanOptions::default()
+        };
+        assert_eq!(
+            store.scan(&options).unwrap(),
+            expected(&mut (90..100).rev()).into_iter().take(4).collect::<Vec<_>>()
+        );
+
+        store.shared.compact().unwrap();
+        let options = ScanOptions {
+            limit: Some(10),
+            ..ScanOptions::default()
+        };
+        assert_eq!(store.scan(&options).unwrap(), expected(&mut (0..100)).into_iter().take(10).collect::<Vec<_>>());
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_scan_during_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LsmOptions {
+            compaction_trigger: usize::MAX,
+            ..small_options()
+        };
+        let store = LsmStore::open(logger(), dir.path(), options).await.unwrap();
+        let expected = |round: usize| -> Vec<(String, Bytes)> {
+            (0..50)
+                .map(|i| (format!("key{:03}", i), Bytes::from(format!("value{}-{}", i, round))))
+                .collect()
+        };
+        for (key, value) in expected(0) {
+            store.set(key, value, None).unwrap();
+        }
+
+        // The tables a scan read the list of are unlinked before it reads them.
+        let tables = store.shared.state.read().unwrap().tables.clone();
+        assert!(tables.len() > 1);
+        store.shared.compact().unwrap();
+        for table in tables.iter() {
+            assert!(!table.path().exists());
+            let entries: Vec<_> = table.iter_from("key").unwrap().collect::<Result<_>>().unwrap();
+            assert!(!entries.is_empty());
+        }
+
+        let scanning = Arc::new(AtomicBool::new(true));
+        let scanner = {
+            let (store, scanning) = (store.clone(), scanning.clone());
+            std::thread::spawn(move || {
+                let mut scans = 0;
+                while scanning.load(Ordering::SeqCst) || scans == 0 {
+                    let entries = store.scan(&ScanOptions::default()).unwrap();
+                    assert_eq!(entries.len(), 50);
+                    scans += 1;
+                }
+            })
+        };
+        for round in 1..20 {
+            for (key, value) in expected(round) {
+                store.set(key, value, None).unwrap();
+            }
+            store.shared.compact().unwrap();
+        }
+        scanning.store(false, Ordering::SeqCst);
+        scanner.join().unwrap();
+        assert_eq!(store.scan(&ScanOptions::default()).unwrap(), expected(19));
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_torn_wal_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LsmStore::open(logger(), dir.path(), LsmOptions::default()).await.unwrap();
+        store.set("key".to_string(), Bytes::from("value"), None).unwrap();
+        store.shutdown_purge_task();
+        drop(store);
+
+        let mut wal = std::fs::OpenOptions::new().append(true).open(dir.path().join(WAL_FILE)).unwrap();
+        wal.write_all(&[0, 1, 2, 3, 0, 0, 0, 9, 0]).unwrap();
+
+        let store = LsmStore::open(logger(), dir.path(), LsmOptions::default()).await.unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("value")));
+        store.set("other".to_string(), Bytes::from("value"), None).unwrap();
+        store.shutdown_purge_task();
+
+        let store = LsmStore::open(logger(), dir.path(), LsmOptions::default()).await.unwrap();
+        assert_eq!(store.get("other").unwrap(), Some(Bytes::from("value")));
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LsmStore::open(logger(), dir.path(), LsmOptions::default()).await.unwrap();
+        store.set("key1".to_string(), Bytes::from("value"), None).unwrap();
+        store.set("key2".to_string(), Bytes::from("value"), None).unwrap();
+        store.shutdown_purge_task();
+        drop(store);
+
+        // The last record of the log, corrupted, was torn by a crash.
+        let wal_path = dir.path().join(WAL_FILE);
+        let mut data = std::fs::read(&wal_path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&wal_path, &data).unwrap();
+        let store = LsmStore::open(logger(), dir.path(), LsmOptions::default()).await.unwrap();
+        assert_eq!(store.get("key1").unwrap(), Some(Bytes::from("value")));
+        assert_eq!(store.get("key2").unwrap(), None);
+        store.set("key2".to_string(), Bytes::from("value"), None).unwrap();
+        store.shutdown_purge_task();
+        drop(store);
+
+        // The records following a corrupted one were acknowledged, be it its
+        // value or its length that is corrupted.
+        let data = std::fs::read(&wal_path).unwrap();
+        for corrupted in [data.len() / 2 - 1, 8] {
+            let mut data = data.clone();
+            data[corrupted] ^= 0xff;
+            std::fs::write(&wal_path, &data).unwrap();
+            let err = LsmStore::open(logger(), dir.path(), LsmOptions::default()).await.unwrap_err();
+            assert!(err.to_string().contains("is corrupted at offset 0"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LsmStore::open(logger(), dir.path(), LsmOptions::default()).await.unwrap();
+        store.set("gone".to_string(), Bytes::from("value"), None).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put("a", Bytes::from("1"));
+        batch.put("b", Bytes::from("1"));
+        batch.delete("gone");
+        store.write_batch(batch).unwrap();
+        store.shutdown_purge_task();
+        drop(store);
+
+        // A batch cut by a crash is dropped as a whole.
+        let mut wal = Wal::open(&dir.path().join(WAL_FILE)).unwrap().0;
+        let value = Some(ExpiringValue::new(Bytes::from("2"), None));
+        wal.append_batch(&[("a".to_string(), value.clone()), ("c".to_string(), value)]).unwrap();
+        drop(wal);
+        let wal_path = dir.path().join(WAL_FILE);
+        let len = std::fs::metadata(&wal_path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&wal_path).unwrap().set_len(len - 1).unwrap();
+
+        let store = LsmStore::open(logger(), dir.path(), LsmOptions::default()).await.unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(Bytes::from("1")));
+        assert_eq!(store.get("b").unwrap(), Some(Bytes::from("1")));
+        assert_eq!(store.get("c").unwrap(), None);
+        assert_eq!(store.get("gone").unwrap(), None);
+
+        let mut batch = WriteBatch::new();
+        batch.put("c", Bytes::from("3"));
+        batch.put_expiring("d", Bytes::from("3"), Duration::from_secs(60));
+        store.write_batch(batch).unwrap();
+        assert_eq!(store.ttl("c").unwrap(), Expiry::Persistent);
+        assert!(matches!(store.ttl("d").unwrap(), Expiry::After(_)));
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_expiration() {
+        let dir = tempfile::tempdir().unwrap();
+        let pub_sub = Arc::new(PubSub::default());
+        let mut events = pub_sub.psubscribe("__keyevent@0__:*");
+        let options = LsmOptions {
+            notifier: Notifier::new(KeyspaceEvents::from_str("EA").unwrap(), pub_sub.clone()),
+            ..small_options()
+        };
+        let store = LsmStore::open(logger(), dir.path(), options).await.unwrap();
+
+        // Some of the values are flushed to the tables along with their
+        // expiration.
+        let hour = Duration::from_secs(3600);
+        let large = Bytes::from("v".repeat(64));
+        store.set("short".to_string(), Bytes::from("value"), Some(Duration::from_millis(50))).unwrap();
+        store.set("long".to_string(), large.clone(), Some(hour)).unwrap();
+        store
+            .set("persisted".to_string(), Bytes::from("value"), Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(store.persist("persisted").unwrap());
+        assert!(!store.persist("persisted").unwrap());
+        store.set("forever".to_string(), Bytes::from("value"), None).unwrap();
+        assert!(store.expire("forever", hour).unwrap());
+        assert!(store.persist("forever").unwrap());
+        assert!(!store.expire("missing", hour).unwrap());
+        assert!(!store.shared.state.read().unwrap().tables.is_empty());
+        assert!(matches!(store.ttl("long").unwrap(), Expiry::After(ttl) if ttl > hour - Duration::from_secs(60) && ttl <= hour));
+        assert_eq!(store.ttl("forever").unwrap(), Expiry::Persistent);
+        assert_eq!(store.ttl("missing").unwrap(), Expiry::Missing);
+
+        // The background task removes the expired keys and notifies them.
+        let expected = [
+            ("set", "short"),
+            ("expire", "short"),
+            ("set", "long"),
+            ("expire", "long"),
+            ("set", "persisted"),
+            ("expire", "persisted"),
+            ("persist", "persisted"),
+            ("set", "forever"),
+            ("expire", "forever"),
+            ("persist", "forever"),
+            ("expired", "short"),
+        ];
+        for (event, key) in expected {
+            let message = events.recv().await.unwrap();
+            assert_eq!(message.channel, format!("__keyevent@0__:{}", event));
+            assert_eq!(message.content, Bytes::from(key));
+        }
+        assert_eq!(store.get("short").unwrap(), None);
+        assert_eq!(store.ttl("short").unwrap(), Expiry::Missing);
+        assert!(!store.delete("short").unwrap());
+        let keys: Vec<String> = store.scan(&ScanOptions::default()).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["forever", "long", "persisted"]);
+
+        // A key that expires while the store is closed is hidden, then removed,
+        // once it is opened again. The others keep their expiration.
+        store.set("closed".to_string(), Bytes::from("value"), Some(Duration::from_millis(50))).unwrap();
+        store.shutdown_purge_task();
+        drop(store);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let store = LsmStore::open(logger(), dir.path(), small_options()).await.unwrap();
+        assert_eq!(store.get("closed").unwrap(), None);
+        assert_eq!(store.get("long").unwrap(), Some(large));
+        assert!(matches!(store.ttl("long").unwrap(), Expiry::After(_)));
+        assert_eq!(store.ttl("persisted").unwrap(), Expiry::Persistent);
+        assert_eq!(store.ttl("forever").unwrap(), Expiry::Persistent);
+        for _ in 0..100 {
+            if store.shared.lookup("closed").unwrap().is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(store.shared.lookup("closed").unwrap(), None);
+        store.shutdown_purge_task();
+    }
+}
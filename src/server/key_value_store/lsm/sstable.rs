@@ -0,0 +1,268 @@
+use crate::Result;
+
+use bytes::{Buf, BufMut, BytesMut};
+use simple_error::bail;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::{get_entry, put_entry, Entry, ExpiringValue, ENTRY_HEADER_LEN};
+
+/// Every `INDEX_INTERVAL`th entry of a table is recorded in its sparse index.
+/// A point lookup reads at most this many entries from disk.
+const INDEX_INTERVAL: usize = 16;
+
+const FOOTER_LEN: u64 = 8 + 4 + 8;
+const MAGIC: u64 = 0x7261_7068_6c73_6d31; // "raphlsm1"
+
+/// Immutable, sorted table of entries persisted on disk.
+///
+/// File layout:
+///
+/// ```text
+/// | entry | entry | ... | index entry | ... | index offset (u64) | index len (u32) | magic (u64) |
+/// ```
+///
+/// Entries are sorted by key and use the same encoding as the write-ahead log
+/// (without the checksum). The sparse index maps the key of every
+/// `INDEX_INTERVAL`th entry to its offset in the file and is kept in memory
+/// while the table is open.
+#[derive(Debug)]
+pub struct SsTable {
+    id: u64,
+    path: PathBuf,
+
+    /// Shared with the iterators, which read it after the table file is
+    /// unlinked by a compaction.
+    file: Arc<File>,
+
+    /// Sparse index of `(key, offset)` pairs, sorted by key.
+    index: Vec<(String, u64)>,
+
+    /// Offset at which the entries end and the index starts.
+    data_len: u64,
+}
+
+impl SsTable {
+    /// Write the sorted `entries` to a new table at `path`.
+    ///
+    /// The table is first written to a temporary file which is renamed once
+    /// fully synced, so a crash never leaves a half written table behind under
+    /// its final name.
+    pub fn create<I>(path: &Path, id: u64, entries: I) -> Result<SsTable>
+    where
+        I: IntoIterator<Item = Result<Entry>>,
+    {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+
+        let mut index = Vec::new();
+        let mut offset: u64 = 0;
+        let mut buf = BytesMut::new();
+        for (i, entry) in entries.into_iter().enumerate() {
+            let (key, value) = entry?;
+
+            buf.clear();
+            put_entry(&mut buf, &key, &value);
+            if i % INDEX_INTERVAL == 0 {
+                index.push((key, offset));
+            }
+
+            writer.write_all(&buf[..])?;
+            offset += buf.len() as u64;
+        }
+
+        buf.clear();
+        for (key, entry_offset) in index.iter() {
+            buf.put_u32(key.len().try_into()?);
+            buf.put(key.as_bytes());
+            buf.put_u64(*entry_offset);
+        }
+        buf.put_u64(offset);
+        buf.put_u32(index.len().try_into()?);
+        buf.put_u64(MAGIC);
+        writer.write_all(&buf[..])?;
+
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp_path, path)?;
+
+        return SsTable::open(path, id);
+    }
+
+    /// Open an existing table and load its sparse index.
+    pub fn open(path: &Path, id: u64) -> Result<SsTable> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        if file_len < FOOTER_LEN {
+            bail!("sstable {:?} is too short to hold a footer", path);
+        }
+
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.read_exact_at(&mut footer, file_len - FOOTER_LEN)?;
+        let mut footer = &footer[..];
+        let data_len = footer.get_u64();
+        let index_len = footer.get_u32();
+        if footer.get_u64() != MAGIC {
+            bail!("sstable {:?} has an invalid footer", path);
+        }
+        if data_len > file_len - FOOTER_LEN {
+            bail!("sstable {:?} index offset is out of bounds", path);
+        }
+
+        let mut raw_index = vec![0u8; (file_len - FOOTER_LEN - data_len).try_into()?];
+        file.read_exact_at(&mut raw_index, data_len)?;
+        let mut src = &raw_index[..];
+        let mut index = Vec::with_capacity(index_len.try_into()?);
+        for _ in 0..index_len {
+            if src.remaining() < 4 {
+                bail!("sstable {:?} index is corrupted", path);
+            }
+            let key_len: usize = src.get_u32().try_into()?;
+            if src.remaining() < key_len + 8 {
+                bail!("sstable {:?} index is corrupted", path);
+            }
+            let key = String::from_utf8(src[..key_len].to_vec())?;
+            src.advance(key_len);
+            index.push((key, src.get_u64()));
+        }
+
+        return Ok(SsTable {
+            id,
+            path: path.to_path_buf(),
+            file: Arc::new(file),
+            index,
+            data_len,
+        });
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Look up `key` in the table.
+    ///
+    /// Returns `None` if the key is not in this table, `Some(None)` if the
+    /// table holds a tombstone for it and `Some(Some(value))` otherwise, even
+    /// if the value expired.
+    pub fn get(&self, key: &str) -> Result<Option<Option<ExpiringValue>>> {
+        // Find the last indexed entry whose key is <= `key`. The key, if
+        // present, lives between that entry and the next indexed one.
+        let block = self.index.partition_point(|(indexed, _)| indexed.as_str() <= key);
+        if block == 0 {
+            return Ok(None);
+        }
+
+        let start = self.index[block - 1].1;
+        let end = self.index.get(block).map(|(_, offset)| *offset).unwrap_or(self.data_len);
+
+        let mut data = vec![0u8; (end - start).try_into()?];
+        self.file.read_exact_at(&mut data, start)?;
+
+        let mut src = &data[..];
+        while src.has_remaining() {
+            match get_entry(&mut src) {
+                Some((entry_key, value)) if entry_key == key => return Ok(Some(value)),
+                Some((entry_key, _)) if entry_key.as_str() > key => return Ok(None),
+                Some(_) => {}
+                None => bail!("sstable {:?} is corrupted at byte {:?}", self.path, end - src.len() as u64),
+            }
+        }
+
+        return Ok(None);
+    }
+
+    /// Iterate over every entry of the table in key order.
+    pub fn iter(&self) -> Result<SsTableIter> {
+        self.iter_at(0)
+    }
+
+    /// Iterate in key order over the entries of the table, starting with the
+    /// indexed block that may hold `key`. Only the entries of that block can
+    /// be smaller than `key`, the caller skips them.
+    pub fn iter_from(&self, key: &str) -> Result<SsTableIter> {
+        let block = self.index.partition_point(|(indexed, _)| indexed.as_str() <= key);
+        let offset = match block {
+            0 => 0,
+            block => self.index[block - 1].1,
+        };
+        self.iter_at(offset)
+    }
+
+    fn iter_at(&self, offset: u64) -> Result<SsTableIter> {
+        let entries = EntriesReader {
+            file: self.file.clone(),
+            offset,
+            end: self.data_len,
+        };
+        Ok(SsTableIter {
+            reader: BufReader::new(entries),
+            buf: BytesMut::new(),
+        })
+    }
+}
+
+/// Sequential reader over the entries of an `SsTable`.
+pub struct SsTableIter {
+    reader: BufReader<EntriesReader>,
+    buf: BytesMut,
+}
+
+/// Reads the entries of a table from `offset` to `end` with positioned reads,
+/// the file handle of the table being shared by every reader.
+struct EntriesReader {
+    file: Arc<File>,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for EntriesReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = (self.end - self.offset).min(buf.len() as u64) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let read = self.file.read_at(&mut buf[..len], self.offset)?;
+        self.offset += read as u64;
+        return Ok(read);
+    }
+}
+
+impl Iterator for SsTableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut header = [0u8; ENTRY_HEADER_LEN];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e.into())),
+        }
+
+        let mut lengths = &header[..];
+        let key_len = lengths.get_u32() as usize;
+        let value_len = lengths.get_u32();
+        let body_len = key_len + if value_len == super::TOMBSTONE { 0 } else { value_len as usize };
+
+        self.buf.clear();
+        self.buf.put(&header[..]);
+        self.buf.resize(ENTRY_HEADER_LEN + body_len, 0);
+        if let Err(e) = self.reader.read_exact(&mut self.buf[ENTRY_HEADER_LEN..]) {
+            return Some(Err(e.into()));
+        }
+
+        let mut src = &self.buf[..];
+        match get_entry(&mut src) {
+            Some(entry) => Some(Ok(entry)),
+            None => Some(Err("sstable entry is corrupted".into())),
+        }
+    }
+}
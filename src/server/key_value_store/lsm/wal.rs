@@ -0,0 +1,188 @@
+use crate::Result;
+
+use bytes::{Buf, BufMut, BytesMut};
+use simple_error::bail;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use super::memtable::MemTable;
+use super::{get_entry, put_entry, Entry, ExpiringValue, ENTRY_HEADER_LEN, TOMBSTONE};
+
+/// Key length marking a batch record. No key is that long.
+const BATCH: u32 = u32::MAX;
+
+/// Write-ahead log backing the current memtable.
+///
+/// Every mutation is appended and synced here before it is applied to the
+/// memtable, so the memtable can be rebuilt after a crash. Once the memtable
+/// has been flushed to an SSTable the log is reset.
+///
+/// Record layout:
+///
+/// ```text
+/// | crc32 (u32) | key len (u32) | value len (u32) | expires at (u64) | key | value |
+/// ```
+///
+/// The checksum covers everything after itself. The expiration is in
+/// milliseconds since the Unix epoch, 0 if the value does not expire. A value
+/// length of `TOMBSTONE` marks a removed key and is followed by no value
+/// bytes.
+///
+/// A batch of writes is logged as a single record, so that it is replayed
+/// either whole or not at all:
+///
+/// ```text
+/// | crc32 (u32) | BATCH (u32) | entries len (u32) | entries |
+/// ```
+///
+/// where the entries are encoded as the `key len` to `value` part of the
+/// records above.
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+}
+
+impl Wal {
+    /// Open the log at `path`, creating it if needed, and replay its records
+    /// into a new `MemTable`.
+    ///
+    /// A record torn by a crash in the middle of an append, at the tail of
+    /// the log, is dropped and the file is truncated right before it. Returns
+    /// the number of bytes that were dropped this way. Any other unreadable
+    /// record fails the open: the records after it were acknowledged.
+    pub fn open(path: &Path) -> Result<(Wal, MemTable, u64)> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut memtable = MemTable::new();
+        let mut src = &data[..];
+        while !src.is_empty() {
+            let (entries, record_len) = match get_record(src) {
+                Some(record) => record,
+                None if is_torn(src) => break,
+                None => bail!("{:?} is corrupted at offset {:?}", path, data.len() - src.len()),
+            };
+            for (key, value) in entries {
+                memtable.insert(key, value);
+            }
+            src = &src[record_len..];
+        }
+
+        let valid_len = (data.len() - src.len()) as u64;
+        let dropped = data.len() as u64 - valid_len;
+        if dropped > 0 {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+
+        let wal = Wal { file };
+        return Ok((wal, memtable, dropped));
+    }
+
+    /// Append a single record and sync it to disk.
+    pub fn append(&mut self, key: &str, value: &Option<ExpiringValue>) -> Result<()> {
+        let mut record = BytesMut::new();
+        put_entry(&mut record, key, value);
+
+        let mut buf = BytesMut::with_capacity(record.len() + 4);
+        buf.put_u32(crc32fast::hash(&record[..]));
+        buf.put(record);
+
+        self.file.write_all(&buf[..])?;
+        self.file.sync_data()?;
+        return Ok(());
+    }
+
+    /// Append the entries as a single batch record and sync it to disk.
+    pub fn append_batch(&mut self, entries: &[Entry]) -> Result<()> {
+        let mut body = BytesMut::new();
+        for (key, value) in entries {
+            put_entry(&mut body, key, value);
+        }
+
+        let mut record = BytesMut::with_capacity(body.len() + 8);
+        record.put_u32(BATCH);
+        record.put_u32(body.len().try_into()?);
+        record.put(body);
+
+        let mut buf = BytesMut::with_capacity(record.len() + 4);
+        buf.put_u32(crc32fast::hash(&record[..]));
+        buf.put(record);
+
+        self.file.write_all(&buf[..])?;
+        self.file.sync_data()?;
+        return Ok(());
+    }
+
+    /// Discard every record. Called once the memtable the log was protecting
+    /// has been persisted as an SSTable.
+    pub fn reset(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        return Ok(());
+    }
+}
+
+/// Returns `true` if the unreadable record `rest` starts with was torn by a
+/// crash: the record runs to the end of the log, or nothing but zeros are
+/// left, and no valid record follows. A record declaring a length past the end
+/// of the log, followed by valid ones, has a corrupted header instead.
+fn is_torn(rest: &[u8]) -> bool {
+    let runs_to_end = rest.iter().all(|byte| *byte == 0) || record_len(rest).is_none_or(|len| len >= rest.len());
+    return runs_to_end && !(1..rest.len()).any(|offset| get_record(&rest[offset..]).is_some());
+}
+
+/// The length the header of the record at the start of `src` declares,
+/// `None` if the header itself is cut.
+fn record_len(src: &[u8]) -> Option<usize> {
+    if src.len() < 12 {
+        return None;
+    }
+    let mut header = &src[4..12];
+    let key_len = header.get_u32() as usize;
+    let len = header.get_u32() as usize;
+    if key_len == BATCH as usize {
+        return Some(12 + len);
+    }
+    let value_len = if len == TOMBSTONE as usize { 0 } else { len };
+    return Some(4 + ENTRY_HEADER_LEN + key_len + value_len);
+}
+
+/// Decode the record at the start of `src`, checking its checksum. Returns
+/// its entries and its length, or `None` if `src` does not start with a whole,
+/// valid record.
+fn get_record(src: &[u8]) -> Option<(Vec<Entry>, usize)> {
+    if src.len() < 8 {
+        return None;
+    }
+    let crc = (&src[..4]).get_u32();
+    let mut record = &src[4..];
+
+    if (&record[..4]).get_u32() != BATCH {
+        let start_len = record.len();
+        let entry = get_entry(&mut record)?;
+        let record_len = start_len - record.len();
+        if crc32fast::hash(&src[4..4 + record_len]) != crc {
+            return None;
+        }
+        return Some((vec![entry], 4 + record_len));
+    }
+
+    if record.len() < 8 {
+        return None;
+    }
+    let body_len: usize = (&record[4..8]).get_u32().try_into().ok()?;
+    if record.len() < 8 + body_len || crc32fast::hash(&record[..8 + body_len]) != crc {
+        return None;
+    }
+    let mut body = &record[8..8 + body_len];
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        entries.push(get_entry(&mut body)?);
+    }
+    return Some((entries, 12 + body_len));
+}
@@ -0,0 +1,404 @@
+//! Append-only files log every write of a `MiniRedis` as the RESP array of a
+//! command, like the AOF of Redis. Replaying the file rebuilds the keys.
+//!
+//! The writes are logged as:
+//!
+//! ```text
+//! SET key value [PXAT unix-time-milliseconds]
+//! DEL key
+//! PEXPIREAT key unix-time-milliseconds
+//! PERSIST key
+//! ```
+//!
+//! Expiration times are absolute, so that replaying the file does not extend
+//! them. The writes of a batch are wrapped in `MULTI` and `EXEC`: a batch
+//! torn by a crash is dropped as a whole.
+//!
+//! The file grows with every write. Once it doubled since it was last
+//! written from scratch, it is rewritten in the background with a single
+//! `SET` per live key. The writes made during the rewrite are both appended
+//! to the current file and buffered, the buffer is appended to the new file
+//! before it replaces the current one.
+
+use crate::connection::{Frame, FrameError, Parser, ParserError};
+use crate::server::key_value_store::FsyncPolicy;
+use crate::Result;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use simple_error::bail;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::snapshot;
+
+/// A write logged to the append-only file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Set {
+        key: String,
+        value: Bytes,
+        expires_at: Option<SystemTime>,
+    },
+    Delete {
+        key: String,
+    },
+    Expire {
+        key: String,
+        expires_at: SystemTime,
+    },
+    Persist {
+        key: String,
+    },
+}
+
+/// An open append-only file.
+#[derive(Debug)]
+pub struct Aof {
+    path: PathBuf,
+    file: File,
+    fsync: FsyncPolicy,
+
+    /// Size of the file.
+    len: u64,
+
+    /// Size of the file when it was last written from scratch.
+    base_len: u64,
+
+    /// Size the file reaches before it is rewritten for the first time.
+    rewrite_min_len: u64,
+
+    /// Writes appended since the rewrite started, `None` if the file is not
+    /// being rewritten.
+    rewrite_buffer: Option<BytesMut>,
+
+    /// True if writes were appended since the file was last synced.
+    unsynced: bool,
+}
+
+impl Aof {
+    /// Open the append-only file at `path` and return the writes it logs, in
+    /// order.
+    ///
+    /// A write torn by a crash at the end of the file is dropped, the file is
+    /// truncated before it. Fails if the file is corrupted anywhere else.
+    pub fn open(logger: &slog::Logger, path: &Path, fsync: FsyncPolicy, rewrite_min_len: u64) -> Result<(Aof, Vec<Op>)> {
+        let data = std::fs::read(path)?;
+        let mut ops = Vec::new();
+        let mut batch: Option<Vec<Op>> = None;
+
+        // End of the last complete write, batches included.
+        let mut end = 0;
+        let mut cursor = Cursor::new(&data[..]);
+        while (cursor.position() as usize) < data.len() {
+            let start = cursor.position();
+            match Frame::check(&mut cursor) {
+                Ok(()) => {}
+                Err(FrameError::Incomplete) => break,
+                Err(FrameError::Other(err)) => bail!("append-only file {:?} is corrupted at byte {:?}: {}", path, start, err),
+            }
+            cursor.set_position(start);
+
+            let logged = Frame::parse(&mut cursor).map_err(Into::into).and_then(Logged::from_frame);
+            match (logged, batch.as_mut()) {
+                (Ok(Logged::Multi), None) => batch = Some(Vec::new()),
+                (Ok(Logged::Exec), Some(_)) => {
+                    ops.extend(batch.take().unwrap_or_default());
+                    end = cursor.position();
+                }
+                (Ok(Logged::Op(op)), Some(batch)) => batch.push(op),
+                (Ok(Logged::Op(op)), None) => {
+                    ops.push(op);
+                    end = cursor.position();
+                }
+                (Ok(logged), _) => bail!("append-only file {:?} is corrupted at byte {:?}: unexpected {:?}", path, start, logged),
+                (Err(err), _) => bail!("append-only file {:?} is corrupted at byte {:?}: {}", path, start, err),
+            }
+        }
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        if end < data.len() as u64 {
+            warn!(
+                logger,
+                "Dropping torn write of {:?} bytes at the end of append-only file {:?}, left by a crash.",
+                data.len() as u64 - end,
+                path
+            );
+            file.set_len(end)?;
+            file.sync_all()?;
+        }
+
+        let aof = Aof {
+            path: path.to_path_buf(),
+            file,
+            fsync,
+            len: end,
+            base_len: end,
+            rewrite_min_len,
+            rewrite_buffer: None,
+            unsynced: false,
+        };
+        return Ok((aof, ops));
+    }
+
+    /// Create the append-only file at `path`, logging `entries`.
+    pub fn create(path: &Path, entries: &[snapshot::Entry], fsync: FsyncPolicy, rewrite_min_len: u64) -> Result<Aof> {
+        let tmp_path = write_base(path, entries)?;
+        std::fs::rename(&tmp_path, path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        let len = file.metadata()?.len();
+        return Ok(Aof {
+            path: path.to_path_buf(),
+            file,
+            fsync,
+            len,
+            base_len: len,
+            rewrite_min_len,
+            rewrite_buffer: None,
+            unsynced: false,
+        });
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `ops` to the file, as a batch if there are several of them.
+    pub fn append(&mut self, ops: Vec<Op>) -> Result<()> {
+        let mut buf = BytesMut::new();
+        let batch = ops.len() > 1;
+        if batch {
+            buf.put(command(&["multi"]).create_bytes()?);
+        }
+        for op in ops {
+            buf.put(op.into_frame()?.create_bytes()?);
+        }
+        if batch {
+            buf.put(command(&["exec"]).create_bytes()?);
+        }
+
+        self.file.write_all(&buf[..])?;
+        self.len += buf.len() as u64;
+        if let Some(rewrite_buffer) = self.rewrite_buffer.as_mut() {
+            rewrite_buffer.put(&buf[..]);
+        }
+        match self.fsync {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            FsyncPolicy::EverySec => self.unsynced = true,
+            FsyncPolicy::Never => {}
+        }
+        return Ok(());
+    }
+
+    /// A handle to sync the file with, if writes were appended since it was
+    /// last synced. The handle is synced without holding the `Aof`.
+    pub fn take_unsynced(&mut self) -> Result<Option<File>> {
+        if !self.unsynced {
+            return Ok(None);
+        }
+        self.unsynced = false;
+        return Ok(Some(self.file.try_clone()?));
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = false;
+        return Ok(());
+    }
+
+    /// Returns `true` if the file doubled since it was last written from
+    /// scratch, and is not being rewritten already.
+    pub fn should_rewrite(&self) -> bool {
+        self.rewrite_buffer.is_none() && self.len >= self.rewrite_min_len && self.len >= 2 * self.base_len
+    }
+
+    /// Start buffering the appended writes, see `finish_rewrite`.
+    pub fn start_rewrite(&mut self) {
+        self.rewrite_buffer = Some(BytesMut::new());
+    }
+
+    /// Replace the file by the one written by `write_base` at `tmp_path`,
+    /// once the writes appended since `start_rewrite` are appended to it.
+    pub fn finish_rewrite(&mut self, tmp_path: &Path) -> Result<()> {
+        let buffer = match self.rewrite_buffer.take() {
+            Some(buffer) => buffer,
+            None => bail!("append-only file {:?} is not being rewritten", self.path),
+        };
+        let mut file = OpenOptions::new().append(true).open(tmp_path)?;
+        file.write_all(&buffer[..])?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &self.path)?;
+
+        self.len = file.metadata()?.len();
+        self.base_len = self.len;
+        self.file = file;
+        self.unsynced = false;
+        return Ok(());
+    }
+
+    /// Stop buffering the appended writes, the current file is kept.
+    pub fn abort_rewrite(&mut self) {
+        self.rewrite_buffer = None;
+    }
+}
+
+/// Write `entries` to a temporary file next to the append-only file at
+/// `path`, as the start of a rewritten file. Returns the path of the
+/// temporary file.
+pub fn write_base(path: &Path, entries: &[snapshot::Entry]) -> Result<PathBuf> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for entry in entries {
+        let op = Op::Set {
+            key: entry.key.clone(),
+            value: entry.value.clone(),
+            expires_at: entry.expires_at,
+        };
+        writer.write_all(&op.into_frame()?.create_bytes()?[..])?;
+    }
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    return Ok(tmp_path);
+}
+
+/// A frame of the append-only file.
+#[derive(Debug)]
+enum Logged {
+    Op(Op),
+    Multi,
+    Exec,
+}
+
+impl Logged {
+    fn from_frame(frame: Frame) -> Result<Logged> {
+        let mut parser = Parser::new(frame)?;
+        let logged = match &parser.next_string()?.to_lowercase()[..] {
+            "multi" => Logged::Multi,
+            "exec" => Logged::Exec,
+            "set" => {
+                let key = parser.next_string()?;
+                let value = parser.next_bytes()?;
+                let expires_at = match parser.next_string() {
+                    Ok(option) if option.to_lowercase() == "pxat" => Some(from_millis(parser.next_int()?)),
+                    Ok(option) => bail!("unknown SET option {:?}", option),
+                    Err(ParserError::EndOfStream) => None,
+                    Err(err) => return Err(err.into()),
+                };
+                Logged::Op(Op::Set { key, value, expires_at })
+            }
+            "del" => Logged::Op(Op::Delete { key: parser.next_string()? }),
+            "pexpireat" => Logged::Op(Op::Expire {
+                key: parser.next_string()?,
+                expires_at: from_millis(parser.next_int()?),
+            }),
+            "persist" => Logged::Op(Op::Persist { key: parser.next_string()? }),
+            name => bail!("unknown command {:?}", name),
+        };
+        parser.finish()?;
+        return Ok(logged);
+    }
+}
+
+impl Op {
+    fn into_frame(self) -> Result<Frame> {
+        let mut frame = Frame::array();
+        match self {
+            Op::Set { key, value, expires_at } => {
+                frame.push_bulk(Bytes::from("set".as_bytes()));
+                frame.push_bulk(Bytes::from(key.into_bytes()));
+                frame.push_bulk(value);
+                if let Some(expires_at) = expires_at {
+                    frame.push_bulk(Bytes::from("pxat".as_bytes()));
+                    frame.push_int(to_millis(expires_at)?.try_into()?);
+                }
+            }
+            Op::Delete { key } => {
+                frame.push_bulk(Bytes::from("del".as_bytes()));
+                frame.push_bulk(Bytes::from(key.into_bytes()));
+            }
+            Op::Expire { key, expires_at } => {
+                frame.push_bulk(Bytes::from("pexpireat".as_bytes()));
+                frame.push_bulk(Bytes::from(key.into_bytes()));
+                frame.push_int(to_millis(expires_at)?.try_into()?);
+            }
+            Op::Persist { key } => {
+                frame.push_bulk(Bytes::from("persist".as_bytes()));
+                frame.push_bulk(Bytes::from(key.into_bytes()));
+            }
+        }
+        return Ok(frame);
+    }
+}
+
+fn command(parts: &[&'static str]) -> Frame {
+    let mut frame = Frame::array();
+    for part in parts {
+        frame.push_bulk(Bytes::from(part.as_bytes()));
+    }
+    return frame;
+}
+
+fn to_millis(when: SystemTime) -> Result<u64> {
+    return Ok(when.duration_since(UNIX_EPOCH)?.as_millis().try_into()?);
+}
+
+fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_append_replay() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("appendonly.aof");
+        let expires_at = UNIX_EPOCH + Duration::from_millis(4_000_000_000_123);
+        let ops = vec![
+            Op::Set {
+                key: "a".to_string(),
+                value: Bytes::from("1"),
+                expires_at: None,
+            },
+            Op::Set {
+                key: "b".to_string(),
+                value: Bytes::from(&b"\r\n\x00"[..]),
+                expires_at: Some(expires_at),
+            },
+            Op::Expire {
+                key: "a".to_string(),
+                expires_at,
+            },
+            Op::Persist { key: "a".to_string() },
+            Op::Delete { key: "b".to_string() },
+        ];
+
+        let mut aof = Aof::create(&path, &[], FsyncPolicy::Always, 0).unwrap();
+        aof.append(ops[..1].to_vec()).unwrap();
+        aof.append(ops[1..].to_vec()).unwrap();
+        drop(aof);
+        let (_, replayed) = Aof::open(&logger, &path, FsyncPolicy::Always, 0).unwrap();
+        assert_eq!(replayed, ops);
+
+        // A torn batch is dropped as a whole.
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+        let (_, replayed) = Aof::open(&logger, &path, FsyncPolicy::Always, 0).unwrap();
+        assert_eq!(replayed, ops[..1]);
+        let (_, replayed) = Aof::open(&logger, &path, FsyncPolicy::Always, 0).unwrap();
+        assert_eq!(replayed, ops[..1]);
+
+        // Corruption elsewhere is not repaired.
+        std::fs::write(&path, b"*1\r\n$3\r\nfoo\r\n").unwrap();
+        assert!(Aof::open(&logger, &path, FsyncPolicy::Always, 0).is_err());
+    }
+}
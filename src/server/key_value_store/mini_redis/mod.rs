@@ -0,0 +1,976 @@
+mod aof;
+mod snapshot;
+
+use crate::Result;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use simple_error::bail;
+use tokio::sync::Notify;
+use tokio::time::{self, Duration, Instant};
+
+use crate::server::key_value_store::expiration::{purge_expired_tasks, Expirations, PurgeExpired};
+use crate::server::key_value_store::lock::LockFile;
+use crate::server::key_value_store::{Expiry, FsyncPolicy, KeyEvent, KeyValueStore, Notifier, ScanOptions, Write, WriteBatch};
+use aof::{Aof, Op};
+
+const SNAPSHOT_FILE: &str = "mini-redis.rdb";
+const AOF_FILE: &str = "mini-redis.aof";
+
+/// Server state shared across all connections.
+///
+/// `MiniRedis` contains a `BTreeMap` storing the key/value data. Pub/sub
+/// channels live in the server, see `PubSub`.
+///
+/// A `MiniRedis` instance is a handle to shared state. Cloning `MiniRedis` is shallow and
+/// only incurs an atomic ref count increment.
+///
+/// When a `MiniRedis` value is created, a background task is spawned. This task is
+/// used to expire values after the requested duration has elapsed. The task
+/// runs until all instances of `MiniRedis` are dropped, at which point the task
+/// terminates.
+///
+/// A `MiniRedis` opened on a data directory saves its keys to a snapshot file
+/// periodically, when asked to and on shutdown, and loads them back when it
+/// opens. Writes between two snapshots are lost on a crash, unless the store
+/// also logs them to an append-only file, which it then loads instead.
+#[derive(Debug, Clone)]
+pub struct MiniRedis {
+    /// Handle to shared state. The background task will also have an
+    /// `Arc<Shared>`.
+    shared: Arc<Shared>,
+}
+
+/// Options of a `MiniRedis` opened on a data directory.
+#[derive(Debug, Clone)]
+pub struct MiniRedisOptions {
+    /// How often the keys are saved if they changed since the last snapshot.
+    /// `None` only saves them on request and on shutdown.
+    pub snapshot_interval: Option<Duration>,
+
+    /// Log every write to an append-only file, and rebuild the keys from it
+    /// on startup.
+    pub append_only: bool,
+
+    /// When the writes logged to the append-only file are synced to disk.
+    pub fsync: FsyncPolicy,
+
+    /// Size the append-only file reaches before it is first rewritten. It is
+    /// then rewritten each time it doubles.
+    pub aof_rewrite_min_len: u64,
+
+    /// Publishes the keyspace notifications of the store.
+    pub notifier: Notifier,
+}
+
+impl Default for MiniRedisOptions {
+    fn default() -> Self {
+        MiniRedisOptions {
+            snapshot_interval: Some(Duration::from_secs(60)),
+            append_only: false,
+            fsync: FsyncPolicy::default(),
+            aof_rewrite_min_len: 64 * 1024 * 1024,
+            notifier: Notifier::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// The shared state is guarded by a mutex. This is a `std::sync::Mutex` and
+    /// not a Tokio mutex. This is because there are no asynchronous operations
+    /// being performed while holding the mutex. Additionally, the critical
+    /// sections are very small.
+    ///
+    /// A Tokio mutex is mostly intended to be used when locks need to be held
+    /// across `.await` yield points. All other cases are **usually** best
+    /// served by a std mutex. If the critical section does not include any
+    /// async operations but is long (CPU intensive or performing blocking
+    /// operations), then the entire operation, including waiting for the mutex,
+    /// is considered a "blocking" operation and `tokio::task::spawn_blocking`
+    /// should be used.
+    state: Mutex<State>,
+
+    /// Notifies the background task handling entry expiration. The background
+    /// task waits on this to be notified, then checks for expired values or the
+    /// shutdown signal.
+    background_task: Notify,
+
+    /// `None` if the store only lives in memory.
+    snapshots: Option<Snapshots>,
+
+    /// Publishes the keyspace notifications, while holding the state mutex
+    /// so they come in the order the writes are applied.
+    notifier: Notifier,
+
+    logger: slog::Logger,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The key-value data. A `std::collections::BTreeMap` keeps the keys
+    /// sorted for range scans.
+    entries: BTreeMap<String, Entry>,
+
+    /// Tracks key TTLs.
+    expirations: Expirations,
+
+    /// Logs the writes, `None` if the store does not keep an append-only
+    /// file. Writes are logged while holding the mutex, in the order they
+    /// are applied.
+    aof: Option<Aof>,
+
+    /// Number of writes applied since the store opened, tells whether the
+    /// keys changed since the last snapshot.
+    changes: u64,
+
+    /// True when the MiniRedis instance is shutting down. This happens when all `MiniRedis`
+    /// values drop. Setting this to `true` signals to the background task to
+    /// exit.
+    shutdown: bool,
+}
+
+/// Entry in the key-value store
+#[derive(Debug)]
+struct Entry {
+    /// Stored data
+    data: Bytes,
+}
+
+/// Saves the keys of a `MiniRedis` to its snapshot file.
+///
+/// The keys are copied while holding the state mutex, values are `Bytes` so
+/// only their reference counts are. The file is written after the mutex is
+/// released: clients only wait for the copy, not for the disk.
+#[derive(Debug)]
+struct Snapshots {
+    path: PathBuf,
+
+    /// Held while a snapshot is written, so that two saves never write the
+    /// file at the same time.
+    writing: Mutex<()>,
+
+    /// True while a background save runs.
+    in_background: AtomicBool,
+
+    /// Value of `State::changes` when the last snapshot was taken.
+    saved_changes: AtomicU64,
+
+    /// Notifies the background task saving the keys periodically of the
+    /// shutdown.
+    snapshot_task: Notify,
+
+    /// Lock of the snapshot file, released on shutdown.
+    lock: Mutex<Option<LockFile>>,
+}
+
+impl MiniRedis {
+    /// Open the store of the data directory `data_dir`, loading the keys of
+    /// its last snapshot. Fails if another process has the store open.
+    pub async fn new(logger: slog::Logger, data_dir: &Path, options: MiniRedisOptions) -> Result<MiniRedis> {
+        std::fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(SNAPSHOT_FILE);
+        let mut lock_name = path.file_name().unwrap_or_default().to_os_string();
+        lock_name.push(".lock");
+        let lock = LockFile::acquire(&logger, path.with_file_name(lock_name))?;
+
+        let mut state = State::default();
+        let aof_path = data_dir.join(AOF_FILE);
+        if options.append_only && aof_path.exists() {
+            let (aof, ops) = Aof::open(&logger, &aof_path, options.fsync, options.aof_rewrite_min_len)?;
+            let count = ops.len();
+            for op in ops {
+                state.replay(op);
+            }
+            state.aof = Some(aof);
+            info!(
+                logger,
+                "Loaded {:?} keys from {:?} writes of append-only file {:?}.",
+                state.entries.len(),
+                count,
+                aof_path
+            );
+        } else {
+            if path.exists() {
+                let entries = snapshot::read(&path)?;
+                let count = entries.len();
+                for entry in entries {
+                    state.replay(Op::Set {
+                        key: entry.key,
+                        value: entry.value,
+                        expires_at: entry.expires_at,
+                    });
+                }
+                info!(
+                    logger,
+                    "Loaded {:?} keys from snapshot {:?}, dropped {:?} expired ones.",
+                    state.entries.len(),
+                    path,
+                    count - state.entries.len()
+                );
+            }
+            if options.append_only {
+                state.aof = Some(Aof::create(&aof_path, &state.live_entries(), options.fsync, options.aof_rewrite_min_len)?);
+                info!(logger, "Created append-only file {:?}.", aof_path);
+            }
+        }
+
+        let snapshots = Snapshots {
+            path,
+            writing: Mutex::new(()),
+            in_background: AtomicBool::new(false),
+            saved_changes: AtomicU64::new(0),
+            snapshot_task: Notify::new(),
+            lock: Mutex::new(Some(lock)),
+        };
+        let store = MiniRedis::start(logger, state, Some(snapshots), options.notifier);
+        if let Some(interval) = options.snapshot_interval {
+            tokio::spawn(snapshot_task(store.shared.clone(), interval));
+        }
+        if options.append_only && options.fsync == FsyncPolicy::EverySec {
+            tokio::spawn(aof_sync_task(store.shared.clone()));
+        }
+        return Ok(store);
+    }
+
+    /// Create a new, empty, `MiniRedis` instance that only lives in memory.
+    /// Allocates shared state and spawns a background task to manage key
+    /// expiration.
+    pub fn in_memory() -> MiniRedis {
+        MiniRedis::start(slog::Logger::root(slog::Discard, o!()), State::default(), None, Notifier::default())
+    }
+
+    fn start(logger: slog::Logger, state: State, snapshots: Option<Snapshots>, notifier: Notifier) -> MiniRedis {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(state),
+            background_task: Notify::new(),
+            snapshots,
+            notifier,
+            logger,
+        });
+
+        // Start the background task.
+        tokio::spawn(purge_expired_tasks(shared.clone()));
+
+        MiniRedis { shared }
+    }
+
+    /// Rewrite the append-only file on the blocking thread pool, if
+    /// `State::start_aof_rewrite` started a rewrite.
+    fn rewrite_aof_in_background(&self, entries: Option<Vec<snapshot::Entry>>) {
+        if let Some(entries) = entries {
+            let shared = self.shared.clone();
+            tokio::task::spawn_blocking(move || shared.rewrite_aof(entries));
+        }
+    }
+}
+
+impl KeyValueStore for MiniRedis {
+    /// Get the value associated with a key.
+    ///
+    /// Returns `None` if there is no value associated with the key. This may be
+    /// due to never having assigned a value to the key or a previously assigned
+    /// value expired.
+    fn get(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        // Acquire the lock, get the entry and clone the value.
+        //
+        // Because data is stored using `Bytes`, a clone here is a shallow
+        // clone. Data is not copied.
+        let state = self.shared.state.lock().unwrap();
+
+        // The background task may not have purged the key yet.
+        if state.expirations.is_expired(key) {
+            return Ok(None);
+        }
+        Ok(state.entries.get(key).map(|entry| entry.data.clone()))
+    }
+
+    /// Set the value associated with a key along with an optional expiration
+    /// Duration.
+    ///
+    /// If a value is already associated with the key, it is removed.
+    fn set(&self, key: String, value: Bytes, expire: Option<Duration>) -> crate::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.log(|| {
+            vec![Op::Set {
+                key: key.clone(),
+                value: value.clone(),
+                expires_at: expire.map(|duration| SystemTime::now() + duration),
+            }]
+        })?;
+
+        // If this `set` becomes the key that expires **next**, the background
+        // task needs to be notified so it can update its state.
+        //
+        // Replacing the expiration also clears the one of the previous value
+        // associated with the key, if any.
+        let notify = state.expirations.set(&key, expire.map(|duration| Instant::now() + duration));
+
+        self.shared.notifier.notify(KeyEvent::Set, &key);
+        if expire.is_some() {
+            self.shared.notifier.notify(KeyEvent::Expire, &key);
+        }
+
+        // Insert the entry into the `BTreeMap`.
+        state.entries.insert(key, Entry { data: value });
+        state.changes += 1;
+        let rewrite = state.start_aof_rewrite();
+
+        // Release the mutex before notifying the background task. This helps
+        // reduce contention by avoiding the background task waking up only to
+        // be unable to acquire the mutex due to this function still holding it.
+        drop(state);
+        self.rewrite_aof_in_background(rewrite);
+
+        if notify {
+            // Finally, only notify the background task if it needs to update
+            // its state to reflect a new expiration.
+            self.shared.background_task.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Remove the value associated with a key along with its expiration.
+    ///
+    /// Returns `false` if there was no value associated with the key.
+    fn delete(&self, key: &str) -> crate::Result<bool> {
+        let mut state = self.shared.state.lock().unwrap();
+        let expired = state.expirations.is_expired(key);
+        if state.entries.contains_key(key) {
+            state.log(|| vec![Op::Delete { key: key.to_string() }])?;
+        }
+
+        // The expiration must be removed as well, otherwise the background
+        // task would purge the next value set for this key when it fires. The
+        // task does not need to be notified: its next wake up can only be
+        // later than it planned.
+        state.expirations.set(key, None);
+        let removed = state.entries.remove(key).is_some();
+        state.changes += removed as u64;
+        if removed && !expired {
+            self.shared.notifier.notify(KeyEvent::Del, key);
+        }
+        let rewrite = state.start_aof_rewrite();
+        drop(state);
+        self.rewrite_aof_in_background(rewrite);
+
+        Ok(removed && !expired)
+    }
+
+    /// Set the expiration of the value associated with a key.
+    ///
+    /// Returns `false` if there is no value associated with the key.
+    fn expire(&self, key: &str, expire: Duration) -> crate::Result<bool> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.entries.contains_key(key) || state.expirations.is_expired(key) {
+            return Ok(false);
+        }
+
+        state.log(|| {
+            vec![Op::Expire {
+                key: key.to_string(),
+                expires_at: SystemTime::now() + expire,
+            }]
+        })?;
+        let notify = state.expirations.set(key, Some(Instant::now() + expire));
+        state.changes += 1;
+        self.shared.notifier.notify(KeyEvent::Expire, key);
+        let rewrite = state.start_aof_rewrite();
+        drop(state);
+        self.rewrite_aof_in_background(rewrite);
+
+        if notify {
+            self.shared.background_task.notify_one();
+        }
+        Ok(true)
+    }
+
+    fn ttl(&self, key: &str) -> crate::Result<Expiry> {
+        let state = self.shared.state.lock().unwrap();
+        if !state.entries.contains_key(key) {
+            return Ok(Expiry::Missing);
+        }
+
+        let expiry = match state.expirations.get(key) {
+            Some(when) if when <= Instant::now() => Expiry::Missing,
+            Some(when) => Expiry::After(when - Instant::now()),
+            None => Expiry::Persistent,
+        };
+        Ok(expiry)
+    }
+
+    /// Remove the expiration of the value associated with a key.
+    ///
+    /// Returns `false` if the key does not exist or has no expiration.
+    fn persist(&self, key: &str) -> crate::Result<bool> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.entries.contains_key(key) || state.expirations.is_expired(key) || state.expirations.get(key).is_none() {
+            return Ok(false);
+        }
+
+        state.log(|| vec![Op::Persist { key: key.to_string() }])?;
+        state.expirations.set(key, None);
+        state.changes += 1;
+        self.shared.notifier.notify(KeyEvent::Persist, key);
+        let rewrite = state.start_aof_rewrite();
+        drop(state);
+        self.rewrite_aof_in_background(rewrite);
+        Ok(true)
+    }
+
+    fn scan(&self, options: &ScanOptions) -> crate::Result<Vec<(String, Bytes)>> {
+        let state = self.shared.state.lock().unwrap();
+        if options.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let range = state.entries.range(options.range());
+        let entries: Box<dyn Iterator<Item = (&String, &Entry)>> = if options.reverse { Box::new(range.rev()) } else { Box::new(range) };
+        Ok(entries
+            .filter(|(key, _)| !state.expirations.is_expired(key))
+            .take(options.limit.unwrap_or(usize::MAX))
+            .map(|(key, entry)| (key.clone(), entry.data.clone()))
+            .collect())
+    }
+
+    /// Apply the writes while holding the mutex, no other operation can see
+    /// them half applied.
+    fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.log(|| {
+            let now = SystemTime::now();
+            batch
+                .iter()
+                .map(|write| match write {
+                    Write::Set { key, value, expire } => Op::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                        expires_at: expire.map(|duration| now + duration),
+                    },
+                    Write::Delete { key } => Op::Delete { key: key.clone() },
+                })
+                .collect()
+        })?;
+        let mut notify = false;
+        for write in batch {
+            match write {
+                Write::Set { key, value, expire } => {
+                    notify |= state.expirations.set(&key, expire.map(|duration| Instant::now() + duration));
+                    self.shared.notifier.notify(KeyEvent::Set, &key);
+                    if expire.is_some() {
+                        self.shared.notifier.notify(KeyEvent::Expire, &key);
+                    }
+                    state.entries.insert(key, Entry { data: value });
+                }
+                Write::Delete { key } => {
+                    let expired = state.expirations.is_expired(&key);
+                    state.expirations.set(&key, None);
+                    if state.entries.remove(&key).is_some() && !expired {
+                        self.shared.notifier.notify(KeyEvent::Del, &key);
+                    }
+                }
+            }
+        }
+        state.changes += 1;
+        let rewrite = state.start_aof_rewrite();
+        drop(state);
+        self.rewrite_aof_in_background(rewrite);
+
+        if notify {
+            self.shared.background_task.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Write a snapshot of the keys, blocking until it is synced to disk.
+    fn save(&self) -> Result<()> {
+        self.shared.save()
+    }
+
+    /// Write a snapshot of the keys on the blocking thread pool. Fails if a
+    /// background save is already running.
+    fn bgsave(&self) -> Result<()> {
+        let snapshots = self.shared.snapshots()?;
+        if snapshots.in_background.swap(true, Ordering::SeqCst) {
+            bail!("a background save is already in progress");
+        }
+
+        let shared = self.shared.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = shared.save() {
+                error!(shared.logger, "err = {}, failed to save the keys in the background", err);
+            }
+            if let Some(snapshots) = shared.snapshots.as_ref() {
+                snapshots.in_background.store(false, Ordering::SeqCst);
+            }
+        });
+        Ok(())
+    }
+
+    /// Signals the purge background task to shut down. This is called by the
+    /// `DbShutdown`s `Drop` implementation.
+    ///
+    /// A store opened on a data directory syncs its append-only file, saves
+    /// its keys a last time and releases the lock of its snapshot file.
+    fn shutdown_purge_task(&self) {
+        // The background task must be signaled to shut down. This is done by
+        // setting `State::shutdown` to `true` and signalling the task.
+        let mut state = self.shared.state.lock().unwrap();
+        state.shutdown = true;
+        if let Some(aof) = state.aof.as_mut() {
+            if let Err(err) = aof.sync() {
+                error!(self.shared.logger, "err = {}, failed to sync the append-only file on shutdown", err);
+            }
+        }
+
+        // Drop the lock before signalling the background task. This helps
+        // reduce lock contention by ensuring the background task doesn't
+        // wake up only to be unable to acquire the mutex.
+        drop(state);
+        self.shared.background_task.notify_one();
+
+        if let Some(snapshots) = &self.shared.snapshots {
+            snapshots.snapshot_task.notify_one();
+            if let Err(err) = self.shared.save() {
+                error!(self.shared.logger, "err = {}, failed to save the keys on shutdown", err);
+            }
+            snapshots.lock.lock().unwrap().take();
+        }
+    }
+}
+
+impl Shared {
+    fn snapshots(&self) -> Result<&Snapshots> {
+        match &self.snapshots {
+            Some(snapshots) => Ok(snapshots),
+            None => bail!("the store only lives in memory, it has no snapshot file"),
+        }
+    }
+
+    /// Returns `true` if keys were written since the last snapshot.
+    fn has_unsaved_changes(&self) -> bool {
+        match &self.snapshots {
+            Some(snapshots) => self.state.lock().unwrap().changes != snapshots.saved_changes.load(Ordering::SeqCst),
+            None => false,
+        }
+    }
+
+    /// Copy the live keys under the state mutex, then write them to the
+    /// snapshot file without holding it.
+    fn save(&self) -> Result<()> {
+        let snapshots = self.snapshots()?;
+        let _writing = snapshots.writing.lock().unwrap();
+
+        let state = self.state.lock().unwrap();
+        let entries = state.live_entries();
+        let changes = state.changes;
+        drop(state);
+
+        snapshot::write(&snapshots.path, &entries)?;
+        snapshots.saved_changes.store(changes, Ordering::SeqCst);
+        info!(self.logger, "Saved {:?} keys to snapshot {:?}.", entries.len(), snapshots.path);
+        return Ok(());
+    }
+
+    /// Write the keys copied by `State::start_aof_rewrite` to a new
+    /// append-only file, without holding the state mutex, then replace the
+    /// current file by it.
+    fn rewrite_aof(&self, entries: Vec<snapshot::Entry>) {
+        let path = match self.state.lock().unwrap().aof.as_ref() {
+            Some(aof) => aof.path().to_path_buf(),
+            None => return,
+        };
+
+        let rewritten = aof::write_base(&path, &entries).and_then(|tmp_path| match self.state.lock().unwrap().aof.as_mut() {
+            Some(aof) => aof.finish_rewrite(&tmp_path),
+            None => Ok(()),
+        });
+        match rewritten {
+            Ok(()) => info!(self.logger, "Rewrote append-only file {:?} with {:?} keys.", path, entries.len()),
+            Err(err) => {
+                error!(self.logger, "err = {}, failed to rewrite append-only file {:?}", err, path);
+                if let Some(aof) = self.state.lock().unwrap().aof.as_mut() {
+                    aof.abort_rewrite();
+                }
+            }
+        }
+    }
+}
+
+impl State {
+    /// Log the writes built by `ops` to the append-only file, if there is
+    /// one. Must be called before the writes are applied: they are not if
+    /// they can not be logged.
+    fn log(&mut self, ops: impl FnOnce() -> Vec<Op>) -> Result<()> {
+        match self.aof.as_mut() {
+            Some(aof) => aof.append(ops()),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply a write read back from the append-only file or the snapshot
+    /// file. Keys whose expiration is past are dropped.
+    fn replay(&mut self, op: Op) {
+        match op {
+            Op::Set { key, value, expires_at } => {
+                self.expirations.set(&key, None);
+                self.entries.insert(key.clone(), Entry { data: value });
+                if let Some(when) = expires_at {
+                    self.expire_at(&key, when);
+                }
+            }
+            Op::Delete { key } => {
+                self.expirations.set(&key, None);
+                self.entries.remove(&key);
+            }
+            Op::Expire { key, expires_at } if self.entries.contains_key(&key) => self.expire_at(&key, expires_at),
+            Op::Expire { .. } => {}
+            Op::Persist { key } => {
+                self.expirations.set(&key, None);
+            }
+        }
+    }
+
+    fn expire_at(&mut self, key: &str, when: SystemTime) {
+        match when.duration_since(SystemTime::now()) {
+            Ok(left) => {
+                self.expirations.set(key, Some(Instant::now() + left));
+            }
+            // The key expired while the store was closed.
+            Err(_) => {
+                self.expirations.set(key, None);
+                self.entries.remove(key);
+            }
+        }
+    }
+
+    /// Copy the keys that did not expire, with their expiration time.
+    ///
+    /// Values are `Bytes`, only their reference counts are copied.
+    fn live_entries(&self) -> Vec<snapshot::Entry> {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let mut entries = Vec::with_capacity(self.entries.len());
+        for (key, entry) in self.entries.iter() {
+            let expires_at = match self.expirations.get(key) {
+                Some(when) if when <= now => continue,
+                Some(when) => Some(wall_now + (when - now)),
+                None => None,
+            };
+            entries.push(snapshot::Entry {
+                key: key.clone(),
+                value: entry.data.clone(),
+                expires_at,
+            });
+        }
+        return entries;
+    }
+
+    /// Start rewriting the append-only file if it grew enough since it was
+    /// last written from scratch. Returns the keys to write to the new file,
+    /// copied along with starting the rewrite so that every later write is
+    /// buffered for it.
+    fn start_aof_rewrite(&mut self) -> Option<Vec<snapshot::Entry>> {
+        if !self.aof.as_ref()?.should_rewrite() {
+            return None;
+        }
+        let entries = self.live_entries();
+        self.aof.as_mut()?.start_rewrite();
+        return Some(entries);
+    }
+}
+
+/// Routine of the background task saving the keys every `interval` if they
+/// changed, until the store shuts down.
+async fn snapshot_task(shared: Arc<Shared>, interval: Duration) {
+    let snapshots = shared.snapshots.as_ref().expect("the snapshot task needs snapshots");
+    let mut ticks = time::interval_at(Instant::now() + interval, interval);
+    while !shared.is_shutdown() {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = snapshots.snapshot_task.notified() => continue,
+        }
+        if shared.is_shutdown() || !shared.has_unsaved_changes() {
+            continue;
+        }
+
+        let saving = shared.clone();
+        match tokio::task::spawn_blocking(move || saving.save()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!(shared.logger, "err = {}, failed to save the keys", err),
+            Err(err) => error!(shared.logger, "err = {}, failed to save the keys", err),
+        }
+    }
+}
+
+/// Routine of the background task syncing the append-only file about once
+/// per second, for the `EverySec` fsync policy.
+async fn aof_sync_task(shared: Arc<Shared>) {
+    let mut ticks = time::interval(Duration::from_secs(1));
+    while !shared.is_shutdown() {
+        ticks.tick().await;
+        let file = match shared.state.lock().unwrap().aof.as_mut().map(Aof::take_unsynced) {
+            Some(Ok(Some(file))) => file,
+            Some(Ok(None)) | None => continue,
+            Some(Err(err)) => {
+                error!(shared.logger, "err = {}, failed to sync the append-only file", err);
+                continue;
+            }
+        };
+        match tokio::task::spawn_blocking(move || file.sync_data()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!(shared.logger, "err = {}, failed to sync the append-only file", err),
+            Err(err) => error!(shared.logger, "err = {}, failed to sync the append-only file", err),
+        }
+    }
+}
+
+impl PurgeExpired for Shared {
+    /// Purge all expired keys and return the `Instant` at which the **next**
+    /// key will expire. The background task will sleep until this instant.
+    fn purge_expired_keys(&self) -> Option<Instant> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.shutdown {
+            // The database is shutting down. All handles to the shared state
+            // have dropped. The background task should exit.
+            return None;
+        }
+
+        // This is needed to make the borrow checker happy. In short, `lock()`
+        // returns a `MutexGuard` and not a `&mut State`. The borrow checker is
+        // not able to see "through" the mutex guard and determine that it is
+        // safe to access both `state.expirations` and `state.entries` mutably,
+        // so we get a "real" mutable reference to `State` outside of the loop.
+        let state = &mut *state;
+
+        // Find all keys scheduled to expire **before** now.
+        let now = Instant::now();
+
+        while let Some(key) = state.expirations.pop_expired(now) {
+            // The key expired, remove it
+            if state.entries.remove(&key).is_some() {
+                self.notifier.notify(KeyEvent::Expired, &key);
+            }
+        }
+
+        // Done purging, this is the instant at which the next key expires. The
+        // worker task will wait until this instant.
+        state.expirations.next_expiration()
+    }
+
+    /// Returns `true` if the database is shutting down
+    ///
+    /// The `shutdown` flag is set when all `MiniRedis` values have dropped, indicating
+    /// that the shared state can no longer be accessed.
+    fn is_shutdown(&self) -> bool {
+        self.state.lock().unwrap().shutdown
+    }
+
+    fn expiration_task(&self) -> &Notify {
+        &self.background_task
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn test_delete() {
+        let store = MiniRedis::in_memory();
+        store.set("key".to_string(), Bytes::from("value"), Some(Duration::from_secs(60))).unwrap();
+        assert!(store.delete("key").unwrap());
+        assert!(!store.delete("key").unwrap());
+        assert_eq!(store.get("key").unwrap(), None);
+
+        let state = store.shared.state.lock().unwrap();
+        assert!(state.entries.is_empty());
+        assert_eq!(state.expirations.next_expiration(), None);
+        drop(state);
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_expiration() {
+        time::pause();
+        let store = MiniRedis::in_memory();
+
+        // Keys do not expire unless asked to.
+        store.set("forever".to_string(), Bytes::from("value"), None).unwrap();
+        store.set("short".to_string(), Bytes::from("value"), Some(Duration::from_secs(10))).unwrap();
+        store.set("long".to_string(), Bytes::from("value"), None).unwrap();
+        assert!(store.expire("long", Duration::from_secs(100)).unwrap());
+        assert!(!store.expire("missing", Duration::from_secs(100)).unwrap());
+
+        assert_eq!(store.ttl("forever").unwrap(), Expiry::Persistent);
+        assert_eq!(store.ttl("short").unwrap(), Expiry::After(Duration::from_secs(10)));
+        assert_eq!(store.ttl("missing").unwrap(), Expiry::Missing);
+
+        time::advance(Duration::from_secs(11)).await;
+        assert_eq!(store.get("short").unwrap(), None);
+        assert_eq!(store.ttl("short").unwrap(), Expiry::Missing);
+        assert_eq!(store.get("long").unwrap(), Some(Bytes::from("value")));
+
+        assert!(store.persist("long").unwrap());
+        assert!(!store.persist("long").unwrap());
+        time::advance(Duration::from_secs(200)).await;
+        assert_eq!(store.get("long").unwrap(), Some(Bytes::from("value")));
+        assert_eq!(store.get("forever").unwrap(), Some(Bytes::from("value")));
+
+        // The background task purged the expired key.
+        assert!(!store.shared.state.lock().unwrap().entries.contains_key("short"));
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        time::pause();
+        let store = MiniRedis::in_memory();
+        for key in ["b", "a", "ab", "c", "abc"] {
+            store.set(key.to_string(), Bytes::from(key), None).unwrap();
+        }
+        store.set("abb".to_string(), Bytes::from("abb"), Some(Duration::from_secs(1))).unwrap();
+        time::advance(Duration::from_secs(2)).await;
+
+        let options = ScanOptions {
+            prefix: Some("a".to_string()),
+            ..ScanOptions::default()
+        };
+        let keys: Vec<String> = store.scan(&options).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["a", "ab", "abc"]);
+
+        let options = ScanOptions {
+            end: Some("c".to_string()),
+            limit: Some(2),
+            reverse: true,
+            ..ScanOptions::default()
+        };
+        let entries = store.scan(&options).unwrap();
+        assert_eq!(entries, [("b".to_string(), Bytes::from("b")), ("abc".to_string(), Bytes::from("abc"))]);
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let dir = tempfile::tempdir().unwrap();
+        let options = MiniRedisOptions {
+            snapshot_interval: None,
+            ..MiniRedisOptions::default()
+        };
+        let store = MiniRedis::new(logger.clone(), dir.path(), options.clone()).await.unwrap();
+        assert!(MiniRedis::new(logger.clone(), dir.path(), options.clone()).await.is_err());
+
+        store.set("a".to_string(), Bytes::from("1"), None).unwrap();
+        store.set("b".to_string(), Bytes::from("2"), Some(Duration::from_secs(60))).unwrap();
+        store.set("short".to_string(), Bytes::from("3"), Some(Duration::from_millis(50))).unwrap();
+        store.save().unwrap();
+        assert!(!store.shared.has_unsaved_changes());
+        let saved = snapshot::read(&dir.path().join(SNAPSHOT_FILE)).unwrap();
+        assert_eq!(saved.len(), 3);
+        assert!(saved[1].expires_at.is_some());
+
+        // The shutdown saves the writes made since the last snapshot.
+        store.delete("a").unwrap();
+        store.set("c".to_string(), Bytes::from("4"), None).unwrap();
+        assert!(store.shared.has_unsaved_changes());
+        store.shutdown_purge_task();
+
+        // Expired keys are not loaded, the others keep their expiration.
+        time::sleep(Duration::from_millis(100)).await;
+        let store = MiniRedis::new(logger.clone(), dir.path(), options).await.unwrap();
+        assert_eq!(store.get("a").unwrap(), None);
+        assert_eq!(store.get("c").unwrap(), Some(Bytes::from("4")));
+        assert!(!store.shared.state.lock().unwrap().entries.contains_key("short"));
+        match store.ttl("b").unwrap() {
+            Expiry::After(left) => assert!(left > Duration::from_secs(50) && left <= Duration::from_secs(60)),
+            other => panic!("expected b to expire, got {:?}", other),
+        }
+
+        // Background saves run one at a time.
+        store.set("d".to_string(), Bytes::from("5"), None).unwrap();
+        store.bgsave().unwrap();
+        while store.shared.snapshots.as_ref().unwrap().in_background.load(Ordering::SeqCst) {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!store.shared.has_unsaved_changes());
+        store.shutdown_purge_task();
+
+        // The keys are saved periodically if they changed.
+        let options = MiniRedisOptions {
+            snapshot_interval: Some(Duration::from_millis(20)),
+            ..MiniRedisOptions::default()
+        };
+        let store = MiniRedis::new(logger, dir.path(), options).await.unwrap();
+        assert_eq!(store.get("d").unwrap(), Some(Bytes::from("5")));
+        store.set("e".to_string(), Bytes::from("6"), None).unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!store.shared.has_unsaved_changes());
+        assert_eq!(snapshot::read(&dir.path().join(SNAPSHOT_FILE)).unwrap().len(), 4);
+        store.shutdown_purge_task();
+
+        // A store that only lives in memory has nothing to save to.
+        let store = MiniRedis::in_memory();
+        assert!(store.save().is_err());
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_append_only() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let dir = tempfile::tempdir().unwrap();
+        let aof_path = dir.path().join(AOF_FILE);
+
+        // Turning the append-only file on logs the keys of the snapshot.
+        let options = MiniRedisOptions {
+            snapshot_interval: None,
+            ..MiniRedisOptions::default()
+        };
+        let store = MiniRedis::new(logger.clone(), dir.path(), options.clone()).await.unwrap();
+        store.set("snapshotted".to_string(), Bytes::from("1"), None).unwrap();
+        store.shutdown_purge_task();
+
+        let options = MiniRedisOptions {
+            append_only: true,
+            aof_rewrite_min_len: 1024,
+            ..options
+        };
+        let store = MiniRedis::new(logger.clone(), dir.path(), options.clone()).await.unwrap();
+        assert_eq!(store.get("snapshotted").unwrap(), Some(Bytes::from("1")));
+        for i in 0..100 {
+            store.set("counter".to_string(), Bytes::from(format!("{:03}", i)), None).unwrap();
+        }
+        store.set("a".to_string(), Bytes::from("2"), Some(Duration::from_secs(60))).unwrap();
+        store.set("b".to_string(), Bytes::from("3"), Some(Duration::from_secs(60))).unwrap();
+        assert!(store.persist("b").unwrap());
+        store.set("short".to_string(), Bytes::from("4"), None).unwrap();
+        assert!(store.expire("short", Duration::from_millis(50)).unwrap());
+        let mut batch = WriteBatch::new();
+        batch.put("c", Bytes::from("5"));
+        batch.delete("snapshotted");
+        store.write_batch(batch).unwrap();
+        assert!(store.delete("c").unwrap());
+
+        // The file was rewritten in the background as it grew.
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(std::fs::metadata(&aof_path).unwrap().len() < 100 * 30);
+        store.shutdown_purge_task();
+
+        // The keys are rebuilt from the append-only file, not the snapshot.
+        std::fs::remove_file(dir.path().join(SNAPSHOT_FILE)).unwrap();
+        let store = MiniRedis::new(logger, dir.path(), options).await.unwrap();
+        let keys: Vec<String> = store.scan(&ScanOptions::default()).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["a", "b", "counter"]);
+        assert_eq!(store.get("counter").unwrap(), Some(Bytes::from("099")));
+        assert!(matches!(store.ttl("a").unwrap(), Expiry::After(_)));
+        assert_eq!(store.ttl("b").unwrap(), Expiry::Persistent);
+        store.shutdown_purge_task();
+    }
+}
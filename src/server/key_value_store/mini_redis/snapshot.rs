@@ -0,0 +1,164 @@
+//! Snapshot files hold every key of a `MiniRedis` at a point in time, like the
+//! RDB files of Redis.
+//!
+//! Layout, all integers are big endian:
+//!
+//! ```text
+//! | magic | expires at (u64) | key len (u32) | value len (u32) | key | value | ... | crc32 (u32) |
+//! ```
+//!
+//! The expiration time is in milliseconds since the Unix epoch, `0` if the key
+//! does not expire. The checksum covers every byte before it.
+
+use crate::Result;
+
+use bytes::{Buf, Bytes};
+use simple_error::bail;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"RAPHRDB1";
+
+/// Length of the fixed-size start of an entry.
+const ENTRY_HEADER_LEN: usize = 8 + 4 + 4;
+
+/// A key of a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: String,
+    pub value: Bytes,
+
+    /// When the key expires, `None` if it does not.
+    pub expires_at: Option<SystemTime>,
+}
+
+/// Write `entries` to the snapshot file at `path`.
+///
+/// The file is written to a temporary path and renamed once synced, a crash
+/// leaves the previous snapshot in place.
+pub fn write(path: &Path, entries: &[Entry]) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut writer = Checksummed {
+        writer: BufWriter::new(File::create(&tmp_path)?),
+        hasher: crc32fast::Hasher::new(),
+    };
+    writer.write_all(MAGIC)?;
+    for entry in entries {
+        let expires_at = match entry.expires_at {
+            Some(when) => when.duration_since(UNIX_EPOCH)?.as_millis().try_into()?,
+            None => 0u64,
+        };
+        let key_len: u32 = entry.key.len().try_into()?;
+        let value_len: u32 = entry.value.len().try_into()?;
+        writer.write_all(&expires_at.to_be_bytes())?;
+        writer.write_all(&key_len.to_be_bytes())?;
+        writer.write_all(&value_len.to_be_bytes())?;
+        writer.write_all(entry.key.as_bytes())?;
+        writer.write_all(&entry.value[..])?;
+    }
+
+    let Checksummed { mut writer, hasher } = writer;
+    writer.write_all(&hasher.finalize().to_be_bytes())?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp_path, path)?;
+    return Ok(());
+}
+
+/// Read the snapshot file at `path`. Fails if it does not pass its checksum.
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let data = Bytes::from(std::fs::read(path)?);
+    if data.len() < MAGIC.len() + 4 || &data[..MAGIC.len()] != MAGIC {
+        bail!("{:?} is not a snapshot file", path);
+    }
+
+    let content = data.slice(..data.len() - 4);
+    if crc32fast::hash(&content) != (&data[content.len()..]).get_u32() {
+        bail!("snapshot file {:?} fails its checksum", path);
+    }
+
+    let mut entries = Vec::new();
+    let mut offset = MAGIC.len();
+    while offset < content.len() {
+        if content.len() - offset < ENTRY_HEADER_LEN {
+            bail!("snapshot file {:?} is truncated", path);
+        }
+        let mut header = &content[offset..offset + ENTRY_HEADER_LEN];
+        let expires_at = match header.get_u64() {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        };
+        let key_len = header.get_u32() as usize;
+        let value_len = header.get_u32() as usize;
+        offset += ENTRY_HEADER_LEN;
+        if content.len() - offset < key_len + value_len {
+            bail!("snapshot file {:?} is truncated", path);
+        }
+
+        let key = String::from_utf8(content[offset..offset + key_len].to_vec())?;
+        offset += key_len;
+        let value = content.slice(offset..offset + value_len);
+        offset += value_len;
+        entries.push(Entry { key, value, expires_at });
+    }
+
+    return Ok(entries);
+}
+
+/// Computes the checksum of the bytes written through it.
+struct Checksummed<W> {
+    writer: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        return Ok(len);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.rdb");
+        let entries = vec![
+            Entry {
+                key: "key".to_string(),
+                value: Bytes::from(&b"binary\x00value"[..]),
+                expires_at: None,
+            },
+            Entry {
+                key: "expiring".to_string(),
+                value: Bytes::new(),
+                expires_at: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+            },
+        ];
+        write(&path, &entries).unwrap();
+        assert_eq!(read(&path).unwrap(), entries);
+
+        write(&path, &[]).unwrap();
+        assert_eq!(read(&path).unwrap(), Vec::new());
+
+        let mut content = std::fs::read(&path).unwrap();
+        content[0] ^= 0xff;
+        std::fs::write(&path, &content).unwrap();
+        assert!(read(&path).is_err());
+    }
+}
@@ -1,27 +1,55 @@
+pub mod adapter;
+pub mod batch;
+pub mod btree;
+pub mod changes;
+pub mod expiration;
+pub mod lock;
+pub mod lsm;
 pub mod mini_redis;
+pub mod notify;
+pub mod scan;
 pub mod simple_store;
 
+use async_trait::async_trait;
 use bytes::Bytes;
 use simple_error::bail;
 use std::fmt::Debug;
+use std::path::Path;
+use std::time::Duration;
 
+pub use adapter::SyncAdapter;
+pub use batch::{Write, WriteBatch};
+use btree::BTreeStore;
+pub use changes::{Change, Changes, Snapshot};
+use lsm::{LsmOptions, LsmStore};
 use mini_redis::MiniRedis;
+pub use mini_redis::MiniRedisOptions;
+pub use notify::{KeyEvent, KeyspaceEvents, Notifier};
+pub use scan::ScanOptions;
 use simple_store::SimpleStore;
+pub use simple_store::SimpleStoreOptions;
 
 #[derive(Debug)]
 pub enum Backend {
     SimpleStore,
     MiniRedis,
+    Lsm,
+    BTree,
 }
 
 const MINI_REDIS: &str = "mini-redis";
 const SIMPLE_STORE: &str = "simple-store";
+const LSM: &str = "lsm";
+const BTREE: &str = "btree";
 
 impl Backend {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(backend_name: &str) -> crate::Result<Self> {
         match backend_name {
             MINI_REDIS => Ok(Backend::MiniRedis),
             SIMPLE_STORE => Ok(Backend::SimpleStore),
+            LSM => Ok(Backend::Lsm),
+            BTREE => Ok(Backend::BTree),
             _ => {
                 bail!("Backend {:?} does not exist", backend_name)
             }
@@ -29,20 +57,166 @@ impl Backend {
     }
 
     pub fn possible_names() -> Vec<&'static str> {
-        return vec![MINI_REDIS, SIMPLE_STORE];
+        return vec![MINI_REDIS, SIMPLE_STORE, LSM, BTREE];
     }
 }
 
-pub async fn get_kv_store(logger: slog::Logger, backend: Backend) -> crate::Result<Box<dyn KeyValueStore>> {
+/// When a backend syncs its writes to disk, like the `appendfsync` setting of
+/// Redis.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FsyncPolicy {
+    /// Writes are synced before they are acknowledged.
+    #[default]
+    Always,
+
+    /// Writes are synced in the background about once per second. A crash
+    /// loses up to a second of acknowledged writes.
+    EverySec,
+
+    /// Writes are never synced explicitly, the operating system flushes them
+    /// when it sees fit.
+    Never,
+}
+
+const ALWAYS: &str = "always";
+const EVERYSEC: &str = "everysec";
+const NEVER: &str = "never";
+
+impl FsyncPolicy {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(policy_name: &str) -> crate::Result<Self> {
+        match policy_name {
+            ALWAYS => Ok(FsyncPolicy::Always),
+            EVERYSEC => Ok(FsyncPolicy::EverySec),
+            NEVER => Ok(FsyncPolicy::Never),
+            _ => {
+                bail!("Fsync policy {:?} does not exist", policy_name)
+            }
+        }
+    }
+
+    pub fn possible_names() -> Vec<&'static str> {
+        return vec![ALWAYS, EVERYSEC, NEVER];
+    }
+}
+
+/// Options of the backends, each backend only reads its own.
+#[derive(Debug, Clone, Default)]
+pub struct StoreOptions {
+    pub simple_store: SimpleStoreOptions,
+    pub mini_redis: MiniRedisOptions,
+
+    /// Keyspace notifications published by the store, whatever its backend.
+    pub keyspace_events: KeyspaceEvents,
+}
+
+/// Open the store of `backend`, which keeps its files under `data_dir`.
+///
+/// Only the simple-store and mini-redis backends are tuned by `options`, the
+/// other persistent backends sync every write and read through their own
+/// caches. The fsync policy also applies to the append-only file of the
+/// mini-redis backend.
+///
+/// The store publishes its keyspace notifications with `notifier`.
+///
+/// The simple-store backend is asynchronous, the others are served through a
+/// `SyncAdapter`.
+pub async fn get_kv_store(
+    logger: slog::Logger,
+    backend: Backend,
+    data_dir: &Path,
+    options: StoreOptions,
+    notifier: Notifier,
+) -> crate::Result<Box<dyn AsyncKeyValueStore>> {
+    if !matches!(backend, Backend::SimpleStore) {
+        let ignored = &options.simple_store;
+        let logs_writes = matches!(backend, Backend::MiniRedis) && options.mini_redis.append_only;
+        if ignored.fsync != FsyncPolicy::Always && !logs_writes {
+            warn!(logger, "The {:?} backend has no fsync policy, ignoring {:?}.", backend, ignored.fsync);
+        }
+        if ignored.mmap {
+            warn!(logger, "The {:?} backend does not map its files, ignoring mmap.", backend);
+        }
+        if ignored.repair {
+            warn!(logger, "The {:?} backend has no repair, ignoring it.", backend);
+        }
+    }
+
     match backend {
-        Backend::MiniRedis => Ok(Box::new(MiniRedis::new())),
-        Backend::SimpleStore => Ok(Box::new(SimpleStore::new(logger).await?)),
+        Backend::MiniRedis => {
+            // Writes block on the append-only file, if there is one.
+            let append_only = options.mini_redis.append_only;
+            let options = MiniRedisOptions {
+                notifier,
+                ..options.mini_redis
+            };
+            let store = Box::new(MiniRedis::new(logger, data_dir, options).await?);
+            Ok(Box::new(if append_only {
+                SyncAdapter::new(store)
+            } else {
+                SyncAdapter::in_memory(store)
+            }))
+        }
+        Backend::SimpleStore => {
+            let options = SimpleStoreOptions {
+                notifier,
+                ..options.simple_store
+            };
+            Ok(Box::new(SimpleStore::new(logger, data_dir, options).await?))
+        }
+        Backend::Lsm => {
+            let options = LsmOptions {
+                notifier,
+                ..LsmOptions::default()
+            };
+            Ok(Box::new(SyncAdapter::new(Box::new(LsmStore::new(logger, data_dir, options).await?))))
+        }
+        Backend::BTree => Ok(Box::new(SyncAdapter::new(Box::new(BTreeStore::new(logger, data_dir, notifier).await?)))),
     }
 }
 
+/// How long a key has left to live.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expiry {
+    /// The key does not exist.
+    Missing,
+
+    /// The key exists and does not expire.
+    Persistent,
+
+    /// The key expires after the duration.
+    After(Duration),
+}
+
+/// Synchronous interface of a store. The calls of a persistent backend block
+/// on disk I/O, see `AsyncKeyValueStore` for the interface the server uses.
 pub trait KeyValueStore: Debug + KeyValueStoreClone + Send + Sync {
     fn get(&self, key: &str) -> crate::Result<Option<Bytes>>;
-    fn set(&self, key: String, value: Bytes) -> crate::Result<()>;
+    /// Set the value of `key`, expiring after `expire` if it is not `None`.
+    /// Any previous expiration of the key is discarded.
+    fn set(&self, key: String, value: Bytes, expire: Option<Duration>) -> crate::Result<()>;
+    /// Remove `key` and its value. Returns `true` if the key existed.
+    fn delete(&self, key: &str) -> crate::Result<bool>;
+    /// Make `key` expire after `expire`. Returns `false` if the key does not
+    /// exist.
+    fn expire(&self, key: &str, expire: Duration) -> crate::Result<bool>;
+    fn ttl(&self, key: &str) -> crate::Result<Expiry>;
+    /// Remove the expiration of `key`. Returns `true` if the key had one.
+    fn persist(&self, key: &str) -> crate::Result<bool>;
+    /// Entries whose key is in the range of `options`, sorted by key.
+    fn scan(&self, options: &ScanOptions) -> crate::Result<Vec<(String, Bytes)>>;
+    /// Apply the writes of `batch` in order, as a whole: readers see either
+    /// none or all of them. Persistent backends sync the batch to disk once.
+    fn write_batch(&self, batch: WriteBatch) -> crate::Result<()>;
+    /// Write a snapshot of every key to disk and wait for it. Backends that
+    /// persist every write take no snapshots.
+    fn save(&self) -> crate::Result<()> {
+        bail!("the backend persists every write, it takes no snapshots");
+    }
+    /// Start writing a snapshot of every key to disk, without waiting for it.
+    fn bgsave(&self) -> crate::Result<()> {
+        bail!("the backend persists every write, it takes no snapshots");
+    }
     fn shutdown_purge_task(&self);
 }
 
@@ -64,3 +238,58 @@ impl Clone for Box<dyn KeyValueStore> {
         self.clone_box()
     }
 }
+
+/// Asynchronous interface of a store, the one the server uses.
+///
+/// Its methods mirror those of `KeyValueStore`. A backend implements it
+/// directly when it can wait on disk without blocking the calling task, the
+/// others are served through a `SyncAdapter`.
+#[async_trait]
+pub trait AsyncKeyValueStore: Debug + AsyncKeyValueStoreClone + Send + Sync {
+    async fn get(&self, key: &str) -> crate::Result<Option<Bytes>>;
+    async fn set(&self, key: String, value: Bytes, expire: Option<Duration>) -> crate::Result<()>;
+    async fn delete(&self, key: &str) -> crate::Result<bool>;
+    async fn expire(&self, key: &str, expire: Duration) -> crate::Result<bool>;
+    async fn ttl(&self, key: &str) -> crate::Result<Expiry>;
+    async fn persist(&self, key: &str) -> crate::Result<bool>;
+    async fn scan(&self, options: &ScanOptions) -> crate::Result<Vec<(String, Bytes)>>;
+    async fn write_batch(&self, batch: WriteBatch) -> crate::Result<()>;
+    async fn save(&self) -> crate::Result<()> {
+        Err("the backend persists every write, it takes no snapshots".into())
+    }
+    async fn bgsave(&self) -> crate::Result<()> {
+        Err("the backend persists every write, it takes no snapshots".into())
+    }
+    /// Stream the changes numbered `from` and later, then keep streaming the
+    /// changes as they are committed. Fails if the changes from `from` were
+    /// not kept, or if `from` is past the next change. Only asynchronous, it
+    /// has no `KeyValueStore` counterpart.
+    async fn changes(&self, _from: u64) -> crate::Result<Changes> {
+        Err("the backend does not number its changes".into())
+    }
+    /// Every live key, as of the last change the stream of `changes` would
+    /// include if it started now.
+    async fn snapshot(&self) -> crate::Result<Snapshot> {
+        Err("the backend does not number its changes".into())
+    }
+    fn shutdown_purge_task(&self);
+}
+
+pub trait AsyncKeyValueStoreClone {
+    fn clone_box(&self) -> Box<dyn AsyncKeyValueStore>;
+}
+
+impl<T> AsyncKeyValueStoreClone for T
+where
+    T: 'static + AsyncKeyValueStore + Clone,
+{
+    fn clone_box(&self) -> Box<dyn AsyncKeyValueStore> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn AsyncKeyValueStore> {
+    fn clone(&self) -> Box<dyn AsyncKeyValueStore> {
+        self.clone_box()
+    }
+}
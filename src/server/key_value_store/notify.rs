@@ -0,0 +1,180 @@
+//! Keyspace notifications, like those of Redis.
+//!
+//! Every change to a key is published to two pub/sub channels:
+//!
+//! - `__keyspace@0__:<key>`, with the name of the event as message,
+//! - `__keyevent@0__:<event>`, with the key as message.
+//!
+//! Subscribers filter the keys with a pattern subscription to the first kind,
+//! such as `__keyspace@0__:user:*`, and the events by subscribing to the
+//! second kind, such as `__keyevent@0__:expired`.
+
+use crate::server::PubSub;
+use crate::Result;
+
+use bytes::Bytes;
+use simple_error::bail;
+use std::sync::Arc;
+
+/// A change to a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyEvent {
+    /// The key was set.
+    Set,
+
+    /// The key was deleted.
+    Del,
+
+    /// An expiration was set on the key.
+    Expire,
+
+    /// The expiration of the key was removed.
+    Persist,
+
+    /// The key expired and was removed.
+    Expired,
+}
+
+impl KeyEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyEvent::Set => "set",
+            KeyEvent::Del => "del",
+            KeyEvent::Expire => "expire",
+            KeyEvent::Persist => "persist",
+            KeyEvent::Expired => "expired",
+        }
+    }
+}
+
+/// Which keyspace notifications are published, like the
+/// `notify-keyspace-events` setting of Redis. Nothing is published by
+/// default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KeyspaceEvents {
+    /// Publish to the `__keyspace@0__:<key>` channels.
+    pub keyspace: bool,
+
+    /// Publish to the `__keyevent@0__:<event>` channels.
+    pub keyevent: bool,
+
+    /// Publish the `del`, `expire` and `persist` events.
+    pub generic: bool,
+
+    /// Publish the `set` events.
+    pub string: bool,
+
+    /// Publish the `expired` events.
+    pub expired: bool,
+}
+
+impl KeyspaceEvents {
+    /// Parse the flags of Redis: `K` and `E` for the keyspace and keyevent
+    /// channels, `g` for the generic events, `$` for `set`, `x` for
+    /// `expired` and `A` for all the events. At least one channel kind and
+    /// one event class are needed for anything to be published.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(flags: &str) -> Result<Self> {
+        let mut events = KeyspaceEvents::default();
+        for flag in flags.chars() {
+            match flag {
+                'K' => events.keyspace = true,
+                'E' => events.keyevent = true,
+                'g' => events.generic = true,
+                '$' => events.string = true,
+                'x' => events.expired = true,
+                'A' => {
+                    events.generic = true;
+                    events.string = true;
+                    events.expired = true;
+                }
+                _ => bail!("unsupported keyspace event flag {:?} in {:?}", flag, flags),
+            }
+        }
+        return Ok(events);
+    }
+
+    fn allows(&self, event: KeyEvent) -> bool {
+        let class = match event {
+            KeyEvent::Set => self.string,
+            KeyEvent::Del | KeyEvent::Expire | KeyEvent::Persist => self.generic,
+            KeyEvent::Expired => self.expired,
+        };
+        return class && (self.keyspace || self.keyevent);
+    }
+}
+
+/// Publishes the keyspace notifications of a store to the pub/sub channels
+/// of the server.
+///
+/// The default `Notifier` publishes nothing, for stores used outside of a
+/// server.
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    events: KeyspaceEvents,
+    pub_sub: Option<Arc<PubSub>>,
+}
+
+impl Notifier {
+    pub fn new(events: KeyspaceEvents, pub_sub: Arc<PubSub>) -> Notifier {
+        Notifier {
+            events,
+            pub_sub: Some(pub_sub),
+        }
+    }
+
+    /// Publish `event` on `key`, if the notifications of its class are
+    /// enabled.
+    pub fn notify(&self, event: KeyEvent, key: &str) {
+        let pub_sub = match &self.pub_sub {
+            Some(pub_sub) if self.events.allows(event) => pub_sub,
+            _ => return,
+        };
+
+        if self.events.keyspace {
+            pub_sub.publish(&format!("__keyspace@0__:{}", key), Bytes::from(event.name()));
+        }
+        if self.events.keyevent {
+            pub_sub.publish(&format!("__keyevent@0__:{}", event.name()), Bytes::from(key.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::Message;
+
+    #[tokio::test]
+    async fn test_notify() {
+        assert!(KeyspaceEvents::from_str("Kl").is_err());
+        assert_eq!(KeyspaceEvents::from_str("").unwrap(), KeyspaceEvents::default());
+
+        let pub_sub = Arc::new(PubSub::default());
+        let mut keyspace = pub_sub.psubscribe("__keyspace@0__:user:*");
+        let mut keyevent = pub_sub.subscribe("__keyevent@0__:expired");
+
+        // Only the expired events are published, to both kinds of channels.
+        let notifier = Notifier::new(KeyspaceEvents::from_str("KEx").unwrap(), pub_sub.clone());
+        notifier.notify(KeyEvent::Set, "user:1");
+        notifier.notify(KeyEvent::Expired, "user:1");
+        notifier.notify(KeyEvent::Expired, "session:1");
+        assert_eq!(
+            keyspace.recv().await.unwrap(),
+            Message {
+                channel: "__keyspace@0__:user:1".to_string(),
+                content: Bytes::from("expired"),
+            }
+        );
+        assert!(keyspace.try_recv().is_err());
+        assert_eq!(keyevent.recv().await.unwrap().content, Bytes::from("user:1"));
+        assert_eq!(keyevent.recv().await.unwrap().content, Bytes::from("session:1"));
+        assert!(keyevent.try_recv().is_err());
+
+        // Nothing is published without a channel kind.
+        let notifier = Notifier::new(KeyspaceEvents::from_str("A").unwrap(), pub_sub);
+        notifier.notify(KeyEvent::Expired, "user:1");
+        assert!(keyspace.try_recv().is_err());
+        assert!(keyevent.try_recv().is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::Result;
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::ops::Bound;
+
+/// Which entries a `KeyValueStore::scan` returns, and in which order.
+///
+/// The bounds combine: a key is returned if it is at least `start`, below
+/// `end` and starts with `prefix`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanOptions {
+    /// Smallest key returned, inclusive.
+    pub start: Option<String>,
+
+    /// Keys are strictly smaller than `end`.
+    pub end: Option<String>,
+
+    /// Only keys starting with `prefix` are returned.
+    pub prefix: Option<String>,
+
+    /// Maximum number of entries returned, `None` for no limit.
+    pub limit: Option<usize>,
+
+    /// Return the entries in descending key order. With a `limit`, the
+    /// greatest keys of the range are returned.
+    pub reverse: bool,
+}
+
+impl ScanOptions {
+    /// Smallest key of the scanned range, if any.
+    pub fn lower(&self) -> Option<&str> {
+        match (self.start.as_deref(), self.prefix.as_deref()) {
+            (Some(start), Some(prefix)) => Some(start.max(prefix)),
+            (start, prefix) => start.or(prefix),
+        }
+    }
+
+    /// Key above the scanned range, if any. Every scanned key is strictly
+    /// smaller than it.
+    pub fn upper(&self) -> Option<String> {
+        let prefix_end = self.prefix.as_deref().and_then(prefix_end);
+        match (self.end.clone(), prefix_end) {
+            (Some(end), Some(prefix_end)) => Some(end.min(prefix_end)),
+            (end, prefix_end) => end.or(prefix_end),
+        }
+    }
+
+    /// Bounds of the scanned range, as taken by `BTreeMap::range`.
+    pub fn range(&self) -> (Bound<String>, Bound<String>) {
+        let lower = match self.lower() {
+            Some(lower) => Bound::Included(lower.to_string()),
+            None => Bound::Unbounded,
+        };
+        let upper = match self.upper() {
+            Some(upper) => Bound::Excluded(upper),
+            None => Bound::Unbounded,
+        };
+        return (lower, upper);
+    }
+
+    /// Returns `true` if the range is empty, in which case it cannot be
+    /// given to `BTreeMap::range`.
+    pub fn is_empty(&self) -> bool {
+        match (self.lower(), self.upper()) {
+            (Some(lower), Some(upper)) => lower >= upper.as_str(),
+            _ => false,
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.lower().map(|lower| key >= lower).unwrap_or(true) && self.upper().map(|upper| key < upper.as_str()).unwrap_or(true)
+    }
+
+    /// Apply the range, limit and order to `entries`, which must be sorted in
+    /// ascending key order.
+    ///
+    /// Entries are consumed until the end of the range, or until the limit is
+    /// reached for ascending scans. Descending scans only keep the last
+    /// `limit` entries in memory.
+    pub fn collect<I>(&self, entries: I) -> Result<Vec<(String, Bytes)>>
+    where
+        I: IntoIterator<Item = Result<(String, Bytes)>>,
+    {
+        let upper = self.upper();
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let mut collected = VecDeque::new();
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        for entry in entries {
+            let (key, value) = entry?;
+            if self.lower().map(|lower| key.as_str() < lower).unwrap_or(false) {
+                continue;
+            }
+            if upper.as_ref().map(|upper| key >= *upper).unwrap_or(false) {
+                break;
+            }
+
+            if collected.len() == limit {
+                if !self.reverse {
+                    break;
+                }
+                collected.pop_front();
+            }
+            collected.push_back((key, value));
+        }
+
+        let mut collected: Vec<_> = collected.into();
+        if self.reverse {
+            collected.reverse();
+        }
+        return Ok(collected);
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`. Returns `None`
+/// if there is no such key, the range of the prefix is then unbounded.
+///
+/// Strings compare by their UTF-8 bytes, which is the order of their
+/// characters: the last character that can be is replaced by the next one.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(keys: &[&str]) -> Vec<Result<(String, Bytes)>> {
+        keys.iter().map(|key| Ok((key.to_string(), Bytes::from(key.to_string())))).collect()
+    }
+
+    fn keys(entries: Vec<(String, Bytes)>) -> Vec<String> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[tokio::test]
+    async fn test_collect() {
+        let all = ["a", "ab", "abc", "abd", "ac", "b", "c"];
+
+        let options = ScanOptions {
+            prefix: Some("ab".to_string()),
+            ..ScanOptions::default()
+        };
+        assert_eq!(keys(options.collect(entries(&all)).unwrap()), ["ab", "abc", "abd"]);
+        assert_eq!(options.range(), (Bound::Included("ab".to_string()), Bound::Excluded("ac".to_string())));
+
+        let options = ScanOptions {
+            start: Some("abc".to_string()),
+            end: Some("c".to_string()),
+            limit: Some(2),
+            ..ScanOptions::default()
+        };
+        assert_eq!(keys(options.collect(entries(&all)).unwrap()), ["abc", "abd"]);
+
+        let options = ScanOptions { reverse: true, ..options };
+        assert_eq!(keys(options.collect(entries(&all)).unwrap()), ["b", "ac"]);
+
+        let options = ScanOptions {
+            start: Some("b".to_string()),
+            prefix: Some("a".to_string()),
+            ..ScanOptions::default()
+        };
+        assert!(options.is_empty());
+        assert!(options.collect(entries(&all)).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_prefix_end() {
+        assert_eq!(prefix_end("ab"), Some("ac".to_string()));
+        assert_eq!(prefix_end("a\u{10FFFF}"), Some("b".to_string()));
+        assert_eq!(prefix_end("\u{D7FF}"), Some("\u{E000}".to_string()));
+        assert_eq!(prefix_end("\u{10FFFF}"), None);
+        assert_eq!(prefix_end(""), None);
+    }
+}
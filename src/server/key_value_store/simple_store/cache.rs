@@ -0,0 +1,198 @@
+//! Cache of the records most recently read by `get`.
+//!
+//! Entries are keyed by key and remember the location they were read from.
+//! A lookup only hits if the index still points to that location, so a
+//! reader racing with a writer can never bring back an overwritten value.
+//! Writes still remove the entry of their key, to free its memory early.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::record::Record;
+use super::segment::Location;
+
+/// Bytes accounted for each entry on top of its key and value.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Hit and miss counters of a `ReadCache`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Least recently used cache of records, bounded in bytes.
+#[derive(Debug)]
+pub struct ReadCache {
+    /// Bytes the entries may take, 0 disables the cache.
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<String, Cached>,
+
+    /// Keys of the entries by last use, least recent first.
+    order: BTreeMap<u64, String>,
+
+    /// Incremented on every use, orders the entries.
+    tick: u64,
+
+    /// Bytes taken by the entries.
+    size: usize,
+}
+
+#[derive(Debug)]
+struct Cached {
+    location: Location,
+    record: Record,
+    used: u64,
+}
+
+impl ReadCache {
+    pub fn new(capacity: usize) -> ReadCache {
+        ReadCache {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The cached record of `key` if it was read from `location`.
+    pub fn get(&self, key: &str, location: &Location) -> Option<Record> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        let lru = &mut *lru;
+        let cached = match lru.entries.get_mut(key) {
+            Some(cached) if cached.location == *location => cached,
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        lru.tick += 1;
+        lru.order.remove(&cached.used);
+        lru.order.insert(lru.tick, key.to_string());
+        cached.used = lru.tick;
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        return Some(cached.record.clone());
+    }
+
+    /// Cache `record`, read from `location`, evicting the least recently used
+    /// entries to make room for it.
+    pub fn insert(&self, location: Location, record: Record) {
+        let size = entry_size(&record);
+        if size > self.capacity {
+            return;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove(&record.key);
+        while lru.size + size > self.capacity {
+            match lru.order.iter().next().map(|(_, key)| key.clone()) {
+                Some(key) => lru.remove(&key),
+                None => break,
+            }
+        }
+
+        lru.tick += 1;
+        let used = lru.tick;
+        lru.order.insert(used, record.key.clone());
+        lru.size += size;
+        lru.entries.insert(record.key.clone(), Cached { location, record, used });
+    }
+
+    /// Drop the entry of `key`.
+    pub fn remove(&self, key: &str) {
+        if self.capacity > 0 {
+            self.lru.lock().unwrap().remove(key);
+        }
+    }
+
+    /// Point the entry of `key` to `new` if it was read from `old`, as done
+    /// when a merge moves a record.
+    pub fn relocate(&self, key: &str, old: &Location, new: Location) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(cached) = self.lru.lock().unwrap().entries.get_mut(key) {
+            if cached.location == *old {
+                cached.location = new;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Lru {
+    fn remove(&mut self, key: &str) {
+        if let Some(cached) = self.entries.remove(key) {
+            self.order.remove(&cached.used);
+            self.size -= entry_size(&cached.record);
+        }
+    }
+}
+
+fn entry_size(record: &Record) -> usize {
+    record.key.len() + record.value.as_ref().map(|value| value.len()).unwrap_or(0) + ENTRY_OVERHEAD
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::key_value_store::simple_store::segment::SegmentId;
+    use bytes::Bytes;
+
+    fn location(offset: u64) -> Location {
+        Location {
+            segment: SegmentId(1, 0),
+            offset,
+            len: 10,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_cache() {
+        // Room for two entries of a single byte key and value.
+        let cache = ReadCache::new(2 * (2 + ENTRY_OVERHEAD));
+        cache.insert(location(0), Record::new("a", Bytes::from("1")));
+        cache.insert(location(10), Record::new("b", Bytes::from("2")));
+        assert_eq!(cache.get("a", &location(0)), Some(Record::new("a", Bytes::from("1"))));
+
+        // The least recently used entry is evicted.
+        cache.insert(location(20), Record::new("c", Bytes::from("3")));
+        assert_eq!(cache.get("b", &location(10)), None);
+        assert!(cache.get("a", &location(0)).is_some());
+        assert!(cache.get("c", &location(20)).is_some());
+
+        // An entry read from another location does not hit.
+        assert_eq!(cache.get("a", &location(30)), None);
+        cache.relocate("a", &location(0), location(30));
+        assert!(cache.get("a", &location(30)).is_some());
+
+        cache.remove("a");
+        assert_eq!(cache.get("a", &location(30)), None);
+        assert_eq!(cache.stats(), CacheStats { hits: 4, misses: 3 });
+
+        // A disabled cache holds nothing and counts nothing.
+        let cache = ReadCache::new(0);
+        cache.insert(location(0), Record::new("a", Bytes::from("1")));
+        assert_eq!(cache.get("a", &location(0)), None);
+        assert_eq!(cache.stats(), CacheStats::default());
+    }
+}
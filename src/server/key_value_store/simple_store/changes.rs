@@ -0,0 +1,159 @@
+//! Streams of the changes committed to a `SimpleStore`.
+//!
+//! The writer numbers every record it appends, so the log itself is the
+//! history of the changes. A stream reads the segments written by the writer
+//! in log order and skips the records numbered before its start. It reads up
+//! to the end of the log the writer published as durable, then waits for the
+//! writer to publish a new one.
+//!
+//! Merges drop the overwritten records, the changes they held can no longer be
+//! streamed. A stream pins the segments it has left to read: a merge running
+//! meanwhile does not cut it, the open files survive their deletion.
+//!
+//! A snapshot reads the live records of the index, which the writer updates
+//! in the order of the sequence numbers: it holds every change up to the last
+//! one applied, and none after.
+
+use crate::Result;
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use simple_error::bail;
+
+use super::super::{Change, Snapshot};
+use super::record::MAGIC;
+use super::segment::{Location, Segment, SegmentId};
+use super::writer::Durable;
+use super::{Shared, State};
+
+/// Position of a stream in the log.
+pub(super) struct Cursor {
+    /// Segments left to read, oldest first. The first one is being read.
+    segments: VecDeque<Arc<Segment>>,
+
+    /// Offset of the next record in the first segment.
+    offset: u64,
+
+    /// Sequence number of the next change to stream.
+    next_seq: u64,
+}
+
+impl Shared {
+    /// Start a stream at the change numbered `from`. Fails if merges dropped
+    /// some of the changes from `from` on.
+    pub(super) fn open_cursor(&self, from: u64) -> Result<Cursor> {
+        if from == 0 {
+            bail!("sequence numbers start at 1, the stream cannot start at 0");
+        }
+        let state = self.state.read().unwrap();
+        if from < state.history_start {
+            bail!("changes before {:?} were merged, the stream cannot start at {:?}", state.history_start, from);
+        }
+        if from > state.applied_seq + 1 {
+            // The reader saw changes this store does not have: it lost them,
+            // or it was replaced by another one.
+            bail!("the last change is {:?}, the stream cannot start at {:?}", state.applied_seq, from);
+        }
+        return Ok(Cursor {
+            segments: unmerged(&state, None),
+            offset: MAGIC.len() as u64,
+            next_seq: from,
+        });
+    }
+
+    /// Read the changes that follow `cursor`, up to `durable`, and move the
+    /// cursor past them. Returns no change once the cursor reaches `durable`.
+    pub(super) fn read_changes(&self, cursor: &mut Cursor, durable: Durable) -> Result<Vec<Change>> {
+        loop {
+            let segment = match cursor.segments.front() {
+                Some(segment) => segment.clone(),
+                None => return Ok(Vec::new()),
+            };
+            let end = match segment.id.cmp(&durable.segment) {
+                Ordering::Less => segment.len(),
+                Ordering::Equal => durable.len,
+                Ordering::Greater => return Ok(Vec::new()),
+            };
+
+            if cursor.offset < end {
+                let records = segment.read_range(cursor.offset, end)?;
+                cursor.offset = end;
+                let changes: Vec<Change> = records
+                    .into_iter()
+                    .filter(|(record, _)| record.seq >= cursor.next_seq)
+                    .map(|(record, _)| Change {
+                        seq: record.seq,
+                        key: record.key,
+                        value: record.value,
+                        expires_at: record.expires_at,
+                    })
+                    .collect();
+                if let Some(last) = changes.last() {
+                    cursor.next_seq = last.seq + 1;
+                    return Ok(changes);
+                }
+            } else if segment.id < durable.segment {
+                // The segment is sealed and read, move on to the next one.
+                cursor.segments.pop_front();
+                cursor.offset = MAGIC.len() as u64;
+                if cursor.segments.is_empty() {
+                    let state = self.state.read().unwrap();
+                    if cursor.next_seq < state.history_start {
+                        bail!("changes from {:?} were merged before the stream read them", cursor.next_seq);
+                    }
+                    cursor.segments = unmerged(&state, Some(segment.id));
+                }
+            } else {
+                return Ok(Vec::new());
+            }
+        }
+    }
+
+    /// Every live key, as of the last change applied to the index.
+    pub(super) fn snapshot(&self) -> Result<Snapshot> {
+        // Only the locations are collected under the lock, the records are
+        // read once it is released.
+        let (seq, locations) = {
+            let state = self.state.read().unwrap();
+            let locations: Vec<(String, Location, Arc<Segment>)> = state
+                .index
+                .iter()
+                .filter(|(key, _)| !state.expirations.is_expired(key))
+                .map(|(key, location)| (key.clone(), *location, state.segments[&location.segment].clone()))
+                .collect();
+            (state.applied_seq, locations)
+        };
+
+        let mut entries = Vec::with_capacity(locations.len());
+        for (key, location, segment) in locations {
+            let record = match segment.read(&location) {
+                Ok(record) => record,
+                Err(e) => bail!("Index key = {:?} log data is corrupted: {}", key, e),
+            };
+            if record.key != key || record.is_tombstone() {
+                bail!("log data key = {:?} does not match index key = {:?}", record.key, key);
+            }
+            entries.push(Change {
+                seq: record.seq,
+                key,
+                value: record.value,
+                expires_at: record.expires_at,
+            });
+        }
+
+        return Ok(Snapshot { seq, entries });
+    }
+}
+
+/// The segments written by the writer thread after `after`, oldest first. The
+/// outputs of the merges only hold copies of their records.
+fn unmerged(state: &State, after: Option<SegmentId>) -> VecDeque<Arc<Segment>> {
+    return state
+        .segments
+        .values()
+        .filter(|segment| segment.id.1 == 0 && after.is_none_or(|after| segment.id > after))
+        .cloned()
+        .collect();
+}
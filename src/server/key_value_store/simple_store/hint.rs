@@ -0,0 +1,135 @@
+//! Hint files list the key and location of every record of a sealed
+//! segment, tombstones included, without the values. Loading them rebuilds
+//! the index of a segment without reading the segment itself.
+//!
+//! Layout, all integers are big endian:
+//!
+//! ```text
+//! | magic | last seq (u64) | tombstone (u8) | expires at (u64) | key len (u32) | offset (u64) | len (u32) | key | ... | crc32 (u32) |
+//! ```
+//!
+//! The last sequence number is the highest one used by the segment, see
+//! `Segment::last_seq`. The expiration time is in milliseconds since the Unix
+//! epoch, `0` if the key does not expire. The checksum covers every byte
+//! before it. Hint files with another magic are treated as invalid and
+//! rewritten after a scan.
+
+use crate::Result;
+
+use bytes::{Buf, BufMut, BytesMut};
+use simple_error::bail;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::record;
+use super::segment::{Entry, Location, SegmentId};
+
+const MAGIC: &[u8; 8] = b"RAPHHNT1";
+
+/// Length of the fixed-size start of an entry.
+const ENTRY_HEADER_LEN: usize = 1 + 8 + 4 + 8 + 4;
+
+/// Content of a hint file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hint {
+    /// Every record of the segment, in file order.
+    pub entries: Vec<Entry>,
+
+    /// Highest sequence number used by the segment.
+    pub last_seq: u64,
+}
+
+/// Path of the hint file of the segment stored at `segment_path`.
+pub fn path(segment_path: &Path) -> PathBuf {
+    let mut name = segment_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".hint");
+    segment_path.with_file_name(name)
+}
+
+/// Path of the segment a hint file belongs to, the reverse of `path`. Returns
+/// `None` if `hint_path` is not a hint file.
+pub fn segment_path(hint_path: &Path) -> Option<PathBuf> {
+    let name = hint_path.file_name()?.to_str()?;
+    let segment_name = name.strip_suffix(".hint")?;
+    return Some(hint_path.with_file_name(segment_name));
+}
+
+/// Write the hint file of a segment, given all of its records in file order
+/// and the highest sequence number it uses.
+///
+/// The file is written to a temporary path and renamed once synced.
+pub fn write(segment_path: &Path, entries: &[Entry], last_seq: u64) -> Result<()> {
+    let mut buf = BytesMut::new();
+    buf.put(&MAGIC[..]);
+    buf.put_u64(last_seq);
+    for entry in entries.iter() {
+        buf.put_u8(entry.tombstone as u8);
+        buf.put_u64(entry.expires_at.map(record::to_millis).transpose()?.unwrap_or(0));
+        buf.put_u32(entry.key.len().try_into()?);
+        buf.put_u64(entry.location.offset);
+        buf.put_u32(entry.location.len);
+        buf.put(entry.key.as_bytes());
+    }
+    let crc = crc32fast::hash(&buf[..]);
+    buf.put_u32(crc);
+
+    let hint_path = path(segment_path);
+    let mut tmp_name = hint_path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = hint_path.with_file_name(tmp_name);
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf[..])?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, &hint_path)?;
+
+    return Ok(());
+}
+
+/// Read the hint file of the segment `id`. Fails if the file is missing or
+/// does not pass its checksum.
+pub fn read(segment_path: &Path, id: SegmentId) -> Result<Hint> {
+    let data = std::fs::read(path(segment_path))?;
+    if data.len() < MAGIC.len() + 8 + 4 || &data[..MAGIC.len()] != MAGIC {
+        bail!("hint file of segment {} is invalid", id);
+    }
+
+    let (content, mut crc) = data.split_at(data.len() - 4);
+    if crc32fast::hash(content) != crc.get_u32() {
+        bail!("hint file of segment {} fails its checksum", id);
+    }
+
+    let mut entries = Vec::new();
+    let mut src = &content[MAGIC.len()..];
+    let last_seq = src.get_u64();
+    while src.has_remaining() {
+        if src.remaining() < ENTRY_HEADER_LEN {
+            bail!("hint file of segment {} is truncated", id);
+        }
+        let tombstone = src.get_u8() != 0;
+        let expires_at = match src.get_u64() {
+            0 => None,
+            millis => Some(record::from_millis(millis)),
+        };
+        let key_len = src.get_u32() as usize;
+        let offset = src.get_u64();
+        let len = src.get_u32();
+        if src.remaining() < key_len {
+            bail!("hint file of segment {} is truncated", id);
+        }
+        let key = String::from_utf8(src[..key_len].to_vec())?;
+        src.advance(key_len);
+
+        entries.push(Entry {
+            key,
+            location: Location { segment: id, offset, len },
+            tombstone,
+            expires_at,
+        });
+    }
+
+    return Ok(Hint { entries, last_seq });
+}
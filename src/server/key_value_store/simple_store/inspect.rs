@@ -0,0 +1,327 @@
+//! Offline inspection of the log of a `SimpleStore`, behind the `verify`,
+//! `dump` and `stats` commands.
+//!
+//! The segments are read in place, without opening the store nor taking its
+//! lock, so a store that fails to open can still be inspected. Nothing is
+//! written.
+
+use crate::Result;
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::hint;
+use super::layout::Layout;
+use super::record::{self, Record};
+pub use super::segment::Damage;
+use super::segment::{Entry, Location, Segment, SegmentId};
+use super::{is_not_found, LOG_FILE, STORE_DIR};
+
+/// The segments of the log of a store, oldest first.
+#[derive(Debug)]
+pub struct Log {
+    segments: Vec<Segment>,
+}
+
+/// Outcome of the check of a segment by `Log::verify`.
+#[derive(Debug)]
+pub struct SegmentReport {
+    pub path: PathBuf,
+
+    /// Size of the file in bytes.
+    pub len: u64,
+
+    /// Number of records that can be read back, commit records excluded.
+    pub records: usize,
+
+    /// Bytes that can not be read back. The store does not open until they
+    /// are repaired.
+    pub damaged: Vec<Damage>,
+
+    /// Length of the torn write at the end of the segment, dropped when the
+    /// store opens.
+    pub torn_len: Option<u64>,
+
+    pub hint: HintStatus,
+}
+
+/// State of the hint file of a segment.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HintStatus {
+    /// The segment is the active one, it needs no hint file.
+    Unused,
+
+    /// There is none, the segment is scanned when the store opens.
+    Missing,
+
+    /// It lists every record of the segment.
+    Valid,
+
+    /// It can not be read, the segment is scanned when the store opens.
+    Invalid(String),
+
+    /// It is intact but does not list the records of the segment. The store
+    /// would load a wrong index from it.
+    Stale,
+}
+
+impl SegmentReport {
+    /// Returns `true` if the segment keeps the store from opening or would
+    /// load wrong data.
+    pub fn is_corrupted(&self) -> bool {
+        !self.damaged.is_empty() || self.hint == HintStatus::Stale
+    }
+}
+
+/// Space used by the log and its keys, see `Log::stats`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub segments: usize,
+
+    /// Size of the segment files.
+    pub total_bytes: u64,
+
+    /// Bytes of the records of the live keys.
+    pub live_bytes: u64,
+
+    /// Bytes not taken by live records: overwritten, deleted and expired
+    /// records, commit records, unreadable bytes and the magic bytes starting
+    /// each file.
+    pub dead_bytes: u64,
+
+    /// Number of live keys.
+    pub keys: usize,
+
+    /// Number of live keys that expire.
+    pub expiring_keys: usize,
+
+    /// Largest live keys by the size of their record, largest first.
+    pub largest_keys: Vec<(String, u64)>,
+}
+
+impl Log {
+    /// Find the log of the store of the data directory `data_dir`.
+    pub fn open(data_dir: &Path) -> Result<Log> {
+        let layout = Layout::new(data_dir.join(STORE_DIR));
+        layout.check()?;
+        let path = layout.segments_dir().join(LOG_FILE);
+
+        let mut ids = Vec::new();
+        for entry in std::fs::read_dir(layout.segments_dir())? {
+            if let Some(id) = SegmentId::from_path(&path, &entry?.path()) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+
+        let mut segments = Vec::with_capacity(ids.len());
+        for id in ids {
+            segments.push(Segment::open(&id.path(&path), id)?);
+        }
+        return Ok(Log { segments });
+    }
+
+    /// Returns `true` if `segment` may end with a torn write: it is the
+    /// segment the store appends to when it opens.
+    fn is_active(&self, segment: &Segment) -> bool {
+        let newest = self.segments.last().map(|newest| newest.id);
+        return Some(segment.id) == newest && segment.id.1 == 0;
+    }
+
+    /// Check every record of every segment, and the hint files of the sealed
+    /// segments.
+    pub fn verify(&self) -> Result<Vec<SegmentReport>> {
+        let mut reports = Vec::with_capacity(self.segments.len());
+        for segment in self.segments.iter() {
+            let active = self.is_active(segment);
+            let salvage = segment.salvage(active)?;
+
+            let hint = if active {
+                HintStatus::Unused
+            } else {
+                match hint::read(&segment.path, segment.id) {
+                    Ok(hinted) if hinted.entries == entries(&salvage.records) && hinted.last_seq == salvage.last_seq => HintStatus::Valid,
+                    Ok(_) => HintStatus::Stale,
+                    Err(e) if is_not_found(&e) => HintStatus::Missing,
+                    Err(e) => HintStatus::Invalid(e.to_string()),
+                }
+            };
+            reports.push(SegmentReport {
+                path: segment.path.clone(),
+                len: segment.len(),
+                records: salvage.records.len(),
+                damaged: salvage.damaged,
+                torn_len: salvage.torn_at.map(|offset| segment.len() - offset),
+                hint,
+            });
+        }
+        return Ok(reports);
+    }
+
+    /// Write every record that can be read back to `out` as a JSON line, in
+    /// log order. Returns the number of written records.
+    ///
+    /// Values that are not valid UTF-8 are written in hexadecimal, as
+    /// `value_hex`. Tombstones have no value. Records written before changes
+    /// were numbered have no sequence number.
+    pub fn dump(&self, out: &mut dyn Write) -> Result<usize> {
+        let mut count = 0;
+        for segment in self.segments.iter() {
+            for (record, location) in segment.salvage(self.is_active(segment))?.records {
+                let mut line = serde_json::Map::new();
+                line.insert("segment".to_string(), location.segment.to_string().into());
+                line.insert("offset".to_string(), location.offset.into());
+                line.insert("key".to_string(), record.key.into());
+                let seq = Some(record.seq).filter(|seq| *seq != 0);
+                line.insert("seq".to_string(), seq.map(Into::into).unwrap_or(serde_json::Value::Null));
+                line.insert("tombstone".to_string(), record.value.is_none().into());
+                let expires_at = record.expires_at.map(record::to_millis).transpose()?;
+                line.insert("expires_at".to_string(), expires_at.map(Into::into).unwrap_or(serde_json::Value::Null));
+                match record.value.map(|value| String::from_utf8(value.to_vec())) {
+                    Some(Ok(value)) => {
+                        line.insert("value".to_string(), value.into());
+                    }
+                    Some(Err(e)) => {
+                        line.insert("value_hex".to_string(), hex(e.as_bytes()).into());
+                    }
+                    None => {}
+                }
+                writeln!(out, "{}", serde_json::Value::Object(line))?;
+                count += 1;
+            }
+        }
+        return Ok(count);
+    }
+
+    /// Replay the log as the store does when it opens, and report the space
+    /// used by the live keys along with the `top` largest of them.
+    pub fn stats(&self, top: usize) -> Result<Stats> {
+        let now = SystemTime::now();
+        let mut live: HashMap<String, (u64, bool)> = HashMap::new();
+        let mut stats = Stats {
+            segments: self.segments.len(),
+            ..Stats::default()
+        };
+        for segment in self.segments.iter() {
+            let salvage = segment.salvage(self.is_active(segment))?;
+            stats.total_bytes += salvage.torn_at.unwrap_or_else(|| segment.len());
+            for (record, location) in salvage.records {
+                if record.is_tombstone() || record.is_expired(now) {
+                    live.remove(&record.key);
+                } else {
+                    live.insert(record.key, (location.len as u64, record.expires_at.is_some()));
+                }
+            }
+        }
+
+        stats.keys = live.len();
+        stats.expiring_keys = live.values().filter(|(_, expires)| *expires).count();
+        stats.live_bytes = live.values().map(|(len, _)| len).sum();
+        stats.dead_bytes = stats.total_bytes - stats.live_bytes;
+
+        let mut largest: Vec<(String, u64)> = live.into_iter().map(|(key, (len, _))| (key, len)).collect();
+        largest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        largest.truncate(top);
+        stats.largest_keys = largest;
+        return Ok(stats);
+    }
+}
+
+/// The entries a hint file lists for `records`.
+fn entries(records: &[(Record, Location)]) -> Vec<Entry> {
+    records
+        .iter()
+        .map(|(record, location)| Entry {
+            key: record.key.clone(),
+            location: *location,
+            tombstone: record.is_tombstone(),
+            expires_at: record.expires_at,
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::key_value_store::simple_store::{SimpleStore, SimpleStoreOptions};
+    use crate::server::key_value_store::KeyValueStore;
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_inspect() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let dir = tempfile::tempdir().unwrap();
+        let options = SimpleStoreOptions {
+            max_segment_size: 256,
+            ..SimpleStoreOptions::default()
+        };
+        let store = SimpleStore::new(logger.clone(), dir.path(), options).await.unwrap();
+        for i in 0..20 {
+            store.set(format!("key{:02}", i), Bytes::from(format!("value{}", i)), None).unwrap();
+        }
+        store.set("key00".to_string(), Bytes::from("a much longer value"), None).unwrap();
+        store
+            .set("binary".to_string(), Bytes::from(&b"\xff\x00"[..]), Some(Duration::from_secs(60)))
+            .unwrap();
+        store.delete("key01").unwrap();
+        store.shutdown_purge_task();
+
+        let log = Log::open(dir.path()).unwrap();
+        let reports = log.verify().unwrap();
+        assert!(reports.len() > 1);
+        assert!(reports.iter().all(|report| !report.is_corrupted() && report.torn_len.is_none()));
+        assert_eq!(reports.iter().map(|report| report.records).sum::<usize>(), 23);
+        assert_eq!(reports.last().unwrap().hint, HintStatus::Unused);
+
+        let mut out = Vec::new();
+        assert_eq!(log.dump(&mut out).unwrap(), 23);
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["key"], "key00");
+        assert_eq!(lines[0]["value"], "value0");
+        assert_eq!(lines[21]["value_hex"], "ff00");
+        assert!(lines[21]["expires_at"].is_u64());
+        assert_eq!(lines[22]["tombstone"], true);
+        assert!(lines[22].get("value").is_none());
+
+        let stats = log.stats(1).unwrap();
+        assert_eq!(stats.keys, 20);
+        assert_eq!(stats.expiring_keys, 1);
+        assert_eq!(stats.live_bytes + stats.dead_bytes, stats.total_bytes);
+        assert_eq!(stats.largest_keys.len(), 1);
+        assert_eq!(stats.largest_keys[0].0, "key00");
+
+        // Corruption in a sealed segment is reported, and so is a hint file
+        // that does not match its segment.
+        let sealed = &log.segments[0];
+        sealed.write_hint(&entries(&sealed.salvage(false).unwrap().records)).unwrap();
+        let sealed = &reports[0].path;
+        let mut content = std::fs::read(sealed).unwrap();
+        let middle = content.len() / 2;
+        content[middle] ^= 0xff;
+        std::fs::write(sealed, &content).unwrap();
+        let reports = Log::open(dir.path()).unwrap().verify().unwrap();
+        assert_eq!(reports[0].damaged.len(), 1);
+        assert!(reports[0].damaged[0].range.contains(&(middle as u64)));
+        assert_eq!(reports[0].hint, HintStatus::Stale);
+        assert!(reports[1..].iter().all(|report| !report.is_corrupted()));
+
+        // Repairing scans the segment instead of loading its hint file.
+        SimpleStore::repair(logger.clone(), dir.path()).await.unwrap();
+        let reports = Log::open(dir.path()).unwrap().verify().unwrap();
+        assert!(reports.iter().all(|report| !report.is_corrupted()));
+        let store = SimpleStore::new(logger, dir.path(), SimpleStoreOptions::default()).await.unwrap();
+        assert_eq!(store.get("key19").unwrap(), Some(Bytes::from("value19")));
+        store.shutdown_purge_task();
+    }
+}
@@ -0,0 +1,137 @@
+//! Files of a `SimpleStore` under its data directory:
+//!
+//! ```text
+//! <dir>/MANIFEST    layout version, written when the directory is created
+//! <dir>/LOCK        lock file of the process that has the store open
+//! <dir>/segments/   log segments and their hint files
+//! ```
+//!
+//! The manifest holds a single line, `simple-store <version>`. A directory
+//! with another manifest was not written by this version of the store and is
+//! not opened.
+
+use crate::Result;
+
+use simple_error::bail;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const LOCK_FILE: &str = "LOCK";
+const SEGMENTS_DIR: &str = "segments";
+
+/// Name recorded in the manifest.
+const LAYOUT_NAME: &str = "simple-store";
+
+/// Version of the layout written by this version of the store.
+const LAYOUT_VERSION: u32 = 1;
+
+/// Paths of the files of a store under its data directory.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub dir: PathBuf,
+}
+
+impl Layout {
+    pub fn new(dir: impl AsRef<Path>) -> Layout {
+        Layout {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.dir.join(MANIFEST_FILE)
+    }
+
+    pub fn lock_path(&self) -> PathBuf {
+        self.dir.join(LOCK_FILE)
+    }
+
+    pub fn segments_dir(&self) -> PathBuf {
+        self.dir.join(SEGMENTS_DIR)
+    }
+
+    /// Returns `true` if the directory holds a manifest.
+    pub fn exists(&self) -> bool {
+        self.manifest_path().exists()
+    }
+
+    /// Check the manifest of an existing layout, failing if there is none.
+    pub fn check(&self) -> Result<()> {
+        match std::fs::read_to_string(self.manifest_path()) {
+            Ok(content) => return check_manifest(&self.manifest_path(), &content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => bail!("{:?} does not hold a {} store", self.dir, LAYOUT_NAME),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    /// Create the directories and the manifest if they do not exist yet, or
+    /// check the manifest of an existing layout. The lock file is created by
+    /// `LockFile::acquire`.
+    pub fn create_or_check(&self) -> Result<()> {
+        std::fs::create_dir_all(self.segments_dir())?;
+
+        match std::fs::read_to_string(self.manifest_path()) {
+            Ok(content) => return check_manifest(&self.manifest_path(), &content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // The manifest is written last and atomically: a directory without one
+        // is created again from scratch.
+        let tmp_path = self.dir.join(format!("{}.tmp", MANIFEST_FILE));
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(format!("{} {}\n", LAYOUT_NAME, LAYOUT_VERSION).as_bytes())?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, self.manifest_path())?;
+        File::open(&self.dir)?.sync_all()?;
+        return Ok(());
+    }
+
+    /// Move the files of the log whose base path is `legacy`, written before
+    /// data directories, to the segments directory. Returns the number of
+    /// moved files.
+    pub fn adopt_legacy_log(&self, legacy: &Path) -> Result<usize> {
+        let base_name = match legacy.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => return Ok(0),
+        };
+        let dir = match legacy.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        std::fs::create_dir_all(self.segments_dir())?;
+        let mut moved = 0;
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let is_log_file = match name.to_str() {
+                Some(name) => name == base_name || name.starts_with(&format!("{}.", base_name)),
+                None => false,
+            };
+            if is_log_file && entry.file_type()?.is_file() {
+                std::fs::rename(entry.path(), self.segments_dir().join(&name))?;
+                moved += 1;
+            }
+        }
+        if moved > 0 {
+            File::open(self.segments_dir())?.sync_all()?;
+        }
+        return Ok(moved);
+    }
+}
+
+fn check_manifest(path: &Path, content: &str) -> Result<()> {
+    let mut parts = content.trim_end().splitn(2, ' ');
+    let name = parts.next().unwrap_or_default();
+    let version = parts.next().and_then(|version| version.parse::<u32>().ok());
+    match version {
+        Some(LAYOUT_VERSION) if name == LAYOUT_NAME => return Ok(()),
+        Some(version) if name == LAYOUT_NAME => bail!("{:?} has layout version {:?}, only version {:?} is supported", path, version, LAYOUT_VERSION),
+        _ => bail!("{:?} is not a {} manifest", path, LAYOUT_NAME),
+    }
+}
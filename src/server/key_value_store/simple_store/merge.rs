@@ -0,0 +1,171 @@
+use crate::Result;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::segment::{Location, SegmentId, SegmentWriter};
+use super::Shared;
+
+impl Shared {
+    /// Returns `true` if enough of the sealed segments is taken by overwritten
+    /// records to make a merge worthwhile.
+    pub(super) fn needs_merge(&self) -> bool {
+        let state = self.state.read().unwrap();
+        let (mut total, mut dead) = (0, 0);
+        for segment in state.segments.values().filter(|segment| segment.id < state.active) {
+            total += segment.len();
+            dead += segment.dead_bytes();
+        }
+        return dead > 0 && dead as f64 >= total as f64 * self.options.merge_dead_ratio;
+    }
+
+    /// Rewrite the live records of every sealed segment into new segments and
+    /// delete the old ones.
+    ///
+    /// The changes numbered up to the newest record of the inputs can no
+    /// longer be streamed once the overwritten records are gone. The last
+    /// output marks that number so it is not reused after a restart.
+    ///
+    /// The index keeps serving reads and writes while the records are copied.
+    /// It is only locked at the end to point the keys to their new location,
+    /// skipping the keys that were overwritten in the meantime.
+    pub(super) fn merge(&self) -> Result<()> {
+        let _merge = self.merge.lock().unwrap();
+
+        let (inputs, mut live) = {
+            let state = self.state.read().unwrap();
+            let inputs: HashMap<SegmentId, _> = state
+                .segments
+                .iter()
+                .filter(|(id, _)| **id < state.active)
+                .map(|(id, segment)| (*id, segment.clone()))
+                .collect();
+            let live: Vec<(String, Location)> = state
+                .index
+                .iter()
+                .filter(|(_, location)| inputs.contains_key(&location.segment))
+                .map(|(key, location)| (key.clone(), *location))
+                .collect();
+            (inputs, live)
+        };
+
+        let newest = match inputs.keys().max() {
+            Some(id) => *id,
+            None => return Ok(()),
+        };
+        let last_seq = inputs.values().map(|segment| segment.last_seq()).max().unwrap_or(0);
+        let output_id = |count: usize| SegmentId(newest.0, newest.1 + 1 + count as u32);
+
+        // Copy the records in file order to keep the reads sequential.
+        live.sort_by_key(|(_, location)| (location.segment, location.offset));
+
+        let mut outputs = Vec::new();
+        let mut relocations = Vec::with_capacity(live.len());
+        let mut writer: Option<SegmentWriter> = None;
+        for (key, location) in live {
+            let record = inputs[&location.segment].read(&location)?;
+
+            if writer.as_ref().map(|w| w.len() >= self.options.max_segment_size).unwrap_or(true) {
+                if let Some(full) = writer.take() {
+                    outputs.push(Arc::new(full.finish()?));
+                }
+                let id = output_id(outputs.len());
+                writer = Some(SegmentWriter::create(id.path(&self.path), id)?);
+            }
+
+            let new_location = writer.as_mut().expect("writer was just created").append(&record)?;
+            relocations.push((key, location, new_location));
+        }
+        if writer.is_none() && last_seq > 0 {
+            let id = output_id(outputs.len());
+            writer = Some(SegmentWriter::create(id.path(&self.path), id)?);
+        }
+        if let Some(mut last) = writer.take() {
+            last.mark(last_seq)?;
+            outputs.push(Arc::new(last.finish()?));
+        }
+        if self.options.mmap {
+            for output in outputs.iter() {
+                output.map()?;
+            }
+        }
+
+        {
+            let mut state = self.state.write().unwrap();
+            for output in outputs.iter() {
+                state.segments.insert(output.id, output.clone());
+            }
+            for (key, old, new) in relocations {
+                match state.index.get_mut(&key) {
+                    Some(location) if *location == old => {
+                        *location = new;
+                        // A cached value read from a mapping would keep the
+                        // mapping of the input alive.
+                        if inputs[&old.segment].is_mapped() {
+                            self.cache.remove(&key);
+                        } else {
+                            self.cache.relocate(&key, &old, new);
+                        }
+                    }
+                    // The key was overwritten during the merge, the copy is
+                    // already dead.
+                    _ => state.segments[&new.segment].add_dead_bytes(new.len),
+                }
+            }
+            for id in inputs.keys() {
+                state.segments.remove(id);
+            }
+            state.history_start = state.history_start.max(last_seq + 1);
+        }
+
+        // Readers may still hold the inputs, their open file handles keep
+        // working after the files are unlinked.
+        let mut inputs: Vec<_> = inputs.into_values().collect();
+        inputs.sort_by_key(|segment| segment.id);
+        for segment in inputs {
+            segment.remove_files()?;
+        }
+
+        return Ok(());
+    }
+}
+
+/// Routine executed by the background merge task.
+///
+/// Wait to be notified. On notification, write the hint files of the newly
+/// sealed segments, then merge the sealed segments if they hold enough dead
+/// records. If `shutdown` is set, terminate the task.
+pub(super) async fn merge_task(logger: slog::Logger, shared: Arc<Shared>) {
+    while !shared.is_shutdown() {
+        for segment in shared.unhinted_segments() {
+            let id = segment.id;
+            let result = tokio::task::spawn_blocking(move || segment.scan(false).and_then(|records| segment.write_hint(&records))).await;
+            match result {
+                Ok(Ok(())) => debug!(logger, "Wrote hint file of segment {}", id),
+                Ok(Err(err)) => error!(logger, "err = {}, failed to write hint file of segment {}", err, id),
+                Err(err) => error!(logger, "err = {}, hint task panicked", err),
+            }
+        }
+
+        if !shared.needs_merge() {
+            shared.background_task.notified().await;
+            continue;
+        }
+
+        // Merging is long, blocking work: keep it off the async workers.
+        let merge_shared = shared.clone();
+        match tokio::task::spawn_blocking(move || merge_shared.merge()).await {
+            Ok(Ok(())) => info!(logger, "Merged sealed log segments"),
+            Ok(Err(err)) => {
+                error!(logger, "err = {}, failed to merge log segments", err);
+                // Wait for the next write before trying again instead of
+                // spinning on a failing merge.
+                shared.background_task.notified().await;
+            }
+            Err(err) => {
+                error!(logger, "err = {}, merge task panicked", err);
+                shared.background_task.notified().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,1787 @@
+mod cache;
+mod changes;
+mod hint;
+pub mod inspect;
+mod layout;
+mod merge;
+mod record;
+mod segment;
+mod writer;
+
+use crate::Result;
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use simple_error::bail;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+use super::expiration::{purge_expired_tasks, to_instant, Expirations, PurgeExpired};
+use super::lock::LockFile;
+use super::{Changes, Expiry, FsyncPolicy, KeyEvent, KeyValueStore, Notifier, ScanOptions, Snapshot, WriteBatch};
+pub use cache::CacheStats;
+use cache::ReadCache;
+use layout::Layout;
+use merge::merge_task;
+use record::{Record, MAGIC};
+use segment::{ActiveSegment, Corruption, Entry, Location, Segment, SegmentId};
+use writer::{Durable, Op, Request, Writer};
+
+/// Append-only log backend with an in-memory index, in the style of Bitcask.
+///
+/// The log is split in size-bounded segment files. Records are appended to
+/// the active segment, which is sealed and replaced by a new one once it
+/// grows past `max_segment_size`. The index maps every key to the location of
+/// its latest record, sorted by key for range scans.
+///
+/// Deleting a key appends a tombstone record and removes the key from the
+/// index. Expiring keys are written with their expiration time, a background
+/// task removes them from the index once they expire. Expired records are
+/// skipped on recovery.
+///
+/// Writes are committed by a dedicated writer thread, which appends the
+/// records of concurrent writes together and syncs them once, as set by the
+/// `fsync` option. See the `writer` module.
+///
+/// With the `mmap` option, sealed segments are mapped in memory and `get`
+/// returns slices of the mappings instead of copies.
+///
+/// Every change is numbered in the order it is committed, and its sequence
+/// number is stored in its record. The changes can be streamed from any
+/// sequence number the merges kept, see the `changes` module.
+///
+/// Overwritten records and tombstones stay in the log until a background
+/// task merges the sealed segments: their live records are copied to new
+/// segments and the old segments are deleted. Merges always take every
+/// sealed segment, so no older record is left for a dropped tombstone to
+/// shadow.
+#[derive(Debug, Clone)]
+pub struct SimpleStore {
+    logger: slog::Logger,
+    shared: Arc<Shared>,
+}
+
+/// Tuning knobs of a `SimpleStore`.
+#[derive(Debug, Clone)]
+pub struct SimpleStoreOptions {
+    /// Size in bytes above which the active segment is sealed.
+    pub max_segment_size: u64,
+
+    /// Fraction of the sealed segments taken by overwritten records above
+    /// which they are merged.
+    pub merge_dead_ratio: f64,
+
+    /// When the writes are synced to disk.
+    pub fsync: FsyncPolicy,
+
+    /// Bytes of recently read records kept in memory, 0 disables the cache.
+    pub read_cache_size: usize,
+
+    /// Serve the reads of sealed segments from memory maps of their files,
+    /// see `Segment::map`.
+    pub mmap: bool,
+
+    /// Rewrite corrupted segments without their unreadable records on
+    /// startup, instead of failing to open the store.
+    pub repair: bool,
+
+    /// Publishes the keyspace notifications of the store.
+    pub notifier: Notifier,
+}
+
+impl Default for SimpleStoreOptions {
+    fn default() -> Self {
+        SimpleStoreOptions {
+            max_segment_size: 64 * 1024 * 1024,
+            merge_dead_ratio: 0.5,
+            fsync: FsyncPolicy::default(),
+            read_cache_size: 32 * 1024 * 1024,
+            mmap: false,
+            repair: false,
+            notifier: Notifier::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// Base path of the log. Segments are stored next to it, suffixed with
+    /// their id.
+    path: PathBuf,
+
+    options: SimpleStoreOptions,
+
+    /// The shared state is guarded by a lock. This is a `std::sync::RwLock`
+    /// and not a Tokio lock. This is because there are no asynchronous
+    /// operations being performed while holding the lock. Additionally, the
+    /// critical sections are very small.
+    ///
+    /// A Tokio mutex is mostly intended to be used when locks need to be held
+    /// across `.await` yield points. All other cases are **usually** best
+    /// served by a std mutex. If the critical section does not include any
+    /// async operations but is long (CPU intensive or performing blocking
+    /// operations), then the entire operation, including waiting for the mutex,
+    /// is considered a "blocking" operation and `tokio::task::spawn_blocking`
+    /// should be used.
+    state: RwLock<State>,
+
+    /// Records recently read by `get`.
+    cache: ReadCache,
+
+    /// Queue of the writer thread, which owns the segment being appended to.
+    writer: Mutex<mpsc::Sender<Request>>,
+
+    /// Held for the whole duration of a merge so only one runs at a time.
+    merge: Mutex<()>,
+
+    /// Lock of the store directory, `None` for a log opened without one. It
+    /// is released on shutdown.
+    lock: Mutex<Option<LockFile>>,
+
+    /// Notifies the background merge task that records were overwritten or
+    /// that the store is shutting down.
+    background_task: Notify,
+
+    /// Notifies the background task purging expired keys.
+    expiration_task: Notify,
+
+    /// End of the log on disk, published by the writer thread.
+    durable: watch::Receiver<Durable>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Index of the keys -> location of their latest record
+    index: BTreeMap<String, Location>,
+
+    /// Every segment of the log, oldest first.
+    segments: BTreeMap<SegmentId, Arc<Segment>>,
+
+    /// Id of the active segment. All the segments before it are sealed.
+    active: SegmentId,
+
+    /// Tracks when the keys of the index expire.
+    expirations: Expirations,
+
+    /// Sequence number of the oldest change that can be streamed: the changes
+    /// before it were dropped by merges.
+    history_start: u64,
+
+    /// Sequence number of the last change applied to the index.
+    applied_seq: u64,
+
+    /// True when the  instance is shutting down. This happens when all `SimpleSTore`
+    /// values drop. Setting this to `true` signals to the background task to
+    /// exit.
+    shutdown: bool,
+}
+
+/// Directory of the store under the data directory.
+const STORE_DIR: &str = "simple-store.raphdb";
+
+/// Base name of the log segments.
+const LOG_FILE: &str = "log.raphdb";
+
+impl SimpleStore {
+    /// Open the store of the data directory `data_dir`, creating it if needed.
+    ///
+    /// Logs written before data directories, `log.raphdb` and its segments
+    /// right under `data_dir`, are moved into the new store directory.
+    pub async fn new(logger: slog::Logger, data_dir: &Path, options: SimpleStoreOptions) -> Result<SimpleStore> {
+        let layout = Layout::new(data_dir.join(STORE_DIR));
+        return SimpleStore::open_layout(logger, layout, Some(data_dir.join(LOG_FILE)), options).await;
+    }
+
+    /// Open the store laid out in `dir`, see the `layout` module, creating it
+    /// if it does not exist. Fails if another process has it open.
+    pub async fn open_dir(logger: slog::Logger, dir: impl AsRef<Path>, options: SimpleStoreOptions) -> Result<SimpleStore> {
+        return SimpleStore::open_layout(logger, Layout::new(dir), None, options).await;
+    }
+
+    /// Lock the directory of `layout`, move the files of the `legacy` log into
+    /// it if it is new, then open its log.
+    async fn open_layout(logger: slog::Logger, layout: Layout, legacy: Option<PathBuf>, options: SimpleStoreOptions) -> Result<SimpleStore> {
+        std::fs::create_dir_all(&layout.dir)?;
+        let lock = LockFile::acquire(&logger, layout.lock_path())?;
+
+        if let Some(legacy) = legacy.filter(|_| !layout.exists()) {
+            let moved = layout.adopt_legacy_log(&legacy)?;
+            if moved > 0 {
+                info!(logger, "Moved {:?} log files to {:?}.", moved, layout.segments_dir());
+            }
+        }
+        layout.create_or_check()?;
+
+        return SimpleStore::start(logger, layout.segments_dir().join(LOG_FILE), options, Some(lock)).await;
+    }
+
+    /// Open the log whose base path is `path`, creating it if it does not
+    /// exist, and spawn the writer thread and the background tasks.
+    ///
+    /// Unlike `open_dir`, nothing keeps other processes from opening the same
+    /// log.
+    pub async fn open(logger: slog::Logger, path: impl AsRef<Path>, options: SimpleStoreOptions) -> Result<SimpleStore> {
+        return SimpleStore::start(logger, path, options, None).await;
+    }
+
+    /// Rewrite the corrupted segments of the store of the data directory
+    /// `data_dir` without their unreadable records, as done when it opens
+    /// with the `repair` option, and drop the torn write at its end.
+    ///
+    /// Hint files that can not be trusted are removed first: the segments
+    /// they belong to are scanned, and repaired if needed, instead of being
+    /// loaded from them.
+    pub async fn repair(logger: slog::Logger, data_dir: &Path) -> Result<()> {
+        let log = inspect::Log::open(data_dir)?;
+        // Keep a running server from using the hint files while they are
+        // removed.
+        let lock = LockFile::acquire(&logger, Layout::new(data_dir.join(STORE_DIR)).lock_path())?;
+        for report in log.verify()? {
+            if report.is_corrupted() {
+                match std::fs::remove_file(hint::path(&report.path)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        drop(lock);
+
+        let options = SimpleStoreOptions {
+            repair: true,
+            ..SimpleStoreOptions::default()
+        };
+        let store = SimpleStore::new(logger, data_dir, options).await?;
+        store.shutdown_purge_task();
+        return Ok(());
+    }
+
+    /// Hits and misses of the read cache since the store was opened.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.shared.cache.stats()
+    }
+
+    async fn start(logger: slog::Logger, path: impl AsRef<Path>, options: SimpleStoreOptions, lock: Option<LockFile>) -> Result<SimpleStore> {
+        let path = path.as_ref().to_path_buf();
+        let (index, mut segments, expirations) = SimpleStore::init(logger.clone(), &path, options.repair).await?;
+
+        // Keep appending to the newest segment, unless it was written by a
+        // merge: those must stay ordered before anything written after them.
+        let newest = *segments.keys().next_back().expect("init creates a segment");
+        let active = if newest.1 == 0 {
+            segments[&newest].clone()
+        } else {
+            let id = SegmentId(newest.0 + 1, 0);
+            let segment = Arc::new(Segment::create(&id.path(&path), id)?);
+            segments.insert(id, segment.clone());
+            segment
+        };
+
+        if options.mmap {
+            for segment in segments.values().filter(|segment| segment.id != active.id) {
+                segment.map()?;
+            }
+        }
+
+        // Changes are numbered from 1. The merged segments only keep the
+        // changes that were not overwritten.
+        let next_seq = segments.values().map(|segment| segment.last_seq()).max().unwrap_or(0) + 1;
+        let merged_seq = segments.values().filter(|segment| segment.id.1 != 0).map(|segment| segment.last_seq()).max();
+        let history_start = merged_seq.unwrap_or(0) + 1;
+
+        let active = ActiveSegment::new(active)?;
+        let (writer, requests) = mpsc::channel();
+        let (durable_tx, durable) = watch::channel(Durable {
+            segment: active.segment.id,
+            len: active.segment.len(),
+        });
+        let shared = Arc::new(Shared {
+            path,
+            cache: ReadCache::new(options.read_cache_size),
+            options,
+            state: RwLock::new(State {
+                index,
+                segments,
+                active: active.segment.id,
+                expirations,
+                history_start,
+                applied_seq: next_seq - 1,
+                shutdown: false,
+            }),
+            writer: Mutex::new(writer),
+            merge: Mutex::new(()),
+            lock: Mutex::new(lock),
+            background_task: Notify::new(),
+            expiration_task: Notify::new(),
+            durable,
+        });
+
+        // Start the writer and the background tasks.
+        Writer::spawn(logger.clone(), &shared, active, requests, next_seq, durable_tx)?;
+        tokio::spawn(merge_task(logger.clone(), shared.clone()));
+        tokio::spawn(purge_expired_tasks(shared.clone()));
+
+        return Ok(SimpleStore { logger, shared });
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub async fn init(logger: slog::Logger, path: &Path, repair: bool) -> Result<(BTreeMap<String, Location>, BTreeMap<SegmentId, Arc<Segment>>, Expirations)> {
+        // Logs written before segments were introduced are a single file at
+        // the base path. It becomes the oldest segment.
+        if tokio::fs::metadata(path).await.is_ok() {
+            if !SimpleStore::is_binary_log(path).await? {
+                info!(logger, "Found text log file, migrating it to the binary format...");
+                let count = SimpleStore::migrate_text_log(path).await?;
+                info!(logger, "Migrated {:?} records.", count);
+            }
+
+            let id = SegmentId(0, 0);
+            info!(logger, "Found single file log, turning it into segment {}.", id);
+            tokio::fs::rename(path, id.path(path)).await?;
+        }
+
+        let mut ids = Vec::new();
+        let dir = log_dir(path);
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let entry_path = entry.path();
+            if let Some(id) = SegmentId::from_path(path, &entry_path) {
+                ids.push(id);
+            } else if is_leftover(path, &entry_path) {
+                // A merge or migration was interrupted before completing.
+                tokio::fs::remove_file(&entry_path).await?;
+            } else if let Some(segment_path) = hint::segment_path(&entry_path) {
+                // The segment was deleted by a merge but not its hint file.
+                if SegmentId::from_path(path, &segment_path).is_some() && tokio::fs::metadata(&segment_path).await.is_err() {
+                    tokio::fs::remove_file(&entry_path).await?;
+                }
+            }
+        }
+        ids.sort_unstable();
+
+        if ids.is_empty() {
+            info!(logger, "No log file found, creating new log file...");
+            let id = SegmentId(1, 0);
+            let segment = Arc::new(Segment::create(&id.path(path), id)?);
+            info!(logger, "Log file created!");
+            return Ok((BTreeMap::new(), vec![(id, segment)].into_iter().collect(), Expirations::default()));
+        }
+
+        // The newest segment is kept as the active one unless it was written by
+        // a merge, see `SimpleStore::open`.
+        let newest = *ids.last().expect("ids is not empty");
+        let active = if newest.1 == 0 { Some(newest) } else { None };
+
+        info!(logger, "Found {:?} log segments, recovering indexes...", ids.len());
+        let mut index: BTreeMap<String, Location> = BTreeMap::new();
+        let mut segments = BTreeMap::new();
+        let mut expirations = Expirations::default();
+        let now = SystemTime::now();
+        let mut scanned = 0;
+        for id in ids {
+            let segment = Arc::new(Segment::open(&id.path(path), id)?);
+
+            // Sealed segments are loaded from their hint file if it is
+            // there and intact, the others are scanned. Only the active
+            // segment may end with a torn write, the sealed ones were synced.
+            let hinted = if Some(id) == active { None } else { Some(segment.read_hint()) };
+            let (segment, records) = match hinted {
+                Some(Ok(records)) => (segment, records),
+                Some(Err(e)) => {
+                    if !is_not_found(&e) {
+                        warn!(logger, "err = {}, scanning segment {} instead", e, id);
+                    }
+                    let (segment, records) = SimpleStore::recover(&logger, segment, false, repair).await?;
+                    segment.write_hint(&records)?;
+                    scanned += 1;
+                    (segment, records)
+                }
+                None => {
+                    scanned += 1;
+                    SimpleStore::recover(&logger, segment, true, repair).await?
+                }
+            };
+
+            // Bytes not covered by a record, commit records and uncommitted
+            // transactions, are dropped by the next merge.
+            let covered: u64 = records.iter().map(|entry| entry.location.len as u64).sum();
+            segment.add_dead_bytes(segment.len().saturating_sub(MAGIC.len() as u64 + covered).try_into()?);
+
+            for entry in records {
+                let expired = entry.expires_at.map(|when| when <= now).unwrap_or(false);
+                let prev = if entry.tombstone || expired {
+                    // Tombstones and expired records are dropped by the next
+                    // merge.
+                    segment.add_dead_bytes(entry.location.len);
+                    expirations.set(&entry.key, None);
+                    index.remove(&entry.key)
+                } else {
+                    expirations.set(&entry.key, entry.expires_at.map(to_instant));
+                    index.insert(entry.key, entry.location)
+                };
+                if let Some(prev) = prev {
+                    segments.get(&prev.segment).unwrap_or(&segment).add_dead_bytes(prev.len);
+                }
+            }
+            segments.insert(id, segment);
+        }
+        info!(logger, "Recovered {:?} indexes, scanned {:?} segments.", index.len(), scanned);
+
+        return Ok((index, segments, expirations));
+    }
+
+    /// Read every record of `segment`, checking their checksum. Returns the
+    /// segment with the entry of each of its records, in file order.
+    ///
+    /// A torn write at the end of the segment is dropped if `torn_tail` is
+    /// set, see `Segment::scan`. Recovery fails on any other corruption,
+    /// unless `repair` is set: the segment is then rewritten without its
+    /// unreadable records and replaced by the new one.
+    pub async fn recover(logger: &slog::Logger, segment: Arc<Segment>, torn_tail: bool, repair: bool) -> Result<(Arc<Segment>, Vec<Entry>)> {
+        let file_len = segment.len();
+        let scanned = segment.clone();
+        let err = match tokio::task::spawn_blocking(move || scanned.scan(torn_tail)).await? {
+            Ok(records) => {
+                if segment.len() < file_len {
+                    warn!(
+                        logger,
+                        "Dropping a torn write of {:?} bytes at the end of segment {}.",
+                        file_len - segment.len(),
+                        segment.id
+                    );
+                }
+                return Ok((segment, records));
+            }
+            Err(err) => err,
+        };
+
+        let corruption = match err.downcast_ref::<Corruption>() {
+            Some(corruption) => corruption,
+            None => return Err(err),
+        };
+        if !repair {
+            bail!("{}, start with --repair to drop the unreadable records", corruption);
+        }
+        warn!(logger, "{}, repairing segment {}...", corruption, segment.id);
+
+        let id = segment.id;
+        let repaired = tokio::task::spawn_blocking(move || segment.repair()).await??;
+        for damage in repaired.damaged.iter() {
+            warn!(
+                logger,
+                "Dropped bytes {:?} to {:?} of segment {}: {}.", damage.range.start, damage.range.end, id, damage.reason
+            );
+        }
+        info!(logger, "Repaired segment {}, the corrupted file is kept at {:?}.", id, repaired.backup);
+
+        let segment = Arc::new(repaired.segment);
+        let scanned = segment.clone();
+        let records = tokio::task::spawn_blocking(move || scanned.scan(false)).await??;
+        return Ok((segment, records));
+    }
+
+    /// Returns `true` if the log at `path` starts with the binary format magic
+    /// bytes.
+    async fn is_binary_log(path: &Path) -> Result<bool> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut magic = [0u8; MAGIC.len()];
+        let len = read_full(&mut file, &mut magic).await?;
+        return Ok(len == MAGIC.len() && &magic == MAGIC);
+    }
+
+    /// Rewrite a legacy `key,value\n` text log in the binary format. Returns
+    /// the number of migrated records.
+    ///
+    /// The new log is written next to the old one and renamed over it once
+    /// synced, so a crash during the migration leaves the text log untouched.
+    async fn migrate_text_log(path: &Path) -> Result<usize> {
+        let file = tokio::fs::File::open(path).await?;
+        let mut lines = tokio::io::BufReader::new(file).lines();
+
+        let tmp_path = leftover_path(path, "migrating");
+        let mut writer = tokio::io::BufWriter::new(tokio::fs::File::create(&tmp_path).await?);
+        writer.write_all(MAGIC).await?;
+
+        let mut count = 0;
+        let mut byte_offset: usize = 0;
+        while let Some(line) = lines.next_line().await? {
+            let (key, value) = match line.split_once(',') {
+                Some(key_value) => key_value,
+                None => bail!("text log file data is corrupted at byte {:?}", byte_offset),
+            };
+            let record = Record::new(key, Bytes::copy_from_slice(value.as_bytes()));
+            writer.write_all(&record.encode()?[..]).await?;
+
+            count += 1;
+            // +1 is for the /n byte
+            byte_offset += line.len() + 1;
+        }
+
+        writer.flush().await?;
+        writer.into_inner().sync_all().await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+
+        return Ok(count);
+    }
+}
+
+impl KeyValueStore for SimpleStore {
+    fn get(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        let value = self.shared.read(key)?.and_then(|record| record.value);
+        debug!(self.logger, "Get: {:?} | {:?}", key, value);
+        return Ok(value);
+    }
+
+    fn set(&self, key: String, value: Bytes, expire: Option<Duration>) -> crate::Result<()> {
+        let expires_at = expire.map(|duration| SystemTime::now() + duration);
+        self.shared.submit(Op::Put(Record::with_expiration(&key, value.clone(), expires_at)))?;
+
+        debug!(self.logger, "Set: {:?} | {:?} | {:?}", key, value, expire);
+        return Ok(());
+    }
+
+    fn delete(&self, key: &str) -> crate::Result<bool> {
+        let exists = self.shared.submit(Op::Delete(key.to_string()))?;
+
+        debug!(self.logger, "Delete: {:?} | {:?}", key, exists);
+        return Ok(exists);
+    }
+
+    /// Rewrite the record of `key` with its new expiration time.
+    fn expire(&self, key: &str, expire: Duration) -> crate::Result<bool> {
+        let expires_at = Some(SystemTime::now() + expire);
+        let exists = self.shared.submit(Op::Expire {
+            key: key.to_string(),
+            expires_at,
+        })?;
+
+        debug!(self.logger, "Expire: {:?} | {:?} | {:?}", key, expire, exists);
+        return Ok(exists);
+    }
+
+    fn ttl(&self, key: &str) -> crate::Result<Expiry> {
+        return Ok(self.shared.ttl(key));
+    }
+
+    /// Rewrite the record of `key` without an expiration time.
+    fn persist(&self, key: &str) -> crate::Result<bool> {
+        let persisted = self.shared.submit(Op::Expire {
+            key: key.to_string(),
+            expires_at: None,
+        })?;
+
+        debug!(self.logger, "Persist: {:?} | {:?}", key, persisted);
+        return Ok(persisted);
+    }
+
+    fn scan(&self, options: &ScanOptions) -> crate::Result<Vec<(String, Bytes)>> {
+        let entries = self.shared.scan(options)?;
+        debug!(self.logger, "Scan: {:?} | {:?} entries", options, entries.len());
+        return Ok(entries);
+    }
+
+    /// Write the records of the batch and their commit record in a single
+    /// append, with the writes committed along with it. A batch cut by a
+    /// crash is ignored on recovery.
+    fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        let len = batch.len();
+        self.shared.submit(Op::Batch(batch))?;
+
+        debug!(self.logger, "Write batch: {:?} writes", len);
+        return Ok(());
+    }
+
+    /// Signals the merge and expiration background tasks to shut down, syncs
+    /// the writes not synced yet and releases the lock of the store
+    /// directory.
+    fn shutdown_purge_task(&self) {
+        let mut state = self.shared.state.write().unwrap();
+        state.shutdown = true;
+        drop(state);
+        self.shared.background_task.notify_one();
+        self.shared.expiration_task.notify_one();
+        if let Err(err) = self.shared.submit(Op::Sync) {
+            error!(self.logger, "err = {}, failed to sync the log on shutdown", err);
+        }
+        let stats = self.cache_stats();
+        info!(self.logger, "Read cache: {:?} hits, {:?} misses.", stats.hits, stats.misses);
+        self.shared.lock.lock().unwrap().take();
+    }
+}
+
+/// Same as the `KeyValueStore` implementation, except that the calls never
+/// block the calling task: reads run on the blocking thread pool, writes
+/// wait for the writer thread asynchronously.
+#[async_trait]
+impl super::AsyncKeyValueStore for SimpleStore {
+    async fn get(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        let shared = self.shared.clone();
+        let owned_key = key.to_string();
+        let value = tokio::task::spawn_blocking(move || shared.read(&owned_key))
+            .await??
+            .and_then(|record| record.value);
+        debug!(self.logger, "Get: {:?} | {:?}", key, value);
+        return Ok(value);
+    }
+
+    async fn set(&self, key: String, value: Bytes, expire: Option<Duration>) -> crate::Result<()> {
+        let expires_at = expire.map(|duration| SystemTime::now() + duration);
+        self.shared
+            .submit_async(Op::Put(Record::with_expiration(&key, value.clone(), expires_at)))
+            .await?;
+
+        debug!(self.logger, "Set: {:?} | {:?} | {:?}", key, value, expire);
+        return Ok(());
+    }
+
+    async fn delete(&self, key: &str) -> crate::Result<bool> {
+        let exists = self.shared.submit_async(Op::Delete(key.to_string())).await?;
+
+        debug!(self.logger, "Delete: {:?} | {:?}", key, exists);
+        return Ok(exists);
+    }
+
+    async fn expire(&self, key: &str, expire: Duration) -> crate::Result<bool> {
+        let expires_at = Some(SystemTime::now() + expire);
+        let exists = self
+            .shared
+            .submit_async(Op::Expire {
+                key: key.to_string(),
+                expires_at,
+            })
+            .await?;
+
+        debug!(self.logger, "Expire: {:?} | {:?} | {:?}", key, expire, exists);
+        return Ok(exists);
+    }
+
+    /// Expirations are tracked in memory, there is no I/O to wait for.
+    async fn ttl(&self, key: &str) -> crate::Result<Expiry> {
+        Ok(self.shared.ttl(key))
+    }
+
+    async fn persist(&self, key: &str) -> crate::Result<bool> {
+        let persisted = self
+            .shared
+            .submit_async(Op::Expire {
+                key: key.to_string(),
+                expires_at: None,
+            })
+            .await?;
+
+        debug!(self.logger, "Persist: {:?} | {:?}", key, persisted);
+        return Ok(persisted);
+    }
+
+    async fn scan(&self, options: &ScanOptions) -> crate::Result<Vec<(String, Bytes)>> {
+        let shared = self.shared.clone();
+        let owned_options = options.clone();
+        let entries = tokio::task::spawn_blocking(move || shared.scan(&owned_options)).await??;
+        debug!(self.logger, "Scan: {:?} | {:?} entries", options, entries.len());
+        return Ok(entries);
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        let len = batch.len();
+        self.shared.submit_async(Op::Batch(batch)).await?;
+
+        debug!(self.logger, "Write batch: {:?} writes", len);
+        return Ok(());
+    }
+
+    /// The segments are read on the blocking thread pool. Once the stream
+    /// reaches the end of the log, it waits for the writer thread to sync new
+    /// records.
+    async fn changes(&self, from: u64) -> crate::Result<Changes> {
+        let shared = self.shared.clone();
+        let mut cursor = shared.open_cursor(from)?;
+        let mut durable = shared.durable.clone();
+        debug!(self.logger, "Changes: from {:?}", from);
+
+        return Ok(Box::pin(async_stream::try_stream! {
+            loop {
+                let end = *durable.borrow_and_update();
+                let reader = shared.clone();
+                let (moved, changes) = tokio::task::spawn_blocking(move || {
+                    let changes = reader.read_changes(&mut cursor, end);
+                    (cursor, changes)
+                })
+                .await?;
+                cursor = moved;
+
+                let changes = changes?;
+                if changes.is_empty() && durable.changed().await.is_err() {
+                    // The writer thread stopped.
+                    break;
+                }
+                for change in changes {
+                    yield change;
+                }
+            }
+        }));
+    }
+
+    async fn snapshot(&self) -> crate::Result<Snapshot> {
+        let shared = self.shared.clone();
+        let snapshot = tokio::task::spawn_blocking(move || shared.snapshot()).await??;
+        debug!(self.logger, "Snapshot: {:?} keys as of {:?}", snapshot.entries.len(), snapshot.seq);
+        return Ok(snapshot);
+    }
+
+    fn shutdown_purge_task(&self) {
+        KeyValueStore::shutdown_purge_task(self);
+    }
+}
+
+impl Shared {
+    /// Read the latest record of `key`. Returns `None` if the key does not
+    /// exist or has expired.
+    fn read(&self, key: &str) -> Result<Option<Record>> {
+        let (location, segment) = {
+            let state = self.state.read().unwrap();
+            match state.index.get(key) {
+                Some(location) => (*location, state.segments[&location.segment].clone()),
+                None => return Ok(None),
+            }
+        };
+
+        let record = match self.cache.get(key, &location) {
+            Some(record) => record,
+            None => {
+                let record = match segment.read(&location) {
+                    Ok(record) => record,
+                    Err(e) => bail!("Index key = {:?} log data is corrupted: {}", key, e),
+                };
+                if record.key != key {
+                    bail!("log data key = {:?} does not match index key = {:?}", record.key, key);
+                }
+                if record.is_tombstone() {
+                    bail!("Index key = {:?} points to a tombstone", key);
+                }
+                self.cache.insert(location, record.clone());
+                record
+            }
+        };
+
+        // The background task may not have purged the key yet.
+        if record.is_expired(SystemTime::now()) {
+            return Ok(None);
+        }
+        return Ok(Some(record));
+    }
+
+    /// How long `key` has left to live.
+    fn ttl(&self, key: &str) -> Expiry {
+        let state = self.state.read().unwrap();
+        if !state.index.contains_key(key) {
+            return Expiry::Missing;
+        }
+
+        let now = Instant::now();
+        return match state.expirations.get(key) {
+            Some(when) if when <= now => Expiry::Missing,
+            Some(when) => Expiry::After(when - now),
+            None => Expiry::Persistent,
+        };
+    }
+
+    /// Read the entries in the range of `options`.
+    fn scan(&self, options: &ScanOptions) -> Result<Vec<(String, Bytes)>> {
+        if options.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Only the locations are collected under the lock, the records are
+        // read once it is released.
+        let locations: Vec<(String, Location, Arc<Segment>)> = {
+            let state = self.state.read().unwrap();
+            let range = state.index.range(options.range());
+            let locations: Box<dyn Iterator<Item = (&String, &Location)>> = if options.reverse { Box::new(range.rev()) } else { Box::new(range) };
+            locations
+                .filter(|(key, _)| !state.expirations.is_expired(key))
+                .take(options.limit.unwrap_or(usize::MAX))
+                .map(|(key, location)| (key.clone(), *location, state.segments[&location.segment].clone()))
+                .collect()
+        };
+
+        let mut entries = Vec::with_capacity(locations.len());
+        for (key, location, segment) in locations {
+            let record = match segment.read(&location) {
+                Ok(record) => record,
+                Err(e) => bail!("Index key = {:?} log data is corrupted: {}", key, e),
+            };
+            match record.value {
+                Some(value) if record.key == key => entries.push((key, value)),
+                _ => bail!("log data key = {:?} does not match index key = {:?}", record.key, key),
+            }
+        }
+
+        return Ok(entries);
+    }
+
+    /// Point the index to `record`, written at `location`. Returns whether a
+    /// record of a sealed segment was overwritten and whether the key is now
+    /// the next to expire.
+    fn index(&self, state: &mut State, record: &Record, location: Location) -> (bool, bool) {
+        self.cache.remove(&record.key);
+        let prev = if record.is_tombstone() {
+            // The tombstone itself is dead weight, the next merge drops it
+            // along with the records it deletes.
+            state.segments[&location.segment].add_dead_bytes(location.len);
+            state.index.remove(&record.key)
+        } else {
+            state.index.insert(record.key.clone(), location)
+        };
+        let expires_next = state.expirations.set(&record.key, record.expires_at.map(to_instant));
+        let overwrote_sealed = match prev {
+            Some(prev) => {
+                state.segments[&prev.segment].add_dead_bytes(prev.len);
+                prev.segment < state.active
+            }
+            None => false,
+        };
+        return (overwrote_sealed, expires_next);
+    }
+
+    /// Wake up the background tasks after a write, once the state lock is
+    /// released.
+    fn notify(&self, overwrote_sealed: bool, expires_next: bool) {
+        if overwrote_sealed {
+            self.background_task.notify_one();
+        }
+        if expires_next {
+            self.expiration_task.notify_one();
+        }
+    }
+
+    /// Seal the active segment and start a new one.
+    fn rotate(&self, active: &mut ActiveSegment) -> Result<()> {
+        if self.options.mmap {
+            active.segment.map()?;
+        }
+
+        let id = SegmentId(active.segment.id.0 + 1, 0);
+        let segment = Arc::new(Segment::create(&id.path(&self.path), id)?);
+        *active = ActiveSegment::new(segment.clone())?;
+
+        let mut state = self.state.write().unwrap();
+        state.segments.insert(id, segment);
+        state.active = id;
+        drop(state);
+
+        // Let the background task write the hint file of the sealed segment.
+        self.background_task.notify_one();
+        return Ok(());
+    }
+
+    /// Sealed segments that do not have a hint file yet.
+    fn unhinted_segments(&self) -> Vec<Arc<Segment>> {
+        let state = self.state.read().unwrap();
+        state
+            .segments
+            .values()
+            .filter(|segment| segment.id < state.active && !segment.has_hint())
+            .cloned()
+            .collect()
+    }
+
+    /// Returns `true` if the store is shutting down.
+    fn is_shutdown(&self) -> bool {
+        self.state.read().unwrap().shutdown
+    }
+}
+
+impl PurgeExpired for Shared {
+    /// Remove the expired keys from the index. Their records are left in the
+    /// log for the next merge, recovery skips them.
+    fn purge_expired_keys(&self) -> Option<Instant> {
+        let mut state = self.state.write().unwrap();
+        if state.shutdown {
+            return None;
+        }
+
+        let state = &mut *state;
+        let now = Instant::now();
+        let mut purged_sealed = false;
+        while let Some(key) = state.expirations.pop_expired(now) {
+            if let Some(location) = state.index.remove(&key) {
+                self.options.notifier.notify(KeyEvent::Expired, &key);
+                self.cache.remove(&key);
+                state.segments[&location.segment].add_dead_bytes(location.len);
+                purged_sealed |= location.segment < state.active;
+            }
+        }
+
+        if purged_sealed {
+            self.background_task.notify_one();
+        }
+        state.expirations.next_expiration()
+    }
+
+    fn is_shutdown(&self) -> bool {
+        Shared::is_shutdown(self)
+    }
+
+    fn expiration_task(&self) -> &Notify {
+        &self.expiration_task
+    }
+}
+
+fn is_not_found(err: &crate::Error) -> bool {
+    matches!(err.downcast_ref::<std::io::Error>(), Some(e) if e.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// Directory holding the log whose base path is `path`.
+fn log_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Path of a temporary file belonging to the log whose base path is `path`.
+fn leftover_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", suffix));
+    path.with_file_name(name)
+}
+
+/// Returns `true` if `entry` is a temporary file of the log whose base path
+/// is `path`, left behind by an interrupted merge or migration.
+fn is_leftover(path: &Path, entry: &Path) -> bool {
+    let base_name = path.file_name().and_then(|n| n.to_str());
+    let name = entry.file_name().and_then(|n| n.to_str());
+    match (base_name, name) {
+        (Some(base_name), Some(name)) => name.starts_with(base_name) && (name.ends_with(".tmp") || name.ends_with(".migrating")),
+        _ => false,
+    }
+}
+
+/// Fill `buf` from `reader`, stopping early only at the end of the stream.
+/// Returns the number of bytes read.
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]).await? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    return Ok(len);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::key_value_store::KeyspaceEvents;
+    use crate::server::PubSub;
+    use record::HEADER_LEN;
+    use std::io::Write as _;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    fn small_segments() -> SimpleStoreOptions {
+        SimpleStoreOptions {
+            max_segment_size: 256,
+            ..SimpleStoreOptions::default()
+        }
+    }
+
+    fn segment_files(dir: &Path) -> usize {
+        let path = dir.join(LOG_FILE);
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| SegmentId::from_path(&path, &entry.as_ref().unwrap().path()).is_some())
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_set_get_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+
+        let binary = Bytes::from(&b"multi\nline, with commas\x00"[..]);
+        store.set("key".to_string(), Bytes::from("value"), None).unwrap();
+        store.set("odd,key".to_string(), binary.clone(), None).unwrap();
+        store.set("key".to_string(), Bytes::from("other"), None).unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("other")));
+        assert_eq!(store.get("odd,key").unwrap(), Some(binary.clone()));
+        assert_eq!(store.get("missing").unwrap(), None);
+        store.shutdown_purge_task();
+
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("other")));
+        assert_eq!(store.get("odd,key").unwrap(), Some(binary));
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_text_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        std::fs::write(&path, "key1,value1\nkey2,a,b\nkey1,value2\n").unwrap();
+
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        assert_eq!(store.get("key1").unwrap(), Some(Bytes::from("value2")));
+        assert_eq!(store.get("key2").unwrap(), Some(Bytes::from("a,b")));
+        assert!(SimpleStore::is_binary_log(&SegmentId(0, 0).path(&path)).await.unwrap());
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_rotation_and_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+
+        for round in 0..5 {
+            for i in 0..20 {
+                store.set(format!("key{}", i), Bytes::from(format!("value{}-{}", i, round)), None).unwrap();
+            }
+        }
+        let before = segment_files(dir.path());
+        assert!(before > 2);
+        assert!(store.shared.needs_merge());
+
+        store.shared.merge().unwrap();
+        assert!(segment_files(dir.path()) < before);
+        assert!(!store.shared.needs_merge());
+        for i in 0..20 {
+            assert_eq!(store.get(&format!("key{}", i)).unwrap(), Some(Bytes::from(format!("value{}-4", i))));
+        }
+
+        // Writes after the merge still win over the merged records on
+        // recovery.
+        store.set("key0".to_string(), Bytes::from("latest"), None).unwrap();
+        store.shutdown_purge_task();
+
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        assert_eq!(store.get("key0").unwrap(), Some(Bytes::from("latest")));
+        for i in 1..20 {
+            assert_eq!(store.get(&format!("key{}", i)).unwrap(), Some(Bytes::from(format!("value{}-4", i))));
+        }
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        store.shutdown_purge_task();
+
+        for i in 0..20 {
+            store.set(format!("key{}", i), Bytes::from(format!("value{}", i)), None).unwrap();
+        }
+        for i in (0..20).step_by(2) {
+            assert!(store.delete(&format!("key{}", i)).unwrap());
+        }
+        assert!(!store.delete("key0").unwrap());
+        assert!(!store.delete("missing").unwrap());
+
+        let check = |store: &SimpleStore| {
+            for i in 0..20 {
+                let expected = if i % 2 == 0 { None } else { Some(Bytes::from(format!("value{}", i))) };
+                assert_eq!(store.get(&format!("key{}", i)).unwrap(), expected);
+            }
+        };
+        check(&store);
+
+        // Tombstones survive a restart.
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        store.shutdown_purge_task();
+        check(&store);
+
+        // A deleted key can be set again, and merges drop the deleted
+        // records.
+        store.set("key0".to_string(), Bytes::from("again"), None).unwrap();
+        store.shared.merge().unwrap();
+        assert_eq!(store.get("key0").unwrap(), Some(Bytes::from("again")));
+        assert_eq!(store.get("key2").unwrap(), None);
+
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        store.shutdown_purge_task();
+        assert_eq!(store.get("key0").unwrap(), Some(Bytes::from("again")));
+        assert_eq!(store.get("key2").unwrap(), None);
+        assert_eq!(store.get("key1").unwrap(), Some(Bytes::from("value1")));
+    }
+
+    #[tokio::test]
+    async fn test_expiration_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+
+        let hour = Duration::from_secs(3600);
+        store.set("short".to_string(), Bytes::from("value"), Some(Duration::from_millis(50))).unwrap();
+        store.set("long".to_string(), Bytes::from("value"), Some(hour)).unwrap();
+        store
+            .set("persisted".to_string(), Bytes::from("value"), Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(store.persist("persisted").unwrap());
+        store.set("forever".to_string(), Bytes::from("value"), None).unwrap();
+        assert!(store.expire("forever", hour).unwrap());
+        assert!(store.persist("forever").unwrap());
+        assert!(!store.persist("forever").unwrap());
+        assert!(!store.expire("missing", hour).unwrap());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.get("short").unwrap(), None);
+        assert_eq!(store.ttl("short").unwrap(), Expiry::Missing);
+        store.shutdown_purge_task();
+
+        // Expiration times survive a restart, expired keys stay gone.
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        assert_eq!(store.get("short").unwrap(), None);
+        assert_eq!(store.ttl("short").unwrap(), Expiry::Missing);
+        assert_eq!(store.get("long").unwrap(), Some(Bytes::from("value")));
+        assert!(matches!(store.ttl("long").unwrap(), Expiry::After(ttl) if ttl > hour - Duration::from_secs(60) && ttl <= hour));
+        assert_eq!(store.ttl("persisted").unwrap(), Expiry::Persistent);
+        assert_eq!(store.get("persisted").unwrap(), Some(Bytes::from("value")));
+        assert_eq!(store.ttl("forever").unwrap(), Expiry::Persistent);
+
+        // Merges keep the expiration times.
+        store.shared.submit(Op::Rotate).unwrap();
+        store.shared.merge().unwrap();
+        store.shutdown_purge_task();
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        assert!(matches!(store.ttl("long").unwrap(), Expiry::After(_)));
+        assert_eq!(store.ttl("forever").unwrap(), Expiry::Persistent);
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let pub_sub = Arc::new(PubSub::default());
+        let mut events = pub_sub.psubscribe("__keyevent@0__:*");
+        let options = SimpleStoreOptions {
+            notifier: Notifier::new(KeyspaceEvents::from_str("EA").unwrap(), pub_sub.clone()),
+            ..SimpleStoreOptions::default()
+        };
+        let store = SimpleStore::open(logger(), &path, options).await.unwrap();
+
+        store.set("a".to_string(), Bytes::from("value"), None).unwrap();
+        assert!(store.expire("a", Duration::from_secs(3600)).unwrap());
+        assert!(store.persist("a").unwrap());
+        assert!(store.delete("a").unwrap());
+        assert!(!store.delete("a").unwrap());
+        store.set("b".to_string(), Bytes::from("value"), Some(Duration::from_millis(50))).unwrap();
+
+        // Expired keys are notified when the background task removes them.
+        let expected = [
+            ("set", "a"),
+            ("expire", "a"),
+            ("persist", "a"),
+            ("del", "a"),
+            ("set", "b"),
+            ("expire", "b"),
+            ("expired", "b"),
+        ];
+        for (event, key) in expected {
+            let message = events.recv().await.unwrap();
+            assert_eq!(message.channel, format!("__keyevent@0__:{}", event));
+            assert_eq!(message.content, Bytes::from(key));
+        }
+        assert!(events.try_recv().is_err());
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_changes() {
+        use tokio_stream::StreamExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        // Only merge when the test says so.
+        store.shutdown_purge_task();
+        let kv: Box<dyn crate::server::key_value_store::AsyncKeyValueStore> = Box::new(store.clone());
+
+        // Sequence numbers start at 1, even on an empty store.
+        let err = kv.changes(0).await.err().unwrap();
+        assert!(err.to_string().contains("sequence numbers start at 1"), "{}", err);
+        drop(kv.changes(1).await.unwrap());
+
+        for round in 0..3 {
+            for i in 0..10 {
+                kv.set(format!("key{}", i), Bytes::from(format!("value{}-{}", i, round)), None).await.unwrap();
+            }
+        }
+        kv.delete("key0").await.unwrap();
+        kv.expire("key1", Duration::from_secs(60)).await.unwrap();
+        assert!(segment_files(dir.path()) > 2);
+
+        // Every change is streamed in order, then the stream waits for new
+        // ones.
+        let mut changes = kv.changes(1).await.unwrap();
+        for seq in 1..=30 {
+            let change = changes.next().await.unwrap().unwrap();
+            assert_eq!(change.seq, seq);
+            assert_eq!(change.key, format!("key{}", (seq - 1) % 10));
+            assert_eq!(change.value, Some(Bytes::from(format!("value{}-{}", (seq - 1) % 10, (seq - 1) / 10))));
+        }
+        let deleted = changes.next().await.unwrap().unwrap();
+        assert_eq!((deleted.seq, deleted.key.as_str(), deleted.value), (31, "key0", None));
+        let expiring = changes.next().await.unwrap().unwrap();
+        assert_eq!((expiring.seq, expiring.value), (32, Some(Bytes::from("value1-2"))));
+        assert!(expiring.expires_at.is_some());
+        assert!(tokio::time::timeout(Duration::from_millis(50), changes.next()).await.is_err());
+
+        kv.set("tail".to_string(), Bytes::from("value"), None).await.unwrap();
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!((change.seq, change.key.as_str()), (33, "tail"));
+
+        // A stream resumes from any sequence number.
+        let mut resumed = kv.changes(20).await.unwrap();
+        assert_eq!(resumed.next().await.unwrap().unwrap().seq, 20);
+
+        // A merge drops the history of the overwritten keys, but not the
+        // changes already being streamed.
+        store.shared.merge().unwrap();
+        let history_start = store.shared.state.read().unwrap().history_start;
+        assert!(history_start > 20);
+        assert!(kv.changes(1).await.is_err());
+        for seq in 21..=33 {
+            assert_eq!(resumed.next().await.unwrap().unwrap().seq, seq);
+        }
+        let mut after_merge = kv.changes(history_start).await.unwrap();
+        assert_eq!(after_merge.next().await.unwrap().unwrap().seq, history_start);
+        drop((changes, resumed, after_merge));
+
+        // Sequence numbers are not reused after a restart, even the ones of
+        // the merged records.
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        let kv: Box<dyn crate::server::key_value_store::AsyncKeyValueStore> = Box::new(store.clone());
+        assert_eq!(store.shared.state.read().unwrap().history_start, history_start);
+        kv.set("restarted".to_string(), Bytes::from("value"), None).await.unwrap();
+        let mut changes = kv.changes(34).await.unwrap();
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!((change.seq, change.key.as_str()), (34, "restarted"));
+        drop(changes);
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_sequence() {
+        use tokio_stream::StreamExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let options = SimpleStoreOptions {
+            max_segment_size: 1,
+            ..SimpleStoreOptions::default()
+        };
+        let store = SimpleStore::open(logger(), &path, options).await.unwrap();
+        let kv: Box<dyn crate::server::key_value_store::AsyncKeyValueStore> = Box::new(store.clone());
+        kv.set("a".to_string(), Bytes::from("1"), None).await.unwrap();
+
+        // The next segment can not be created, the write fails before its
+        // record reaches the log.
+        let active = store.shared.state.read().unwrap().active;
+        let blocker = SegmentId(active.0 + 1, 0).path(&path);
+        std::fs::create_dir(&blocker).unwrap();
+        assert!(kv.set("b".to_string(), Bytes::from("1"), None).await.is_err());
+        std::fs::remove_dir(&blocker).unwrap();
+        kv.set("c".to_string(), Bytes::from("1"), None).await.unwrap();
+
+        // Its sequence number goes to the next record.
+        let mut changes = kv.changes(1).await.unwrap();
+        for (seq, key) in [(1, "a"), (2, "c")] {
+            let change = changes.next().await.unwrap().unwrap();
+            assert_eq!((change.seq, change.key.as_str()), (seq, key));
+        }
+        drop(changes);
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_failed_sync() {
+        use tokio_stream::StreamExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let options = SimpleStoreOptions {
+            fsync: FsyncPolicy::Always,
+            ..SimpleStoreOptions::default()
+        };
+        let store = SimpleStore::open(logger(), &path, options.clone()).await.unwrap();
+        let kv: Box<dyn crate::server::key_value_store::AsyncKeyValueStore> = Box::new(store.clone());
+        kv.set("a".to_string(), Bytes::from("1"), None).await.unwrap();
+
+        // The write is reported failed, but its record is in the log: it is
+        // read and streamed like the others.
+        store.shared.submit(Op::FailSync).unwrap();
+        assert!(kv.set("b".to_string(), Bytes::from("1"), None).await.is_err());
+        assert_eq!(kv.get("b").await.unwrap(), Some(Bytes::from("1")));
+        kv.set("c".to_string(), Bytes::from("1"), None).await.unwrap();
+        assert_eq!(store.shared.state.read().unwrap().applied_seq, 3);
+
+        let mut changes = kv.changes(1).await.unwrap();
+        for (seq, key) in [(1, "a"), (2, "b"), (3, "c")] {
+            let change = changes.next().await.unwrap().unwrap();
+            assert_eq!((change.seq, change.key.as_str()), (seq, key));
+        }
+        drop(changes);
+        store.shutdown_purge_task();
+        drop((kv, store));
+
+        let store = SimpleStore::open(logger(), &path, options).await.unwrap();
+        assert_eq!(store.get("b").unwrap(), Some(Bytes::from("1")));
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_group_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        for fsync in [FsyncPolicy::Always, FsyncPolicy::EverySec, FsyncPolicy::Never] {
+            let options = SimpleStoreOptions { fsync, ..small_segments() };
+            let store = SimpleStore::open(logger(), &path, options.clone()).await.unwrap();
+
+            // Concurrent writers, each committed along with the others queued
+            // at the same time.
+            let writers: Vec<_> = (0..8)
+                .map(|i| {
+                    let store = store.clone();
+                    std::thread::spawn(move || {
+                        for j in 0..20 {
+                            store.set(format!("{:?}-{}-{}", fsync, i, j), Bytes::from("value"), None).unwrap();
+                        }
+                        // Only one of the concurrent deletes of a key finds it.
+                        (0..20).filter(|j| store.delete(&format!("{:?}-shared-{}", fsync, j)).unwrap()).count()
+                    })
+                })
+                .collect();
+            for j in 0..20 {
+                store.set(format!("{:?}-shared-{}", fsync, j), Bytes::from("value"), None).unwrap();
+            }
+            let deleted: usize = writers.into_iter().map(|writer| writer.join().unwrap()).sum();
+            assert!(deleted <= 20);
+            store.shutdown_purge_task();
+
+            // Shutting down syncs the writes, whatever the policy.
+            let store = SimpleStore::open(logger(), &path, options).await.unwrap();
+            for i in 0..8 {
+                for j in 0..20 {
+                    assert_eq!(store.get(&format!("{:?}-{}-{}", fsync, i, j)).unwrap(), Some(Bytes::from("value")));
+                }
+            }
+            let left = (0..20).filter(|j| store.get(&format!("{:?}-shared-{}", fsync, j)).unwrap().is_some()).count();
+            assert_eq!(deleted + left, 20);
+            store.shutdown_purge_task();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        let kv: Box<dyn crate::server::key_value_store::AsyncKeyValueStore> = Box::new(store.clone());
+
+        // The tasks share the single thread of the test runtime: they would
+        // deadlock if waiting for the writer blocked it.
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let kv = kv.clone();
+                tokio::spawn(async move {
+                    for j in 0..10 {
+                        kv.set(format!("key{}-{}", i, j), Bytes::from("value"), None).await.unwrap();
+                    }
+                    assert!(kv.expire(&format!("key{}-0", i), Duration::from_secs(60)).await.unwrap());
+                    assert!(kv.persist(&format!("key{}-0", i)).await.unwrap());
+                    assert!(kv.delete(&format!("key{}-1", i)).await.unwrap());
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(kv.get("key3-0").await.unwrap(), Some(Bytes::from("value")));
+        assert_eq!(kv.get("key3-1").await.unwrap(), None);
+        assert_eq!(kv.ttl("key3-0").await.unwrap(), Expiry::Persistent);
+        let options = ScanOptions {
+            prefix: Some("key3-".to_string()),
+            ..ScanOptions::default()
+        };
+        assert_eq!(kv.scan(&options).await.unwrap().len(), 9);
+        let mut batch = WriteBatch::new();
+        batch.delete("key3-0");
+        kv.write_batch(batch).await.unwrap();
+        kv.shutdown_purge_task();
+
+        // The writes went through the same log as the blocking ones.
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        assert_eq!(store.get("key3-0").unwrap(), None);
+        assert_eq!(store.get("key3-2").unwrap(), Some(Bytes::from("value")));
+        assert_eq!(store.get("key7-9").unwrap(), Some(Bytes::from("value")));
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_read_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        store.set("key".to_string(), Bytes::from("value"), None).unwrap();
+
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("value")));
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("value")));
+        assert_eq!(store.cache_stats(), CacheStats { hits: 1, misses: 1 });
+
+        // Writes invalidate the cached record.
+        store.set("key".to_string(), Bytes::from("other"), None).unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("other")));
+        assert!(store.expire("key", Duration::from_secs(60)).unwrap());
+        assert!(matches!(store.ttl("key").unwrap(), Expiry::After(_)));
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("other")));
+        assert!(store.delete("key").unwrap());
+        assert_eq!(store.get("key").unwrap(), None);
+        // `expire` and `delete` read the cached record too.
+        assert_eq!(store.cache_stats(), CacheStats { hits: 3, misses: 3 });
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_mmap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let options = SimpleStoreOptions {
+            mmap: true,
+            read_cache_size: 0,
+            ..small_segments()
+        };
+        let store = SimpleStore::open(logger(), &path, options.clone()).await.unwrap();
+        let mapped = |store: &SimpleStore| -> Vec<bool> {
+            let state = store.shared.state.read().unwrap();
+            state.segments.values().map(|segment| segment.is_mapped()).collect()
+        };
+
+        for round in 0..5 {
+            for i in 0..20 {
+                store.set(format!("key{}", i), Bytes::from(format!("value{}-{}", i, round)), None).unwrap();
+            }
+        }
+        // Segments are mapped once sealed, never while written to.
+        let before = mapped(&store);
+        assert!(before.len() > 2);
+        assert_eq!(before.iter().filter(|mapped| !**mapped).count(), 1);
+        assert!(!before.last().unwrap());
+
+        // Values read from a mapping are slices of it.
+        store.shared.submit(Op::Rotate).unwrap();
+        let location = store.shared.state.read().unwrap().index["key0"];
+        let segment = store.shared.state.read().unwrap().segments[&location.segment].clone();
+        let raw = segment.read_raw(&location).unwrap();
+        let value = store.get("key0").unwrap().unwrap();
+        assert_eq!(value, Bytes::from("value0-4"));
+        assert!(raw.as_ptr_range().contains(&value.as_ptr()));
+
+        // The merge outputs are mapped, and values read from the inputs stay
+        // valid after their files are removed.
+        store.shared.merge().unwrap();
+        assert!(!segment.path.exists());
+        assert_eq!(value, Bytes::from("value0-4"));
+        assert!(mapped(&store).iter().rev().skip(1).all(|mapped| *mapped));
+        for i in 0..20 {
+            assert_eq!(store.get(&format!("key{}", i)).unwrap(), Some(Bytes::from(format!("value{}-4", i))));
+        }
+        store.shutdown_purge_task();
+
+        // Sealed segments are mapped on startup.
+        let store = SimpleStore::open(logger(), &path, options).await.unwrap();
+        assert!(mapped(&store).iter().rev().skip(1).all(|mapped| *mapped));
+        for i in 0..20 {
+            assert_eq!(store.get(&format!("key{}", i)).unwrap(), Some(Bytes::from(format!("value{}-4", i))));
+        }
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+
+        for i in 0..30 {
+            store.set(format!("key{:02}", i), Bytes::from(format!("value{}", i)), None).unwrap();
+        }
+        store.delete("key11").unwrap();
+        store.set("key12".to_string(), Bytes::from("other"), None).unwrap();
+        store.set("key13".to_string(), Bytes::from("value13"), Some(Duration::from_millis(1))).unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let options = ScanOptions {
+            prefix: Some("key1".to_string()),
+            limit: Some(3),
+            ..ScanOptions::default()
+        };
+        let entries = store.scan(&options).unwrap();
+        assert_eq!(
+            entries,
+            [
+                ("key10".to_string(), Bytes::from("value10")),
+                ("key12".to_string(), Bytes::from("other")),
+                ("key14".to_string(), Bytes::from("value14")),
+            ]
+        );
+
+        let options = ScanOptions {
+            start: Some("key05".to_string()),
+            end: Some("key08".to_string()),
+            reverse: true,
+            ..ScanOptions::default()
+        };
+        let keys: Vec<String> = store.scan(&options).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["key07", "key06", "key05"]);
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_write_batch_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        store.set("a".to_string(), Bytes::from("1"), None).unwrap();
+        store.set("gone".to_string(), Bytes::from("1"), None).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put("a", Bytes::from("2"));
+        batch.put_expiring("b", Bytes::from("1"), Duration::from_secs(60));
+        batch.delete("gone");
+        batch.delete("missing");
+        store.write_batch(batch).unwrap();
+        let segment_path = {
+            let state = store.shared.state.read().unwrap();
+            state.segments[&state.active].path.clone()
+        };
+        store.shutdown_purge_task();
+
+        // A crash cuts a batch before its commit record.
+        let mut file = std::fs::OpenOptions::new().append(true).open(&segment_path).unwrap();
+        for record in [Record::new("a", Bytes::from("3")), Record::new("c", Bytes::from("1"))] {
+            file.write_all(&record.encode_in_transaction().unwrap()[..]).unwrap();
+        }
+        drop(file);
+
+        let check = |store: &SimpleStore| {
+            assert_eq!(store.get("a").unwrap(), Some(Bytes::from("2")));
+            assert_eq!(store.get("b").unwrap(), Some(Bytes::from("1")));
+            assert!(matches!(store.ttl("b").unwrap(), Expiry::After(_)));
+            assert_eq!(store.get("c").unwrap(), None);
+            assert_eq!(store.get("gone").unwrap(), None);
+        };
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        check(&store);
+
+        // Writes appended after the cut batch do not commit it.
+        store.set("d".to_string(), Bytes::from("1"), None).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("e", Bytes::from("1"));
+        store.write_batch(batch).unwrap();
+        store.shutdown_purge_task();
+
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        check(&store);
+        assert_eq!(store.get("d").unwrap(), Some(Bytes::from("1")));
+        assert_eq!(store.get("e").unwrap(), Some(Bytes::from("1")));
+        store.shutdown_purge_task();
+
+        // A crash in the middle of a record of a batch leaves a partial record
+        // at the end of the log, it is dropped with the rest of the batch.
+        let mut file = std::fs::OpenOptions::new().append(true).open(&segment_path).unwrap();
+        file.write_all(&Record::new("f", Bytes::from("1")).encode_in_transaction().unwrap()[..])
+            .unwrap();
+        let torn = Record::new("g", Bytes::from("1")).encode_in_transaction().unwrap();
+        file.write_all(&torn[..torn.len() - 1]).unwrap();
+        drop(file);
+
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        check(&store);
+        assert_eq!(store.get("f").unwrap(), None);
+        assert_eq!(store.get("g").unwrap(), None);
+        let mut batch = WriteBatch::new();
+        batch.put("h", Bytes::from("1"));
+        batch.delete("d");
+        store.write_batch(batch).unwrap();
+        store.shutdown_purge_task();
+
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        check(&store);
+        assert_eq!(store.get("d").unwrap(), None);
+        assert_eq!(store.get("h").unwrap(), Some(Bytes::from("1")));
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_torn_write_and_repair() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        store.set("a".to_string(), Bytes::from("1"), None).unwrap();
+        let segment_path = {
+            let state = store.shared.state.read().unwrap();
+            state.segments[&state.active].path.clone()
+        };
+        store.shutdown_purge_task();
+        let clean_len = std::fs::metadata(&segment_path).unwrap().len();
+
+        // A crash cuts the last record, or leaves its last bytes or a whole
+        // block zeroed. The torn write is dropped.
+        let record = Record::new("b", Bytes::from("1")).encode().unwrap();
+        let mut zeroed = record.clone();
+        let last = zeroed.len() - 1;
+        zeroed[last] = 0;
+        for torn in [&record[..record.len() - 1], &zeroed[..], &[0u8; 4096][..]] {
+            let mut file = std::fs::OpenOptions::new().append(true).open(&segment_path).unwrap();
+            file.write_all(torn).unwrap();
+            drop(file);
+
+            let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+            assert_eq!(store.get("a").unwrap(), Some(Bytes::from("1")));
+            assert_eq!(store.get("b").unwrap(), None);
+            assert_eq!(std::fs::metadata(&segment_path).unwrap().len(), clean_len);
+            store.shutdown_purge_task();
+        }
+
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        for key in ["b", "c", "d"] {
+            store.set(key.to_string(), Bytes::from("1"), None).unwrap();
+        }
+        let corrupted = store.shared.state.read().unwrap().index["c"];
+        store.shutdown_purge_task();
+
+        // A corrupted record in the middle of the log keeps the store from
+        // opening, even if its length runs past the end of the file.
+        let clean = std::fs::read(&segment_path).unwrap();
+        let mut content = clean.clone();
+        content[corrupted.offset as usize + HEADER_LEN - 4] = 0x7f;
+        std::fs::write(&segment_path, &content).unwrap();
+        let err = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap_err();
+        assert!(err.to_string().contains(&format!("corrupted at byte {:?}", corrupted.offset)));
+        assert_eq!(std::fs::read(&segment_path).unwrap(), content);
+
+        let mut content = clean;
+        content[corrupted.offset as usize + HEADER_LEN] ^= 0xff;
+        std::fs::write(&segment_path, &content).unwrap();
+        let err = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap_err();
+        assert!(err.to_string().contains(&format!("corrupted at byte {:?}", corrupted.offset)));
+        assert!(err.to_string().contains("--repair"));
+
+        // Repairing drops the corrupted record only, and keeps the corrupted
+        // file.
+        let repair = SimpleStoreOptions {
+            repair: true,
+            ..SimpleStoreOptions::default()
+        };
+        let store = SimpleStore::open(logger(), &path, repair).await.unwrap();
+        for (key, value) in [
+            ("a", Some(Bytes::from("1"))),
+            ("b", Some(Bytes::from("1"))),
+            ("c", None),
+            ("d", Some(Bytes::from("1"))),
+        ] {
+            assert_eq!(store.get(key).unwrap(), value);
+        }
+        store.shutdown_purge_task();
+        let backups = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().contains(".corrupt-"))
+            .count();
+        assert_eq!(backups, 1);
+
+        let store = SimpleStore::open(logger(), &path, SimpleStoreOptions::default()).await.unwrap();
+        assert_eq!(store.get("d").unwrap(), Some(Bytes::from("1")));
+        store.shutdown_purge_task();
+    }
+
+    #[tokio::test]
+    async fn test_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // A log written before data directories is moved into the store
+        // directory.
+        let store = SimpleStore::open(logger(), dir.path().join(LOG_FILE), SimpleStoreOptions::default())
+            .await
+            .unwrap();
+        store.set("key".to_string(), Bytes::from("value"), None).unwrap();
+        store.shutdown_purge_task();
+
+        let store = SimpleStore::new(logger(), dir.path(), SimpleStoreOptions::default()).await.unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("value")));
+        store.set("other".to_string(), Bytes::from("value"), None).unwrap();
+        store.shutdown_purge_task();
+
+        let layout = Layout::new(dir.path().join(STORE_DIR));
+        assert!(layout.exists() && layout.lock_path().exists());
+        assert!(layout.segments_dir().join("log.raphdb.000001.000").exists());
+        assert!(!dir.path().join("log.raphdb.000001.000").exists());
+
+        let store = SimpleStore::new(logger(), dir.path(), SimpleStoreOptions::default()).await.unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(Bytes::from("value")));
+        assert_eq!(store.get("other").unwrap(), Some(Bytes::from("value")));
+
+        // The directory is locked until the store shuts down.
+        assert!(SimpleStore::new(logger(), dir.path(), SimpleStoreOptions::default()).await.is_err());
+        store.shutdown_purge_task();
+
+        std::fs::write(layout.manifest_path(), "simple-store 2\n").unwrap();
+        assert!(SimpleStore::new(logger(), dir.path(), SimpleStoreOptions::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hint_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        store.shutdown_purge_task();
+
+        for i in 0..40 {
+            store.set(format!("key{}", i % 25), Bytes::from(format!("value{}", i)), None).unwrap();
+        }
+        store.delete("key3").unwrap();
+        let sealed = store.shared.unhinted_segments();
+        assert!(sealed.len() > 1);
+        for segment in sealed.iter() {
+            segment.write_hint(&segment.scan(false).unwrap()).unwrap();
+        }
+        assert!(store.shared.unhinted_segments().is_empty());
+
+        let expected: Vec<_> = (0..25).map(|i| store.get(&format!("key{}", i)).unwrap()).collect();
+        let check = |store: &SimpleStore| {
+            for (i, value) in expected.iter().enumerate() {
+                assert_eq!(&store.get(&format!("key{}", i)).unwrap(), value);
+            }
+        };
+
+        // Reopen from the hint files.
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        store.shutdown_purge_task();
+        check(&store);
+
+        // A corrupted hint file falls back to scanning its segment, and is
+        // rewritten.
+        let hint_path = hint::path(&sealed[0].path);
+        let mut data = std::fs::read(&hint_path).unwrap();
+        data[10] ^= 0xff;
+        std::fs::write(&hint_path, &data).unwrap();
+        assert!(hint::read(&sealed[0].path, sealed[0].id).is_err());
+
+        let store = SimpleStore::open(logger(), &path, small_segments()).await.unwrap();
+        store.shutdown_purge_task();
+        check(&store);
+        assert!(hint::read(&sealed[0].path, sealed[0].id).is_ok());
+    }
+}
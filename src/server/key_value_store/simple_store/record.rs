@@ -0,0 +1,393 @@
+use crate::Result;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use simple_error::bail;
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Every binary log starts with these bytes. Logs without them were written
+/// in the legacy `key,value\n` text format.
+pub const MAGIC: &[u8; 8] = b"RAPHDB\x00\x01";
+
+/// Length of an encoded record header.
+pub const HEADER_LEN: usize = 4 + 1 + 4 + 4;
+
+/// Kind of a record that associates a value to a key.
+const SET: u8 = 1;
+
+/// Kind of a record that removes a key, it has no value.
+const DEL: u8 = 2;
+
+/// Kind of a record that associates a value to a key until a point in time.
+const SET_EX: u8 = 3;
+
+/// Kind of the record ending a transaction. It has no key, its value is the
+/// number of records of the transaction (u32).
+const COMMIT: u8 = 4;
+
+/// Kind of the record that only carries a sequence number. It has no key,
+/// its value is the sequence number (u64). Merges write one when they drop
+/// the newest records of their inputs, so the numbers are never reused.
+const MARK: u8 = 5;
+
+/// Flag set on the kind of the records written by a transaction. They only
+/// take effect if the commit record of the transaction follows them.
+const TRANSACTION: u8 = 0x80;
+
+/// Flag set on the kind of the records that have a sequence number. Their
+/// value starts with it (u64), before the expiration time.
+const SEQUENCED: u8 = 0x40;
+
+/// A single entry of the log.
+///
+/// Encoding, all integers are big endian:
+///
+/// ```text
+/// | crc32 (u32) | kind (u8) | key len (u32) | value len (u32) | key | value |
+/// ```
+///
+/// The checksum covers every byte of the record after itself. Keys and values
+/// are arbitrary bytes, keys must be valid UTF-8.
+///
+/// Deleting a key appends a tombstone: a record without a value. Records of
+/// expiring keys start their value with the expiration time, in milliseconds
+/// since the Unix epoch (u64). It is counted in the value length.
+///
+/// Every change is numbered by the writer, in the order it is committed. The
+/// sequence number comes first in the value, with the `SEQUENCED` flag.
+/// Records written before changes were numbered have none.
+///
+/// The records of a transaction are written in a row with the `TRANSACTION`
+/// flag, followed by a `COMMIT` record. Flagged records that are not
+/// committed were cut by a crash and are ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub key: String,
+
+    /// `None` for a tombstone.
+    pub value: Option<Bytes>,
+
+    /// When the key expires, `None` if it does not.
+    pub expires_at: Option<SystemTime>,
+
+    /// Sequence number of the change, `0` until the writer numbers it and for
+    /// the records written before changes were numbered.
+    pub seq: u64,
+}
+
+/// Decoded fixed-size start of a record, used to know how many more bytes to
+/// read.
+#[derive(Debug)]
+pub struct Header {
+    crc: u32,
+    kind: u8,
+    key_len: u32,
+    value_len: u32,
+}
+
+impl Header {
+    pub fn parse(mut src: &[u8]) -> Header {
+        Header {
+            crc: src.get_u32(),
+            kind: src.get_u8(),
+            key_len: src.get_u32(),
+            value_len: src.get_u32(),
+        }
+    }
+
+    /// Number of bytes following the header.
+    pub fn body_len(&self) -> usize {
+        self.key_len as usize + self.value_len as usize
+    }
+
+    /// Returns `true` if the record was written by a transaction.
+    pub fn in_transaction(&self) -> bool {
+        self.kind & TRANSACTION != 0
+    }
+
+    /// Returns `true` if the record commits a transaction.
+    pub fn is_commit(&self) -> bool {
+        self.kind == COMMIT
+    }
+
+    /// Returns `true` if the record only carries a sequence number.
+    pub fn is_mark(&self) -> bool {
+        self.kind == MARK | SEQUENCED
+    }
+
+    /// Returns `true` if the kind of the record is one this version writes.
+    /// A header of an unknown kind can not be trusted for its lengths either.
+    pub fn has_known_kind(&self) -> bool {
+        matches!(self.kind & !(TRANSACTION | SEQUENCED), SET | DEL | SET_EX) || self.kind == COMMIT || self.is_mark()
+    }
+
+    /// Check the checksum of the record against its `body`.
+    fn verify(&self, body: &[u8]) -> Result<()> {
+        if body.len() != self.body_len() {
+            bail!("record body is {:?} bytes long, expected {:?}", body.len(), self.body_len());
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&[self.kind]);
+        hasher.update(&self.key_len.to_be_bytes());
+        hasher.update(&self.value_len.to_be_bytes());
+        hasher.update(body);
+        if hasher.finalize() != self.crc {
+            bail!("record checksum mismatch");
+        }
+        return Ok(());
+    }
+}
+
+impl Record {
+    pub fn new(key: impl ToString, value: Bytes) -> Record {
+        Record::with_expiration(key, value, None)
+    }
+
+    pub fn with_expiration(key: impl ToString, value: Bytes, expires_at: Option<SystemTime>) -> Record {
+        Record {
+            key: key.to_string(),
+            value: Some(value),
+            expires_at,
+            seq: 0,
+        }
+    }
+
+    pub fn tombstone(key: impl ToString) -> Record {
+        Record {
+            key: key.to_string(),
+            value: None,
+            expires_at: None,
+            seq: 0,
+        }
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.value.is_none()
+    }
+
+    /// Returns `true` if the key has expired at `now`.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.map(|when| when <= now).unwrap_or(false)
+    }
+
+    /// Number of bytes of the encoded record.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.key.len() + self.value_len()
+    }
+
+    /// Number of bytes after the key, including the sequence number and the
+    /// expiration time.
+    fn value_len(&self) -> usize {
+        let seq_len = if self.seq != 0 { 8 } else { 0 };
+        let expiration_len = if self.expires_at.is_some() { 8 } else { 0 };
+        seq_len + expiration_len + self.value.as_ref().map(|value| value.len()).unwrap_or(0)
+    }
+
+    pub fn encode(&self) -> Result<BytesMut> {
+        self.encode_kind(0)
+    }
+
+    /// Encode the record as part of a transaction.
+    pub fn encode_in_transaction(&self) -> Result<BytesMut> {
+        self.encode_kind(TRANSACTION)
+    }
+
+    fn encode_kind(&self, mut flags: u8) -> Result<BytesMut> {
+        let kind = match (&self.value, self.expires_at) {
+            (None, _) => DEL,
+            (Some(_), None) => SET,
+            (Some(_), Some(_)) => SET_EX,
+        };
+        if self.seq != 0 {
+            flags |= SEQUENCED;
+        }
+
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        buf.put_u32(0);
+        buf.put_u8(kind | flags);
+        buf.put_u32(self.key.len().try_into()?);
+        buf.put_u32(self.value_len().try_into()?);
+        buf.put(self.key.as_bytes());
+        if self.seq != 0 {
+            buf.put_u64(self.seq);
+        }
+        if let Some(when) = self.expires_at {
+            buf.put_u64(to_millis(when)?);
+        }
+        if let Some(value) = &self.value {
+            buf.put(&value[..]);
+        }
+
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_be_bytes());
+        return Ok(buf);
+    }
+
+    /// Decode a record from its header and the `header.body_len()` bytes that
+    /// follow it, verifying its checksum.
+    pub fn decode(header: &Header, body: &[u8]) -> Result<Record> {
+        header.verify(body)?;
+        return Record::decode_verified(header, Bytes::copy_from_slice(body));
+    }
+
+    /// Like `Record::decode`, but the value is a slice of `body` instead of a
+    /// copy.
+    pub fn decode_bytes(header: &Header, body: Bytes) -> Result<Record> {
+        header.verify(&body)?;
+        return Record::decode_verified(header, body);
+    }
+
+    fn decode_verified(header: &Header, mut body: Bytes) -> Result<Record> {
+        let mut value = body.split_off(header.key_len as usize);
+        let key = String::from_utf8(body.to_vec()).map_err(|_| "record key is not valid UTF-8")?;
+        let seq = if header.kind & SEQUENCED == 0 {
+            0
+        } else if value.len() >= 8 {
+            value.get_u64()
+        } else {
+            bail!("record is too short for its sequence number");
+        };
+        let (value, expires_at) = match header.kind & !(TRANSACTION | SEQUENCED) {
+            SET => (Some(value), None),
+            SET_EX if value.len() >= 8 => {
+                let when = (&value[..8]).get_u64();
+                (Some(value.slice(8..)), Some(from_millis(when)))
+            }
+            SET_EX => bail!("expiring record is too short for its expiration time"),
+            DEL if value.is_empty() => (None, None),
+            DEL => bail!("tombstone record has a value"),
+            kind => bail!("unknown record kind {:?}", kind),
+        };
+        return Ok(Record { key, value, expires_at, seq });
+    }
+}
+
+/// Encode the record committing a transaction of `count` records.
+pub fn encode_commit(count: u32) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + 4);
+    buf.put_u32(0);
+    buf.put_u8(COMMIT);
+    buf.put_u32(0);
+    buf.put_u32(4);
+    buf.put_u32(count);
+
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_be_bytes());
+    return buf;
+}
+
+/// Decode a commit record, verifying its checksum. Returns the number of
+/// records of the transaction.
+pub fn decode_commit(header: &Header, mut body: &[u8]) -> Result<u32> {
+    header.verify(body)?;
+    if !header.is_commit() || header.key_len != 0 || body.len() != 4 {
+        bail!("invalid commit record");
+    }
+    return Ok(body.get_u32());
+}
+
+/// Encode a record marking the sequence numbers up to `seq` as used.
+pub fn encode_mark(seq: u64) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + 8);
+    buf.put_u32(0);
+    buf.put_u8(MARK | SEQUENCED);
+    buf.put_u32(0);
+    buf.put_u32(8);
+    buf.put_u64(seq);
+
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_be_bytes());
+    return buf;
+}
+
+/// Decode a mark record, verifying its checksum. Returns its sequence number.
+pub fn decode_mark(header: &Header, mut body: &[u8]) -> Result<u64> {
+    header.verify(body)?;
+    if !header.is_mark() || header.key_len != 0 || body.len() != 8 {
+        bail!("invalid mark record");
+    }
+    return Ok(body.get_u64());
+}
+
+/// Milliseconds elapsed between the Unix epoch and `when`.
+pub fn to_millis(when: SystemTime) -> Result<u64> {
+    return Ok(when.duration_since(UNIX_EPOCH)?.as_millis().try_into()?);
+}
+
+pub fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encode_decode() {
+        let record = Record::new("key,with\ncomma", Bytes::from(&b"binary\n,\x00value"[..]));
+        let buf = record.encode().unwrap();
+        assert_eq!(buf.len(), record.encoded_len());
+
+        let header = Header::parse(&buf[..HEADER_LEN]);
+        assert_eq!(header.body_len(), buf.len() - HEADER_LEN);
+        assert_eq!(Record::decode(&header, &buf[HEADER_LEN..]).unwrap(), record);
+
+        let mut corrupted = buf.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        assert!(Record::decode(&header, &corrupted[HEADER_LEN..]).is_err());
+
+        let when = from_millis(1_700_000_000_123);
+        let expiring = Record::with_expiration("key", Bytes::from("value"), Some(when));
+        let buf = expiring.encode().unwrap();
+        assert_eq!(buf.len(), expiring.encoded_len());
+        let header = Header::parse(&buf[..HEADER_LEN]);
+        let decoded = Record::decode(&header, &buf[HEADER_LEN..]).unwrap();
+        assert_eq!(decoded, expiring);
+        assert!(!decoded.is_expired(when - Duration::from_millis(1)));
+        assert!(decoded.is_expired(when));
+
+        // Decoding from `Bytes` slices the value out of the body.
+        let body = buf.freeze().slice(HEADER_LEN..);
+        let decoded = Record::decode_bytes(&header, body.clone()).unwrap();
+        assert_eq!(decoded, expiring);
+        assert!(body.as_ptr_range().contains(&decoded.value.unwrap().as_ptr()));
+
+        let tombstone = Record::tombstone("key");
+        let buf = tombstone.encode().unwrap();
+        assert_eq!(buf.len(), tombstone.encoded_len());
+        let header = Header::parse(&buf[..HEADER_LEN]);
+        assert_eq!(Record::decode(&header, &buf[HEADER_LEN..]).unwrap(), tombstone);
+
+        let buf = expiring.encode_in_transaction().unwrap();
+        let header = Header::parse(&buf[..HEADER_LEN]);
+        assert!(header.in_transaction());
+        assert_eq!(Record::decode(&header, &buf[HEADER_LEN..]).unwrap(), expiring);
+
+        let mut sequenced = expiring.clone();
+        sequenced.seq = 42;
+        let buf = sequenced.encode_in_transaction().unwrap();
+        assert_eq!(buf.len(), sequenced.encoded_len());
+        let header = Header::parse(&buf[..HEADER_LEN]);
+        assert!(header.in_transaction() && header.has_known_kind());
+        assert_eq!(Record::decode(&header, &buf[HEADER_LEN..]).unwrap(), sequenced);
+        let mut tombstone = Record::tombstone("key");
+        tombstone.seq = 43;
+        let buf = tombstone.encode().unwrap();
+        let header = Header::parse(&buf[..HEADER_LEN]);
+        assert_eq!(Record::decode(&header, &buf[HEADER_LEN..]).unwrap(), tombstone);
+
+        let buf = encode_mark(44);
+        let header = Header::parse(&buf[..HEADER_LEN]);
+        assert!(header.is_mark() && header.has_known_kind());
+        assert_eq!(decode_mark(&header, &buf[HEADER_LEN..]).unwrap(), 44);
+        assert!(Record::decode(&header, &buf[HEADER_LEN..]).is_err());
+
+        let buf = encode_commit(3);
+        let header = Header::parse(&buf[..HEADER_LEN]);
+        assert!(header.is_commit() && !header.in_transaction());
+        assert_eq!(decode_commit(&header, &buf[HEADER_LEN..]).unwrap(), 3);
+        assert!(Record::decode(&header, &buf[HEADER_LEN..]).is_err());
+    }
+}
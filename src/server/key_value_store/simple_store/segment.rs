@@ -0,0 +1,725 @@
+use crate::Result;
+
+use bytes::Bytes;
+use memmap2::Mmap;
+use simple_error::bail;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::hint;
+use super::record::{decode_commit, decode_mark, encode_mark, Header, Record, HEADER_LEN, MAGIC};
+
+/// Identifies a segment file and orders segments from oldest to newest.
+///
+/// Segments started by the writer are numbered `(n, 0)`. The segments written
+/// by a merge are numbered `(n, 1)`, `(n, 2)`, ... where `(n, _)` is the
+/// newest of the merged segments: they sort right after their inputs and
+/// before anything written since, so replaying the segments in order always
+/// yields the latest value of every key, even if the merge was interrupted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SegmentId(pub u64, pub u32);
+
+impl fmt::Display for SegmentId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{:06}.{:03}", self.0, self.1)
+    }
+}
+
+impl SegmentId {
+    /// Path of the segment for the store whose base log path is `base`.
+    pub fn path(&self, base: &Path) -> PathBuf {
+        let mut name = base.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", self));
+        base.with_file_name(name)
+    }
+
+    /// Parse the id out of a segment path, the reverse of `SegmentId::path`.
+    /// Returns `None` if `path` is not a segment of `base`.
+    pub fn from_path(base: &Path, path: &Path) -> Option<SegmentId> {
+        let base_name = base.file_name()?.to_str()?;
+        let name = path.file_name()?.to_str()?;
+        let mut parts = name.strip_prefix(base_name)?.strip_prefix('.')?.split('.');
+
+        let id = parts.next()?.parse().ok()?;
+        let sub = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        return Some(SegmentId(id, sub));
+    }
+}
+
+/// Position of a record in the log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub segment: SegmentId,
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// Key and location of a record, as listed by a scan or a hint file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: String,
+    pub location: Location,
+
+    /// True if the record deletes the key.
+    pub tombstone: bool,
+
+    /// When the key expires, `None` if it does not.
+    pub expires_at: Option<SystemTime>,
+}
+
+/// A log segment file, opened for reading.
+///
+/// The open file handle is shared by all readers through an `Arc`. Records
+/// are read with positional reads (`pread`), which do not move a file offset,
+/// so concurrent readers need neither a lock nor a handle of their own. A
+/// merge can remove the file while a reader still holds the segment: the
+/// handle keeps working until the last reader drops it.
+///
+/// Sealed segments can also be memory-mapped, see `Segment::map`. Records are
+/// then read straight from the mapping and their values are slices of it,
+/// without any copy.
+#[derive(Debug)]
+pub struct Segment {
+    pub id: SegmentId,
+    pub path: PathBuf,
+    file: File,
+
+    /// The whole file, once mapped.
+    mapping: OnceLock<Bytes>,
+
+    /// Size of the file in bytes.
+    len: AtomicU64,
+
+    /// Bytes taken by records that have since been overwritten.
+    dead_bytes: AtomicU64,
+
+    /// True once the segment has an up to date hint file.
+    has_hint: AtomicBool,
+
+    /// Highest sequence number of the records of the segment, see
+    /// `Segment::last_seq`.
+    last_seq: AtomicU64,
+}
+
+impl Segment {
+    /// Create an empty segment file, holding only the magic bytes.
+    pub fn create(path: &Path, id: SegmentId) -> Result<Segment> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(MAGIC)?;
+        file.sync_all()?;
+        return Segment::open(path, id);
+    }
+
+    pub fn open(path: &Path, id: SegmentId) -> Result<Segment> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        return Ok(Segment {
+            id,
+            path: path.to_path_buf(),
+            file,
+            mapping: OnceLock::new(),
+            len: AtomicU64::new(len),
+            dead_bytes: AtomicU64::new(0),
+            has_hint: AtomicBool::new(false),
+            last_seq: AtomicU64::new(0),
+        });
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn dead_bytes(&self) -> u64 {
+        self.dead_bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn add_dead_bytes(&self, len: u32) {
+        self.dead_bytes.fetch_add(len as u64, Ordering::SeqCst);
+    }
+
+    /// Highest sequence number used by the segment: the one of its newest
+    /// change, or of the mark record a merge wrote for the changes it
+    /// dropped. `0` until the segment is scanned or its hint file is loaded.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::SeqCst)
+    }
+
+    /// Record that the segment uses sequence numbers up to `seq`.
+    pub fn advance_seq(&self, seq: u64) {
+        self.last_seq.fetch_max(seq, Ordering::SeqCst);
+    }
+
+    /// Map the file in memory and serve the reads from the mapping. Only
+    /// sealed segments may be mapped: their file never changes again.
+    ///
+    /// The mapping is released once the segment and every value read from it
+    /// are dropped. It stays valid after a merge unlinks the file, but the
+    /// disk space is only reclaimed once it is released.
+    pub fn map(&self) -> Result<()> {
+        if self.mapping.get().is_some() {
+            return Ok(());
+        }
+        // Safety: sealed segments are never written again, only unlinked,
+        // which keeps the mapped pages readable. The mapping breaks if
+        // another process truncates the file, as for any other file of the
+        // data directory, which is locked against other servers.
+        let mmap = unsafe { Mmap::map(&self.file)? };
+        if mmap.len() as u64 != self.len() {
+            bail!("segment {} is {:?} bytes long, expected {:?}", self.id, mmap.len(), self.len());
+        }
+        let _ = self.mapping.set(Bytes::from_owner(mmap));
+        return Ok(());
+    }
+
+    /// Returns `true` if the reads are served from a mapping of the file.
+    pub fn is_mapped(&self) -> bool {
+        self.mapping.get().is_some()
+    }
+
+    pub fn has_hint(&self) -> bool {
+        self.has_hint.load(Ordering::SeqCst)
+    }
+
+    /// Write the hint file of the segment from the key and location of all of
+    /// its records.
+    pub fn write_hint(&self, entries: &[Entry]) -> Result<()> {
+        hint::write(&self.path, entries, self.last_seq())?;
+        self.has_hint.store(true, Ordering::SeqCst);
+        return Ok(());
+    }
+
+    /// Load the key and location of all the records from the hint file.
+    pub fn read_hint(&self) -> Result<Vec<Entry>> {
+        let hint = hint::read(&self.path, self.id)?;
+        self.advance_seq(hint.last_seq);
+        self.has_hint.store(true, Ordering::SeqCst);
+        return Ok(hint.entries);
+    }
+
+    /// Delete the segment file and its hint file.
+    pub fn remove_files(&self) -> Result<()> {
+        std::fs::remove_file(&self.path)?;
+        match std::fs::remove_file(hint::path(&self.path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        return Ok(());
+    }
+
+    /// Read every record of the segment, checking their checksum. Returns the
+    /// entry of each record, in file order.
+    ///
+    /// Records of transactions are only returned once their commit record is
+    /// read. Commit records themselves have no entry.
+    ///
+    /// A crash in the middle of a write leaves a torn record at the end of
+    /// the segment being written: a record cut short, or whose last bytes did
+    /// not reach the disk. If `torn_tail` is set, the segment is then
+    /// considered to end right before it, and `ActiveSegment::new` truncates
+    /// the file there. Any other unreadable record fails the scan with a
+    /// `Corruption`, see `Segment::repair`.
+    pub fn scan(&self, torn_tail: bool) -> Result<Vec<Entry>> {
+        let read = self.read_records(torn_tail, false)?;
+        if let Some(offset) = read.torn_at {
+            self.len.store(offset, Ordering::SeqCst);
+        }
+        self.advance_seq(read.last_seq);
+
+        let entries = read
+            .records
+            .into_iter()
+            .map(|(record, location)| Entry {
+                tombstone: record.is_tombstone(),
+                expires_at: record.expires_at,
+                key: record.key,
+                location,
+            })
+            .collect();
+        return Ok(entries);
+    }
+
+    /// Read every record that can be read back, like `scan`, but going on
+    /// past unreadable bytes instead of failing.
+    ///
+    /// Unreadable bytes are skipped up to the next offset a record can be
+    /// read at. The records of a transaction whose commit can not be trusted
+    /// anymore are dropped with them.
+    pub fn salvage(&self, torn_tail: bool) -> Result<Salvage> {
+        return self.read_records(torn_tail, true);
+    }
+
+    /// Rewrite a corrupted segment with only the records `salvage` reads back,
+    /// committed transactions becoming plain records. The corrupted file is
+    /// kept next to the new one.
+    pub fn repair(&self) -> Result<Repair> {
+        let salvage = self.salvage(false)?;
+
+        let mut backup = self.path.file_name().unwrap_or_default().to_os_string();
+        backup.push(format!(".corrupt-{}", SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()));
+        let backup = self.path.with_file_name(backup);
+        std::fs::hard_link(&self.path, &backup)?;
+
+        // The new segment replaces the corrupted one with a rename, the
+        // segment path always holds one or the other.
+        let mut writer = SegmentWriter::create(self.path.clone(), self.id)?;
+        for (record, _) in salvage.records.iter() {
+            writer.append(record)?;
+        }
+        writer.mark(salvage.last_seq)?;
+        return Ok(Repair {
+            segment: writer.finish()?,
+            damaged: salvage.damaged,
+            backup,
+        });
+    }
+
+    /// Read the records of the segment, see `scan` and `salvage`. Without
+    /// `salvage`, the first unreadable record fails with a `Corruption`.
+    fn read_records(&self, torn_tail: bool, salvage: bool) -> Result<Salvage> {
+        let data = self.contents()?;
+        let mut scan = Scan::default();
+        let mut damaged: Vec<Damage> = Vec::new();
+        let mut torn_at = None;
+        let mut offset = MAGIC.len();
+        while offset < data.len() {
+            let reason = match scan.next(self.id, &data, offset) {
+                Ok(len) => {
+                    offset += len;
+                    continue;
+                }
+                Err(reason) => reason,
+            };
+
+            if torn_tail && is_torn(&data.slice(offset..)) {
+                torn_at = Some(offset as u64);
+                break;
+            }
+            if !salvage {
+                return Err(Corruption {
+                    path: self.path.clone(),
+                    offset: offset as u64,
+                    file_len: data.len() as u64,
+                    reason,
+                }
+                .into());
+            }
+
+            // The commit of a transaction cut by the unreadable bytes may
+            // follow them, and commit records that are not its own.
+            scan.pending.clear();
+            let start = offset as u64;
+            offset += 1;
+            while offset < data.len() && parse(&data.slice(offset..)).is_err() {
+                offset += 1;
+            }
+            match damaged.last_mut() {
+                Some(last) if last.range.end == start => last.range.end = offset as u64,
+                _ => damaged.push(Damage {
+                    range: start..offset as u64,
+                    reason,
+                }),
+            }
+        }
+
+        return Ok(Salvage {
+            records: scan.records,
+            damaged,
+            torn_at,
+            last_seq: scan.last_seq,
+        });
+    }
+
+    /// Read the committed records between the offsets `start` and `end`,
+    /// which must both be at the start of a write. The segment is read as
+    /// it is being appended to, so its last records may not be synced yet.
+    pub fn read_range(&self, start: u64, end: u64) -> Result<Vec<(Record, Location)>> {
+        let data = self.contents()?;
+        if end > data.len() as u64 {
+            bail!("range ends at {:?}, past the end of segment {}", end, self.id);
+        }
+        let data = data.slice(..end as usize);
+        let mut scan = Scan::default();
+        let mut offset = start as usize;
+        while offset < data.len() {
+            match scan.next(self.id, &data, offset) {
+                Ok(len) => offset += len,
+                Err(reason) => {
+                    return Err(Corruption {
+                        path: self.path.clone(),
+                        offset: offset as u64,
+                        file_len: self.len(),
+                        reason,
+                    }
+                    .into())
+                }
+            }
+        }
+        return Ok(scan.records);
+    }
+
+    /// The content of the file: its mapping, or a temporary one if it is not
+    /// mapped.
+    fn contents(&self) -> Result<Bytes> {
+        if let Some(mapping) = self.mapping.get() {
+            return Ok(mapping.clone());
+        }
+
+        // Safety: see `Segment::map`. The writer only appends to the active
+        // segment, the bytes already written do not change.
+        let mmap = unsafe { Mmap::map(&self.file)? };
+        if mmap.len() < MAGIC.len() || &mmap[..MAGIC.len()] != MAGIC {
+            bail!("{:?} is not a binary log file", self.path);
+        }
+        return Ok(Bytes::from_owner(mmap));
+    }
+
+    /// Read the raw bytes of the record at `location`. They are a slice of
+    /// the mapping if the segment is mapped.
+    pub fn read_raw(&self, location: &Location) -> Result<Bytes> {
+        if let Some(mapping) = self.mapping.get() {
+            let start = location.offset as usize;
+            let end = start + location.len as usize;
+            if end > mapping.len() {
+                bail!("record at {:?} ends past the end of segment {}", location, self.id);
+            }
+            return Ok(mapping.slice(start..end));
+        }
+
+        let mut buf = vec![0u8; location.len as usize];
+        self.file.read_exact_at(&mut buf, location.offset)?;
+        return Ok(Bytes::from(buf));
+    }
+
+    /// Read and decode the record at `location`.
+    pub fn read(&self, location: &Location) -> Result<Record> {
+        let buf = self.read_raw(location)?;
+        if buf.len() < HEADER_LEN {
+            bail!("record at {:?} is shorter than a header", location);
+        }
+        let header = Header::parse(&buf[..HEADER_LEN]);
+        return Record::decode_bytes(&header, buf.slice(HEADER_LEN..));
+    }
+}
+
+/// An unreadable record, which fails the scan of its segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Corruption {
+    pub path: PathBuf,
+
+    /// Offset of the record in the segment.
+    pub offset: u64,
+
+    /// Size of the segment file.
+    pub file_len: u64,
+
+    /// Why the record can not be read.
+    pub reason: String,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "log file {:?} data is corrupted at byte {:?} of {:?}: {}",
+            self.path, self.offset, self.file_len, self.reason
+        )
+    }
+}
+
+impl std::error::Error for Corruption {}
+
+/// Bytes of a segment that can not be read back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Damage {
+    pub range: Range<u64>,
+
+    /// Why the record at the start of the range can not be read.
+    pub reason: String,
+}
+
+/// Outcome of `Segment::salvage`.
+#[derive(Debug)]
+pub struct Salvage {
+    /// The committed records, with their location, in file order.
+    pub records: Vec<(Record, Location)>,
+
+    pub damaged: Vec<Damage>,
+
+    /// Where a torn write starts at the end of the segment, if it has one.
+    pub torn_at: Option<u64>,
+
+    /// Highest sequence number of the records and mark records read back.
+    pub last_seq: u64,
+}
+
+/// Outcome of `Segment::repair`.
+#[derive(Debug)]
+pub struct Repair {
+    /// The rewritten segment.
+    pub segment: Segment,
+
+    /// The bytes of the corrupted file that were dropped.
+    pub damaged: Vec<Damage>,
+
+    /// Where the corrupted file is kept.
+    pub backup: PathBuf,
+}
+
+/// Records read so far by a scan of a segment, with their location.
+#[derive(Default)]
+struct Scan {
+    records: Vec<(Record, Location)>,
+
+    /// Records of the transaction being read, waiting for its commit.
+    pending: Vec<(Record, Location)>,
+
+    /// Highest sequence number of the records read so far.
+    last_seq: u64,
+}
+
+impl Scan {
+    /// Read the record at `offset` of `data`. Returns its length, or why it
+    /// can not be read.
+    fn next(&mut self, id: SegmentId, data: &Bytes, offset: usize) -> std::result::Result<usize, String> {
+        let (parsed, len) = parse(&data.slice(offset..))?;
+        match parsed {
+            Parsed::Commit(count) => {
+                let count = count as usize;
+                if count > self.pending.len() {
+                    return Err(format!("commit of {:?} records, only {:?} are pending", count, self.pending.len()));
+                }
+                // Older pending records belong to a transaction that was cut
+                // by a crash.
+                let committed = self.pending.len() - count;
+                for (record, location) in self.pending.drain(committed..) {
+                    self.last_seq = self.last_seq.max(record.seq);
+                    self.records.push((record, location));
+                }
+                self.pending.clear();
+            }
+            Parsed::Mark(seq) => {
+                self.pending.clear();
+                self.last_seq = self.last_seq.max(seq);
+            }
+            Parsed::Record { record, in_transaction } => {
+                let location = Location {
+                    segment: id,
+                    offset: offset as u64,
+                    len: len.try_into().map_err(|_| format!("record is {:?} bytes long", len))?,
+                };
+                if in_transaction {
+                    self.pending.push((record, location));
+                } else {
+                    self.pending.clear();
+                    self.last_seq = self.last_seq.max(record.seq);
+                    self.records.push((record, location));
+                }
+            }
+        }
+        return Ok(len);
+    }
+}
+
+/// A record read from a segment.
+enum Parsed {
+    Record {
+        record: Record,
+        in_transaction: bool,
+    },
+
+    /// Commits the given number of records.
+    Commit(u32),
+
+    /// Marks the sequence numbers up to the given one as used.
+    Mark(u64),
+}
+
+/// Decode the record `buf` starts with, verifying its checksum. Returns it
+/// with its length, or why it can not be read.
+fn parse(buf: &Bytes) -> std::result::Result<(Parsed, usize), String> {
+    if buf.len() < HEADER_LEN {
+        return Err(format!("header is cut after {:?} bytes", buf.len()));
+    }
+    let header = Header::parse(&buf[..HEADER_LEN]);
+    let len = HEADER_LEN + header.body_len();
+    if buf.len() < len {
+        return Err(format!("record is {:?} bytes long, only {:?} are left", len, buf.len()));
+    }
+
+    let body = buf.slice(HEADER_LEN..len);
+    if header.is_commit() {
+        let count = decode_commit(&header, &body).map_err(|e| e.to_string())?;
+        return Ok((Parsed::Commit(count), len));
+    }
+    if header.is_mark() {
+        let seq = decode_mark(&header, &body).map_err(|e| e.to_string())?;
+        return Ok((Parsed::Mark(seq), len));
+    }
+    let in_transaction = header.in_transaction();
+    let record = Record::decode_bytes(&header, body).map_err(|e| e.to_string())?;
+    return Ok((Parsed::Record { record, in_transaction }, len));
+}
+
+/// Returns `true` if an unreadable record followed by `rest`, up to the end
+/// of its segment, was torn by a crash: the record runs to the end of the
+/// file, or nothing but zeros are left, as file systems may leave after a
+/// crash. A crash only tears the last write, a record followed by a valid one
+/// is damaged instead, be it its length that is corrupted.
+fn is_torn(rest: &Bytes) -> bool {
+    if rest.iter().all(|byte| *byte == 0) {
+        return true;
+    }
+    if rest.len() >= HEADER_LEN {
+        // The lengths of a header of unknown kind are garbage.
+        let header = Header::parse(&rest[..HEADER_LEN]);
+        if !header.has_known_kind() || HEADER_LEN + header.body_len() < rest.len() {
+            return false;
+        }
+    }
+    return !(1..rest.len()).any(|offset| parse(&rest.slice(offset..)).is_ok());
+}
+
+/// The segment new records are appended to.
+#[derive(Debug)]
+pub struct ActiveSegment {
+    pub segment: Arc<Segment>,
+    file: File,
+}
+
+impl ActiveSegment {
+    pub fn new(segment: Arc<Segment>) -> Result<ActiveSegment> {
+        let file = OpenOptions::new().append(true).open(&segment.path)?;
+
+        // Drop the partial record a scan stopped at, see `Segment::scan`.
+        if file.metadata()?.len() > segment.len() {
+            file.set_len(segment.len())?;
+            file.sync_all()?;
+        }
+        Ok(ActiveSegment { segment, file })
+    }
+
+    /// Append encoded records and return where they were written. They are
+    /// only durable once `sync` returns.
+    pub fn write(&mut self, buf: &[u8]) -> Result<Location> {
+        let offset = self.segment.len();
+        if let Err(e) = self.file.write_all(buf) {
+            // Drop what was written of `buf`, the next records must start
+            // right at the end of the segment.
+            let _ = self.file.set_len(offset);
+            return Err(e.into());
+        }
+        self.segment.len.fetch_add(buf.len() as u64, Ordering::SeqCst);
+
+        return Ok(Location {
+            segment: self.segment.id,
+            offset,
+            len: buf.len().try_into()?,
+        });
+    }
+
+    /// Sync the records written so far to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_data()?;
+        return Ok(());
+    }
+}
+
+/// Writes a whole new segment in one go, as done by merges.
+///
+/// The records are buffered and written to a temporary file which is synced
+/// and renamed to the segment path by `finish`.
+pub struct SegmentWriter {
+    id: SegmentId,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    len: u64,
+
+    /// Entries of the records written so far, for the hint file.
+    entries: Vec<Entry>,
+
+    /// Highest sequence number written so far.
+    last_seq: u64,
+}
+
+impl SegmentWriter {
+    pub fn create(path: PathBuf, id: SegmentId) -> Result<SegmentWriter> {
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(MAGIC)?;
+
+        return Ok(SegmentWriter {
+            id,
+            path,
+            tmp_path,
+            writer,
+            len: MAGIC.len() as u64,
+            entries: Vec::new(),
+            last_seq: 0,
+        });
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn append(&mut self, record: &Record) -> Result<Location> {
+        let buf = record.encode()?;
+        let offset = self.len;
+        self.writer.write_all(&buf[..])?;
+        self.len += buf.len() as u64;
+        self.last_seq = self.last_seq.max(record.seq);
+
+        let location = Location {
+            segment: self.id,
+            offset,
+            len: buf.len().try_into()?,
+        };
+        self.entries.push(Entry {
+            key: record.key.clone(),
+            location,
+            tombstone: record.is_tombstone(),
+            expires_at: record.expires_at,
+        });
+        return Ok(location);
+    }
+
+    /// Append a mark record if `seq` is higher than the sequence numbers of
+    /// the records written so far, so the segment keeps it used.
+    pub fn mark(&mut self, seq: u64) -> Result<()> {
+        if seq > self.last_seq {
+            let buf = encode_mark(seq);
+            self.writer.write_all(&buf[..])?;
+            self.len += buf.len() as u64;
+            self.last_seq = seq;
+        }
+        return Ok(());
+    }
+
+    /// Sync the segment, move it to its final path and write its hint file.
+    pub fn finish(self) -> Result<Segment> {
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&self.tmp_path, &self.path)?;
+
+        let segment = Segment::open(&self.path, self.id)?;
+        segment.advance_seq(self.last_seq);
+        segment.write_hint(&self.entries)?;
+        return Ok(segment);
+    }
+}
@@ -0,0 +1,522 @@
+//! Group commit of the writes of a `SimpleStore`.
+//!
+//! Writers do not touch the log themselves: they queue their operation for
+//! the writer thread and wait for its reply. The thread takes every operation
+//! queued at once, resolves them in order, writes all of their records with a
+//! single `write` and points the index to them. It then syncs them with a
+//! single sync, if the `FsyncPolicy` asks for it, and replies to every writer
+//! of the group. A failed sync fails the replies, but the records stay in the
+//! log and the index.
+//!
+//! Operations that depend on the current record of a key, such as deleting
+//! or expiring it, are resolved by the thread against the index and the
+//! records of the operations queued before them in the same group.
+//!
+//! The thread numbers every record it appends with the next sequence number,
+//! and publishes how far the log is synced for the streams of changes.
+
+use crate::Result;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::BytesMut;
+use simple_error::{bail, SimpleError};
+use tokio::sync::{oneshot, watch};
+
+use super::record::{encode_commit, Record};
+use super::segment::{ActiveSegment, Location, SegmentId};
+use super::Shared;
+use crate::server::key_value_store::{FsyncPolicy, KeyEvent, Write, WriteBatch};
+
+/// Most operations committed together.
+const MAX_GROUP_LEN: usize = 1024;
+
+/// Delay between two syncs with `FsyncPolicy::EverySec`.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A write queued for the writer thread.
+#[derive(Debug)]
+pub(super) enum Op {
+    /// Append the record. Replies `true`.
+    Put(Record),
+
+    /// Append a tombstone if the key exists. Replies `true` if it did.
+    Delete(String),
+
+    /// Rewrite the record of an existing key with a new expiration time, or
+    /// without one if `expires_at` is `None`. Replies `true` if the key was
+    /// rewritten: it exists and, when removing the expiration, has one.
+    Expire { key: String, expires_at: Option<SystemTime> },
+
+    /// Append the writes of the batch as a transaction. Its expirations start
+    /// when it is committed. Replies `true`.
+    Batch(WriteBatch),
+
+    /// Sync the writes of the group and every write before it, whatever the
+    /// policy. Replies `true`.
+    Sync,
+
+    /// Seal the active segment once the writes queued before are written.
+    /// Replies `true`.
+    #[cfg(test)]
+    Rotate,
+
+    /// Make the next sync of the log fail. Replies `true`.
+    #[cfg(test)]
+    FailSync,
+}
+
+#[derive(Debug)]
+pub(super) struct Request {
+    op: Op,
+    reply: Reply,
+}
+
+/// Where the writer thread replies, depending on how the writer waits.
+#[derive(Debug)]
+enum Reply {
+    Blocking(SyncSender<Result<bool>>),
+    Async(oneshot::Sender<Result<bool>>),
+}
+
+impl Reply {
+    fn send(self, result: Result<bool>) {
+        // The writer may have given up waiting, the reply is then dropped.
+        match self {
+            Reply::Blocking(reply) => {
+                let _ = reply.send(result);
+            }
+            Reply::Async(reply) => {
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+impl Shared {
+    /// Queue `op` for the writer thread and block until it is committed.
+    pub(super) fn submit(&self, op: Op) -> Result<bool> {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.queue(op, Reply::Blocking(reply))?;
+        match result.recv() {
+            Ok(result) => return result,
+            Err(_) => bail!("the writer of the log has stopped"),
+        }
+    }
+
+    /// Queue `op` for the writer thread and wait until it is committed,
+    /// without blocking the calling task.
+    pub(super) async fn submit_async(&self, op: Op) -> Result<bool> {
+        let (reply, result) = oneshot::channel();
+        self.queue(op, Reply::Async(reply))?;
+        match result.await {
+            Ok(result) => return result,
+            Err(_) => bail!("the writer of the log has stopped"),
+        }
+    }
+
+    fn queue(&self, op: Op, reply: Reply) -> Result<()> {
+        if self.writer.lock().unwrap().send(Request { op, reply }).is_err() {
+            bail!("the writer of the log has stopped");
+        }
+        return Ok(());
+    }
+}
+
+/// End of the part of the log that is on disk, up to which changes are
+/// streamed: the records before `len` in `segment`, and every record of the
+/// segments before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Durable {
+    pub segment: SegmentId,
+    pub len: u64,
+}
+
+/// Owns the active segment and commits the queued writes.
+///
+/// The thread only holds a weak reference to the shared state: it stops once
+/// the store is dropped, syncing what it wrote last.
+pub(super) struct Writer {
+    logger: slog::Logger,
+    shared: Weak<Shared>,
+    active: ActiveSegment,
+    requests: Receiver<Request>,
+    fsync: FsyncPolicy,
+
+    /// True if records were written since the last sync.
+    dirty: bool,
+    last_sync: Instant,
+
+    /// Sequence number of the next record.
+    next_seq: u64,
+
+    /// Publishes the end of the log once it is synced, or once it is written
+    /// with `FsyncPolicy::Never`.
+    durable: watch::Sender<Durable>,
+
+    /// True if the next sync must fail.
+    #[cfg(test)]
+    fail_sync: bool,
+}
+
+/// Records of the operations of a group, encoded back to back.
+#[derive(Default)]
+struct Group {
+    buf: BytesMut,
+
+    /// Every record with its offset and length in `buf`.
+    records: Vec<(Record, usize, usize)>,
+
+    /// Length of the commit records of the transactions of the group.
+    commits_len: usize,
+
+    /// Sequence numbers of the first and last records of the group.
+    first_seq: u64,
+    last_seq: u64,
+}
+
+impl Writer {
+    /// Spawn the writer thread of `shared`, which takes over the active
+    /// segment and commits the requests sent to `shared.writer`. Records are
+    /// numbered from `next_seq` on.
+    pub(super) fn spawn(
+        logger: slog::Logger,
+        shared: &Arc<Shared>,
+        active: ActiveSegment,
+        requests: Receiver<Request>,
+        next_seq: u64,
+        durable: watch::Sender<Durable>,
+    ) -> Result<()> {
+        let writer = Writer {
+            logger,
+            shared: Arc::downgrade(shared),
+            active,
+            requests,
+            fsync: shared.options.fsync,
+            dirty: false,
+            last_sync: Instant::now(),
+            next_seq,
+            durable,
+            #[cfg(test)]
+            fail_sync: false,
+        };
+        std::thread::Builder::new()
+            .name("simple-store-writer".to_string())
+            .spawn(move || writer.run())?;
+        return Ok(());
+    }
+
+    fn run(mut self) {
+        loop {
+            let first = match self.sync_deadline() {
+                Some(deadline) => match self.requests.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(request) => Some(request),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match self.requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => break,
+                },
+            };
+
+            if let Some(first) = first {
+                let shared = match self.shared.upgrade() {
+                    Some(shared) => shared,
+                    None => break,
+                };
+                let mut requests = vec![first];
+                requests.extend(self.requests.try_iter().take(MAX_GROUP_LEN - 1));
+                self.commit(&shared, requests);
+            }
+
+            // Under a steady stream of writes the queue is never empty long
+            // enough for the timeout to fire.
+            if self.sync_deadline().map(|deadline| deadline <= Instant::now()).unwrap_or(false) {
+                self.sync_logged();
+            }
+        }
+
+        self.sync_logged();
+    }
+
+    /// When the written records must be synced, `None` if there is nothing to
+    /// sync or the policy does not sync in the background.
+    fn sync_deadline(&self) -> Option<Instant> {
+        if self.dirty && self.fsync == FsyncPolicy::EverySec {
+            return Some(self.last_sync + SYNC_INTERVAL);
+        }
+        return None;
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.dirty {
+            #[cfg(test)]
+            if std::mem::take(&mut self.fail_sync) {
+                bail!("injected sync failure");
+            }
+            self.active.sync()?;
+            self.dirty = false;
+        }
+        self.last_sync = Instant::now();
+        self.publish_durable();
+        return Ok(());
+    }
+
+    /// Let the streams of changes read up to the end of the active segment.
+    fn publish_durable(&self) {
+        let durable = Durable {
+            segment: self.active.segment.id,
+            len: self.active.segment.len(),
+        };
+        if *self.durable.borrow() != durable {
+            // Only fails once the store and every stream are dropped.
+            let _ = self.durable.send(durable);
+        }
+    }
+
+    /// Sync in the background, where there is no one to report errors to.
+    fn sync_logged(&mut self) {
+        if let Err(err) = self.sync() {
+            error!(self.logger, "err = {}, failed to sync the log", err);
+        }
+    }
+
+    /// Write the records of `requests` in one go, sync them as the policy
+    /// says, update the index and reply.
+    fn commit(&mut self, shared: &Shared, requests: Vec<Request>) {
+        let now = SystemTime::now();
+        let mut group = Group::default();
+        // Latest record of the keys written by the group, `None` for deleted
+        // keys.
+        let mut overlay: HashMap<String, Option<Record>> = HashMap::new();
+        let mut sync = self.fsync == FsyncPolicy::Always;
+        // Keyspace notifications of the group, published once it is written.
+        let mut events = Vec::new();
+
+        let mut replies = Vec::with_capacity(requests.len());
+        for Request { op, reply } in requests {
+            let result = match op {
+                Op::Sync => {
+                    sync = true;
+                    Ok(true)
+                }
+                #[cfg(test)]
+                Op::Rotate => self.write(shared, std::mem::take(&mut group), true).map(|_| true),
+                #[cfg(test)]
+                Op::FailSync => {
+                    self.fail_sync = true;
+                    Ok(true)
+                }
+                op => {
+                    let expire = matches!(op, Op::Expire { .. });
+                    self.resolve(shared, &overlay, op, now).and_then(|(mut records, transaction, result)| {
+                        group.push(&mut records, transaction, &mut self.next_seq)?;
+                        for record in records {
+                            events.extend(record_events(&record, expire));
+                            let key = record.key.clone();
+                            overlay.insert(key, if record.is_tombstone() { None } else { Some(record) });
+                        }
+                        Ok(result)
+                    })
+                }
+            };
+            replies.push((reply, result));
+        }
+
+        if let Err(err) = self.write(shared, group, false) {
+            error!(self.logger, "err = {}, failed to write to the log", err);
+            for (reply, _) in replies {
+                reply.send(Err(SimpleError::new(format!("failed to write to the log: {}", err)).into()));
+            }
+            return;
+        }
+        // The records are in the log and the index from here on, even if the
+        // sync fails.
+        for (event, key) in events {
+            shared.options.notifier.notify(event, &key);
+        }
+        if sync {
+            if let Err(err) = self.sync() {
+                error!(self.logger, "err = {}, failed to sync the log", err);
+                for (reply, _) in replies {
+                    reply.send(Err(SimpleError::new(format!("failed to sync the log: {}", err)).into()));
+                }
+                return;
+            }
+        }
+        for (reply, result) in replies {
+            reply.send(result);
+        }
+    }
+
+    /// Turn `op` into the records to append. Returns them, whether they form
+    /// a transaction, and the reply of the operation.
+    fn resolve(&self, shared: &Shared, overlay: &HashMap<String, Option<Record>>, op: Op, now: SystemTime) -> Result<(Vec<Record>, bool, bool)> {
+        let read = |key: &str| -> Result<Option<Record>> {
+            match overlay.get(key) {
+                Some(Some(record)) if !record.is_expired(now) => return Ok(Some(record.clone())),
+                Some(_) => return Ok(None),
+                None => return shared.read(key),
+            }
+        };
+
+        match op {
+            Op::Put(record) => return Ok((vec![record], false, true)),
+            Op::Delete(key) => match read(&key)? {
+                Some(_) => return Ok((vec![Record::tombstone(key)], false, true)),
+                None => return Ok((Vec::new(), false, false)),
+            },
+            Op::Expire { key, expires_at } => match read(&key)? {
+                Some(mut record) if expires_at.is_some() || record.expires_at.is_some() => {
+                    record.expires_at = expires_at;
+                    return Ok((vec![record], false, true));
+                }
+                _ => return Ok((Vec::new(), false, false)),
+            },
+            Op::Batch(batch) => {
+                let mut records = Vec::with_capacity(batch.len());
+                // Whether the keys written earlier in the batch exist.
+                let mut exists = HashMap::new();
+                for write in batch {
+                    match write {
+                        Write::Set { key, value, expire } => {
+                            exists.insert(key.clone(), true);
+                            records.push(Record::with_expiration(key, value, expire.map(|duration| now + duration)));
+                        }
+                        // Tombstones are only written for keys that exist, as
+                        // done by `delete`.
+                        Write::Delete { key } => {
+                            let existed = match exists.get(&key) {
+                                Some(existed) => *existed,
+                                None => read(&key)?.is_some(),
+                            };
+                            if existed {
+                                records.push(Record::tombstone(&key));
+                            }
+                            exists.insert(key, false);
+                        }
+                    }
+                }
+                return Ok((records, true, true));
+            }
+            Op::Sync => unreachable!("syncs have no records"),
+            #[cfg(test)]
+            Op::Rotate | Op::FailSync => unreachable!("test operations have no records"),
+        }
+    }
+
+    /// Append the records of the group to the active segment, sealing it
+    /// first if it is full or `rotate` is set, then point the index to them.
+    /// Syncing them is left to the caller.
+    ///
+    /// If the records can not be appended, their sequence numbers are given
+    /// to the next records: the changes streamed have no gap.
+    fn write(&mut self, shared: &Shared, group: Group, rotate: bool) -> Result<()> {
+        let written = match self.append(shared, &group, rotate) {
+            Ok(written) => written,
+            Err(err) => {
+                if !group.records.is_empty() {
+                    self.next_seq = group.first_seq;
+                }
+                return Err(err);
+            }
+        };
+        if self.fsync == FsyncPolicy::Never {
+            self.publish_durable();
+        }
+        let written = match written {
+            Some(written) => written,
+            None => return Ok(()),
+        };
+
+        let mut state = shared.state.write().unwrap();
+        let (mut overwrote_sealed, mut expires_next) = (false, false);
+        for (record, offset, len) in group.records.iter() {
+            let location = Location {
+                segment: written.segment,
+                offset: written.offset + *offset as u64,
+                len: (*len).try_into()?,
+            };
+            let (overwrote, expires) = shared.index(&mut state, record, location);
+            overwrote_sealed |= overwrote;
+            expires_next |= expires;
+        }
+        // Commit records are only needed until their records are merged.
+        state.segments[&written.segment].add_dead_bytes(group.commits_len.try_into()?);
+        state.applied_seq = state.applied_seq.max(group.last_seq);
+        drop(state);
+
+        shared.notify(overwrote_sealed, expires_next);
+        return Ok(());
+    }
+
+    /// Append the records of the group to the active segment, sealing it
+    /// first if it is full or `rotate` is set. Returns where they were
+    /// written, `None` if the group has no records.
+    fn append(&mut self, shared: &Shared, group: &Group, rotate: bool) -> Result<Option<Location>> {
+        if rotate || (!group.buf.is_empty() && self.active.segment.len() >= shared.options.max_segment_size) {
+            // Sealed segments are synced whatever the policy.
+            self.sync()?;
+            shared.rotate(&mut self.active)?;
+        }
+        if group.buf.is_empty() {
+            return Ok(None);
+        }
+        self.dirty = true;
+        let written = self.active.write(&group.buf[..])?;
+        self.active.segment.advance_seq(group.last_seq);
+        return Ok(Some(written));
+    }
+}
+
+impl Group {
+    /// Number `records` from `next_seq` on and encode them, as a transaction
+    /// followed by its commit record if `transaction` is set. Nothing is
+    /// added, and `next_seq` is left as is, if one of them fails to encode.
+    fn push(&mut self, records: &mut [Record], transaction: bool, next_seq: &mut u64) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let mut encoded = Vec::with_capacity(records.len());
+        for (seq, record) in (*next_seq..).zip(records.iter_mut()) {
+            record.seq = seq;
+            encoded.push(if transaction { record.encode_in_transaction()? } else { record.encode()? });
+        }
+        if self.records.is_empty() {
+            self.first_seq = *next_seq;
+        }
+        *next_seq += records.len() as u64;
+        self.last_seq = *next_seq - 1;
+        for (record, buf) in records.iter().zip(encoded) {
+            self.records.push((record.clone(), self.buf.len(), buf.len()));
+            self.buf.extend_from_slice(&buf[..]);
+        }
+        if transaction {
+            let commit = encode_commit(records.len().try_into()?);
+            self.commits_len += commit.len();
+            self.buf.extend_from_slice(&commit[..]);
+        }
+        return Ok(());
+    }
+}
+
+/// Keyspace notifications of writing `record`, which rewrites the expiration
+/// of its key if `expire` is set.
+fn record_events(record: &Record, expire: bool) -> Vec<(KeyEvent, String)> {
+    let key = record.key.clone();
+    if record.is_tombstone() {
+        return vec![(KeyEvent::Del, key)];
+    }
+    match (expire, record.expires_at.is_some()) {
+        (true, true) => return vec![(KeyEvent::Expire, key)],
+        (true, false) => return vec![(KeyEvent::Persist, key)],
+        (false, true) => return vec![(KeyEvent::Set, key.clone()), (KeyEvent::Expire, key)],
+        (false, false) => return vec![(KeyEvent::Set, key)],
+    }
+}
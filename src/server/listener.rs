@@ -1,6 +1,6 @@
 use crate::{
     connection::Connection,
-    server::{drop_guard::*, handler::*, Shutdown},
+    server::{drop_guard::*, handler::*, PubSub, Role, Shutdown, Transaction, Watches},
 };
 
 use std::sync::Arc;
@@ -12,13 +12,20 @@ use tokio::time::{self, Duration};
 pub struct Listener {
     /// Shared database handle.
     ///
-    /// Contains the key / value store as well as the broadcast channels for
-    /// pub/sub.
-    ///
     /// This holds a wrapper around an `Arc`. The internal `Db` can be
     /// retrieved and passed into the per connection state (`Handler`).
     pub db_holder: DropGuard,
 
+    /// Keys watched by the connections, shared by their transactions.
+    pub watches: Arc<Watches>,
+
+    /// Pub/sub channels, shared by the connections.
+    pub pub_sub: Arc<PubSub>,
+
+    /// What the server is to the other servers, decides which commands its
+    /// connections serve.
+    pub role: Role,
+
     /// TCP listener supplied by the `run` caller.
     pub listener: TcpListener,
 
@@ -84,6 +91,9 @@ impl Listener {
 
             let mut handler = Handler {
                 kv: self.db_holder.kv_store.clone(),
+                transaction: Transaction::new(self.watches.clone()),
+                pub_sub: self.pub_sub.clone(),
+                role: self.role.clone(),
 
                 connection: Connection::new(socket),
                 limit_connections: self.limit_connections.clone(),
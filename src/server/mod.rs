@@ -4,22 +4,48 @@ mod handler;
 pub mod key_value_store;
 mod listener;
 
+mod pub_sub;
+pub(crate) use pub_sub::{Message, PubSub};
+
+pub(crate) mod raft;
+pub use raft::{Members, NodeId, RaftOptions};
+
+mod replication;
+
 mod shutdown;
-use shutdown::Shutdown;
+pub(crate) use shutdown::Shutdown;
+
+mod transaction;
+pub(crate) use transaction::{Transaction, Watches};
 
 use clap::{AppSettings, Arg};
+use simple_error::bail;
 use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::signal;
 use tokio::sync::{broadcast, mpsc, Semaphore};
 
+use crate::connection::Command;
 use crate::server::{drop_guard::DropGuard, listener::Listener};
 use key_value_store::*;
 
 pub const CMD_NAME: &str = "start-server";
 
 const BACKEND_ARG: &str = "backend";
+const DATA_DIR_ARG: &str = "data-dir";
+const FSYNC_ARG: &str = "fsync";
+const MMAP_ARG: &str = "mmap";
+const REPAIR_ARG: &str = "repair";
+const SNAPSHOT_INTERVAL_ARG: &str = "snapshot-interval";
+const APPEND_ONLY_ARG: &str = "appendonly";
+const NOTIFY_KEYSPACE_EVENTS_ARG: &str = "notify-keyspace-events";
+const PORT_ARG: &str = "port";
+const REPLICA_OF_ARG: &str = "replica-of";
+const NODE_ID_ARG: &str = "node-id";
+const CLUSTER_MEMBERS_ARG: &str = "cluster-members";
 pub fn cmd<'a, 'b>() -> clap::App<'a, 'b> {
     let backend_arg = Arg::with_name("backend")
         .value_name(BACKEND_ARG)
@@ -30,10 +56,89 @@ pub fn cmd<'a, 'b>() -> clap::App<'a, 'b> {
         .possible_values(&Backend::possible_names())
         .help("The KeyValueStore backend implementation.");
 
+    let data_dir_arg = Arg::with_name(DATA_DIR_ARG)
+        .value_name("DIR")
+        .long("data-dir")
+        .takes_value(true)
+        .default_value(".")
+        .help("The directory the KeyValueStore backend keeps its files in.");
+
+    let fsync_arg = Arg::with_name(FSYNC_ARG)
+        .value_name("POLICY")
+        .long("fsync")
+        .takes_value(true)
+        .default_value("always")
+        .possible_values(&FsyncPolicy::possible_names())
+        .help("When writes, or the append-only file of the mini-redis backend, are synced to disk: before replying, once per second, or when the OS decides.");
+
+    let mmap_arg = Arg::with_name(MMAP_ARG)
+        .long("mmap")
+        .help("Serve the reads of sealed log segments from memory maps, for read-mostly datasets.");
+
+    let repair_arg = Arg::with_name(REPAIR_ARG)
+        .long("repair")
+        .help("Drop the unreadable records of corrupted log segments on startup instead of refusing to start.");
+
+    let snapshot_interval_arg = Arg::with_name(SNAPSHOT_INTERVAL_ARG)
+        .value_name("SECONDS")
+        .long("snapshot-interval")
+        .takes_value(true)
+        .default_value("60")
+        .help("How often the mini-redis backend saves its keys to its snapshot file if they changed, 0 to only save them on SAVE and on shutdown.");
+
+    let append_only_arg = Arg::with_name(APPEND_ONLY_ARG)
+        .long("appendonly")
+        .help("Log every write of the mini-redis backend to an append-only file, and rebuild the keys from it on startup.");
+
+    let notify_keyspace_events_arg = Arg::with_name(NOTIFY_KEYSPACE_EVENTS_ARG)
+        .value_name("FLAGS")
+        .long("notify-keyspace-events")
+        .takes_value(true)
+        .default_value("")
+        .help("Which keyspace notifications are published, with the flags of Redis: K and E for the __keyspace@0__ and __keyevent@0__ channels, g for del, expire and persist, $ for set, x for expired and A for all the events.");
+
+    let port_arg = Arg::with_name(PORT_ARG)
+        .value_name("PORT")
+        .long("port")
+        .takes_value(true)
+        .default_value(DEFAULT_PORT)
+        .help("The port the server listens on.");
+
+    let replica_of_arg = Arg::with_name(REPLICA_OF_ARG)
+        .value_name("HOST:PORT")
+        .long("replica-of")
+        .takes_value(true)
+        .help("Replicate the server at this address, which must use the simple-store backend. The replica serves reads and rejects writes.");
+
+    let node_id_arg = Arg::with_name(NODE_ID_ARG)
+        .value_name("ID")
+        .long("node-id")
+        .takes_value(true)
+        .conflicts_with(REPLICA_OF_ARG)
+        .help("Run as the node of a Raft cluster with this id. Only the leader serves commands, the other nodes redirect their clients to it.");
+
+    let cluster_members_arg = Arg::with_name(CLUSTER_MEMBERS_ARG)
+        .value_name("ID=HOST:PORT,...")
+        .long("cluster-members")
+        .takes_value(true)
+        .requires(NODE_ID_ARG)
+        .help("The members of a new cluster, the node included. Leave it out for a node joining an existing cluster with CLUSTER ADD.");
+
     clap::App::new("start-server")
         .about("starts a raphDB server")
         .setting(AppSettings::ArgRequiredElseHelp)
         .arg(backend_arg)
+        .arg(data_dir_arg)
+        .arg(fsync_arg)
+        .arg(mmap_arg)
+        .arg(repair_arg)
+        .arg(snapshot_interval_arg)
+        .arg(append_only_arg)
+        .arg(notify_keyspace_events_arg)
+        .arg(port_arg)
+        .arg(replica_of_arg)
+        .arg(node_id_arg)
+        .arg(cluster_members_arg)
 }
 
 pub const DEFAULT_PORT: &str = "6379";
@@ -41,25 +146,173 @@ pub const DEFAULT_PORT: &str = "6379";
 pub async fn run(logger: slog::Logger, matches: &clap::ArgMatches<'_>) -> crate::Result<()> {
     let backend_name = matches.value_of(BACKEND_ARG).expect("backend arg is required");
     let backend = Backend::from_str(backend_name)?;
+    let data_dir = PathBuf::from(matches.value_of(DATA_DIR_ARG).expect("data-dir arg has a default value"));
+    let snapshot_interval = matches.value_of(SNAPSHOT_INTERVAL_ARG).expect("snapshot-interval arg has a default value");
+    let snapshot_interval = match snapshot_interval.parse() {
+        Ok(0) => None,
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => bail!("invalid snapshot interval {:?}", snapshot_interval),
+    };
+    let fsync = FsyncPolicy::from_str(matches.value_of(FSYNC_ARG).expect("fsync arg has a default value"))?;
+    let keyspace_events = matches
+        .value_of(NOTIFY_KEYSPACE_EVENTS_ARG)
+        .expect("notify-keyspace-events arg has a default value");
+    let options = StoreOptions {
+        simple_store: SimpleStoreOptions {
+            fsync,
+            mmap: matches.is_present(MMAP_ARG),
+            repair: matches.is_present(REPAIR_ARG),
+            ..SimpleStoreOptions::default()
+        },
+        mini_redis: MiniRedisOptions {
+            snapshot_interval,
+            append_only: matches.is_present(APPEND_ONLY_ARG),
+            fsync,
+            ..MiniRedisOptions::default()
+        },
+        keyspace_events: KeyspaceEvents::from_str(keyspace_events)?,
+    };
+    let port = matches.value_of(PORT_ARG).expect("port arg has a default value");
+    let mode = match (matches.value_of(REPLICA_OF_ARG), matches.value_of(NODE_ID_ARG)) {
+        (Some(primary), _) => Mode::ReplicaOf(primary.to_string()),
+        (None, Some(id)) => {
+            let id = match id.parse() {
+                Ok(id) => id,
+                Err(_) => bail!("invalid node id {:?}", id),
+            };
+            let members = parse_members(matches.value_of(CLUSTER_MEMBERS_ARG).unwrap_or_default())?;
+            Mode::Cluster(RaftOptions::new(id, members))
+        }
+        (None, None) => Mode::Standalone,
+    };
 
-    info!(logger, "Starting raphDB server with KeyValueStore = {:?}", backend_name);
+    info!(
+        logger,
+        "Starting raphDB server with KeyValueStore = {:?}, data directory = {:?}, fsync = {:?}, mmap = {:?}, mode = {:?}",
+        backend_name,
+        data_dir,
+        options.simple_store.fsync,
+        options.simple_store.mmap,
+        mode
+    );
 
-    let listener = TcpListener::bind(&format!("127.0.0.1:{}", DEFAULT_PORT)).await?;
-    start_server(logger, listener, signal::ctrl_c(), backend).await;
-    return Ok(());
+    let listener = TcpListener::bind(&format!("127.0.0.1:{}", port)).await?;
+    return start_server(logger, listener, signal::ctrl_c(), backend, &data_dir, options, mode).await;
+}
+
+/// Parse `id=host:port` pairs separated by commas.
+fn parse_members(members: &str) -> crate::Result<Members> {
+    let mut parsed = Members::new();
+    for member in members.split(',').filter(|member| !member.is_empty()) {
+        match member.split_once('=').map(|(id, address)| (id.parse(), address)) {
+            Some((Ok(id), address)) if !address.is_empty() => {
+                parsed.insert(id, address.to_string());
+            }
+            _ => bail!("invalid cluster member {:?}, expected ID=HOST:PORT", member),
+        }
+    }
+    return Ok(parsed);
+}
+
+/// How a server relates to other servers.
+#[derive(Debug, Clone, Default)]
+pub enum Mode {
+    /// The server serves every command on its own.
+    #[default]
+    Standalone,
+
+    /// Replicate the store of the server at the address, and reject the
+    /// writes of the clients.
+    ReplicaOf(String),
+
+    /// Run a node of a Raft cluster, see `raft`.
+    Cluster(RaftOptions),
+}
+
+/// What a running server is to the other servers, decides which commands it
+/// serves.
+#[derive(Debug, Clone)]
+pub(crate) enum Role {
+    Standalone,
+
+    /// A replica only applies the writes of its primary.
+    Replica,
+
+    /// A node of a cluster, serving the data commands only as the leader.
+    Node(Arc<raft::Node>),
+}
+
+impl Role {
+    /// The error `cmd`, a data command, is rejected with, if it is.
+    pub(crate) fn refusal(&self, cmd: &Command) -> Option<String> {
+        match self {
+            Role::Standalone => None,
+            Role::Replica if cmd.modified_keys().is_empty() => None,
+            Role::Replica => Some("READONLY You can't write against a read only replica.".to_string()),
+            Role::Node(node) => node.refusal(),
+        }
+    }
 }
 
 const MAX_CONNECTIONS: usize = 250;
 
-pub async fn start_server(logger: slog::Logger, listener: TcpListener, shutdown: impl Future, backend: Backend) {
+/// Serve the store of `backend` until `shutdown` completes. Fails if the
+/// store can not be opened, for instance if another server uses `data_dir`.
+/// `options` tune the backend, and select the keyspace notifications the
+/// store publishes to the subscribed connections.
+///
+/// `mode` makes the server a replica of another server, or a node of a
+/// cluster. A node keeps its raft state in `data_dir` too.
+pub async fn start_server(
+    logger: slog::Logger,
+    listener: TcpListener,
+    shutdown: impl Future,
+    backend: Backend,
+    data_dir: &Path,
+    options: StoreOptions,
+    mode: Mode,
+) -> crate::Result<()> {
     let (notify_shutdown, _) = broadcast::channel(1);
     let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
 
-    let kv = get_kv_store(logger.clone(), backend).await.expect("kv store backend does not exist");
+    let pub_sub = Arc::new(PubSub::default());
+    let notifier = Notifier::new(options.keyspace_events, pub_sub.clone());
+    let kv = get_kv_store(logger.clone(), backend, data_dir, options, notifier).await?;
+
+    let (kv, role) = match mode {
+        Mode::Standalone => (kv, Role::Standalone),
+        Mode::ReplicaOf(primary) => {
+            let shutdown = Shutdown::new(notify_shutdown.subscribe());
+            tokio::spawn(replication::replicate(
+                logger.clone(),
+                primary,
+                kv.clone(),
+                shutdown,
+                shutdown_complete_tx.clone(),
+            ));
+            (kv, Role::Replica)
+        }
+        Mode::Cluster(raft_options) => {
+            let node = raft::Node::start(
+                logger.clone(),
+                raft_options,
+                data_dir,
+                kv.clone(),
+                &notify_shutdown,
+                shutdown_complete_tx.clone(),
+            )
+            .await?;
+            let kv: Box<dyn AsyncKeyValueStore> = Box::new(raft::RaftStore::new(node.clone(), kv));
+            (kv, Role::Node(node))
+        }
+    };
 
     let mut server = Listener {
         listener,
         db_holder: DropGuard::new(kv),
+        watches: Arc::new(Watches::default()),
+        pub_sub,
+        role,
         limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
         notify_shutdown,
         shutdown_complete_tx,
@@ -100,4 +353,5 @@ pub async fn start_server(logger: slog::Logger, listener: TcpListener, shutdown:
     // `Sender` instances are held by connection handler tasks. When those drop,
     // the `mpsc` channel will close and `recv()` will return `None`.
     let _ = shutdown_complete_rx.recv().await;
+    return Ok(());
 }
@@ -0,0 +1,277 @@
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Number of messages a subscriber can lag behind before it misses some.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Pub/sub channels of a server, shared by all of its connections.
+///
+/// Like Redis, pub/sub has a key space of its own: channels are not keys of
+/// the store and messages are never stored. A message published to a channel
+/// nobody subscribed to is dropped.
+#[derive(Debug, Default)]
+pub struct PubSub {
+    /// Sender of each channel subscribed to.
+    channels: Mutex<HashMap<String, broadcast::Sender<Message>>>,
+
+    /// Sender of each glob pattern subscribed to.
+    patterns: Mutex<HashMap<String, broadcast::Sender<Message>>>,
+}
+
+/// A message published to a channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub channel: String,
+    pub content: Bytes,
+}
+
+impl PubSub {
+    /// Receive the messages published to `channel` from now on.
+    pub fn subscribe(&self, channel: &str) -> broadcast::Receiver<Message> {
+        subscribe(&self.channels, channel)
+    }
+
+    /// Receive the messages published to the channels matching the glob
+    /// `pattern` from now on, see `glob_match`.
+    pub fn psubscribe(&self, pattern: &str) -> broadcast::Receiver<Message> {
+        subscribe(&self.patterns, pattern)
+    }
+
+    /// Send `content` to the subscribers of `channel`. Returns the number of
+    /// subscriptions the message was sent to, pattern subscriptions included.
+    pub fn publish(&self, channel: &str, content: Bytes) -> usize {
+        let message = Message {
+            channel: channel.to_string(),
+            content,
+        };
+
+        let mut receivers = 0;
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(channel) {
+            match sender.send(message.clone()) {
+                Ok(count) => receivers += count,
+                // Every subscriber is gone.
+                Err(_) => {
+                    channels.remove(channel);
+                }
+            }
+        }
+        drop(channels);
+
+        let mut patterns = self.patterns.lock().unwrap();
+        patterns.retain(|pattern, sender| {
+            if !glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                return sender.receiver_count() > 0;
+            }
+            match sender.send(message.clone()) {
+                Ok(count) => {
+                    receivers += count;
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+        return receivers;
+    }
+}
+
+fn subscribe(senders: &Mutex<HashMap<String, broadcast::Sender<Message>>>, name: &str) -> broadcast::Receiver<Message> {
+    let mut senders = senders.lock().unwrap();
+    match senders.get(name) {
+        Some(sender) => sender.subscribe(),
+        None => {
+            let (sender, receiver) = broadcast::channel(CHANNEL_CAPACITY);
+            senders.insert(name.to_string(), sender);
+            receiver
+        }
+    }
+}
+
+/// Returns `true` if `text` matches the glob `pattern`, with the syntax of the
+/// patterns of Redis:
+///
+/// - `?` matches any byte,
+/// - `*` matches any sequence of bytes, the empty one included,
+/// - `[abc]` matches one of the listed bytes, `[^abc]` any other byte and
+///   `[a-c]` a range of bytes,
+/// - `\` escapes the next byte.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // Position in `pattern` after the last `*`, and position in `text` that
+    // star matched up to. On a mismatch, the star swallows one more byte.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        let matched = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p + 1, text[t]),
+            Some(b'\\') if p + 1 < pattern.len() => Some(p + 2).filter(|_| pattern[p + 1] == text[t]),
+            Some(byte) => Some(p + 1).filter(|_| *byte == text[t]),
+            None => None,
+        };
+        match (matched, star) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    return pattern[p..].iter().all(|byte| *byte == b'*');
+}
+
+/// Match `byte` against the class starting at `start`, just after its `[`.
+/// Returns the position following the class if `byte` is in it.
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<usize> {
+    let mut p = start;
+    let negated = pattern.get(p) == Some(&b'^');
+    if negated {
+        p += 1;
+    }
+
+    let mut found = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            found |= pattern[p + 1] == byte;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (low, high) = (pattern[p].min(pattern[p + 2]), pattern[p].max(pattern[p + 2]));
+            found |= low <= byte && byte <= high;
+            p += 3;
+        } else {
+            found |= pattern[p] == byte;
+            p += 1;
+        }
+    }
+
+    // An unterminated class runs to the end of the pattern.
+    let next = (p + 1).min(pattern.len());
+    return Some(next).filter(|_| found != negated);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::client::{self, Message as ClientMessage};
+    use crate::server::{key_value_store::Backend, start_server, Mode, StoreOptions};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("news.*", "news.sport", true),
+            ("news.*", "news.", true),
+            ("news.*", "weather", false),
+            ("*", "", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[a-c]llo", "hdllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("*.sport.*", "news.sport.today", true),
+            ("a*b*c", "axxbyyc", true),
+            ("a*b*c", "axxbyy", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(glob_match(pattern.as_bytes(), text.as_bytes()), *expected, "{:?} {:?}", pattern, text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let pub_sub = PubSub::default();
+        assert_eq!(pub_sub.publish("news", Bytes::from("dropped")), 0);
+
+        let mut channel = pub_sub.subscribe("news");
+        let mut pattern = pub_sub.psubscribe("n*");
+        let mut other = pub_sub.psubscribe("weather.*");
+        assert_eq!(pub_sub.publish("news", Bytes::from("hello")), 2);
+        let message = Message {
+            channel: "news".to_string(),
+            content: Bytes::from("hello"),
+        };
+        assert_eq!(channel.recv().await.unwrap(), message);
+        assert_eq!(pattern.recv().await.unwrap(), message);
+        assert!(other.try_recv().is_err());
+
+        // The senders of the channels nobody listens to anymore are dropped.
+        drop(channel);
+        drop(pattern);
+        assert_eq!(pub_sub.publish("news", Bytes::from("hello")), 0);
+        assert!(pub_sub.channels.lock().unwrap().is_empty());
+        assert_eq!(pub_sub.patterns.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let server = tokio::spawn(async move {
+            let options = StoreOptions::default();
+            start_server(logger, listener, stopped, Backend::MiniRedis, dir.path(), options, Mode::Standalone)
+                .await
+                .unwrap();
+        });
+
+        let subscriber = client::connect(addr).await.unwrap();
+        let messages = subscriber.subscribe(&["news"]).await.unwrap();
+        tokio::pin!(messages);
+        let pattern_subscriber = client::connect(addr).await.unwrap();
+        let pattern_messages = pattern_subscriber.psubscribe(&["news.*"]).await.unwrap();
+        tokio::pin!(pattern_messages);
+
+        let mut publisher = client::connect(addr).await.unwrap();
+        assert_eq!(publisher.publish("news", Bytes::from("hello")).await.unwrap(), 1);
+        assert_eq!(publisher.publish("news.sport", Bytes::from("goal")).await.unwrap(), 1);
+        assert_eq!(publisher.publish("weather", Bytes::from("rain")).await.unwrap(), 0);
+
+        let message = messages.next().await.unwrap().unwrap();
+        assert_eq!(
+            message,
+            ClientMessage {
+                channel: "news".to_string(),
+                pattern: None,
+                content: Bytes::from("hello"),
+            }
+        );
+        let message = pattern_messages.next().await.unwrap().unwrap();
+        assert_eq!(
+            message,
+            ClientMessage {
+                channel: "news.sport".to_string(),
+                pattern: Some("news.*".to_string()),
+                content: Bytes::from("goal"),
+            }
+        );
+
+        // The publisher is not in subscriber mode and keeps serving commands.
+        publisher.set("key", Bytes::from("value")).await.unwrap();
+
+        // Subscriber mode ends on shutdown, which closes the subscriptions.
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        assert!(messages.next().await.is_none());
+        assert!(pattern_messages.next().await.is_none());
+    }
+}
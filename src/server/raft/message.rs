@@ -0,0 +1,503 @@
+//! Entries of the log and RPCs between the nodes, with their frames.
+//!
+//! Entries and snapshots are written to disk with the same frames as they are
+//! sent with. Times are in milliseconds since the epoch: an entry expires a
+//! key at the same time on every node, however late it is applied.
+
+use crate::connection::{Frame, Parser};
+use crate::server::key_value_store::Change;
+
+use super::{Members, NodeId};
+
+use bytes::Bytes;
+use simple_error::bail;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What an entry of the log does once committed.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Op {
+    /// Appended by a new leader, to commit the entries of the previous terms.
+    Noop,
+
+    Set {
+        key: String,
+        value: Bytes,
+        expires_at: Option<SystemTime>,
+    },
+
+    Delete {
+        key: String,
+    },
+
+    Expire {
+        key: String,
+        expires_at: SystemTime,
+    },
+
+    Persist {
+        key: String,
+    },
+
+    /// `Set` and `Delete` ops applied as a whole.
+    Batch(Vec<Op>),
+
+    /// The members of the cluster from this entry on, appended or not.
+    Config(Members),
+}
+
+/// An entry of the log.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub op: Op,
+}
+
+/// The store as of an entry of the log, replacing the entries up to it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Snapshot {
+    /// Index of the last entry included in the snapshot.
+    pub index: u64,
+
+    /// Term of the last entry included in the snapshot.
+    pub term: u64,
+
+    /// The members of the cluster as of the last entry.
+    pub members: Members,
+
+    /// The live keys. Sequence numbers are the index of the snapshot.
+    pub entries: Vec<Change>,
+}
+
+/// An RPC, sent as a `RAFT` command.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Request {
+    /// `RAFT VOTE term candidate last_index last_term`.
+    Vote {
+        term: u64,
+        candidate: NodeId,
+        last_index: u64,
+        last_term: u64,
+    },
+
+    /// `RAFT APPEND term leader prev_index prev_term commit [entry...]`, a
+    /// heartbeat if there is no entry.
+    Append {
+        term: u64,
+        leader: NodeId,
+        prev_index: u64,
+        prev_term: u64,
+        commit: u64,
+        entries: Vec<Entry>,
+    },
+
+    /// `RAFT SNAPSHOT term leader snapshot`, sent in one piece.
+    InstallSnapshot { term: u64, leader: NodeId, snapshot: Arc<Snapshot> },
+}
+
+/// The reply to a `Request` of the same kind.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Response {
+    /// `[vote, term, granted]`.
+    Vote { term: u64, granted: bool },
+
+    /// `[append, term, success, last_index]`. `last_index` is the index of the
+    /// last entry matching the leader's on success, a hint of where the logs
+    /// diverge otherwise.
+    Append { term: u64, success: bool, last_index: u64 },
+
+    /// `[snapshot, term]`.
+    InstallSnapshot { term: u64 },
+}
+
+impl Op {
+    pub fn to_frame(&self) -> crate::Result<Frame> {
+        let mut frame = Frame::array();
+        match self {
+            Op::Noop => frame.push_bulk(Bytes::from("noop".as_bytes())),
+            Op::Set { key, value, expires_at } => {
+                frame.push_bulk(Bytes::from("set".as_bytes()));
+                frame.push_bulk(Bytes::from(key.clone().into_bytes()));
+                frame.push_bulk(value.clone());
+                push_time(&mut frame, *expires_at)?;
+            }
+            Op::Delete { key } => {
+                frame.push_bulk(Bytes::from("del".as_bytes()));
+                frame.push_bulk(Bytes::from(key.clone().into_bytes()));
+            }
+            Op::Expire { key, expires_at } => {
+                frame.push_bulk(Bytes::from("expire".as_bytes()));
+                frame.push_bulk(Bytes::from(key.clone().into_bytes()));
+                push_time(&mut frame, Some(*expires_at))?;
+            }
+            Op::Persist { key } => {
+                frame.push_bulk(Bytes::from("persist".as_bytes()));
+                frame.push_bulk(Bytes::from(key.clone().into_bytes()));
+            }
+            Op::Batch(ops) => {
+                frame.push_bulk(Bytes::from("batch".as_bytes()));
+                for op in ops {
+                    frame.push_frame(op.to_frame()?);
+                }
+            }
+            Op::Config(members) => {
+                frame.push_bulk(Bytes::from("config".as_bytes()));
+                push_members(&mut frame, members)?;
+            }
+        }
+        return Ok(frame);
+    }
+
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Op> {
+        let name = parser.next_string()?;
+        let op = match &name[..] {
+            "noop" => Op::Noop,
+            "set" => Op::Set {
+                key: parser.next_string()?,
+                value: parser.next_bytes()?,
+                expires_at: next_time(parser)?,
+            },
+            "del" => Op::Delete { key: parser.next_string()? },
+            "expire" => {
+                let key = parser.next_string()?;
+                match next_time(parser)? {
+                    Some(expires_at) => Op::Expire { key, expires_at },
+                    None => bail!("protocol error; expire op without a time"),
+                }
+            }
+            "persist" => Op::Persist { key: parser.next_string()? },
+            "batch" => {
+                let mut ops = Vec::new();
+                while !parser.is_empty() {
+                    match Op::parse_frames(&mut parser.next_array()?)? {
+                        op @ (Op::Set { .. } | Op::Delete { .. }) => ops.push(op),
+                        op => bail!("protocol error; {:?} in a batch", op),
+                    }
+                }
+                Op::Batch(ops)
+            }
+            "config" => Op::Config(next_members(parser)?),
+            _ => bail!("protocol error; unknown op {:?}", name),
+        };
+        parser.finish()?;
+        return Ok(op);
+    }
+}
+
+impl Entry {
+    /// `[index, term, op]`.
+    pub fn to_frame(&self) -> crate::Result<Frame> {
+        let mut frame = Frame::array();
+        frame.push_int(self.index.try_into()?);
+        frame.push_int(self.term.try_into()?);
+        frame.push_frame(self.op.to_frame()?);
+        return Ok(frame);
+    }
+
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Entry> {
+        let entry = Entry {
+            index: parser.next_int()?,
+            term: parser.next_int()?,
+            op: Op::parse_frames(&mut parser.next_array()?)?,
+        };
+        parser.finish()?;
+        return Ok(entry);
+    }
+}
+
+impl Snapshot {
+    /// `[index, term, [id, address...], [[key, value, expires_at]...]]`.
+    pub fn to_frame(&self) -> crate::Result<Frame> {
+        let mut frame = Frame::array();
+        frame.push_int(self.index.try_into()?);
+        frame.push_int(self.term.try_into()?);
+        let mut members = Frame::array();
+        push_members(&mut members, &self.members)?;
+        frame.push_frame(members);
+        let mut entries = Frame::array();
+        for entry in &self.entries {
+            let mut change = Frame::array();
+            change.push_bulk(Bytes::from(entry.key.clone().into_bytes()));
+            match &entry.value {
+                Some(value) => change.push_bulk(value.clone()),
+                None => bail!("the snapshot holds the deleted key {:?}", entry.key),
+            }
+            push_time(&mut change, entry.expires_at)?;
+            entries.push_frame(change);
+        }
+        frame.push_frame(entries);
+        return Ok(frame);
+    }
+
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Snapshot> {
+        let index = parser.next_int()?;
+        let term = parser.next_int()?;
+        let members = next_members(&mut parser.next_array()?)?;
+        let mut changes = parser.next_array()?;
+        let mut entries = Vec::new();
+        while !changes.is_empty() {
+            let mut change = changes.next_array()?;
+            entries.push(Change {
+                seq: index,
+                key: change.next_string()?,
+                value: Some(change.next_bytes()?),
+                expires_at: next_time(&mut change)?,
+            });
+            change.finish()?;
+        }
+        parser.finish()?;
+        return Ok(Snapshot { index, term, members, entries });
+    }
+}
+
+impl Request {
+    pub fn term(&self) -> u64 {
+        match self {
+            Request::Vote { term, .. } | Request::Append { term, .. } | Request::InstallSnapshot { term, .. } => *term,
+        }
+    }
+
+    /// Parse the frames following `RAFT`.
+    pub fn parse_frames(parser: &mut Parser) -> crate::Result<Request> {
+        let kind = parser.next_string()?;
+        let request = match &kind.to_lowercase()[..] {
+            "vote" => Request::Vote {
+                term: parser.next_int()?,
+                candidate: parser.next_int()?,
+                last_index: parser.next_int()?,
+                last_term: parser.next_int()?,
+            },
+            "append" => {
+                let (term, leader) = (parser.next_int()?, parser.next_int()?);
+                let (prev_index, prev_term, commit) = (parser.next_int()?, parser.next_int()?, parser.next_int()?);
+                let mut entries = Vec::new();
+                while !parser.is_empty() {
+                    entries.push(Entry::parse_frames(&mut parser.next_array()?)?);
+                }
+                Request::Append {
+                    term,
+                    leader,
+                    prev_index,
+                    prev_term,
+                    commit,
+                    entries,
+                }
+            }
+            "snapshot" => Request::InstallSnapshot {
+                term: parser.next_int()?,
+                leader: parser.next_int()?,
+                snapshot: Arc::new(Snapshot::parse_frames(&mut parser.next_array()?)?),
+            },
+            _ => bail!("protocol error; unknown RAFT request {:?}", kind),
+        };
+        return Ok(request);
+    }
+
+    pub fn to_frame(&self) -> crate::Result<Frame> {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("raft".as_bytes()));
+        match self {
+            Request::Vote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => {
+                frame.push_bulk(Bytes::from("vote".as_bytes()));
+                for value in [term, candidate, last_index, last_term] {
+                    frame.push_int((*value).try_into()?);
+                }
+            }
+            Request::Append {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                commit,
+                entries,
+            } => {
+                frame.push_bulk(Bytes::from("append".as_bytes()));
+                for value in [term, leader, prev_index, prev_term, commit] {
+                    frame.push_int((*value).try_into()?);
+                }
+                for entry in entries {
+                    frame.push_frame(entry.to_frame()?);
+                }
+            }
+            Request::InstallSnapshot { term, leader, snapshot } => {
+                frame.push_bulk(Bytes::from("snapshot".as_bytes()));
+                frame.push_int((*term).try_into()?);
+                frame.push_int((*leader).try_into()?);
+                frame.push_frame(snapshot.to_frame()?);
+            }
+        }
+        return Ok(frame);
+    }
+}
+
+impl Response {
+    pub fn term(&self) -> u64 {
+        match self {
+            Response::Vote { term, .. } | Response::Append { term, .. } | Response::InstallSnapshot { term } => *term,
+        }
+    }
+
+    pub fn from_frame(frame: Frame) -> crate::Result<Response> {
+        let mut parser = Parser::new(frame)?;
+        let kind = parser.next_string()?;
+        let response = match &kind[..] {
+            "vote" => Response::Vote {
+                term: parser.next_int()?,
+                granted: parser.next_int()? == 1,
+            },
+            "append" => Response::Append {
+                term: parser.next_int()?,
+                success: parser.next_int()? == 1,
+                last_index: parser.next_int()?,
+            },
+            "snapshot" => Response::InstallSnapshot { term: parser.next_int()? },
+            _ => bail!("protocol error; unknown RAFT response {:?}", kind),
+        };
+        parser.finish()?;
+        return Ok(response);
+    }
+
+    pub fn to_frame(&self) -> crate::Result<Frame> {
+        let mut frame = Frame::array();
+        match self {
+            Response::Vote { term, granted } => {
+                frame.push_bulk(Bytes::from("vote".as_bytes()));
+                frame.push_int((*term).try_into()?);
+                frame.push_int(*granted as i64);
+            }
+            Response::Append { term, success, last_index } => {
+                frame.push_bulk(Bytes::from("append".as_bytes()));
+                frame.push_int((*term).try_into()?);
+                frame.push_int(*success as i64);
+                frame.push_int((*last_index).try_into()?);
+            }
+            Response::InstallSnapshot { term } => {
+                frame.push_bulk(Bytes::from("snapshot".as_bytes()));
+                frame.push_int((*term).try_into()?);
+            }
+        }
+        return Ok(frame);
+    }
+}
+
+fn push_time(frame: &mut Frame, time: Option<SystemTime>) -> crate::Result<()> {
+    match time {
+        Some(time) => frame.push_int(time.duration_since(UNIX_EPOCH)?.as_millis().try_into()?),
+        None => frame.push_null(),
+    }
+    return Ok(());
+}
+
+fn next_time(parser: &mut Parser) -> crate::Result<Option<SystemTime>> {
+    match parser.next_frame()? {
+        Frame::Null => Ok(None),
+        Frame::Integer(millis) => Ok(Some(UNIX_EPOCH + Duration::from_millis(millis.try_into()?))),
+        frame => bail!("protocol error; expected a time, got {:?}", frame),
+    }
+}
+
+/// `id address` pairs.
+fn push_members(frame: &mut Frame, members: &Members) -> crate::Result<()> {
+    for (id, address) in members {
+        frame.push_int((*id).try_into()?);
+        frame.push_bulk(Bytes::from(address.clone().into_bytes()));
+    }
+    return Ok(());
+}
+
+fn next_members(parser: &mut Parser) -> crate::Result<Members> {
+    let mut members = Members::new();
+    while !parser.is_empty() {
+        members.insert(parser.next_int()?, parser.next_string()?);
+    }
+    return Ok(members);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frames() {
+        let expires_at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let members: Members = vec![(1, "127.0.0.1:7001".to_string()), (2, "127.0.0.1:7002".to_string())].into_iter().collect();
+        let ops = vec![
+            Op::Noop,
+            Op::Set {
+                key: "key".to_string(),
+                value: Bytes::from("value"),
+                expires_at: Some(expires_at),
+            },
+            Op::Expire {
+                key: "key".to_string(),
+                expires_at,
+            },
+            Op::Persist { key: "key".to_string() },
+            Op::Batch(vec![
+                Op::Set {
+                    key: "a".to_string(),
+                    value: Bytes::from("1"),
+                    expires_at: None,
+                },
+                Op::Delete { key: "b".to_string() },
+            ]),
+            Op::Config(members.clone()),
+        ];
+        let entries: Vec<Entry> = ops
+            .into_iter()
+            .enumerate()
+            .map(|(i, op)| Entry {
+                index: i as u64 + 5,
+                term: 2,
+                op,
+            })
+            .collect();
+        let request = Request::Append {
+            term: 2,
+            leader: 1,
+            prev_index: 4,
+            prev_term: 1,
+            commit: 3,
+            entries,
+        };
+        let mut parser = Parser::new(request.to_frame().unwrap()).unwrap();
+        assert_eq!(parser.next_string().unwrap(), "raft");
+        assert_eq!(Request::parse_frames(&mut parser).unwrap(), request);
+
+        let snapshot = Arc::new(Snapshot {
+            index: 9,
+            term: 2,
+            members,
+            entries: vec![Change {
+                seq: 9,
+                key: "key".to_string(),
+                value: Some(Bytes::from("value")),
+                expires_at: Some(expires_at),
+            }],
+        });
+        let request = Request::InstallSnapshot { term: 3, leader: 2, snapshot };
+        let mut parser = Parser::new(request.to_frame().unwrap()).unwrap();
+        parser.next_string().unwrap();
+        assert_eq!(Request::parse_frames(&mut parser).unwrap(), request);
+
+        let response = Response::Append {
+            term: 3,
+            success: false,
+            last_index: 7,
+        };
+        assert_eq!(Response::from_frame(response.to_frame().unwrap()).unwrap(), response);
+
+        let mut frame = Op::Batch(vec![Op::Noop]).to_frame().unwrap();
+        assert!(Op::parse_frames(&mut Parser::new(frame.clone()).unwrap()).is_err());
+        frame = Op::Noop.to_frame().unwrap();
+        frame.push_int(1);
+        assert!(Op::parse_frames(&mut Parser::new(frame).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Cluster mode: the store is replicated by a cluster of nodes with the Raft
+//! consensus algorithm.
+//!
+//! Writes are entries of a log the leader replicates to the other nodes. Once
+//! a majority of the nodes stored an entry, it is committed, and every node
+//! applies it to its store, the state machine. Only the leader serves the
+//! data commands, the other nodes redirect the clients to it with a `MOVED`
+//! error.
+//!
+//! - `message`: the entries of the log, and the RPCs between the nodes. They
+//!   are sent as `RAFT` commands on the port the clients use.
+//! - `storage`: the term, vote, log and snapshot of a node, on disk.
+//! - `node`: elections, log replication, and application of the committed
+//!   entries.
+//! - `store`: the store a node serves, which appends the writes to the log.
+//!
+//! The members of the cluster are entries of the log too, changed one node at
+//! a time with `CLUSTER ADD` and `CLUSTER REMOVE`. A node that joins starts
+//! without members and waits for the leader to send it the log. The log is
+//! compacted into a snapshot of the store every `snapshot_threshold` applied
+//! entries, the leader sends its snapshot to the nodes missing the compacted
+//! entries.
+
+mod message;
+mod node;
+mod storage;
+mod store;
+
+pub(crate) use message::Request;
+pub(crate) use node::Node;
+pub(crate) use store::RaftStore;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Identifier of a node, unique in its cluster.
+pub type NodeId = u64;
+
+/// The address of each member of a cluster.
+pub type Members = BTreeMap<NodeId, String>;
+
+/// Configuration of a node of a cluster.
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    pub id: NodeId,
+
+    /// The members a new cluster starts with, the node itself included. Every
+    /// node of the new cluster must be started with the same members. Ignored
+    /// once the node has a log, empty for a node joining an existing cluster.
+    pub members: Members,
+
+    /// How often the leader sends heartbeats to the other nodes.
+    pub heartbeat_interval: Duration,
+
+    /// How long a node waits without hearing from a leader before it starts an
+    /// election, doubled at most at random.
+    pub election_timeout: Duration,
+
+    /// Number of applied entries that are compacted into a snapshot.
+    pub snapshot_threshold: u64,
+}
+
+impl RaftOptions {
+    pub fn new(id: NodeId, members: Members) -> RaftOptions {
+        RaftOptions {
+            id,
+            members,
+            heartbeat_interval: Duration::from_millis(50),
+            election_timeout: Duration::from_millis(500),
+            snapshot_threshold: 10_000,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::client::{self, Client};
+    use crate::server::key_value_store::Backend;
+    use crate::server::{start_server, Mode, StoreOptions};
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use tokio::time;
+
+    /// A node running in a server of the test.
+    struct TestNode {
+        id: NodeId,
+        addr: SocketAddr,
+        dir: TempDir,
+        stop: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+    }
+
+    impl TestNode {
+        /// Bind a loopback port for the node `id`, without starting it.
+        async fn bind(id: NodeId) -> (TestNode, TcpListener) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let node = TestNode {
+                id,
+                addr: listener.local_addr().unwrap(),
+                dir: tempfile::tempdir().unwrap(),
+                stop: None,
+            };
+            return (node, listener);
+        }
+
+        fn start(&mut self, listener: TcpListener, members: &Members, snapshot_threshold: u64) {
+            let options = RaftOptions {
+                heartbeat_interval: Duration::from_millis(20),
+                election_timeout: Duration::from_millis(150),
+                snapshot_threshold,
+                ..RaftOptions::new(self.id, members.clone())
+            };
+            self.stop = Some(server(listener, self.dir.path().to_path_buf(), options));
+        }
+
+        async fn stop(&mut self) {
+            let (stop, handle) = self.stop.take().unwrap();
+            stop.send(()).unwrap();
+            handle.await.unwrap();
+        }
+
+        async fn info(&self, field: &str) -> String {
+            let info = client::connect(self.addr).await.unwrap().cluster_info().await.unwrap();
+            return info
+                .lines()
+                .find_map(|line| line.strip_prefix(&format!("{}:", field)))
+                .unwrap_or_else(|| panic!("no {:?} in {:?}", field, info))
+                .to_string();
+        }
+    }
+
+    fn server(listener: TcpListener, dir: PathBuf, options: RaftOptions) -> (oneshot::Sender<()>, JoinHandle<()>) {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let handle = tokio::spawn(async move {
+            let mode = Mode::Cluster(options);
+            start_server(logger, listener, stopped, Backend::SimpleStore, Path::new(&dir), StoreOptions::default(), mode)
+                .await
+                .unwrap();
+        });
+        return (stop, handle);
+    }
+
+    /// Start a cluster of `count` nodes.
+    async fn cluster(count: u64, snapshot_threshold: u64) -> Vec<TestNode> {
+        let mut bound = Vec::new();
+        for id in 1..=count {
+            bound.push(TestNode::bind(id).await);
+        }
+        let members: Members = bound.iter().map(|(node, _)| (node.id, node.addr.to_string())).collect();
+        let mut nodes = Vec::new();
+        for (mut node, listener) in bound {
+            node.start(listener, &members, snapshot_threshold);
+            nodes.push(node);
+        }
+        return nodes;
+    }
+
+    /// The index in `nodes` of the leader, once it serves commands.
+    async fn leader(nodes: &[TestNode]) -> usize {
+        for _ in 0..500 {
+            for (i, node) in nodes.iter().enumerate().filter(|(_, node)| node.stop.is_some()) {
+                if let Ok(mut client) = client::connect(node.addr).await {
+                    if client.get("probe").await.is_ok() {
+                        return i;
+                    }
+                }
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no leader was elected");
+    }
+
+    async fn wait_for_applied(node: &TestNode, index: u64) {
+        for _ in 0..500 {
+            if node.info("last_applied").await.parse::<u64>().unwrap() >= index {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("node {} never applied {}", node.id, index);
+    }
+
+    async fn connect(node: &TestNode) -> Client {
+        client::connect(node.addr).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cluster() {
+        let mut nodes = cluster(3, 1000).await;
+        let first = leader(&nodes).await;
+        let mut client = connect(&nodes[first]).await;
+        client.set("key1", Bytes::from("value1")).await.unwrap();
+        client.set_expires("key2", Bytes::from("value2"), Duration::from_secs(60)).await.unwrap();
+        client.set("key3", Bytes::from("value3")).await.unwrap();
+        assert_eq!(client.del(&["key3", "missing"]).await.unwrap(), 1);
+        assert!(client.persist("key2").await.unwrap());
+        assert!(!client.expire("missing", Duration::from_secs(1)).await.unwrap());
+        assert_eq!(client.get("key1").await.unwrap(), Some(Bytes::from("value1")));
+
+        // The followers apply the writes, and redirect their clients.
+        let commit = nodes[first].info("commit_index").await.parse().unwrap();
+        for (i, node) in nodes.iter().enumerate().filter(|(i, _)| *i != first) {
+            wait_for_applied(node, commit).await;
+            let err = connect(node).await.get("key1").await.unwrap_err();
+            assert_eq!(err.to_string(), format!("MOVED {}", nodes[first].addr), "node {}", i);
+            assert_eq!(node.info("role").await, "follower");
+        }
+
+        // Another leader takes over when the leader stops.
+        drop(client);
+        nodes[first].stop().await;
+        let second = leader(&nodes).await;
+        assert_ne!(first, second);
+        let mut client = connect(&nodes[second]).await;
+        assert_eq!(client.get("key1").await.unwrap(), Some(Bytes::from("value1")));
+        assert_eq!(client.ttl("key2").await.unwrap(), crate::server::key_value_store::Expiry::Persistent);
+        assert_eq!(client.get("key3").await.unwrap(), None);
+        client.set("key4", Bytes::from("value4")).await.unwrap();
+
+        // The stopped node catches up as a follower once restarted.
+        let listener = TcpListener::bind(nodes[first].addr).await.unwrap();
+        nodes[first].start(listener, &Members::new(), 1000);
+        let commit = nodes[second].info("commit_index").await.parse().unwrap();
+        wait_for_applied(&nodes[first], commit).await;
+        let err = connect(&nodes[first]).await.get("key4").await.unwrap_err();
+        assert_eq!(err.to_string(), format!("MOVED {}", nodes[second].addr));
+
+        drop(client);
+        for node in nodes.iter_mut() {
+            node.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_membership() {
+        let mut nodes = cluster(3, 5).await;
+        let first = leader(&nodes).await;
+        let mut client = connect(&nodes[first]).await;
+        for i in 0..20 {
+            client.set(&format!("key{}", i), Bytes::from(format!("value{}", i))).await.unwrap();
+        }
+        assert!(nodes[first].info("snapshot_index").await.parse::<u64>().unwrap() > 0);
+
+        // A new node gets the snapshot of the leader, then its log.
+        let (mut joining, listener) = TestNode::bind(4).await;
+        joining.start(listener, &Members::new(), 5);
+        assert_eq!(joining.info("role").await, "follower");
+        client.cluster_add(4, &joining.addr.to_string()).await.unwrap();
+        let err = client.cluster_add(4, &joining.addr.to_string()).await.unwrap_err();
+        assert!(err.to_string().contains("already a member"), "{}", err);
+        let commit = nodes[first].info("commit_index").await.parse().unwrap();
+        wait_for_applied(&joining, commit).await;
+        assert!(joining.info("snapshot_index").await.parse::<u64>().unwrap() > 0);
+        assert_eq!(joining.info("members").await.split(',').count(), 4);
+
+        // A leader removing itself steps down, the others elect a new one.
+        let err = connect(&nodes[(first + 1) % 3]).await.cluster_remove(1).await.unwrap_err();
+        assert!(err.to_string().starts_with("MOVED"), "{}", err);
+        client.cluster_remove(nodes[first].id).await.unwrap();
+        drop(client);
+        nodes[first].stop().await;
+        nodes.push(joining);
+        let second = leader(&nodes).await;
+        let mut client = connect(&nodes[second]).await;
+        for i in 0..20 {
+            let value = client.get(&format!("key{}", i)).await.unwrap();
+            assert_eq!(value, Some(Bytes::from(format!("value{}", i))));
+        }
+        client.set("key20", Bytes::from("value20")).await.unwrap();
+        assert_eq!(nodes[second].info("members").await.split(',').count(), 3);
+
+        drop(client);
+        for node in nodes.iter_mut().filter(|node| node.stop.is_some()) {
+            node.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_isolated_leader() {
+        let mut nodes = cluster(3, 1000).await;
+        let first = leader(&nodes).await;
+        let mut client = connect(&nodes[first]).await;
+        client.set("key", Bytes::from("value")).await.unwrap();
+
+        // Cut off from the other nodes, the leader stops serving reads once
+        // its lease expires, as they could elect another leader.
+        for i in (0..3).filter(|&i| i != first) {
+            nodes[i].stop().await;
+        }
+        time::sleep(Duration::from_millis(150)).await;
+        let err = client.get("key").await.unwrap_err();
+        assert!(err.to_string().starts_with("CLUSTERDOWN"), "{}", err);
+        assert_eq!(client.ttl("key").await.unwrap_err().to_string(), err.to_string());
+
+        drop(client);
+        nodes[first].stop().await;
+    }
+}
@@ -0,0 +1,934 @@
+//! A node of the cluster: elections, replication of the log, and application
+//! of the committed entries to the store.
+//!
+//! The state of the node is behind a mutex, held by calls that never await.
+//! The calls that write to the storage run on the blocking threads. Two tasks
+//! drive the node:
+//!
+//! - The driver ticks regularly, and whenever there is something to send. A
+//!   follower or candidate whose election timeout elapsed starts an election.
+//!   A leader sends each follower the entries it misses, its snapshot if they
+//!   were compacted, or a heartbeat. RPCs are sent from their own tasks, one at
+//!   a time per follower, and their responses update the state.
+//! - The applier applies the committed entries to the store in log order, and
+//!   replies to the writes waiting on them. Once enough entries are applied,
+//!   it compacts them into a snapshot of the store.
+
+use crate::connection::{Connection, Frame};
+use crate::server::key_value_store::{Change, Expiry, ScanOptions, Write, WriteBatch};
+use crate::server::{replication, Shutdown};
+use crate::AsyncKeyValueStore;
+
+use super::message::{Entry, Op, Request, Response, Snapshot};
+use super::storage::Storage;
+use super::{Members, NodeId, RaftOptions};
+
+use simple_error::bail;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
+use tokio::task;
+use tokio::time::{self, Instant};
+
+/// Entries sent in one `APPEND`, or applied in one go, at most.
+const MAX_ENTRIES: usize = 256;
+
+/// Delay before the applier retries after failing to apply an entry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// What applying an entry returned, for the write waiting on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Reply {
+    Done,
+
+    /// The `bool` returned by `delete`, `expire` or `persist`.
+    Bool(bool),
+}
+
+/// What a leader knows of a follower.
+#[derive(Debug)]
+struct Progress {
+    /// Index of the next entry to send.
+    next: u64,
+
+    /// Index of the last entry known to match the leader's.
+    matched: u64,
+
+    /// An RPC was sent and its response is awaited.
+    in_flight: bool,
+
+    last_sent: Option<Instant>,
+
+    /// When the last RPC the follower answered in the leader's term was sent.
+    acked: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Raft {
+    storage: Storage,
+    state: State,
+    leader: Option<NodeId>,
+    commit_index: u64,
+    last_applied: u64,
+
+    /// When a follower or candidate starts the next election.
+    election_deadline: Instant,
+
+    /// When the node last heard from the leader. A node that heard from it
+    /// less than an election timeout ago votes for no other candidate, which
+    /// gives the leader a lease to serve reads, see `Raft::lease_holds`.
+    last_contact: Option<Instant>,
+
+    /// The nodes that voted for the candidate.
+    votes: HashSet<NodeId>,
+
+    /// The followers of the leader.
+    progress: HashMap<NodeId, Progress>,
+
+    /// Index of the first entry of the leader's term. The leader only serves
+    /// commands once it applied it, and with it the entries of the previous
+    /// terms.
+    term_start: u64,
+
+    /// The writes proposed by the leader, by index, with their term.
+    pending: HashMap<u64, (u64, oneshot::Sender<Reply>)>,
+
+    /// A snapshot the applier must load into the store.
+    snapshot_to_apply: Option<Arc<Snapshot>>,
+
+    /// The node was shut down, its state is no longer updated.
+    stopped: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Node {
+    id: NodeId,
+    logger: slog::Logger,
+    options: RaftOptions,
+
+    /// The state machine.
+    kv: Box<dyn AsyncKeyValueStore>,
+
+    raft: Mutex<Raft>,
+
+    /// Wakes the driver up: there are entries or responses to process.
+    wakeup: Notify,
+
+    /// Wakes the applier up: entries were committed, or a snapshot installed.
+    committed: Notify,
+
+    /// Idle connections to the other nodes.
+    connections: Mutex<HashMap<NodeId, Connection>>,
+}
+
+impl Node {
+    /// Open the state of the node in the `raft` directory of `data_dir`, and
+    /// start driving it until `notify_shutdown` fires. The committed entries
+    /// are applied to `kv`.
+    pub async fn start(
+        logger: slog::Logger,
+        options: RaftOptions,
+        data_dir: &Path,
+        kv: Box<dyn AsyncKeyValueStore>,
+        notify_shutdown: &broadcast::Sender<()>,
+        shutdown_complete: mpsc::Sender<()>,
+    ) -> crate::Result<Arc<Node>> {
+        let storage = {
+            let (logger, dir, members) = (logger.clone(), data_dir.join("raft"), options.members.clone());
+            task::spawn_blocking(move || Storage::open(&logger, &dir, &members)).await??
+        };
+        info!(
+            logger,
+            "Starting raft node {} at term {} with the entries from {} to {}",
+            options.id,
+            storage.term(),
+            storage.snapshot().index,
+            storage.last_index()
+        );
+
+        let snapshot = storage.snapshot().clone();
+        let node = Arc::new(Node {
+            id: options.id,
+            raft: Mutex::new(Raft {
+                storage,
+                state: State::Follower,
+                leader: None,
+                // The snapshot holds committed entries only.
+                commit_index: snapshot.index,
+                last_applied: 0,
+                election_deadline: election_deadline(&options, Instant::now()),
+                // The node may have answered the leader just before it
+                // stopped, its lease holds until the election timeout elapses.
+                last_contact: Some(Instant::now()),
+                votes: HashSet::new(),
+                progress: HashMap::new(),
+                term_start: 0,
+                pending: HashMap::new(),
+                snapshot_to_apply: (snapshot.index > 0).then_some(snapshot),
+                stopped: false,
+            }),
+            logger,
+            options,
+            kv,
+            wakeup: Notify::new(),
+            committed: Notify::new(),
+            connections: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(drive(node.clone(), Shutdown::new(notify_shutdown.subscribe()), shutdown_complete.clone()));
+        tokio::spawn(apply(node.clone(), Shutdown::new(notify_shutdown.subscribe()), shutdown_complete));
+        return Ok(node);
+    }
+
+    /// Why the node does not serve the data commands, `None` if it does:
+    ///
+    /// - `MOVED host:port` on a follower that knows the leader.
+    /// - `CLUSTERDOWN` while no leader is known.
+    /// - `TRYAGAIN` on a new leader, until it applied the entries committed by
+    ///   the previous ones.
+    /// - `CLUSTERDOWN` on a leader that lost its lease: another leader may
+    ///   have been elected.
+    pub fn refusal(&self) -> Option<String> {
+        let raft = self.raft.lock().unwrap();
+        match (raft.state, raft.leader) {
+            (State::Leader, _) if raft.last_applied < raft.term_start => Some("TRYAGAIN the leader is applying the entries of the previous terms".to_string()),
+            (State::Leader, _) if !raft.lease_holds(self.id, &self.options, Instant::now()) => {
+                Some("CLUSTERDOWN the leader can not reach a majority of the nodes".to_string())
+            }
+            (State::Leader, _) => None,
+            (_, Some(leader)) if raft.storage.members().contains_key(&leader) => Some(format!("MOVED {}", raft.storage.members()[&leader])),
+            _ => Some("CLUSTERDOWN no leader is elected".to_string()),
+        }
+    }
+
+    /// The state of the node, as `field:value` lines.
+    pub fn info(&self) -> String {
+        let raft = self.raft.lock().unwrap();
+        let state = match raft.state {
+            State::Follower => "follower",
+            State::Candidate => "candidate",
+            State::Leader => "leader",
+        };
+        let members: Vec<String> = raft.storage.members().iter().map(|(id, address)| format!("{}={}", id, address)).collect();
+        let fields = [
+            ("node_id", self.id.to_string()),
+            ("role", state.to_string()),
+            ("term", raft.storage.term().to_string()),
+            ("leader", raft.leader.map(|id| id.to_string()).unwrap_or_default()),
+            ("commit_index", raft.commit_index.to_string()),
+            ("last_applied", raft.last_applied.to_string()),
+            ("last_index", raft.storage.last_index().to_string()),
+            ("snapshot_index", raft.storage.snapshot().index.to_string()),
+            ("members", members.join(",")),
+        ];
+        return fields.iter().map(|(field, value)| format!("{}:{}\r\n", field, value)).collect();
+    }
+
+    /// Fail unless the node is the leader and holds its lease, see
+    /// `Raft::lease_holds`. Checked before serving a read.
+    pub fn confirm_leadership(&self) -> crate::Result<()> {
+        let raft = self.raft.lock().unwrap();
+        if raft.stopped || raft.state != State::Leader {
+            bail!("the node is not the leader");
+        }
+        if !raft.lease_holds(self.id, &self.options, Instant::now()) {
+            bail!("the leader can not reach a majority of the nodes");
+        }
+        return Ok(());
+    }
+
+    /// Append `op` to the log of the leader, and wait for it to be applied.
+    /// Fails if the node is not the leader, or stops being the leader before
+    /// the entry is applied: the entry may still be committed by the next
+    /// leader.
+    pub async fn propose(self: &Arc<Self>, op: Op) -> crate::Result<Reply> {
+        return self.propose_with(move |_| Ok(op)).await;
+    }
+
+    /// Add the node `id`, listening on `address`, to the cluster.
+    pub async fn add_member(self: &Arc<Self>, id: NodeId, address: String) -> crate::Result<()> {
+        self.propose_with(move |raft| {
+            let mut members = raft.config_change()?;
+            if members.get(&id) == Some(&address) {
+                bail!("node {} is already a member", id);
+            }
+            members.insert(id, address);
+            return Ok(Op::Config(members));
+        })
+        .await?;
+        return Ok(());
+    }
+
+    /// Remove the node `id` from the cluster. A leader removing itself steps
+    /// down once the change is committed.
+    pub async fn remove_member(self: &Arc<Self>, id: NodeId) -> crate::Result<()> {
+        self.propose_with(move |raft| {
+            let mut members = raft.config_change()?;
+            if members.remove(&id).is_none() {
+                bail!("node {} is not a member", id);
+            }
+            if members.is_empty() {
+                bail!("the last member of the cluster can not be removed");
+            }
+            return Ok(Op::Config(members));
+        })
+        .await?;
+        return Ok(());
+    }
+
+    async fn propose_with<F>(self: &Arc<Self>, make_op: F) -> crate::Result<Reply>
+    where
+        F: FnOnce(&Raft) -> crate::Result<Op> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let node = self.clone();
+        task::spawn_blocking(move || node.append_proposal(make_op, tx)).await??;
+        self.wakeup.notify_one();
+        match rx.await {
+            Ok(reply) => Ok(reply),
+            Err(_) => bail!("the node lost the leadership, the write may or may not be applied"),
+        }
+    }
+
+    fn append_proposal<F>(&self, make_op: F, reply: oneshot::Sender<Reply>) -> crate::Result<()>
+    where
+        F: FnOnce(&Raft) -> crate::Result<Op>,
+    {
+        let mut raft = self.raft.lock().unwrap();
+        if raft.stopped || raft.state != State::Leader {
+            bail!("the node is not the leader");
+        }
+        let entry = Entry {
+            index: raft.storage.last_index() + 1,
+            term: raft.storage.term(),
+            op: make_op(&raft)?,
+        };
+        let (index, term) = (entry.index, entry.term);
+        raft.storage.append(vec![entry])?;
+        raft.pending.insert(index, (term, reply));
+        self.advance_commit(&mut raft);
+        return Ok(());
+    }
+
+    /// Handle an RPC from another node.
+    pub async fn handle(self: &Arc<Self>, request: Request) -> crate::Result<Response> {
+        let node = self.clone();
+        return task::spawn_blocking(move || node.handle_request(request)).await?;
+    }
+
+    fn handle_request(&self, request: Request) -> crate::Result<Response> {
+        let mut raft = self.raft.lock().unwrap();
+        if raft.stopped {
+            bail!("the node is shutting down");
+        }
+        let now = Instant::now();
+        let term = raft.storage.term();
+        match request {
+            Request::Vote {
+                term: candidate_term,
+                candidate,
+                last_index,
+                last_term,
+            } => {
+                // A node that heard from the leader recently ignores the
+                // candidates: a node removed from the cluster, which no longer
+                // hears from it, can not disrupt it.
+                let leader_alive = raft.state == State::Leader || raft.last_contact.is_some_and(|contact| now < contact + self.options.election_timeout);
+                if candidate_term < term || leader_alive {
+                    return Ok(Response::Vote { term, granted: false });
+                }
+                if candidate_term > term {
+                    self.become_follower(&mut raft, candidate_term)?;
+                }
+                let up_to_date = (last_term, last_index) >= (raft.storage.last_term(), raft.storage.last_index());
+                let granted = up_to_date && raft.storage.voted_for().is_none_or(|voted_for| voted_for == candidate);
+                if granted {
+                    raft.storage.set_term(candidate_term, Some(candidate))?;
+                    raft.election_deadline = election_deadline(&self.options, now);
+                }
+                return Ok(Response::Vote { term: candidate_term, granted });
+            }
+            Request::Append {
+                term: leader_term,
+                leader,
+                prev_index,
+                prev_term,
+                commit,
+                entries,
+            } => {
+                if leader_term < term {
+                    let last_index = raft.storage.last_index();
+                    return Ok(Response::Append {
+                        term,
+                        success: false,
+                        last_index,
+                    });
+                }
+                self.follow(&mut raft, leader_term, leader, now)?;
+
+                let failure = |last_index| Response::Append {
+                    term: leader_term,
+                    success: false,
+                    last_index,
+                };
+                if prev_index > raft.storage.last_index() {
+                    return Ok(failure(raft.storage.last_index()));
+                }
+                // The entries up to the snapshot are committed, they match.
+                if prev_index >= raft.storage.snapshot().index && raft.storage.term_at(prev_index) != Some(prev_term) {
+                    return Ok(failure(prev_index - 1));
+                }
+
+                let last_new = prev_index + entries.len() as u64;
+                let mut new_entries = Vec::new();
+                for entry in entries {
+                    if new_entries.is_empty() {
+                        if entry.index <= raft.storage.snapshot().index {
+                            continue;
+                        }
+                        match raft.storage.term_at(entry.index) {
+                            Some(term) if term == entry.term => continue,
+                            Some(_) => {
+                                warn!(self.logger, "Dropping the uncommitted entries from {}", entry.index);
+                                raft.storage.truncate(entry.index)?;
+                            }
+                            None => {}
+                        }
+                    }
+                    new_entries.push(entry);
+                }
+                raft.storage.append(new_entries)?;
+
+                if commit > raft.commit_index && last_new > raft.commit_index {
+                    raft.commit_index = commit.min(last_new);
+                    self.committed.notify_one();
+                }
+                return Ok(Response::Append {
+                    term: leader_term,
+                    success: true,
+                    last_index: last_new,
+                });
+            }
+            Request::InstallSnapshot {
+                term: leader_term,
+                leader,
+                snapshot,
+            } => {
+                if leader_term < term {
+                    return Ok(Response::InstallSnapshot { term });
+                }
+                self.follow(&mut raft, leader_term, leader, now)?;
+                if snapshot.index > raft.commit_index {
+                    info!(self.logger, "Installing the snapshot of node {} at {}", leader, snapshot.index);
+                    raft.storage.install(snapshot.clone())?;
+                    raft.commit_index = snapshot.index;
+                    raft.snapshot_to_apply = Some(snapshot);
+                    self.committed.notify_one();
+                }
+                return Ok(Response::InstallSnapshot { term: leader_term });
+            }
+        }
+    }
+
+    /// Start the next election if its timeout elapsed, or send the entries
+    /// the followers miss. Returns the RPCs to send.
+    fn tick(&self) -> crate::Result<Vec<(NodeId, Request)>> {
+        let mut raft = self.raft.lock().unwrap();
+        if raft.stopped {
+            return Ok(Vec::new());
+        }
+        let now = Instant::now();
+        if raft.state == State::Leader {
+            return Ok(self.replicate(&mut raft, now));
+        }
+        // A node that is not a member, one that was removed or that waits to
+        // be added, never starts an election.
+        if now < raft.election_deadline || !raft.storage.members().contains_key(&self.id) {
+            return Ok(Vec::new());
+        }
+
+        let term = raft.storage.term() + 1;
+        raft.storage.set_term(term, Some(self.id))?;
+        info!(self.logger, "Node {} starts an election for term {}", self.id, term);
+        raft.state = State::Candidate;
+        raft.leader = None;
+        raft.votes = std::iter::once(self.id).collect();
+        raft.election_deadline = election_deadline(&self.options, now);
+        if quorum(raft.storage.members(), |id| raft.votes.contains(&id)) {
+            self.become_leader(&mut raft)?;
+            return Ok(self.replicate(&mut raft, now));
+        }
+
+        let (last_index, last_term) = (raft.storage.last_index(), raft.storage.last_term());
+        let requests = raft
+            .storage
+            .members()
+            .keys()
+            .filter(|&&id| id != self.id)
+            .map(|&id| {
+                let request = Request::Vote {
+                    term,
+                    candidate: self.id,
+                    last_index,
+                    last_term,
+                };
+                (id, request)
+            })
+            .collect();
+        return Ok(requests);
+    }
+
+    /// The RPCs the leader sends to the followers that are not waiting on
+    /// one: the entries they miss, or a heartbeat once it is due.
+    fn replicate(&self, raft: &mut Raft, now: Instant) -> Vec<(NodeId, Request)> {
+        let members = raft.storage.members().clone();
+        raft.progress.retain(|id, _| members.contains_key(id));
+        let (term, last_index, commit) = (raft.storage.term(), raft.storage.last_index(), raft.commit_index);
+
+        let mut requests = Vec::new();
+        for &id in members.keys().filter(|&&id| id != self.id) {
+            let progress = raft.progress.entry(id).or_insert(Progress {
+                next: last_index + 1,
+                matched: 0,
+                in_flight: false,
+                last_sent: None,
+                acked: None,
+            });
+            let heartbeat_due = progress.last_sent.is_none_or(|sent| now >= sent + self.options.heartbeat_interval);
+            if progress.in_flight || (progress.next > last_index && !heartbeat_due) {
+                continue;
+            }
+            progress.in_flight = true;
+            progress.last_sent = Some(now);
+
+            let request = if progress.next <= raft.storage.snapshot().index {
+                Request::InstallSnapshot {
+                    term,
+                    leader: self.id,
+                    snapshot: raft.storage.snapshot().clone(),
+                }
+            } else {
+                let prev_index = progress.next - 1;
+                Request::Append {
+                    term,
+                    leader: self.id,
+                    prev_index,
+                    prev_term: raft.storage.term_at(prev_index).expect("the log holds the entries after the snapshot"),
+                    commit,
+                    entries: raft.storage.entries(progress.next, MAX_ENTRIES),
+                }
+            };
+            requests.push((id, request));
+        }
+        return requests;
+    }
+
+    /// Send `request` to `peer` and process its response.
+    async fn send(self: Arc<Self>, peer: NodeId, request: Request) {
+        let response = match time::timeout(self.options.election_timeout, self.call(peer, &request)).await {
+            Ok(response) => response,
+            Err(_) => Err("the RPC timed out".into()),
+        };
+        if let Err(err) = &response {
+            debug!(self.logger, "err = {}, RPC to node {} failed", err, peer);
+        }
+        let node = self.clone();
+        match task::spawn_blocking(move || node.on_response(peer, &request, response)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!(self.logger, "err = {}, failed to process the response of node {}", err, peer),
+            Err(err) => error!(self.logger, "err = {}, failed to process the response of node {}", err, peer),
+        }
+    }
+
+    async fn call(&self, peer: NodeId, request: &Request) -> crate::Result<Response> {
+        let connection = self.connections.lock().unwrap().remove(&peer);
+        let mut connection = match connection {
+            Some(connection) => connection,
+            None => {
+                let address = self.raft.lock().unwrap().storage.members().get(&peer).cloned();
+                match address {
+                    Some(address) => Connection::new(TcpStream::connect(address).await?),
+                    None => bail!("node {} is not a member", peer),
+                }
+            }
+        };
+        connection.write_frame(&request.to_frame()?).await?;
+        let response = match connection.read_frame().await? {
+            Some(Frame::Error(err)) => bail!("node {} replied {}", peer, err),
+            Some(frame) => Response::from_frame(frame)?,
+            None => bail!("node {} closed the connection", peer),
+        };
+        self.connections.lock().unwrap().insert(peer, connection);
+        return Ok(response);
+    }
+
+    fn on_response(&self, peer: NodeId, request: &Request, response: crate::Result<Response>) -> crate::Result<()> {
+        let mut raft = self.raft.lock().unwrap();
+        let term = raft.storage.term();
+        if raft.stopped || request.term() != term {
+            return Ok(());
+        }
+        let response = match response {
+            Ok(response) => response,
+            Err(_) => {
+                if let Some(progress) = raft.progress.get_mut(&peer) {
+                    progress.in_flight = false;
+                }
+                return Ok(());
+            }
+        };
+        if response.term() > term {
+            return self.become_follower(&mut raft, response.term());
+        }
+
+        match (request, response) {
+            (Request::Vote { .. }, Response::Vote { granted: true, .. }) if raft.state == State::Candidate => {
+                raft.votes.insert(peer);
+                if quorum(raft.storage.members(), |id| raft.votes.contains(&id)) {
+                    self.become_leader(&mut raft)?;
+                    self.wakeup.notify_one();
+                }
+            }
+            (Request::Append { .. }, Response::Append { success, last_index, .. }) if raft.state == State::Leader => {
+                if let Some(progress) = raft.progress.get_mut(&peer) {
+                    progress.in_flight = false;
+                    progress.acked = progress.last_sent;
+                    if success {
+                        progress.matched = progress.matched.max(last_index);
+                        progress.next = progress.matched + 1;
+                    } else {
+                        progress.next = (progress.next - 1).min(last_index + 1).max(progress.matched + 1);
+                    }
+                    self.advance_commit(&mut raft);
+                    self.wakeup.notify_one();
+                }
+            }
+            (Request::InstallSnapshot { snapshot, .. }, Response::InstallSnapshot { .. }) if raft.state == State::Leader => {
+                if let Some(progress) = raft.progress.get_mut(&peer) {
+                    progress.in_flight = false;
+                    progress.acked = progress.last_sent;
+                    progress.matched = progress.matched.max(snapshot.index);
+                    progress.next = progress.matched + 1;
+                    self.advance_commit(&mut raft);
+                    self.wakeup.notify_one();
+                }
+            }
+            _ => {}
+        }
+        return Ok(());
+    }
+
+    fn become_leader(&self, raft: &mut Raft) -> crate::Result<()> {
+        let term = raft.storage.term();
+        info!(self.logger, "Node {} is the leader of term {}", self.id, term);
+        raft.state = State::Leader;
+        raft.leader = Some(self.id);
+        raft.progress.clear();
+        raft.term_start = raft.storage.last_index() + 1;
+        raft.storage.append(vec![Entry {
+            index: raft.term_start,
+            term,
+            op: Op::Noop,
+        }])?;
+        self.advance_commit(raft);
+        return Ok(());
+    }
+
+    /// Move to `term` as a follower, of a leader that is not known yet.
+    fn become_follower(&self, raft: &mut Raft, term: u64) -> crate::Result<()> {
+        if term > raft.storage.term() {
+            raft.storage.set_term(term, None)?;
+        }
+        if raft.state == State::Leader {
+            info!(self.logger, "Node {} steps down at term {}", self.id, term);
+        }
+        raft.state = State::Follower;
+        raft.leader = None;
+        raft.votes.clear();
+        raft.progress.clear();
+        // The writes waiting on the leader fail.
+        raft.pending.clear();
+        raft.election_deadline = election_deadline(&self.options, Instant::now());
+        return Ok(());
+    }
+
+    /// Follow `leader`, which sent an RPC of `term`.
+    fn follow(&self, raft: &mut Raft, term: u64, leader: NodeId, now: Instant) -> crate::Result<()> {
+        if term > raft.storage.term() || raft.state != State::Follower {
+            self.become_follower(raft, term)?;
+        }
+        if raft.leader != Some(leader) {
+            info!(self.logger, "Node {} follows node {} at term {}", self.id, leader, term);
+        }
+        raft.leader = Some(leader);
+        raft.last_contact = Some(now);
+        raft.election_deadline = election_deadline(&self.options, now);
+        return Ok(());
+    }
+
+    /// Commit the last entry of the leader's term stored by a majority, and
+    /// the entries before it.
+    fn advance_commit(&self, raft: &mut Raft) {
+        if raft.state != State::Leader {
+            return;
+        }
+        let members = raft.storage.members().clone();
+        let term = raft.storage.term();
+        for index in (raft.commit_index + 1..=raft.storage.last_index()).rev() {
+            // The entries of the previous terms are only committed along with
+            // one of the leader's term.
+            if raft.storage.term_at(index) != Some(term) {
+                break;
+            }
+            let stored = |id: NodeId| id == self.id || raft.progress.get(&id).is_some_and(|progress| progress.matched >= index);
+            if quorum(&members, stored) {
+                raft.commit_index = index;
+                self.committed.notify_one();
+                break;
+            }
+        }
+    }
+
+    /// Apply the committed entries that were not applied yet, then compact
+    /// the log if it grew enough.
+    async fn apply_committed(self: &Arc<Self>) -> crate::Result<()> {
+        loop {
+            let (snapshot, entries) = {
+                let mut raft = self.raft.lock().unwrap();
+                match raft.snapshot_to_apply.take() {
+                    Some(snapshot) => (Some(snapshot), Vec::new()),
+                    None if raft.last_applied < raft.commit_index => {
+                        let count = ((raft.commit_index - raft.last_applied) as usize).min(MAX_ENTRIES);
+                        (None, raft.storage.entries(raft.last_applied + 1, count))
+                    }
+                    None => break,
+                }
+            };
+
+            if let Some(snapshot) = snapshot {
+                if let Err(err) = replication::load(self.kv.as_ref(), snapshot.entries.clone()).await {
+                    self.raft.lock().unwrap().snapshot_to_apply.get_or_insert(snapshot);
+                    return Err(err);
+                }
+                let mut raft = self.raft.lock().unwrap();
+                raft.last_applied = raft.last_applied.max(snapshot.index);
+                continue;
+            }
+            if entries.is_empty() {
+                break;
+            }
+            for entry in entries {
+                let reply = apply_op(self.kv.as_ref(), entry.op).await?;
+                self.applied(entry.index, entry.term, reply)?;
+            }
+        }
+        return self.compact().await;
+    }
+
+    /// Record that the entry at `index` of `term` was applied, and reply to
+    /// the write waiting on it.
+    fn applied(&self, index: u64, term: u64, reply: Reply) -> crate::Result<()> {
+        let mut raft = self.raft.lock().unwrap();
+        raft.last_applied = raft.last_applied.max(index);
+        if let Some((pending_term, sender)) = raft.pending.remove(&index) {
+            if pending_term == term {
+                let _ = sender.send(reply);
+            }
+        }
+        // A leader that is no longer a member steps down once the change is
+        // committed.
+        if raft.state == State::Leader && !raft.storage.members().contains_key(&self.id) && raft.last_applied >= raft.storage.members_index() {
+            let term = raft.storage.term();
+            self.become_follower(&mut raft, term)?;
+        }
+        return Ok(());
+    }
+
+    /// Replace the applied entries with a snapshot of the store once there are
+    /// `snapshot_threshold` of them.
+    async fn compact(self: &Arc<Self>) -> crate::Result<()> {
+        let (index, term, members) = {
+            let raft = self.raft.lock().unwrap();
+            if raft.snapshot_to_apply.is_some() || raft.last_applied < raft.storage.snapshot().index + self.options.snapshot_threshold {
+                return Ok(());
+            }
+            let index = raft.last_applied;
+            match raft.storage.term_at(index) {
+                Some(term) => (index, term, raft.storage.members_at(index)),
+                None => return Ok(()),
+            }
+        };
+
+        // The applier is the only writer of the store: it holds every entry
+        // up to `index`, and none after.
+        let entries = dump(self.kv.as_ref(), index).await?;
+        let snapshot = Arc::new(Snapshot { index, term, members, entries });
+        let node = self.clone();
+        task::spawn_blocking(move || node.raft.lock().unwrap().storage.install(snapshot)).await??;
+        info!(self.logger, "Compacted the raft log up to {}", index);
+        return Ok(());
+    }
+}
+
+impl Raft {
+    /// Whether the leader `id` holds a lease to serve reads: a majority of the
+    /// nodes answered RPCs it sent less than an election timeout ago, so they
+    /// vote for no other candidate and no other leader can have been elected
+    /// since. A tenth of the timeout is left for the clocks of the nodes
+    /// running at different rates.
+    fn lease_holds(&self, id: NodeId, options: &RaftOptions, now: Instant) -> bool {
+        let lease = options.election_timeout - options.election_timeout / 10;
+        let acked = |node: NodeId| {
+            node == id
+                || self
+                    .progress
+                    .get(&node)
+                    .and_then(|progress| progress.acked)
+                    .is_some_and(|sent| now < sent + lease)
+        };
+        return quorum(self.storage.members(), acked);
+    }
+
+    /// The members to change, if a membership change can start. Only one
+    /// change is committed at a time, and not before the leader committed an
+    /// entry of its term.
+    fn config_change(&self) -> crate::Result<Members> {
+        if self.storage.members_index() > self.commit_index || self.commit_index < self.term_start {
+            bail!("a membership change is in progress");
+        }
+        return Ok(self.storage.members().clone());
+    }
+}
+
+/// Tick the node until `shutdown` is received.
+async fn drive(node: Arc<Node>, mut shutdown: Shutdown, _shutdown_complete: mpsc::Sender<()>) {
+    let tick = node.options.heartbeat_interval / 2;
+    while !shutdown.is_shutdown() {
+        let ticked = {
+            let node = node.clone();
+            task::spawn_blocking(move || node.tick()).await
+        };
+        match ticked {
+            Ok(Ok(requests)) => {
+                for (peer, request) in requests {
+                    tokio::spawn(node.clone().send(peer, request));
+                }
+            }
+            Ok(Err(err)) => error!(node.logger, "err = {}, raft node failed to tick", err),
+            Err(err) => error!(node.logger, "err = {}, raft node failed to tick", err),
+        }
+        tokio::select! {
+            _ = time::sleep(tick) => {}
+            _ = node.wakeup.notified() => {}
+            _ = shutdown.recv() => {}
+        }
+    }
+    node.raft.lock().unwrap().stopped = true;
+}
+
+/// Apply the committed entries until `shutdown` is received.
+async fn apply(node: Arc<Node>, mut shutdown: Shutdown, _shutdown_complete: mpsc::Sender<()>) {
+    while !shutdown.is_shutdown() {
+        if let Err(err) = node.apply_committed().await {
+            error!(node.logger, "err = {}, failed to apply the committed entries", err);
+        }
+        tokio::select! {
+            _ = node.committed.notified() => {}
+            _ = time::sleep(RETRY_DELAY) => {}
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+async fn apply_op(kv: &dyn AsyncKeyValueStore, op: Op) -> crate::Result<Reply> {
+    match op {
+        Op::Noop | Op::Config(_) => Ok(Reply::Done),
+        Op::Set { .. } => match into_write(op)? {
+            Write::Set { key, value, expire } => {
+                kv.set(key, value, expire).await?;
+                Ok(Reply::Done)
+            }
+            Write::Delete { key } => {
+                kv.delete(&key).await?;
+                Ok(Reply::Done)
+            }
+        },
+        Op::Delete { key } => Ok(Reply::Bool(kv.delete(&key).await?)),
+        Op::Expire { key, expires_at } => match expires_at.duration_since(SystemTime::now()) {
+            Ok(expire) => Ok(Reply::Bool(kv.expire(&key, expire).await?)),
+            Err(_) => Ok(Reply::Bool(kv.delete(&key).await?)),
+        },
+        Op::Persist { key } => Ok(Reply::Bool(kv.persist(&key).await?)),
+        Op::Batch(ops) => {
+            let batch = ops.into_iter().map(into_write).collect::<crate::Result<WriteBatch>>()?;
+            kv.write_batch(batch).await?;
+            Ok(Reply::Done)
+        }
+    }
+}
+
+/// The write applying a `Set` or `Delete` op. A value that already expired is
+/// deleted.
+fn into_write(op: Op) -> crate::Result<Write> {
+    match op {
+        Op::Set { key, value, expires_at } => match expires_at.map(|expires_at| expires_at.duration_since(SystemTime::now())) {
+            Some(Ok(expire)) => Ok(Write::Set {
+                key,
+                value,
+                expire: Some(expire),
+            }),
+            Some(Err(_)) => Ok(Write::Delete { key }),
+            None => Ok(Write::Set { key, value, expire: None }),
+        },
+        Op::Delete { key } => Ok(Write::Delete { key }),
+        op => bail!("{:?} is not a write", op),
+    }
+}
+
+/// Every live key of `kv`, as changes numbered `seq`.
+async fn dump(kv: &dyn AsyncKeyValueStore, seq: u64) -> crate::Result<Vec<Change>> {
+    let now = SystemTime::now();
+    let mut entries = Vec::new();
+    for (key, value) in kv.scan(&ScanOptions::default()).await? {
+        let expires_at = match kv.ttl(&key).await? {
+            Expiry::Missing => continue,
+            Expiry::Persistent => None,
+            Expiry::After(expire) => Some(now + expire),
+        };
+        entries.push(Change {
+            seq,
+            key,
+            value: Some(value),
+            expires_at,
+        });
+    }
+    return Ok(entries);
+}
+
+/// Whether a majority of `members` is `counted`.
+fn quorum(members: &Members, counted: impl Fn(NodeId) -> bool) -> bool {
+    let count = members.keys().filter(|&&id| counted(id)).count();
+    return count > members.len() / 2;
+}
+
+/// When the election timeout started `now` elapses: after between one and
+/// two `election_timeout`, at random so that the nodes rarely start their
+/// elections together.
+fn election_deadline(options: &RaftOptions, now: Instant) -> Instant {
+    let timeout = options.election_timeout;
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(options.id);
+    let jitter = hasher.finish() % (timeout.as_millis() as u64 + 1);
+    return now + timeout + Duration::from_millis(jitter);
+}
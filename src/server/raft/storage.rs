@@ -0,0 +1,428 @@
+//! Persistent state of a node, in the `raft` directory of its data directory:
+//!
+//! - `state`: the current term, and the node voted for in it, as the frame
+//!   `[term, id]`, `id` being `nil` before the node votes.
+//! - `log`: the entries that follow the snapshot, each as the frame of an
+//!   `Entry`.
+//! - `snapshot`: the frame of the last `Snapshot`.
+//!
+//! `state` and `snapshot` are written to a temporary file and renamed once
+//! synced. Entries are appended to `log` and synced before the node replies
+//! or counts them as replicated; an entry torn by a crash is dropped on
+//! startup. Truncating the log, or compacting it into a snapshot, rewrites it.
+
+use crate::connection::{Frame, FrameError, Parser};
+use crate::Result;
+
+use super::message::{Entry, Op, Snapshot};
+use super::{Members, NodeId};
+
+use simple_error::bail;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const STATE_FILE: &str = "state";
+const LOG_FILE: &str = "log";
+const SNAPSHOT_FILE: &str = "snapshot";
+
+#[derive(Debug)]
+pub(super) struct Storage {
+    dir: PathBuf,
+    log: File,
+
+    term: u64,
+    voted_for: Option<NodeId>,
+
+    /// The snapshot, an empty one at index 0 if the log was never compacted.
+    snapshot: Arc<Snapshot>,
+
+    /// The entries following the snapshot: `entries[i].index` is
+    /// `snapshot.index + 1 + i`.
+    entries: Vec<Entry>,
+
+    /// The members of the last config entry, or of the snapshot.
+    members: Members,
+}
+
+impl Storage {
+    /// Open the state in `dir`, creating it if needed. A new log starts with
+    /// a config entry of `members`, at index 1 of term 0: the nodes started
+    /// with the same members start with the same log.
+    pub fn open(logger: &slog::Logger, dir: &Path, members: &Members) -> Result<Storage> {
+        std::fs::create_dir_all(dir)?;
+
+        let (term, voted_for) = match std::fs::read(dir.join(STATE_FILE)) {
+            Ok(data) => {
+                let mut parser = Parser::new(parse_file(&data, &dir.join(STATE_FILE))?)?;
+                let term = parser.next_int()?;
+                let voted_for = match parser.next_frame()? {
+                    Frame::Null => None,
+                    Frame::Integer(id) => Some(id.try_into()?),
+                    frame => bail!("{:?} is corrupted: unexpected {:?}", dir.join(STATE_FILE), frame),
+                };
+                (term, voted_for)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (0, None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let snapshot = match std::fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(data) => Snapshot::parse_frames(&mut Parser::new(parse_file(&data, &dir.join(SNAPSHOT_FILE))?)?)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Snapshot::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let (mut entries, end, len) = read_log(&dir.join(LOG_FILE))?;
+        // Entries left over by a crash during a compaction are already in the
+        // snapshot.
+        entries.retain(|entry| entry.index > snapshot.index);
+        if entries.first().is_some_and(|first| first.index != snapshot.index + 1) {
+            warn!(logger, "The raft log does not follow the snapshot at {:?}, dropping it", snapshot.index);
+            entries.clear();
+        }
+
+        if end < len {
+            warn!(
+                logger,
+                "Dropping torn entry of {:?} bytes at the end of the raft log, left by a crash.",
+                len - end
+            );
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(dir.join(LOG_FILE))?;
+        let fresh = term == 0 && snapshot.index == 0 && entries.is_empty();
+        let members_at_start = snapshot.members.clone();
+        let mut storage = Storage {
+            dir: dir.to_path_buf(),
+            log,
+            term,
+            voted_for,
+            snapshot: Arc::new(snapshot),
+            entries,
+            members: members_at_start,
+        };
+        // The log is rewritten without the dropped entries.
+        storage.rewrite_log()?;
+        storage.update_members();
+        if fresh && !members.is_empty() {
+            storage.append(vec![Entry {
+                index: 1,
+                term: 0,
+                op: Op::Config(members.clone()),
+            }])?;
+        }
+        return Ok(storage);
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn voted_for(&self) -> Option<NodeId> {
+        self.voted_for
+    }
+
+    /// Persist the term and the vote in it.
+    pub fn set_term(&mut self, term: u64, voted_for: Option<NodeId>) -> Result<()> {
+        let mut frame = Frame::array();
+        frame.push_int(term.try_into()?);
+        match voted_for {
+            Some(id) => frame.push_int(id.try_into()?),
+            None => frame.push_null(),
+        }
+        write_file(&self.dir.join(STATE_FILE), &frame)?;
+        self.term = term;
+        self.voted_for = voted_for;
+        return Ok(());
+    }
+
+    pub fn snapshot(&self) -> &Arc<Snapshot> {
+        &self.snapshot
+    }
+
+    /// The members as of the last entry, committed or not.
+    pub fn members(&self) -> &Members {
+        &self.members
+    }
+
+    /// The members as of the entry at `index`.
+    pub fn members_at(&self, index: u64) -> Members {
+        return self
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.op {
+                Op::Config(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot.members.clone());
+    }
+
+    /// Index of the last config entry, the snapshot's index if the snapshot
+    /// holds it.
+    pub fn members_index(&self) -> u64 {
+        return self
+            .entries
+            .iter()
+            .rev()
+            .find(|entry| matches!(entry.op, Op::Config(_)))
+            .map_or(self.snapshot.index, |entry| entry.index);
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot.index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    /// Term of the entry at `index`, `None` if it is not in the log or was
+    /// compacted before the last entry of the snapshot.
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        return self.entry(index).map(|entry| entry.term);
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot.index {
+            return None;
+        }
+        return self.entries.get((index - self.snapshot.index - 1) as usize);
+    }
+
+    /// Up to `max` entries from `from`, which must follow the snapshot.
+    pub fn entries(&self, from: u64, max: usize) -> Vec<Entry> {
+        let start = (from.saturating_sub(self.snapshot.index + 1) as usize).min(self.entries.len());
+        let end = (start + max).min(self.entries.len());
+        return self.entries[start..end].to_vec();
+    }
+
+    /// Append `entries`, which must follow the last entry, and sync them.
+    pub fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut buffer = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.index != self.last_index() + 1 + i as u64 {
+                bail!("entry {:?} does not follow the last entry {:?}", entry.index, self.last_index());
+            }
+            buffer.extend_from_slice(&entry.to_frame()?.create_bytes()?);
+        }
+        self.log.write_all(&buffer)?;
+        self.log.sync_data()?;
+        self.entries.extend(entries);
+        self.update_members();
+        return Ok(());
+    }
+
+    /// Drop the entries from `from` on.
+    pub fn truncate(&mut self, from: u64) -> Result<()> {
+        let keep = from.saturating_sub(self.snapshot.index + 1) as usize;
+        if keep < self.entries.len() {
+            self.entries.truncate(keep);
+            self.rewrite_log()?;
+            self.update_members();
+        }
+        return Ok(());
+    }
+
+    /// Replace the entries up to the last one of `snapshot` with it. The
+    /// entries that follow are kept if the log holds the last entry of the
+    /// snapshot, all of them are dropped otherwise. Older snapshots are
+    /// ignored.
+    pub fn install(&mut self, snapshot: Arc<Snapshot>) -> Result<()> {
+        if snapshot.index <= self.snapshot.index {
+            return Ok(());
+        }
+        write_file(&self.dir.join(SNAPSHOT_FILE), &snapshot.to_frame()?)?;
+
+        if self.term_at(snapshot.index) == Some(snapshot.term) {
+            let compacted = (snapshot.index - self.snapshot.index) as usize;
+            self.entries.drain(..compacted);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot = snapshot;
+        self.rewrite_log()?;
+        self.update_members();
+        return Ok(());
+    }
+
+    /// Write the entries to a new log file, which replaces the current one.
+    fn rewrite_log(&mut self) -> Result<()> {
+        let path = self.dir.join(LOG_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", LOG_FILE));
+        let mut buffer = Vec::new();
+        for entry in &self.entries {
+            buffer.extend_from_slice(&entry.to_frame()?.create_bytes()?);
+        }
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp_path, &path)?;
+        self.log = OpenOptions::new().append(true).open(&path)?;
+        return Ok(());
+    }
+
+    fn update_members(&mut self) {
+        self.members = self.members_at(self.last_index());
+    }
+}
+
+/// The entries of the log file at `path`, the end of the last complete one
+/// and the length of the file.
+fn read_log(path: &Path) -> Result<(Vec<Entry>, u64, u64)> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0, 0)),
+        Err(err) => return Err(err.into()),
+    };
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut end = 0;
+    let mut cursor = Cursor::new(&data[..]);
+    while (cursor.position() as usize) < data.len() {
+        let start = cursor.position();
+        match Frame::check(&mut cursor) {
+            Ok(()) => {}
+            Err(FrameError::Incomplete) => break,
+            Err(FrameError::Other(err)) => bail!("raft log {:?} is corrupted at byte {:?}: {}", path, start, err),
+        }
+        cursor.set_position(start);
+        let entry = Entry::parse_frames(&mut Parser::new(Frame::parse(&mut cursor)?)?)?;
+        if let Some(last) = entries.last() {
+            if entry.index != last.index + 1 {
+                bail!(
+                    "raft log {:?} is corrupted at byte {:?}: entry {:?} follows {:?}",
+                    path,
+                    start,
+                    entry.index,
+                    last.index
+                );
+            }
+        }
+        entries.push(entry);
+        end = cursor.position();
+    }
+    return Ok((entries, end, data.len() as u64));
+}
+
+/// The frame of a file written by `write_file`.
+fn parse_file(data: &[u8], path: &Path) -> Result<Frame> {
+    let mut cursor = Cursor::new(data);
+    match Frame::check(&mut cursor) {
+        Ok(()) if cursor.position() as usize == data.len() => {}
+        _ => bail!("{:?} is corrupted", path),
+    }
+    cursor.set_position(0);
+    return Ok(Frame::parse(&mut cursor)?);
+}
+
+/// Replace the file at `path` with `frame`, through a synced temporary file.
+fn write_file(path: &Path, frame: &Frame) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&frame.create_bytes()?)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp_path, path)?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::key_value_store::Change;
+    use bytes::Bytes;
+
+    fn set(index: u64, term: u64, key: &str) -> Entry {
+        Entry {
+            index,
+            term,
+            op: Op::Set {
+                key: key.to_string(),
+                value: Bytes::from("value"),
+                expires_at: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_storage() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let dir = tempfile::tempdir().unwrap();
+        let members: Members = vec![(1, "127.0.0.1:7001".to_string())].into_iter().collect();
+
+        let mut storage = Storage::open(&logger, dir.path(), &members).unwrap();
+        assert_eq!((storage.last_index(), storage.last_term()), (1, 0));
+        assert_eq!(storage.members(), &members);
+        storage.set_term(2, Some(1)).unwrap();
+        storage.append(vec![set(2, 2, "a"), set(3, 2, "b"), set(4, 2, "c")]).unwrap();
+        assert!(storage.append(vec![set(6, 2, "e")]).is_err());
+        storage.truncate(4).unwrap();
+        storage.append(vec![set(4, 3, "d")]).unwrap();
+        drop(storage);
+
+        // The state survives a restart, the members given to a node that has
+        // a log are ignored.
+        let mut storage = Storage::open(&logger, dir.path(), &Members::new()).unwrap();
+        assert_eq!((storage.term(), storage.voted_for()), (2, Some(1)));
+        assert_eq!((storage.last_index(), storage.last_term(), storage.term_at(3)), (4, 3, Some(2)));
+        assert_eq!(storage.entries(3, 10), vec![set(3, 2, "b"), set(4, 3, "d")]);
+
+        // A torn entry is dropped.
+        let mut file = OpenOptions::new().append(true).open(dir.path().join(LOG_FILE)).unwrap();
+        file.write_all(&set(5, 3, "e").to_frame().unwrap().create_bytes().unwrap()[..10]).unwrap();
+        drop(file);
+        let storage_len = std::fs::metadata(dir.path().join(LOG_FILE)).unwrap().len();
+        storage = Storage::open(&logger, dir.path(), &members).unwrap();
+        assert_eq!(storage.last_index(), 4);
+        assert!(std::fs::metadata(dir.path().join(LOG_FILE)).unwrap().len() < storage_len);
+
+        // Compaction keeps the entries that follow the snapshot.
+        let mut members2 = members.clone();
+        members2.insert(2, "127.0.0.1:7002".to_string());
+        storage
+            .append(vec![Entry {
+                index: 5,
+                term: 3,
+                op: Op::Config(members2.clone()),
+            }])
+            .unwrap();
+        assert_eq!((storage.members(), storage.members_index()), (&members2, 5));
+        assert_eq!(storage.members_at(4), members);
+        let snapshot = Snapshot {
+            index: 3,
+            term: 2,
+            members: members.clone(),
+            entries: vec![Change {
+                seq: 3,
+                key: "a".to_string(),
+                value: Some(Bytes::from("value")),
+                expires_at: None,
+            }],
+        };
+        storage.install(Arc::new(snapshot.clone())).unwrap();
+        assert_eq!((storage.entry(3), storage.term_at(3), storage.last_index()), (None, Some(2), 5));
+        drop(storage);
+        let mut storage = Storage::open(&logger, dir.path(), &members).unwrap();
+        assert_eq!(storage.snapshot().as_ref(), &snapshot);
+        assert_eq!(storage.entries(4, 10).len(), 2);
+        assert_eq!(storage.members(), &members2);
+
+        // A snapshot of entries the log does not have replaces all of them.
+        storage.install(Arc::new(Snapshot { index: 8, term: 4, ..snapshot })).unwrap();
+        assert_eq!((storage.last_index(), storage.last_term()), (8, 4));
+        assert_eq!(storage.members(), &members);
+    }
+}
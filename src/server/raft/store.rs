@@ -0,0 +1,125 @@
+use crate::server::key_value_store::{Changes, Expiry, ScanOptions, Snapshot, Write, WriteBatch};
+use crate::AsyncKeyValueStore;
+
+use super::message::Op;
+use super::node::{Node, Reply};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use simple_error::bail;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// The store served by a node of a cluster.
+///
+/// Writes are appended to the log of the cluster, and return once committed
+/// and applied to `kv`, the state machine of the node. Expirations are turned
+/// into absolute times first, so that every node expires a key at the same
+/// time.
+///
+/// Reads are served by `kv` directly. Only the leader serves commands, see
+/// `Node::refusal`, and only while it holds a lease, which is checked again
+/// before each read: a leader cut off from the majority fails reads instead of
+/// serving stale values.
+#[derive(Debug, Clone)]
+pub(crate) struct RaftStore {
+    node: Arc<Node>,
+    kv: Box<dyn AsyncKeyValueStore>,
+}
+
+impl RaftStore {
+    pub fn new(node: Arc<Node>, kv: Box<dyn AsyncKeyValueStore>) -> RaftStore {
+        RaftStore { node, kv }
+    }
+
+    async fn propose_bool(&self, op: Op) -> crate::Result<bool> {
+        match self.node.propose(op).await? {
+            Reply::Bool(value) => Ok(value),
+            reply => bail!("unexpected reply {:?}", reply),
+        }
+    }
+}
+
+fn expires_at(expire: Option<Duration>) -> Option<SystemTime> {
+    return expire.map(|expire| SystemTime::now() + expire);
+}
+
+#[async_trait]
+impl AsyncKeyValueStore for RaftStore {
+    async fn get(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        self.node.confirm_leadership()?;
+        self.kv.get(key).await
+    }
+
+    async fn set(&self, key: String, value: Bytes, expire: Option<Duration>) -> crate::Result<()> {
+        let op = Op::Set {
+            key,
+            value,
+            expires_at: expires_at(expire),
+        };
+        self.node.propose(op).await?;
+        return Ok(());
+    }
+
+    async fn delete(&self, key: &str) -> crate::Result<bool> {
+        self.propose_bool(Op::Delete { key: key.to_string() }).await
+    }
+
+    async fn expire(&self, key: &str, expire: Duration) -> crate::Result<bool> {
+        let op = Op::Expire {
+            key: key.to_string(),
+            expires_at: SystemTime::now() + expire,
+        };
+        self.propose_bool(op).await
+    }
+
+    async fn ttl(&self, key: &str) -> crate::Result<Expiry> {
+        self.node.confirm_leadership()?;
+        self.kv.ttl(key).await
+    }
+
+    async fn persist(&self, key: &str) -> crate::Result<bool> {
+        self.propose_bool(Op::Persist { key: key.to_string() }).await
+    }
+
+    async fn scan(&self, options: &ScanOptions) -> crate::Result<Vec<(String, Bytes)>> {
+        self.node.confirm_leadership()?;
+        self.kv.scan(options).await
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        let ops = batch
+            .into_iter()
+            .map(|write| match write {
+                Write::Set { key, value, expire } => Op::Set {
+                    key,
+                    value,
+                    expires_at: expires_at(expire),
+                },
+                Write::Delete { key } => Op::Delete { key },
+            })
+            .collect();
+        self.node.propose(Op::Batch(ops)).await?;
+        return Ok(());
+    }
+
+    async fn save(&self) -> crate::Result<()> {
+        self.kv.save().await
+    }
+
+    async fn bgsave(&self) -> crate::Result<()> {
+        self.kv.bgsave().await
+    }
+
+    async fn changes(&self, from: u64) -> crate::Result<Changes> {
+        self.kv.changes(from).await
+    }
+
+    async fn snapshot(&self) -> crate::Result<Snapshot> {
+        self.kv.snapshot().await
+    }
+
+    fn shutdown_purge_task(&self) {
+        self.kv.shutdown_purge_task();
+    }
+}
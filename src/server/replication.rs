@@ -0,0 +1,250 @@
+//! Replication of a primary server to its replicas.
+//!
+//! A replica connects to its primary with `PSYNC`, giving the sequence number
+//! of the last change of the primary it applied. The primary streams the
+//! changes that follow if it still has them. Otherwise it first sends a
+//! snapshot of its keys, which replaces the keys of the replica. The replica
+//! then applies the changes as they are streamed, and resumes from the last
+//! one it applied when the connection breaks.
+//!
+//! The offset of a replica is kept in memory: a restarted replica does a full
+//! sync.
+
+use crate::client::client::{self, Resync};
+use crate::server::key_value_store::{Change, ScanOptions, Write, WriteBatch};
+use crate::server::Shutdown;
+use crate::AsyncKeyValueStore;
+
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_stream::StreamExt;
+
+/// Delay before reconnecting to the primary after the connection broke.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Replicate the store of `primary` to `kv` until `shutdown` is received.
+pub(crate) async fn replicate(
+    logger: slog::Logger,
+    primary: String,
+    kv: Box<dyn AsyncKeyValueStore>,
+    mut shutdown: Shutdown,
+    _shutdown_complete: mpsc::Sender<()>,
+) {
+    // Sequence number of the last change of the primary applied to `kv`.
+    let mut offset = 0;
+    while !shutdown.is_shutdown() {
+        tokio::select! {
+            res = sync(&logger, &primary, kv.as_ref(), &mut offset) => match res {
+                Ok(()) => warn!(logger, "The primary {} closed the replication stream", primary),
+                Err(err) => warn!(logger, "err = {}, replication from {} failed", err, primary),
+            },
+            _ = shutdown.recv() => return,
+        }
+        tokio::select! {
+            _ = time::sleep(RETRY_DELAY) => {}
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+/// Catch up with `primary` from `offset`, then apply its changes until the
+/// connection breaks. `offset` follows the applied changes.
+async fn sync(logger: &slog::Logger, primary: &str, kv: &dyn AsyncKeyValueStore, offset: &mut u64) -> crate::Result<()> {
+    let client = client::connect(primary).await?;
+    let (resync, changes) = client.psync(*offset).await?;
+    match resync {
+        Resync::Full { seq, entries } => {
+            info!(logger, "Full sync from {}: {} keys as of change {}", primary, entries.len(), seq);
+            load(kv, entries).await?;
+            *offset = seq;
+        }
+        Resync::Partial => info!(logger, "Resuming replication from {} after change {}", primary, offset),
+    }
+
+    tokio::pin!(changes);
+    while let Some(change) = changes.next().await {
+        let change = change?;
+        let seq = change.seq;
+        match into_write(change) {
+            Write::Set { key, value, expire } => kv.set(key, value, expire).await?,
+            Write::Delete { key } => {
+                kv.delete(&key).await?;
+            }
+        }
+        *offset = seq;
+    }
+    return Ok(());
+}
+
+/// Replace every key of `kv` with the keys of a snapshot, in a single batch.
+pub(super) async fn load(kv: &dyn AsyncKeyValueStore, entries: Vec<Change>) -> crate::Result<()> {
+    let mut batch = WriteBatch::new();
+    let keys: HashSet<&str> = entries.iter().map(|entry| entry.key.as_str()).collect();
+    for (key, _) in kv.scan(&ScanOptions::default()).await? {
+        if !keys.contains(key.as_str()) {
+            batch.delete(key);
+        }
+    }
+    drop(keys);
+    for entry in entries {
+        batch.push(into_write(entry));
+    }
+    return kv.write_batch(batch).await;
+}
+
+/// The write applying `change`. A value that expired in transit is deleted.
+fn into_write(change: Change) -> Write {
+    let key = change.key;
+    let value = match change.value {
+        Some(value) => value,
+        None => return Write::Delete { key },
+    };
+    let expire = match change.expires_at.map(|expires_at| expires_at.duration_since(SystemTime::now())) {
+        Some(Ok(expire)) => Some(expire),
+        Some(Err(_)) => return Write::Delete { key },
+        None => None,
+    };
+    return Write::Set { key, value, expire };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{key_value_store::Backend, start_server, Mode, StoreOptions};
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+
+    /// Start a server listening on `addr`, stopped by the returned sender.
+    async fn server(addr: &str, backend: Backend, dir: PathBuf, replica_of: Option<SocketAddr>) -> (SocketAddr, oneshot::Sender<()>, JoinHandle<()>) {
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let handle = tokio::spawn(async move {
+            let mode = replica_of.map_or(Mode::Standalone, |primary| Mode::ReplicaOf(primary.to_string()));
+            start_server(logger, listener, stopped, backend, &dir, StoreOptions::default(), mode)
+                .await
+                .unwrap();
+        });
+        return (addr, stop, handle);
+    }
+
+    async fn wait_for(client: &mut client::Client, key: &str, expected: Option<&str>) {
+        for _ in 0..500 {
+            if client.get(key).await.unwrap().as_deref() == expected.map(str::as_bytes) {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{:?} never became {:?} on the replica", key, expected);
+    }
+
+    #[tokio::test]
+    async fn test_replication() {
+        let (primary_dir, replica_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (primary_addr, stop, handle) = server("127.0.0.1:0", Backend::SimpleStore, primary_dir.path().to_path_buf(), None).await;
+        let mut primary = client::connect(primary_addr).await.unwrap();
+        primary.set("key1", Bytes::from("value1")).await.unwrap();
+        primary.set_expires("key2", Bytes::from("value2"), Duration::from_secs(60)).await.unwrap();
+
+        // A replica without changes gets a snapshot, one that is up to date
+        // only gets the changes that follow.
+        let (resync, _) = client::connect(primary_addr).await.unwrap().psync(0).await.unwrap();
+        let offset = match resync {
+            Resync::Full { seq, entries } => {
+                assert_eq!(entries.len(), 2);
+                assert!(entries[1].expires_at.is_some());
+                seq
+            }
+            other => panic!("expected a full sync, got {:?}", other),
+        };
+        let (resync, changes) = client::connect(primary_addr).await.unwrap().psync(offset).await.unwrap();
+        assert_eq!(resync, Resync::Partial);
+        tokio::pin!(changes);
+        primary.set("key3", Bytes::from("value3")).await.unwrap();
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!((change.seq, change.key.as_str()), (offset + 1, "key3"));
+
+        // The replica drops its own keys on the full sync.
+        let (replica_addr, replica_stop, replica_handle) = server("127.0.0.1:0", Backend::MiniRedis, replica_dir.path().to_path_buf(), None).await;
+        let mut replica = client::connect(replica_addr).await.unwrap();
+        replica.set("stale", Bytes::from("value")).await.unwrap();
+        drop(replica);
+        replica_stop.send(()).unwrap();
+        replica_handle.await.unwrap();
+        let (replica_addr, replica_stop, replica_handle) =
+            server("127.0.0.1:0", Backend::MiniRedis, replica_dir.path().to_path_buf(), Some(primary_addr)).await;
+        let mut replica = client::connect(replica_addr).await.unwrap();
+        wait_for(&mut replica, "key3", Some("value3")).await;
+        assert_eq!(replica.get("key1").await.unwrap(), Some(Bytes::from("value1")));
+        assert!(matches!(replica.ttl("key2").await.unwrap(), crate::server::key_value_store::Expiry::After(_)));
+        assert_eq!(replica.get("stale").await.unwrap(), None);
+
+        // It serves reads and rejects writes.
+        let err = replica.set("key1", Bytes::from("other")).await.unwrap_err();
+        assert!(err.to_string().starts_with("READONLY"), "{}", err);
+        assert_eq!(replica.del(&["key1"]).await.unwrap_err().to_string(), err.to_string());
+
+        // It keeps applying the changes of the primary.
+        primary.set("key1", Bytes::from("updated")).await.unwrap();
+        primary.del(&["key3"]).await.unwrap();
+        wait_for(&mut replica, "key1", Some("updated")).await;
+        wait_for(&mut replica, "key3", None).await;
+
+        // It resumes once the primary is back.
+        drop(primary);
+        stop.send(()).unwrap();
+        handle.await.unwrap();
+        let (_, stop, handle) = server(&primary_addr.to_string(), Backend::SimpleStore, primary_dir.path().to_path_buf(), None).await;
+        let mut primary = client::connect(primary_addr).await.unwrap();
+        primary.set("key4", Bytes::from("value4")).await.unwrap();
+        wait_for(&mut replica, "key4", Some("value4")).await;
+        assert_eq!(replica.get("key1").await.unwrap(), Some(Bytes::from("updated")));
+
+        drop((primary, replica));
+        replica_stop.send(()).unwrap();
+        replica_handle.await.unwrap();
+        stop.send(()).unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wiped_primary() {
+        let (primary_dir, replica_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (primary_addr, stop, handle) = server("127.0.0.1:0", Backend::SimpleStore, primary_dir.path().to_path_buf(), None).await;
+        let mut primary = client::connect(primary_addr).await.unwrap();
+        for i in 0..5 {
+            primary.set(&format!("key{}", i), Bytes::from("value")).await.unwrap();
+        }
+        let (replica_addr, replica_stop, replica_handle) =
+            server("127.0.0.1:0", Backend::MiniRedis, replica_dir.path().to_path_buf(), Some(primary_addr)).await;
+        let mut replica = client::connect(replica_addr).await.unwrap();
+        wait_for(&mut replica, "key4", Some("value")).await;
+
+        // A primary that lost its changes cannot continue the stream of the
+        // replica, which gets a full sync instead.
+        drop(primary);
+        stop.send(()).unwrap();
+        handle.await.unwrap();
+        let wiped_dir = tempfile::tempdir().unwrap();
+        let (_, stop, handle) = server(&primary_addr.to_string(), Backend::SimpleStore, wiped_dir.path().to_path_buf(), None).await;
+        let (resync, _) = client::connect(primary_addr).await.unwrap().psync(5).await.unwrap();
+        assert!(matches!(resync, Resync::Full { seq: 0, .. }), "{:?}", resync);
+        let mut primary = client::connect(primary_addr).await.unwrap();
+        primary.set("new", Bytes::from("value")).await.unwrap();
+        wait_for(&mut replica, "new", Some("value")).await;
+        assert_eq!(replica.get("key0").await.unwrap(), None);
+
+        drop((primary, replica));
+        replica_stop.send(()).unwrap();
+        replica_handle.await.unwrap();
+        stop.send(()).unwrap();
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,482 @@
+use crate::{
+    connection::{Command, Frame},
+    server::key_value_store::{AsyncKeyValueStore, Expiry, ScanOptions, Write, WriteBatch},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
+
+/// Watched keys of every connection, shared by all the connections of a
+/// server.
+#[derive(Debug, Default)]
+pub struct Watches {
+    /// Held for reading while a command runs outside of a transaction, and for
+    /// writing while a transaction is executed. No command can run between the
+    /// check of the watched keys of a transaction and its commit.
+    running: RwLock<()>,
+
+    /// For each watched key, the flags of the connections watching it. A flag
+    /// is raised when the key is modified.
+    watchers: Mutex<HashMap<String, Vec<Arc<AtomicBool>>>>,
+}
+
+impl Watches {
+    fn watch(&self, key: &str, flag: &Arc<AtomicBool>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        let flags = watchers.entry(key.to_string()).or_default();
+        if !flags.iter().any(|watcher| Arc::ptr_eq(watcher, flag)) {
+            flags.push(flag.clone());
+        }
+    }
+
+    fn unwatch(&self, key: &str, flag: &Arc<AtomicBool>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if let Some(flags) = watchers.get_mut(key) {
+            flags.retain(|watcher| !Arc::ptr_eq(watcher, flag));
+            if flags.is_empty() {
+                watchers.remove(key);
+            }
+        }
+    }
+
+    /// Raise the flag of every connection watching one of `keys`.
+    ///
+    /// A raised flag stays raised until its connection unwatches all of its
+    /// keys, so the keys no longer need to be watched.
+    fn touch<'a>(&self, keys: impl IntoIterator<Item = &'a str>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if watchers.is_empty() {
+            return;
+        }
+        for key in keys {
+            for flag in watchers.remove(key).unwrap_or_default() {
+                flag.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Transaction state of a connection.
+///
+/// `MULTI` starts queuing the commands of the connection, `EXEC` then runs
+/// them and commits their writes to the store as a whole. The keys watched
+/// with `WATCH` before `MULTI` make `EXEC` apply nothing if another command
+/// modified them in the meantime.
+#[derive(Debug)]
+pub struct Transaction {
+    watches: Arc<Watches>,
+
+    /// Commands queued since `MULTI`, `None` outside of a transaction.
+    queued: Option<Vec<Command>>,
+
+    /// True if a command could not be queued, `EXEC` then aborts.
+    failed: bool,
+
+    /// Keys watched by the connection.
+    watched: Vec<String>,
+
+    /// Raised when one of the watched keys is modified.
+    dirty: Arc<AtomicBool>,
+}
+
+impl Transaction {
+    pub fn new(watches: Arc<Watches>) -> Transaction {
+        Transaction {
+            watches,
+            queued: None,
+            failed: false,
+            watched: Vec::new(),
+            dirty: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns `true` between `MULTI` and `EXEC` or `DISCARD`.
+    pub fn is_active(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    pub fn multi(&mut self) -> Frame {
+        if self.is_active() {
+            return Frame::Error("ERR MULTI calls can not be nested".to_string());
+        }
+        self.queued = Some(Vec::new());
+        self.failed = false;
+        return Frame::Simple("OK".to_string());
+    }
+
+    /// Queue `cmd` until `EXEC`.
+    pub fn queue(&mut self, cmd: Command) -> Frame {
+        match self.queued.as_mut() {
+            Some(queued) => {
+                queued.push(cmd);
+                Frame::Simple("QUEUED".to_string())
+            }
+            None => Frame::Error("ERR command queued without MULTI".to_string()),
+        }
+    }
+
+    /// Make the next `EXEC` abort, if a transaction is active.
+    pub fn fail(&mut self) {
+        if self.is_active() {
+            self.failed = true;
+        }
+    }
+
+    pub fn discard(&mut self) -> Frame {
+        if self.queued.take().is_none() {
+            return Frame::Error("ERR DISCARD without MULTI".to_string());
+        }
+        self.unwatch();
+        return Frame::Simple("OK".to_string());
+    }
+
+    pub fn watch(&mut self, keys: Vec<String>) -> Frame {
+        if self.is_active() {
+            return Frame::Error("ERR WATCH inside MULTI is not allowed".to_string());
+        }
+        for key in keys {
+            self.watches.watch(&key, &self.dirty);
+            self.watched.push(key);
+        }
+        return Frame::Simple("OK".to_string());
+    }
+
+    pub fn unwatch(&mut self) {
+        for key in self.watched.drain(..) {
+            self.watches.unwatch(&key, &self.dirty);
+        }
+        self.dirty = Arc::new(AtomicBool::new(false));
+    }
+
+    /// Run `cmd` outside of a transaction, flagging the connections watching
+    /// the keys it modifies.
+    pub async fn run(&self, cmd: Command, kv: &dyn AsyncKeyValueStore) -> crate::Result<Frame> {
+        let _running = self.watches.running.read().await;
+        self.watches.touch(cmd.modified_keys());
+        return cmd.execute(kv).await;
+    }
+
+    /// Run the queued commands and commit their writes, unless the
+    /// transaction failed or a watched key was modified. Replies with the
+    /// array of the replies of the commands, or `nil` if nothing was applied
+    /// because of a watched key.
+    pub async fn exec(&mut self, kv: Box<dyn AsyncKeyValueStore>) -> Frame {
+        let queued = match self.queued.take() {
+            Some(queued) => queued,
+            None => return Frame::Error("ERR EXEC without MULTI".to_string()),
+        };
+
+        let watches = self.watches.clone();
+        let _running = watches.running.write().await;
+        let dirty = self.dirty.load(Ordering::SeqCst);
+        self.unwatch();
+
+        if self.failed {
+            return Frame::Error("EXECABORT Transaction discarded because of previous errors.".to_string());
+        }
+        if dirty {
+            return Frame::Null;
+        }
+
+        match execute_all(queued, kv).await {
+            Ok((responses, modified)) => {
+                self.watches.touch(modified.iter().map(String::as_str));
+                Frame::Array(responses)
+            }
+            Err(err) => Frame::Error(format!("ERR transaction discarded: {}", err)),
+        }
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.unwatch();
+    }
+}
+
+/// Run `commands` against a `Pending` view of `kv` and write their writes as
+/// a single batch.
+/// Returns the replies of the commands and the keys they modified.
+async fn execute_all(commands: Vec<Command>, kv: Box<dyn AsyncKeyValueStore>) -> crate::Result<(Vec<Frame>, Vec<String>)> {
+    let pending = Pending::new(kv.clone());
+    let mut responses = Vec::with_capacity(commands.len());
+    let mut modified = Vec::new();
+    for cmd in commands {
+        modified.extend(cmd.modified_keys().into_iter().map(str::to_string));
+        responses.push(cmd.execute(&pending).await?);
+    }
+
+    kv.write_batch(pending.into_batch()).await?;
+    return Ok((responses, modified));
+}
+
+/// Value of a key written by a transaction, `None` if it was deleted.
+type PendingValue = Option<(Bytes, Option<Instant>)>;
+
+/// A store buffering the writes of a transaction in front of `kv`.
+///
+/// Reads see the buffered writes, which are only applied to `kv` when they
+/// are all committed together.
+#[derive(Debug, Clone)]
+struct Pending {
+    kv: Box<dyn AsyncKeyValueStore>,
+    writes: Arc<Mutex<BTreeMap<String, PendingValue>>>,
+}
+
+impl Pending {
+    fn new(kv: Box<dyn AsyncKeyValueStore>) -> Pending {
+        Pending {
+            kv,
+            writes: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// The buffered value of `key`: `None` if the transaction did not write
+    /// it, `Some(None)` if it deleted it or if it expired.
+    fn buffered(&self, key: &str) -> Option<Option<(Bytes, Option<Instant>)>> {
+        let writes = self.writes.lock().unwrap();
+        let value = writes.get(key)?.clone();
+        return Some(value.filter(|(_, when)| when.map(|when| when > Instant::now()).unwrap_or(true)));
+    }
+
+    fn put(&self, key: &str, value: PendingValue) {
+        self.writes.lock().unwrap().insert(key.to_string(), value);
+    }
+
+    /// The writes to commit, in key order.
+    fn into_batch(self) -> WriteBatch {
+        let now = Instant::now();
+        let writes = std::mem::take(&mut *self.writes.lock().unwrap());
+        return writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some((value, None)) => Write::Set { key, value, expire: None },
+                Some((value, Some(when))) if when > now => Write::Set {
+                    key,
+                    value,
+                    expire: Some(when - now),
+                },
+                _ => Write::Delete { key },
+            })
+            .collect();
+    }
+}
+
+#[async_trait]
+impl AsyncKeyValueStore for Pending {
+    async fn get(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        match self.buffered(key) {
+            Some(value) => Ok(value.map(|(value, _)| value)),
+            None => self.kv.get(key).await,
+        }
+    }
+
+    async fn set(&self, key: String, value: Bytes, expire: Option<Duration>) -> crate::Result<()> {
+        self.put(&key, Some((value, expire.map(|expire| Instant::now() + expire))));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> crate::Result<bool> {
+        let existed = self.get(key).await?.is_some();
+        self.put(key, None);
+        Ok(existed)
+    }
+
+    /// The expiration is buffered along with the current value of the key.
+    async fn expire(&self, key: &str, expire: Duration) -> crate::Result<bool> {
+        match self.get(key).await? {
+            Some(value) => {
+                self.put(key, Some((value, Some(Instant::now() + expire))));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn ttl(&self, key: &str) -> crate::Result<Expiry> {
+        match self.buffered(key) {
+            Some(Some((_, Some(when)))) => Ok(Expiry::After(when - Instant::now())),
+            Some(Some((_, None))) => Ok(Expiry::Persistent),
+            Some(None) => Ok(Expiry::Missing),
+            None => self.kv.ttl(key).await,
+        }
+    }
+
+    async fn persist(&self, key: &str) -> crate::Result<bool> {
+        let value = match self.get(key).await? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if self.ttl(key).await? == Expiry::Persistent {
+            return Ok(false);
+        }
+        self.put(key, Some((value, None)));
+        Ok(true)
+    }
+
+    async fn scan(&self, options: &ScanOptions) -> crate::Result<Vec<(String, Bytes)>> {
+        if options.is_empty() {
+            return Ok(Vec::new());
+        }
+        let writes: Vec<(String, PendingValue)> = {
+            let writes = self.writes.lock().unwrap();
+            writes.range(options.range()).map(|(key, value)| (key.clone(), value.clone())).collect()
+        };
+
+        // Each buffered write can hide at most one of the entries of `kv`, a
+        // few more entries are enough to fill the limit.
+        let mut wider = options.clone();
+        wider.limit = options.limit.map(|limit| limit.saturating_add(writes.len()));
+        let mut entries: BTreeMap<String, Bytes> = self.kv.scan(&wider).await?.into_iter().collect();
+
+        let now = Instant::now();
+        for (key, value) in writes {
+            match value {
+                Some((value, when)) if when.map(|when| when > now).unwrap_or(true) => {
+                    entries.insert(key, value);
+                }
+                _ => {
+                    entries.remove(&key);
+                }
+            }
+        }
+        return options.collect(entries.into_iter().map(Ok));
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        for write in batch {
+            match write {
+                Write::Set { key, value, expire } => self.set(key, value, expire).await?,
+                Write::Delete { key } => {
+                    self.delete(&key).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn shutdown_purge_task(&self) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::cmd::{Del, Get, Set};
+    use crate::server::key_value_store::mini_redis::MiniRedis;
+    use crate::server::key_value_store::SyncAdapter;
+
+    #[tokio::test]
+    async fn test_pending() {
+        let kv: Box<dyn AsyncKeyValueStore> = Box::new(SyncAdapter::in_memory(Box::new(MiniRedis::in_memory())));
+        for key in ["a", "b", "c", "d"] {
+            kv.set(key.to_string(), Bytes::from(key), None).await.unwrap();
+        }
+
+        let pending = Pending::new(kv.clone());
+        assert!(pending.delete("b").await.unwrap());
+        pending.set("bb".to_string(), Bytes::from("bb"), None).await.unwrap();
+        pending.set("c".to_string(), Bytes::from("new c"), Some(Duration::from_secs(60))).await.unwrap();
+        assert!(pending.persist("c").await.unwrap());
+        assert!(pending.expire("d", Duration::from_secs(60)).await.unwrap());
+
+        // Reads see the pending writes, the store does not.
+        assert_eq!(pending.get("b").await.unwrap(), None);
+        assert_eq!(pending.get("c").await.unwrap(), Some(Bytes::from("new c")));
+        assert_eq!(pending.ttl("c").await.unwrap(), Expiry::Persistent);
+        assert!(matches!(pending.ttl("d").await.unwrap(), Expiry::After(_)));
+        assert_eq!(kv.get("b").await.unwrap(), Some(Bytes::from("b")));
+        assert_eq!(kv.ttl("d").await.unwrap(), Expiry::Persistent);
+
+        let options = ScanOptions {
+            limit: Some(3),
+            ..ScanOptions::default()
+        };
+        let keys: Vec<String> = pending.scan(&options).await.unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["a", "bb", "c"]);
+        let options = ScanOptions {
+            limit: Some(2),
+            reverse: true,
+            ..ScanOptions::default()
+        };
+        let keys: Vec<String> = pending.scan(&options).await.unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["d", "c"]);
+
+        kv.write_batch(pending.into_batch()).await.unwrap();
+        assert_eq!(kv.get("b").await.unwrap(), None);
+        assert_eq!(kv.get("bb").await.unwrap(), Some(Bytes::from("bb")));
+        assert_eq!(kv.get("c").await.unwrap(), Some(Bytes::from("new c")));
+        assert!(matches!(kv.ttl("d").await.unwrap(), Expiry::After(_)));
+    }
+
+    #[tokio::test]
+    async fn test_exec() {
+        let kv: Box<dyn AsyncKeyValueStore> = Box::new(SyncAdapter::in_memory(Box::new(MiniRedis::in_memory())));
+        let watches = Arc::new(Watches::default());
+        let mut transaction = Transaction::new(watches.clone());
+        let mut other = Transaction::new(watches);
+
+        assert!(matches!(transaction.exec(kv.clone()).await, Frame::Error(_)));
+        assert!(matches!(transaction.discard(), Frame::Error(_)));
+
+        assert_eq!(transaction.multi(), Frame::Simple("OK".to_string()));
+        assert!(matches!(transaction.multi(), Frame::Error(_)));
+        assert!(matches!(transaction.watch(vec!["key".to_string()]), Frame::Error(_)));
+        transaction.queue(Command::Set(Set::new("key", Bytes::from("value"), None)));
+        transaction.queue(Command::Get(Get::new("key")));
+        transaction.queue(Command::Del(Del::new(&["key", "missing"])));
+        transaction.queue(Command::Set(Set::new("key", Bytes::from("last"), None)));
+        assert_eq!(kv.get("key").await.unwrap(), None);
+        assert_eq!(
+            transaction.exec(kv.clone()).await,
+            Frame::Array(vec![
+                Frame::Simple("OK".to_string()),
+                Frame::Bulk(Bytes::from("value")),
+                Frame::Integer(1),
+                Frame::Simple("OK".to_string()),
+            ])
+        );
+        assert_eq!(kv.get("key").await.unwrap(), Some(Bytes::from("last")));
+
+        // A watched key modified by another connection aborts the
+        // transaction.
+        transaction.watch(vec!["key".to_string()]);
+        transaction.multi();
+        transaction.queue(Command::Set(Set::new("key", Bytes::from("mine"), None)));
+        other
+            .run(Command::Set(Set::new("key", Bytes::from("theirs"), None)), kv.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(transaction.exec(kv.clone()).await, Frame::Null);
+        assert_eq!(kv.get("key").await.unwrap(), Some(Bytes::from("theirs")));
+
+        // Executing the transaction unwatched the key.
+        transaction.multi();
+        transaction.queue(Command::Set(Set::new("key", Bytes::from("mine"), None)));
+        other
+            .run(Command::Set(Set::new("key", Bytes::from("theirs"), None)), kv.as_ref())
+            .await
+            .unwrap();
+        assert!(matches!(transaction.exec(kv.clone()).await, Frame::Array(_)));
+        assert_eq!(kv.get("key").await.unwrap(), Some(Bytes::from("mine")));
+
+        // So do transactions of other connections.
+        other.watch(vec!["key".to_string()]);
+        transaction.multi();
+        transaction.queue(Command::Del(Del::new(&["key"])));
+        transaction.exec(kv.clone()).await;
+        other.multi();
+        assert_eq!(other.exec(kv.clone()).await, Frame::Null);
+
+        // A command that could not be queued aborts the transaction.
+        transaction.multi();
+        transaction.queue(Command::Set(Set::new("key", Bytes::from("value"), None)));
+        transaction.fail();
+        assert!(matches!(transaction.exec(kv.clone()).await, Frame::Error(_)));
+        assert_eq!(kv.get("key").await.unwrap(), None);
+    }
+}